@@ -0,0 +1,6 @@
+fn main() {
+    println!("cargo:rerun-if-changed=src/adapter/lv2_log.c");
+    cc::Build::new()
+        .file("src/adapter/lv2_log.c")
+        .compile("olivia_lv2_log");
+}
@@ -7,8 +7,13 @@ enum Error {
     GenericController(crate::controller::ControllerError),
     TrackNotFound(IntId),
     PluginInstanceNotFound(IntId),
+    PluginParameterNotFound(IntId, usize),
+    AutomationLaneNotFound(IntId),
+    MidiMappingNotFound(IntId),
     PluginInstanceUpdateNotImplemented,
     UpdatingTrackNotImplemented,
+    NothingToUndo,
+    NothingToRedo,
 }
 
 impl std::error::Error for Error {}
@@ -31,10 +36,15 @@ impl actix_web::error::ResponseError for Error {
             Error::GenericController(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
             Error::TrackNotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
             Error::PluginInstanceNotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
+            Error::PluginParameterNotFound(_, _) => actix_web::http::StatusCode::NOT_FOUND,
+            Error::AutomationLaneNotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
+            Error::MidiMappingNotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
             Error::PluginInstanceUpdateNotImplemented => {
                 actix_web::http::StatusCode::NOT_IMPLEMENTED
             }
             Error::UpdatingTrackNotImplemented => actix_web::http::StatusCode::NOT_IMPLEMENTED,
+            Error::NothingToUndo => actix_web::http::StatusCode::CONFLICT,
+            Error::NothingToRedo => actix_web::http::StatusCode::CONFLICT,
         }
     }
 }
@@ -53,21 +63,51 @@ impl Handler {
     }
 
     fn controller_mut(&mut self) -> &mut Controller {
+        self.controller.update();
         &mut self.controller
     }
+
+    /// See `Controller::update`.
+    pub fn update(&mut self) {
+        self.controller.update();
+    }
+}
+
+pub async fn post_rescan_plugins(
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    actix_web::web::Json(handler.controller_mut().rescan_plugins())
 }
 
-pub async fn get_plugins(data: actix_web::web::Data<Mutex<Handler>>) -> impl actix_web::Responder {
+pub async fn get_unsupported_plugins(
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
     let handler = data.lock().unwrap();
     let plugins: Vec<_> = handler
         .controller()
         .plugin_factory()
-        .metadata()
+        .unsupported()
         .cloned()
         .collect();
     actix_web::web::Json(plugins)
 }
 
+/// The header that holds the number of plugins that match a query, before pagination.
+const TOTAL_COUNT_HEADER: &str = "X-Total-Count";
+
+pub async fn get_plugins(
+    query: actix_web::web::Query<crate::plugin_factory::PluginQuery>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    let (total, plugins) = handler.controller().plugin_factory().search(&query);
+    let plugins: Vec<_> = plugins.into_iter().cloned().collect();
+    actix_web::HttpResponse::Ok()
+        .header(TOTAL_COUNT_HEADER, total.to_string())
+        .json(plugins)
+}
+
 pub async fn get_tracks(data: actix_web::web::Data<Mutex<Handler>>) -> impl actix_web::Responder {
     let handler = data.lock().unwrap();
     let tracks: Vec<_> = handler.controller().tracks().cloned().collect();
@@ -111,6 +151,292 @@ pub async fn delete_track(
     Ok(actix_web::web::Json(""))
 }
 
+pub async fn put_track_input(
+    track_id: actix_web::web::Path<IntId>,
+    input: actix_web::web::Json<crate::controller::TrackInput>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    if handler.controller().track_by_id(track_id.0).is_none() {
+        return Err(Error::TrackNotFound(track_id.0));
+    }
+    handler
+        .controller_mut()
+        .set_track_input(track_id.0, input.0.clone())?;
+    Ok(actix_web::web::Json(input.0))
+}
+
+pub async fn put_track_volume(
+    track_id: actix_web::web::Path<IntId>,
+    volume: actix_web::web::Json<f32>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    if handler.controller().track_by_id(track_id.0).is_none() {
+        return Err(Error::TrackNotFound(track_id.0));
+    }
+    handler
+        .controller_mut()
+        .set_track_volume(track_id.0, volume.0)?;
+    Ok(actix_web::web::Json(volume.0))
+}
+
+pub async fn put_track_pan(
+    track_id: actix_web::web::Path<IntId>,
+    pan: actix_web::web::Json<f32>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    if handler.controller().track_by_id(track_id.0).is_none() {
+        return Err(Error::TrackNotFound(track_id.0));
+    }
+    handler.controller_mut().set_track_pan(track_id.0, pan.0)?;
+    Ok(actix_web::web::Json(pan.0))
+}
+
+pub async fn get_automation_lanes(
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    let lanes: Vec<_> = handler.controller().automation_lanes().cloned().collect();
+    actix_web::web::Json(lanes)
+}
+
+pub async fn get_automation_lane(
+    lane_id: actix_web::web::Path<IntId>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    match handler.controller().automation_lane_by_id(lane_id.0) {
+        Some(l) => Ok(actix_web::web::Json(l.clone())),
+        None => Err(Error::AutomationLaneNotFound(lane_id.0)),
+    }
+}
+
+pub async fn put_automation_lane(
+    lane_id: actix_web::web::Path<IntId>,
+    mut lane: actix_web::web::Json<crate::controller::AutomationLane>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    lane.0.id = lane_id.0;
+    handler
+        .controller_mut()
+        .set_automation_lane(lane.0.clone())?;
+    Ok::<_, Error>(actix_web::web::Json(lane.0))
+}
+
+pub async fn delete_automation_lane(
+    lane_id: actix_web::web::Path<IntId>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    if handler
+        .controller()
+        .automation_lane_by_id(lane_id.0)
+        .is_none()
+    {
+        return Err(Error::AutomationLaneNotFound(lane_id.0));
+    }
+    handler.controller_mut().delete_automation_lane(lane_id.0)?;
+    Ok(actix_web::web::Json(""))
+}
+
+pub async fn get_midi_mappings(
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    let mappings: Vec<_> = handler.controller().midi_mappings().copied().collect();
+    actix_web::web::Json(mappings)
+}
+
+pub async fn put_midi_mapping(
+    mapping_id: actix_web::web::Path<IntId>,
+    mut mapping: actix_web::web::Json<crate::controller::MidiMapping>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    mapping.0.id = mapping_id.0;
+    handler.controller_mut().set_midi_mapping(mapping.0)?;
+    Ok::<_, Error>(actix_web::web::Json(mapping.0))
+}
+
+pub async fn delete_midi_mapping(
+    mapping_id: actix_web::web::Path<IntId>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    if handler
+        .controller()
+        .midi_mapping_by_id(mapping_id.0)
+        .is_none()
+    {
+        return Err(Error::MidiMappingNotFound(mapping_id.0));
+    }
+    handler.controller_mut().delete_midi_mapping(mapping_id.0)?;
+    Ok(actix_web::web::Json(""))
+}
+
+pub async fn get_midi_learn(
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    actix_web::web::Json(handler.controller().midi_learn_status())
+}
+
+pub async fn put_midi_learn(
+    learn: actix_web::web::Json<crate::controller::MidiLearn>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    handler.controller_mut().start_midi_learn(learn.0)?;
+    Ok::<_, Error>(actix_web::web::Json(learn.0))
+}
+
+pub async fn delete_midi_learn(
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    handler.controller_mut().cancel_midi_learn();
+    actix_web::web::Json("")
+}
+
+pub async fn get_session(data: actix_web::web::Data<Mutex<Handler>>) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    actix_web::web::Json(handler.controller().session())
+}
+
+pub async fn put_session(
+    session: actix_web::web::Json<crate::controller::Session>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    handler.controller_mut().load_session(session.0)?;
+    Ok::<_, Error>(actix_web::web::Json(handler.controller().session()))
+}
+
+pub async fn get_history(data: actix_web::web::Data<Mutex<Handler>>) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    actix_web::web::Json(handler.controller().history())
+}
+
+pub async fn post_undo(data: actix_web::web::Data<Mutex<Handler>>) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    if !handler.controller_mut().undo()? {
+        return Err(Error::NothingToUndo);
+    }
+    Ok(actix_web::web::Json(handler.controller().history()))
+}
+
+pub async fn post_redo(data: actix_web::web::Data<Mutex<Handler>>) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    if !handler.controller_mut().redo()? {
+        return Err(Error::NothingToRedo);
+    }
+    Ok(actix_web::web::Json(handler.controller().history()))
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct UndoGroup {
+    description: String,
+}
+
+pub async fn put_undo_group(
+    group: actix_web::web::Json<UndoGroup>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    handler
+        .controller_mut()
+        .begin_undo_group(&group.description);
+    actix_web::web::Json("")
+}
+
+pub async fn delete_undo_group(
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    handler.controller_mut().end_undo_group()?;
+    Ok::<_, Error>(actix_web::web::Json(handler.controller().history()))
+}
+
+pub async fn get_latency(data: actix_web::web::Data<Mutex<Handler>>) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    actix_web::web::Json(handler.controller().latency())
+}
+
+pub async fn get_transport(
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    actix_web::web::Json(handler.controller().transport())
+}
+
+pub async fn put_transport(
+    transport: actix_web::web::Json<crate::controller::Transport>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    handler.controller_mut().set_transport(transport.0);
+    actix_web::web::Json(transport.0)
+}
+
+pub async fn get_tempo_map(
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    let tempo_map: Vec<_> = handler.controller().tempo_map().cloned().collect();
+    actix_web::web::Json(tempo_map)
+}
+
+pub async fn put_tempo_map(
+    tempo_map: actix_web::web::Json<Vec<crate::controller::TempoChange>>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    handler.controller_mut().set_tempo_map(tempo_map.0)?;
+    let tempo_map: Vec<_> = handler.controller().tempo_map().cloned().collect();
+    Ok::<_, Error>(actix_web::web::Json(tempo_map))
+}
+
+pub async fn get_metronome(
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    actix_web::web::Json(handler.controller().metronome())
+}
+
+pub async fn put_metronome(
+    metronome: actix_web::web::Json<crate::controller::Metronome>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    handler.controller_mut().set_metronome(metronome.0)?;
+    Ok::<_, Error>(actix_web::web::Json(metronome.0))
+}
+
+pub async fn get_midi_sync(
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    actix_web::web::Json(handler.controller().midi_sync())
+}
+
+pub async fn put_midi_sync(
+    midi_sync: actix_web::web::Json<crate::controller::MidiSync>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    handler.controller_mut().set_midi_sync(midi_sync.0);
+    actix_web::web::Json(midi_sync.0)
+}
+
+pub async fn get_clips(data: actix_web::web::Data<Mutex<Handler>>) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    let clips: Vec<_> = handler.controller_mut().clips().cloned().collect();
+    actix_web::web::Json(clips)
+}
+
 pub async fn get_plugin_instances(
     data: actix_web::web::Data<Mutex<Handler>>,
 ) -> impl actix_web::Responder {
@@ -133,6 +459,91 @@ pub async fn get_plugin_instance(
     }
 }
 
+pub async fn get_plugin_instance_status(
+    plugin_instance_id: actix_web::web::Path<IntId>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    match handler
+        .controller()
+        .plugin_instance_status(plugin_instance_id.0)
+    {
+        Ok(status) => Ok(actix_web::web::Json(status)),
+        Err(_) => Err(Error::PluginInstanceNotFound(plugin_instance_id.0)),
+    }
+}
+
+pub async fn put_plugin_bypass(
+    plugin_instance_id: actix_web::web::Path<IntId>,
+    bypass: actix_web::web::Json<crate::controller::PluginBypass>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    if handler
+        .controller()
+        .plugin_instance_by_id(plugin_instance_id.0)
+        .is_none()
+    {
+        return Err(Error::PluginInstanceNotFound(plugin_instance_id.0));
+    }
+    handler
+        .controller_mut()
+        .set_plugin_bypass(plugin_instance_id.0, bypass.0)?;
+    Ok(actix_web::web::Json(bypass.0))
+}
+
+pub async fn get_plugin_parameters(
+    plugin_instance_id: actix_web::web::Path<IntId>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let handler = data.lock().unwrap();
+    match handler.controller().plugin_parameters(plugin_instance_id.0) {
+        Ok(parameters) => Ok(actix_web::web::Json(parameters)),
+        Err(_) => Err(Error::PluginInstanceNotFound(plugin_instance_id.0)),
+    }
+}
+
+pub async fn put_plugin_parameter(
+    path: actix_web::web::Path<(IntId, usize)>,
+    value: actix_web::web::Json<f32>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let (plugin_instance_id, index) = path.into_inner();
+    let mut handler = data.lock().unwrap();
+    match handler
+        .controller_mut()
+        .set_plugin_parameter(plugin_instance_id, index, value.0)
+    {
+        Ok(value) => Ok(actix_web::web::Json(value)),
+        Err(crate::controller::ControllerError::PluginInstanceDoesNotExist(id)) => {
+            Err(Error::PluginInstanceNotFound(id))
+        }
+        Err(crate::controller::ControllerError::PluginParameterDoesNotExist(id, index)) => {
+            Err(Error::PluginParameterNotFound(id, index))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn put_plugin_audio_ports(
+    plugin_instance_id: actix_web::web::Path<IntId>,
+    mapping: actix_web::web::Json<crate::controller::AudioPortMapping>,
+    data: actix_web::web::Data<Mutex<Handler>>,
+) -> impl actix_web::Responder {
+    let mut handler = data.lock().unwrap();
+    if handler
+        .controller()
+        .plugin_instance_by_id(plugin_instance_id.0)
+        .is_none()
+    {
+        return Err(Error::PluginInstanceNotFound(plugin_instance_id.0));
+    }
+    handler
+        .controller_mut()
+        .set_plugin_audio_port_mapping(plugin_instance_id.0, mapping.0.clone())?;
+    Ok(actix_web::web::Json(mapping.0))
+}
+
 pub async fn put_plugin_instance(
     plugin_instance_id: actix_web::web::Path<IntId>,
     mut plugin_instance: actix_web::web::Json<crate::controller::PluginInstance>,
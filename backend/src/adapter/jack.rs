@@ -1,17 +1,40 @@
+use crate::adapter::jack_transport::{self, TransportSync};
 use crate::controller;
 use crate::io_backend::IoBackend;
 use std::convert::TryFrom;
 
+/// The maximum number of audio input ports that may be registered.
+pub const MAX_AUDIO_INPUTS: usize = 16;
+
+/// The largest MIDI message, such as a SysEx message, that can be written to a MIDI output port.
+/// Larger messages are dropped.
+const MAX_MIDI_MESSAGE_SIZE: usize = 32768;
+
 pub struct JackBackend {
     client: Option<jack::Client>,
     midi_input: jack::Port<jack::MidiIn>,
+    audio_inputs: Vec<jack::Port<jack::AudioIn>>,
     temp_midi_buffer: Vec<olivia_core::TimedMidi<'static>>,
     outputs: [jack::Port<jack::AudioOut>; 2],
-    processor: controller::Processor,
+    metronome_output: jack::Port<jack::AudioOut>,
+    midi_output: jack::Port<jack::MidiOut>,
+    midi_sync_output: jack::Port<jack::MidiOut>,
+    // Scratch space for encoding MIDI output messages.
+    midi_message_buffer: Vec<u8>,
+    transport_sync: TransportSync,
+    // The processor is boxed so that its address stays the same when the backend is moved. This
+    // allows the timebase callback to reference it.
+    processor: Box<controller::Processor>,
 }
 
 impl JackBackend {
-    pub fn new(processor: controller::Processor) -> Result<JackBackend, jack::Error> {
+    /// Create a new JACK backend with `num_audio_inputs` audio input ports. At most
+    /// `MAX_AUDIO_INPUTS` input ports are registered.
+    pub fn new(
+        mut processor: controller::Processor,
+        num_audio_inputs: usize,
+        transport_sync: TransportSync,
+    ) -> Result<JackBackend, jack::Error> {
         initialize_logging();
         let (client, status) = jack::Client::new("olivia", jack::ClientOptions::NO_START_SERVER)?;
         info!(
@@ -21,10 +44,23 @@ impl JackBackend {
         );
 
         let midi_input = client.register_port("midi_input", jack::MidiIn::default())?;
+        if num_audio_inputs > MAX_AUDIO_INPUTS {
+            warn!(
+                "Requested {} audio inputs but only {} are supported.",
+                num_audio_inputs, MAX_AUDIO_INPUTS
+            );
+        }
+        let audio_inputs = (0..num_audio_inputs.min(MAX_AUDIO_INPUTS))
+            .map(|i| client.register_port(&format!("input_{}", i + 1), jack::AudioIn::default()))
+            .collect::<Result<Vec<_>, _>>()?;
         let outputs = [
             client.register_port("output_l", jack::AudioOut::default())?,
             client.register_port("output_r", jack::AudioOut::default())?,
         ];
+        let metronome_output = client.register_port("metronome", jack::AudioOut::default())?;
+        let midi_output = client.register_port("midi_output", jack::MidiOut::default())?;
+        let midi_sync_output =
+            client.register_port("midi_sync_output", jack::MidiOut::default())?;
         // This is a somewhat large but arbitrary number.
         let temp_midi_buffer_size = 1_000_000;
         info!(
@@ -32,12 +68,20 @@ impl JackBackend {
             temp_midi_buffer_size
         );
         let temp_midi_buffer = Vec::with_capacity(temp_midi_buffer_size);
+        info!("Using {:?} transport sync.", transport_sync);
+        processor.set_external_transport(transport_sync != TransportSync::Internal);
         Ok(JackBackend {
             client: Some(client),
             midi_input,
+            audio_inputs,
             temp_midi_buffer,
             outputs,
-            processor,
+            metronome_output,
+            midi_output,
+            midi_sync_output,
+            midi_message_buffer: vec![0; MAX_MIDI_MESSAGE_SIZE],
+            transport_sync,
+            processor: Box::new(processor),
         })
     }
 }
@@ -55,36 +99,113 @@ impl IoBackend for JackBackend {
         self.client.as_ref().unwrap().sample_rate() as f32
     }
 
+    fn num_audio_inputs(&self) -> usize {
+        self.audio_inputs.len()
+    }
+
     fn run_process_loop(self) {
         let mut s = self;
         let client = s.client.take().unwrap();
+        if s.transport_sync == TransportSync::TimebaseMaster {
+            // The processor is boxed and owned by the async client so it lives as long as the
+            // client and is only touched by the process thread.
+            let processor: *const controller::Processor = s.processor.as_ref();
+            match unsafe { jack_transport::register_timebase_master(&client, processor) } {
+                Ok(()) => info!("Registered as JACK timebase master."),
+                Err(e) => error!("Failed to become JACK timebase master: {:?}", e),
+            }
+        }
         let _async_client = client.activate_async((), s).unwrap();
         std::thread::park();
     }
 }
 
 impl jack::ProcessHandler for JackBackend {
-    fn process(&mut self, _: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
-        self.temp_midi_buffer.clear();
+    fn process(&mut self, client: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+        self.sync_transport(client);
+        // SysEx messages borrow from the JACK port buffer so the MIDI buffer is only borrowed for
+        // the duration of this process call.
+        let mut midi = olivia_core::recycle_midi_buffer(std::mem::take(&mut self.temp_midi_buffer));
         for raw_midi in self.midi_input.iter(ps) {
-            if let Ok(Some(message)) = wmidi::MidiMessage::try_from(raw_midi.bytes)
-                .map(wmidi::MidiMessage::drop_unowned_sysex)
-            {
-                self.temp_midi_buffer.push(olivia_core::TimedMidi {
+            if midi.len() == midi.capacity() {
+                break;
+            }
+            if let Ok(message) = wmidi::MidiMessage::try_from(raw_midi.bytes) {
+                midi.push(olivia_core::TimedMidi {
                     frame: raw_midi.time as usize,
                     message,
                 });
             }
         }
+        let mut inputs: [&[f32]; MAX_AUDIO_INPUTS] = [&[]; MAX_AUDIO_INPUTS];
+        for (input, port) in inputs.iter_mut().zip(self.audio_inputs.iter()) {
+            *input = port.as_slice(ps);
+        }
         let (out_left, out_right) = match &mut self.outputs {
             [left, right] => (left.as_mut_slice(ps), right.as_mut_slice(ps)),
         };
-        self.processor
-            .process(&self.temp_midi_buffer, out_left, out_right);
+        self.processor.process(
+            &inputs[..self.audio_inputs.len()],
+            &midi,
+            out_left,
+            out_right,
+        );
+        self.temp_midi_buffer = olivia_core::recycle_midi_buffer(midi);
+        let metronome_out = self.metronome_output.as_mut_slice(ps);
+        match self.processor.metronome_output() {
+            Some(m) => {
+                let len = metronome_out.len().min(m.len());
+                metronome_out[..len].copy_from_slice(&m[..len]);
+            }
+            None => metronome_out.iter_mut().for_each(|o| *o = 0.0),
+        }
+        write_midi(
+            &mut self.midi_output.writer(ps),
+            self.processor.midi_output(),
+            &mut self.midi_message_buffer,
+        );
+        write_midi(
+            &mut self.midi_sync_output.writer(ps),
+            self.processor.midi_sync_output(),
+            &mut self.midi_message_buffer,
+        );
+        if let Some(playing) = self.processor.take_transport_request() {
+            jack_transport::set_rolling(client, playing);
+        }
         jack::Control::Continue
     }
 }
 
+impl JackBackend {
+    /// Update the processor transport from the JACK transport.
+    fn sync_transport(&mut self, client: &jack::Client) {
+        if self.transport_sync == TransportSync::Internal {
+            return;
+        }
+        let state = jack_transport::query(client);
+        self.processor.sync_transport(state.rolling, state.frame);
+        if self.transport_sync == TransportSync::Follow {
+            if let Some(bbt) = state.bbt {
+                self.processor
+                    .sync_tempo(bbt.bpm, bbt.beats_per_bar, state.frame, bbt.beat);
+            }
+        }
+    }
+}
+
+/// Write MIDI messages to a JACK MIDI port, encoding each message into `buffer`. Messages that
+/// don't fit in `buffer` or in the port buffer are dropped.
+fn write_midi(writer: &mut jack::MidiWriter, midi: &[olivia_core::TimedMidi], buffer: &mut [u8]) {
+    for m in midi.iter() {
+        if let Ok(len) = m.message.copy_to_slice(buffer) {
+            let _ = writer.write(&jack::RawMidi {
+                time: m.frame as u32,
+                bytes: &buffer[..len],
+            });
+        }
+    }
+}
+
 fn initialize_logging() {
     jack::set_error_callback(error_callback);
     jack::set_info_callback(info_callback);
@@ -0,0 +1,149 @@
+use crate::controller;
+use jack_sys as j;
+
+/// The number of ticks per beat reported when acting as the timebase master.
+const TICKS_PER_BEAT: f64 = 1920.0;
+
+/// How the Olivia transport relates to the JACK transport.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransportSync {
+    /// Olivia runs its own transport and ignores the JACK transport.
+    #[default]
+    Internal,
+    /// Follow the JACK transport. Tempo and meter are taken from the timebase master if there is
+    /// one.
+    Follow,
+    /// Follow the JACK transport and provide bar, beat and tick information to other JACK clients
+    /// from the Olivia tempo map.
+    TimebaseMaster,
+}
+
+/// Bar, beat and tick information provided by a timebase master.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Bbt {
+    pub bpm: f64,
+    pub beats_per_bar: u32,
+    /// The number of beats, including fractional beats, since the start of the song.
+    pub beat: f64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TransportState {
+    pub rolling: bool,
+    pub frame: u64,
+    pub bbt: Option<Bbt>,
+}
+
+/// Query the current JACK transport state. This is safe to call from the process thread.
+pub fn query(client: &jack::Client) -> TransportState {
+    let mut pos = j::jack_position_t::default();
+    let state = unsafe { j::jack_transport_query(client.raw(), &mut pos) };
+    let valid = pos.valid;
+    let bbt = if valid & j::JackPositionBBT != 0 {
+        let beats_per_bar = pos.beats_per_bar;
+        let ticks_per_beat = pos.ticks_per_beat;
+        let (bar, beat, tick) = (pos.bar, pos.beat, pos.tick);
+        let tick_fraction = if ticks_per_beat > 0.0 {
+            f64::from(tick) / ticks_per_beat
+        } else {
+            0.0
+        };
+        Some(Bbt {
+            bpm: pos.beats_per_minute,
+            beats_per_bar: beats_per_bar.round().max(1.0) as u32,
+            beat: f64::from(bar - 1) * f64::from(beats_per_bar)
+                + f64::from(beat - 1)
+                + tick_fraction,
+        })
+    } else {
+        None
+    };
+    TransportState {
+        rolling: state == j::JackTransportRolling,
+        frame: u64::from(pos.frame),
+        bbt,
+    }
+}
+
+/// Start or stop the JACK transport. This is safe to call from the process thread.
+pub fn set_rolling(client: &jack::Client, rolling: bool) {
+    unsafe {
+        if rolling {
+            j::jack_transport_start(client.raw());
+        } else {
+            j::jack_transport_stop(client.raw());
+        }
+    }
+}
+
+/// Register as the JACK timebase master. Bar, beat and tick information is computed from the
+/// tempo map of `processor`.
+///
+/// # Safety
+/// `processor` must outlive the client and must only be mutated from the JACK process thread.
+pub unsafe fn register_timebase_master(
+    client: &jack::Client,
+    processor: *const controller::Processor,
+) -> Result<(), jack::Error> {
+    let conditional = 0;
+    let res = j::jack_set_timebase_callback(
+        client.raw(),
+        conditional,
+        Some(timebase_callback),
+        processor as *mut std::ffi::c_void,
+    );
+    match res {
+        0 => Ok(()),
+        _ => Err(jack::Error::CallbackRegistrationError),
+    }
+}
+
+unsafe extern "C" fn timebase_callback(
+    _: j::jack_transport_state_t,
+    _: j::jack_nframes_t,
+    pos: *mut j::jack_position_t,
+    _: std::os::raw::c_int,
+    arg: *mut std::ffi::c_void,
+) {
+    let processor = arg as *const controller::Processor;
+    if let (Some(processor), Some(pos)) = (processor.as_ref(), pos.as_mut()) {
+        fill_bbt(processor.tempo_map(), pos);
+    }
+}
+
+/// Fill in the bar, beat and tick information of `pos` for the frame in `pos`.
+fn fill_bbt(tempo_map: &olivia_core::tempo_map::TempoMap, pos: &mut j::jack_position_t) {
+    let frame = f64::from(pos.frame);
+    let tempo = tempo_map.tempo_at_frame(frame);
+    let position = tempo_map.position_at_frame(frame);
+    let bar_start_beat = tempo_map.beat_at_frame(tempo_map.frame_at_bar(position.bar));
+    pos.valid |= j::JackPositionBBT;
+    pos.bar = position.bar as i32 + 1;
+    pos.beat = position.beat as i32 + 1;
+    pos.tick = (position.beat_fraction * TICKS_PER_BEAT) as i32;
+    pos.bar_start_tick = bar_start_beat * TICKS_PER_BEAT;
+    pos.beats_per_bar = tempo.beats_per_bar as f32;
+    pos.beat_type = 4.0;
+    pos.ticks_per_beat = TICKS_PER_BEAT;
+    pos.beats_per_minute = tempo.bpm;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_bbt_is_one_based() {
+        let tempo_map = olivia_core::tempo_map::TempoMap::new(1000.0, 120.0, 3);
+        let mut pos = j::jack_position_t {
+            frame: 2250,
+            ..Default::default()
+        };
+        fill_bbt(&tempo_map, &mut pos);
+        let (bar, beat, tick, bpm) = (pos.bar, pos.beat, pos.tick, pos.beats_per_minute);
+        assert_eq!((bar, beat, tick, bpm), (2, 2, 960, 120.0));
+        let bar_start_tick = pos.bar_start_tick;
+        assert_eq!(bar_start_tick, 3.0 * TICKS_PER_BEAT);
+    }
+}
@@ -1,45 +1,146 @@
-use crate::plugin_factory::{PluginBuilder, PluginBuilderError, PluginMetadata};
+use crate::adapter::{lv2_log, lv2_options, lv2_sandbox, lv2_worker};
+use crate::config::Lv2Config;
+use crate::plugin_factory::{
+    AudioSettings, PluginBuilder, PluginBuilderError, PluginKind, PluginMetadata, PluginScan,
+    PluginSource, PortSummary, UnsupportedPlugin,
+};
+use olivia_core::plugin::{ParameterChoice, ParameterInfo};
 use sha3::Digest;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicU32;
 use std::sync::Arc;
 use std::sync::RwLock;
 
-pub fn load_plugins() -> Vec<Lv2PluginBuilder> {
-    let w = lilv::World::with_load_all();
-    let mut plugin_builders = Vec::new();
-    let lv2_resources = Arc::new(Lv2Resources {
-        audio_port: w.new_uri("http://lv2plug.in/ns/lv2core#AudioPort"),
-        atom_port: w.new_uri("http://lv2plug.in/ns/ext/atom#AtomPort"),
-        control_port: w.new_uri("http://lv2plug.in/ns/lv2core#ControlPort"),
-        input_port: w.new_uri("http://lv2plug.in/ns/lv2core#InputPort"),
-        output_port: w.new_uri("http://lv2plug.in/ns/lv2core#OutputPort"),
-        urid_map: UridMapFeature::default(),
-    });
-    let supported_features = [w.new_uri(UridMapFeature::URI)];
-    for plugin in w.plugins().iter() {
-        if plugin.uri().as_uri().is_none() {
-            warn!("Could not get uri from {:?}.", plugin.uri().turtle_token());
-            continue;
-        }
-        let mut supported = true;
+/// The LV2 plugins installed on the system. Bundles that are added to or removed from the LV2
+/// path are picked up by `rescan`.
+pub struct Lv2PluginSource {
+    config: Lv2Config,
+    world: lilv::World,
+    lv2_resources: Arc<Lv2Resources>,
+    supported_features: Vec<lilv::Node>,
+    // The bundle directories that have been loaded into the world.
+    bundles: HashSet<PathBuf>,
+    // The URIs of the plugins that have been reported by `rescan` and why they are unsupported, if
+    // they are.
+    plugins: HashMap<String, Option<UnsupportedPlugin>>,
+}
+
+impl Lv2PluginSource {
+    pub fn new(config: Lv2Config) -> Lv2PluginSource {
+        let world = lilv::World::new();
+        if !config.paths.is_empty() {
+            let path = std::env::join_paths(config.paths.iter()).unwrap_or_default();
+            info!("Searching for LV2 plugins in {:?}.", path);
+            world.set_option(
+                LILV_OPTION_LV2_PATH,
+                &world.new_string(&path.to_string_lossy()),
+            );
+        }
+        world.load_all();
+        let urid_map = UridMapFeature::default();
+        let log = lv2_log::LogFeature::new(&|uri| urid_map.map_static(uri));
+        let lv2_resources = Arc::new(Lv2Resources {
+            audio_port: world.new_uri("http://lv2plug.in/ns/lv2core#AudioPort"),
+            atom_port: world.new_uri("http://lv2plug.in/ns/ext/atom#AtomPort"),
+            control_port: world.new_uri("http://lv2plug.in/ns/lv2core#ControlPort"),
+            input_port: world.new_uri("http://lv2plug.in/ns/lv2core#InputPort"),
+            output_port: world.new_uri("http://lv2plug.in/ns/lv2core#OutputPort"),
+            enabled_designation: world.new_uri("http://lv2plug.in/ns/lv2core#enabled"),
+            midi_event: world.new_uri("http://lv2plug.in/ns/ext/midi#MidiEvent"),
+            unit: world.new_uri("http://lv2plug.in/ns/extensions/units#unit"),
+            unit_symbol: world.new_uri("http://lv2plug.in/ns/extensions/units#symbol"),
+            urid_map,
+            log,
+        });
+        let supported_features = vec![
+            world.new_uri(UridMapFeature::URI),
+            world.new_uri(UridMapFeature::UNMAP_URI),
+            world.new_uri(lv2_worker::SCHEDULE_URI),
+            world.new_uri(lv2_options::OPTIONS_URI),
+            world.new_uri(lv2_options::BOUNDED_BLOCK_LENGTH_URI),
+            world.new_uri(lv2_log::LOG_URI),
+        ];
+        // Everything on the LV2 path was loaded by `load_all`.
+        let bundles = find_bundles(&lv2_path(&config));
+        Lv2PluginSource {
+            config,
+            world,
+            lv2_resources,
+            supported_features,
+            bundles,
+            plugins: HashMap::new(),
+        }
+    }
+
+    /// Load bundles that were added to the LV2 path and unload bundles that were removed.
+    fn sync_bundles(&mut self) {
+        let bundles = find_bundles(&lv2_path(&self.config));
+        for removed in self.bundles.difference(&bundles) {
+            info!("Unloading LV2 bundle {:?}.", removed);
+            if !self.world.unload_bundle(&self.bundle_uri(removed)) {
+                warn!("Failed to unload LV2 bundle {:?}.", removed);
+            }
+        }
+        for added in bundles.difference(&self.bundles) {
+            info!("Loading LV2 bundle {:?}.", added);
+            self.world.load_bundle(&self.bundle_uri(added));
+        }
+        self.bundles = bundles;
+    }
+
+    fn bundle_uri(&self, bundle: &Path) -> lilv::Node {
+        // Bundle URIs must end with a slash.
+        let path = format!("{}/", bundle.to_string_lossy().trim_end_matches('/'));
+        self.world.new_file_uri(None, &path)
+    }
+
+    /// Check that the plugin can be hosted and create a builder for it.
+    fn check_plugin(
+        &self,
+        uri: &str,
+        plugin: lilv::Plugin,
+    ) -> Result<Lv2PluginBuilder, Box<UnsupportedPlugin>> {
+        let mut missing_features = Vec::new();
         if let Some(required_features) = plugin.required_features() {
             for feature in required_features.iter() {
-                if supported_features.iter().find(|f| *f == &feature).is_none() {
-                    supported = false;
+                if !self.supported_features.iter().any(|f| f == &feature) {
                     error!(
                         "LV2 plugin {:?} requires feature {:?}.",
                         plugin.uri(),
                         feature
                     );
+                    missing_features.push(
+                        feature
+                            .as_uri()
+                            .map(str::to_string)
+                            .unwrap_or_else(|| feature.turtle_token()),
+                    );
                 }
             }
         }
+        let unsupported_ports: Vec<String> = plugin
+            .ports()
+            .filter(|p| !self.lv2_resources.is_connectable(p))
+            .map(|p| {
+                let symbol = p.symbol().as_str().unwrap_or("").to_string();
+                error!(
+                    "LV2 plugin {:?} has port {:?} of unsupported type.",
+                    plugin.uri(),
+                    symbol
+                );
+                symbol
+            })
+            .collect();
+        let verification_failed = !plugin.verify();
+        if verification_failed {
+            error!("LV2 plugin {:?} failed verification.", plugin.uri());
+        }
         if let Some(optional_features) = plugin.optional_features() {
             for feature in optional_features.iter() {
-                if supported_features.iter().find(|f| *f == &feature).is_none() {
+                if !self.supported_features.iter().any(|f| f == &feature) {
                     warn!(
                         "LV2 plugin {:?} has optional feature {:?}.",
                         plugin.uri(),
@@ -48,34 +149,279 @@ pub fn load_plugins() -> Vec<Lv2PluginBuilder> {
                 }
             }
         }
-        if !supported {
-            continue;
+        let display_name = plugin
+            .name()
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| uri.to_string());
+        if !missing_features.is_empty() || !unsupported_ports.is_empty() || verification_failed {
+            return Err(Box::new(UnsupportedPlugin {
+                id: create_id(uri),
+                uri: uri.to_string(),
+                display_name,
+                missing_features,
+                unsupported_ports,
+                verification_failed,
+            }));
         }
+        let class_hierarchy = class_hierarchy(&self.world, &plugin.class());
+        let ports = port_summary(&self.lv2_resources, &plugin);
+        let parameters = parameters(&self.world, &self.lv2_resources, &plugin);
+        let kind = if class_hierarchy.iter().any(|c| c == INSTRUMENT_CLASS_URI) {
+            PluginKind::Instrument
+        } else if class_hierarchy.iter().any(|c| c == ANALYSER_CLASS_URI) {
+            PluginKind::Analyzer
+        } else {
+            PluginKind::from_ports(&ports)
+        };
+        let node_to_string = |n: lilv::Node| n.as_str().or_else(|| n.as_uri()).map(str::to_string);
         let metadata = PluginMetadata {
             // TODO(wmedrano): Fix the URI.
-            id: create_id(plugin.uri().as_uri().unwrap()),
-            display_name: plugin
-                .name()
-                .as_str()
-                .map(str::to_string)
-                .unwrap_or_else(|| plugin.uri().as_uri().unwrap().to_string()),
+            id: create_id(uri),
+            display_name,
+            class: plugin.class().label().as_str().map(str::to_string),
+            class_hierarchy,
+            author: plugin.author_name().and_then(node_to_string),
+            project: plugin.project().and_then(node_to_string),
+            ports,
+            has_latency: plugin.has_latency(),
+            kind,
+            uri: Some(uri.to_string()),
+        };
+        let sandbox_lv2_path = if self.config.is_sandboxed(uri) {
+            Some(lv2_path(&self.config))
+        } else {
+            None
         };
-        let builder = Lv2PluginBuilder {
-            lv2_resources: lv2_resources.clone(),
+        Ok(Lv2PluginBuilder {
+            lv2_resources: self.lv2_resources.clone(),
+            audio_settings: AudioSettings::default(),
             metadata,
+            parameters,
             plugin,
+            sandbox_lv2_path,
+        })
+    }
+
+    /// Build the plugin with `uri` in this process, regardless of the sandbox settings. Used by
+    /// the plugin host.
+    pub fn build_unsandboxed(
+        &self,
+        uri: &str,
+        audio_settings: AudioSettings,
+    ) -> Result<Box<dyn olivia_core::plugin::PluginInstance>, String> {
+        let plugin = self
+            .world
+            .plugins()
+            .get_by_uri(&self.world.new_uri(uri))
+            .ok_or_else(|| format!("LV2 plugin {} not found", uri))?;
+        let mut builder = self.check_plugin(uri, plugin).map_err(|u| u.to_string())?;
+        builder.audio_settings = audio_settings;
+        builder.sandbox_lv2_path = None;
+        builder.build().map_err(|e| e.to_string())
+    }
+}
+
+impl PluginSource for Lv2PluginSource {
+    fn rescan(&mut self) -> PluginScan {
+        self.sync_bundles();
+        let mut scan = PluginScan::default();
+        let mut plugins = HashMap::new();
+        for plugin in self.world.plugins().iter() {
+            let uri = match plugin.uri().as_uri() {
+                Some(uri) => uri.to_string(),
+                None => {
+                    warn!("Could not get uri from {:?}.", plugin.uri().turtle_token());
+                    continue;
+                }
+            };
+            if !self.config.is_allowed(&uri) {
+                debug!("Skipping LV2 plugin {:?} since it is not allowed.", uri);
+                continue;
+            }
+            let unsupported = match self.plugins.remove(&uri) {
+                Some(unsupported) => unsupported,
+                None => match self.check_plugin(&uri, plugin) {
+                    Ok(builder) => {
+                        scan.added.push(Box::new(builder));
+                        None
+                    }
+                    Err(unsupported) => Some(*unsupported),
+                },
+            };
+            scan.unsupported.extend(unsupported.iter().cloned());
+            plugins.insert(uri, unsupported);
+        }
+        // Plugins that were not found again have been removed.
+        scan.removed = self
+            .plugins
+            .drain()
+            .filter(|(_, unsupported)| unsupported.is_none())
+            .map(|(uri, _)| create_id(&uri))
+            .collect();
+        self.plugins = plugins;
+        scan
+    }
+}
+
+/// The directories that are searched for LV2 bundles. Uses the configured paths, or `LV2_PATH`
+/// if there are none.
+fn lv2_path(config: &Lv2Config) -> Vec<PathBuf> {
+    if !config.paths.is_empty() {
+        return config.paths.clone();
+    }
+    if let Some(path) = std::env::var_os("LV2_PATH") {
+        return std::env::split_paths(&path).collect();
+    }
+    let mut path = Vec::new();
+    if let Some(home) = std::env::var_os("HOME") {
+        path.push(PathBuf::from(home).join(".lv2"));
+    }
+    path.push(PathBuf::from("/usr/local/lib/lv2"));
+    path.push(PathBuf::from("/usr/lib/lv2"));
+    path
+}
+
+/// Find the bundle directories, which end with ".lv2", in `lv2_path`.
+fn find_bundles(lv2_path: &[PathBuf]) -> HashSet<PathBuf> {
+    let mut bundles = HashSet::new();
+    for dir in lv2_path {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(_) => continue,
         };
-        plugin_builders.push(builder);
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.is_dir() && path.extension().is_some_and(|e| e == "lv2") {
+                bundles.insert(path);
+            }
+        }
     }
-    plugin_builders
+    bundles
 }
 
 type PortIndex = usize;
 
+/// The lilv world option for the directories to search for LV2 bundles.
+const LILV_OPTION_LV2_PATH: &str = "http://drobilla.net/ns/lilv#lv2-path";
+
+/// The URI of the LV2 instrument plugin class.
+const INSTRUMENT_CLASS_URI: &str = "http://lv2plug.in/ns/lv2core#InstrumentPlugin";
+
+/// The URI of the LV2 analyser plugin class.
+const ANALYSER_CLASS_URI: &str = "http://lv2plug.in/ns/lv2core#AnalyserPlugin";
+
+/// The URIs of `class` and its parent classes, most specific first.
+fn class_hierarchy(world: &lilv::World, class: &lilv::PluginClass) -> Vec<String> {
+    let classes = world.plugin_classes();
+    let mut hierarchy = Vec::new();
+    let mut class = Some(class.uri());
+    while let Some(uri) = class.take() {
+        let uri_str = match uri.as_uri() {
+            Some(s) => s.to_string(),
+            None => break,
+        };
+        // Guard against cycles in malformed class descriptions.
+        if hierarchy.contains(&uri_str) {
+            break;
+        }
+        hierarchy.push(uri_str);
+        class = classes.get_by_uri(&uri).and_then(|c| c.parent_uri());
+    }
+    hierarchy
+}
+
+fn port_summary(lv2_resources: &Lv2Resources, plugin: &lilv::Plugin) -> PortSummary {
+    let mut summary = PortSummary::default();
+    for port in plugin.ports() {
+        let is_input = lv2_resources.is_input(&port);
+        if lv2_resources.is_audio(&port) {
+            if is_input {
+                summary.audio_inputs += 1;
+            } else {
+                summary.audio_outputs += 1;
+            }
+        } else if lv2_resources.is_atom(&port) {
+            if is_input {
+                summary.midi_inputs += 1;
+            }
+        } else if lv2_resources.is_control(&port) && is_input {
+            summary.controls += 1;
+        }
+    }
+    summary
+}
+
+/// Describe the control input ports of the plugin, except for the port designated as
+/// `lv2:enabled`, which is controlled by bypassing the plugin.
+fn parameters(
+    world: &lilv::World,
+    lv2_resources: &Lv2Resources,
+    plugin: &lilv::Plugin,
+) -> Vec<ParameterInfo> {
+    let enabled_port = plugin
+        .port_by_designation(
+            Some(&lv2_resources.input_port),
+            &lv2_resources.enabled_designation,
+        )
+        .map(|p| p.index());
+    plugin
+        .ports()
+        .filter(|p| lv2_resources.is_control(p) && lv2_resources.is_input(p))
+        .filter(|p| Some(p.index()) != enabled_port)
+        .map(|port| {
+            let symbol = port.symbol().as_str().unwrap_or_default().to_string();
+            let name = port
+                .name()
+                .and_then(|n| n.as_str().map(str::to_string))
+                .unwrap_or_else(|| symbol.clone());
+            let range = port.range();
+            let mut info = ParameterInfo::new(
+                port.index(),
+                &symbol,
+                &name,
+                range.min,
+                range.max,
+                range.default,
+            );
+            // Units are either a resource with a symbol, like `units:db`, or a plain string.
+            info.unit = port.get(&lv2_resources.unit).and_then(|unit| {
+                let symbol = world.get(Some(&unit), Some(&lv2_resources.unit_symbol), None);
+                symbol
+                    .as_ref()
+                    .and_then(lilv::Node::as_str)
+                    .or_else(|| unit.as_str())
+                    .map(str::to_string)
+            });
+            if let Some(scale_points) = port.scale_points() {
+                info.enumeration = scale_points
+                    .iter()
+                    .filter_map(|point| {
+                        let value = match point.value().to_value() {
+                            lilv::Value::Float(f) => f,
+                            lilv::Value::Int(i) => i as f32,
+                            _ => return None,
+                        };
+                        let label = point.label().as_str()?.to_string();
+                        Some(ParameterChoice { value, label })
+                    })
+                    .collect();
+                info.enumeration.sort_by(|a, b| a.value.total_cmp(&b.value));
+            }
+            info
+        })
+        .collect()
+}
+
 pub struct Lv2PluginBuilder {
     lv2_resources: std::sync::Arc<Lv2Resources>,
+    audio_settings: AudioSettings,
     plugin: lilv::Plugin,
     metadata: PluginMetadata,
+    parameters: Vec<ParameterInfo>,
+    // If set, the plugin is run by a plugin host in a child process that searches these
+    // directories for LV2 bundles.
+    sandbox_lv2_path: Option<Vec<PathBuf>>,
 }
 
 impl PluginBuilder for Lv2PluginBuilder {
@@ -83,34 +429,124 @@ impl PluginBuilder for Lv2PluginBuilder {
         self.metadata.clone()
     }
 
+    fn set_audio_settings(&mut self, settings: AudioSettings) {
+        self.audio_settings = settings;
+    }
+
     fn build(
         &self,
     ) -> Result<
         Box<dyn olivia_core::plugin::PluginInstance>,
         crate::plugin_factory::PluginBuilderError,
     > {
-        let features: Vec<*const lv2_raw::LV2Feature> = vec![
-            self.lv2_resources.urid_map.as_lv2_feature(),
-            std::ptr::null(),
+        if let Some(lv2_path) = self.sandbox_lv2_path.as_ref() {
+            let uri = self.metadata.uri.as_deref().unwrap_or_default();
+            let ports = &self.metadata.ports;
+            if ports.audio_inputs.max(ports.audio_outputs) > lv2_sandbox::MAX_AUDIO_PORTS {
+                error!(
+                    "LV2 plugin {:?} has too many audio ports to run in a plugin host.",
+                    uri
+                );
+                return Err(PluginBuilderError::GenericError(
+                    "Plugin has too many audio ports to be sandboxed.",
+                ));
+            }
+            let has_midi_output = self
+                .plugin
+                .ports()
+                .any(|p| self.lv2_resources.is_midi(&p) && self.lv2_resources.is_output(&p));
+            let handles_bypass = self
+                .plugin
+                .port_by_designation(
+                    Some(&self.lv2_resources.input_port),
+                    &self.lv2_resources.enabled_designation,
+                )
+                .is_some();
+            let instance = lv2_sandbox::SandboxedPluginInstance::spawn(
+                uri,
+                self.audio_settings.sample_rate,
+                self.audio_settings.buffer_size,
+                lv2_path,
+                lv2_sandbox::SandboxedPlugin {
+                    parameters: self.parameters.clone(),
+                    handles_bypass,
+                    has_midi_output,
+                },
+            )
+            .map_err(|e| {
+                error!("Failed to start plugin host for {:?}: {}", uri, e);
+                PluginBuilderError::GenericError("Failed to start plugin host.")
+            })?;
+            return Ok(Box::new(instance));
+        }
+        let mut worker = lv2_worker::Worker::default();
+        let urid_map = &self.lv2_resources.urid_map;
+        let options = lv2_options::Options::new(self.audio_settings, MAX_BUFFER_SIZE, &|uri| {
+            urid_map.map_static(uri)
+        });
+        let mut features: Vec<*const lv2_raw::LV2Feature> = vec![
+            urid_map.as_lv2_feature(),
+            worker.as_lv2_feature(),
+            options.as_lv2_feature(),
+            options.bounded_block_length_feature(),
+            self.lv2_resources.log.as_lv2_feature(),
         ];
+        if let Some(f) = urid_map.as_unmap_lv2_feature() {
+            features.push(f);
+        }
+        features.push(std::ptr::null());
+        let sample_rate = self.audio_settings.sample_rate;
         let mut instance = unsafe {
-            self.plugin.instantiate(44100.0, features.as_ptr()).ok_or(
-                PluginBuilderError::GenericError("Failed to instantiate LV2 plugin."),
-            )?
+            self.plugin
+                .instantiate(sample_rate, features.as_ptr())
+                .ok_or(PluginBuilderError::GenericError(
+                    "Failed to instantiate LV2 plugin.",
+                ))?
         };
+        // The worker is declared before the instance in `LV2PluginInstance` so it is dropped, and
+        // its thread joined, before the instance is freed.
+        unsafe { worker.start(&instance) }.map_err(|e| {
+            error!("Failed to start LV2 worker thread: {:?}", e);
+            PluginBuilderError::GenericError("Failed to start LV2 worker thread.")
+        })?;
 
-        let mut audio_output_ports = self
+        let audio_input_ports: Vec<_> = self
+            .plugin
+            .ports()
+            .enumerate()
+            .filter(|(_, p)| self.lv2_resources.is_audio(p) && self.lv2_resources.is_input(p))
+            .map(|(port_index, _)| (port_index, vec![0.0; MAX_BUFFER_SIZE]))
+            .collect();
+        let audio_output_ports: Vec<_> = self
             .plugin
             .ports()
             .enumerate()
             .filter(|(_, p)| self.lv2_resources.is_audio(p) && self.lv2_resources.is_output(p))
-            .map(|(port_index, _)| port_index);
-        let mut sequence_input_ports = self
+            .map(|(port_index, _)| (port_index, vec![0.0; MAX_BUFFER_SIZE]))
+            .collect();
+        let audio_port_mapping =
+            default_audio_port_mapping(audio_input_ports.len(), audio_output_ports.len());
+        let atom_input_ports: Vec<_> = self
             .plugin
             .ports()
             .enumerate()
             .filter(|(_, p)| self.lv2_resources.is_atom(p) && self.lv2_resources.is_input(p))
-            .map(|(port_index, _)| port_index);
+            .map(|(port_index, _)| (port_index, Lv2AtomSequence::new(ATOM_SEQUENCE_SIZE)))
+            .collect();
+        let atom_output_ports: Vec<_> = self
+            .plugin
+            .ports()
+            .enumerate()
+            .filter(|(_, p)| self.lv2_resources.is_atom(p) && self.lv2_resources.is_output(p))
+            .map(|(port_index, p)| {
+                let is_midi = self.lv2_resources.is_midi(&p);
+                (
+                    port_index,
+                    Lv2AtomSequence::new(ATOM_SEQUENCE_SIZE),
+                    is_midi,
+                )
+            })
+            .collect();
         let mut control_inputs: Vec<_> = self
             .plugin
             .ports()
@@ -118,12 +554,7 @@ impl PluginBuilder for Lv2PluginBuilder {
             .filter(|(_, p)| self.lv2_resources.is_control(p) && self.lv2_resources.is_input(p))
             .map(|(i, p)| (i, p.range().default))
             .collect();
-        let midi_uri_cstr = std::ffi::CStr::from_bytes_with_nul(lilv_sys::LILV_URI_MIDI_EVENT)
-            .map_err(|e| {
-                error!("Could not build midi URI CStr: {:?}", e);
-                PluginBuilderError::GenericError("could not build midi URI CStr")
-            })?;
-        let midi_uri = self.lv2_resources.urid_map.map(&midi_uri_cstr);
+        let urids = Urids::new(&self.lv2_resources.urid_map)?;
 
         for i in 0..self.plugin.num_ports() {
             unsafe { instance.connect_port(i, std::ptr::null_mut() as *mut f32) };
@@ -131,68 +562,444 @@ impl PluginBuilder for Lv2PluginBuilder {
         for (i, v) in control_inputs.iter_mut() {
             unsafe { instance.connect_port(*i, v) };
         }
+        // The index within `control_inputs` of the port designated as `lv2:enabled`, if any.
+        let enabled_input = self
+            .plugin
+            .port_by_designation(
+                Some(&self.lv2_resources.input_port),
+                &self.lv2_resources.enabled_designation,
+            )
+            .and_then(|p| control_inputs.iter().position(|(i, _)| *i == p.index()));
+        let mut latency_output = self.plugin.latency_port_index().map(|i| (i, Box::new(0.0)));
+        if let Some((i, v)) = latency_output.as_mut() {
+            unsafe { instance.connect_port(*i, v.as_mut()) };
+        }
 
         Ok(Box::new(LV2PluginInstance {
+            worker,
             instance,
-            midi_buffer: Lv2AtomSequence::new(4096),
-            midi_uri,
-            midi_port: sequence_input_ports.next(),
+            options,
+            urids,
+            atom_input_ports,
+            event_buffer: vec![0; MAX_EVENT_SIZE],
+            transport: None,
+            previous_transport: None,
+            has_midi_output: atom_output_ports.iter().any(|(_, _, is_midi)| *is_midi),
+            atom_output_ports,
+            midi_output: Vec::with_capacity(1024),
+            parameters: self.parameters.clone(),
             control_inputs,
-            out_left_port: audio_output_ports.next(),
-            out_right_port: audio_output_ports.next(),
-            other_output_ports: audio_output_ports.collect(),
+            enabled_input,
+            latency_output,
+            audio_input_ports,
+            audio_output_ports,
+            audio_port_mapping,
         }))
     }
 }
 
+/// The largest number of frames that can be processed at once.
+const MAX_BUFFER_SIZE: usize = 8192;
+
+/// The size in bytes of the buffers for atom sequence ports.
+const ATOM_SEQUENCE_SIZE: usize = 65536;
+
+/// The largest event, such as a SysEx message, that can be sent to an atom input port.
+const MAX_EVENT_SIZE: usize = 8192;
+
+/// The URIDs used while processing. These are mapped ahead of time since mapping is not realtime
+/// safe.
+#[derive(Copy, Clone, Debug)]
+struct Urids {
+    midi_event: u32,
+    atom_chunk: u32,
+    atom_sequence: u32,
+    atom_object: u32,
+    atom_float: u32,
+    atom_int: u32,
+    atom_long: u32,
+    time_position: u32,
+    time_frame: u32,
+    time_speed: u32,
+    time_bar: u32,
+    time_bar_beat: u32,
+    time_beat_unit: u32,
+    time_beats_per_bar: u32,
+    time_beats_per_minute: u32,
+}
+
+impl Urids {
+    fn new(urid_map: &UridMapFeature) -> Result<Urids, PluginBuilderError> {
+        let map = |uri: &[u8]| {
+            CStr::from_bytes_with_nul(uri)
+                .map(|uri| urid_map.map(uri))
+                .map_err(|e| {
+                    error!("Could not build URI CStr: {:?}", e);
+                    PluginBuilderError::GenericError("could not build URI CStr")
+                })
+        };
+        Ok(Urids {
+            midi_event: map(lilv_sys::LILV_URI_MIDI_EVENT)?,
+            atom_chunk: map(lv2_raw::LV2_ATOM__CHUNK)?,
+            atom_sequence: map(lv2_raw::LV2_ATOM__SEQUENCE)?,
+            atom_object: map(lv2_raw::LV2_ATOM__OBJECT)?,
+            atom_float: map(lv2_raw::LV2_ATOM__FLOAT)?,
+            atom_int: map(lv2_raw::LV2_ATOM__INT)?,
+            atom_long: map(lv2_raw::LV2_ATOM__LONG)?,
+            time_position: map(lv2_raw::LV2_TIME__POSITION)?,
+            time_frame: map(lv2_raw::LV2_TIME__FRAME)?,
+            time_speed: map(lv2_raw::LV2_TIME__SPEED)?,
+            time_bar: map(lv2_raw::LV2_TIME__BAR)?,
+            time_bar_beat: map(lv2_raw::LV2_TIME__BARBEAT)?,
+            time_beat_unit: map(lv2_raw::LV2_TIME__BEATUNIT)?,
+            time_beats_per_bar: map(lv2_raw::LV2_TIME__BEATSPERBAR)?,
+            time_beats_per_minute: map(lv2_raw::LV2_TIME__BEATSPERMINUTE)?,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct LV2PluginInstance {
+    // Must be dropped before the instance.
+    worker: lv2_worker::Worker,
     instance: lilv::Instance,
-    midi_buffer: Lv2AtomSequence,
-    midi_uri: u32,
-    midi_port: Option<usize>,
+    // Referenced by the instance so it must be dropped after the instance.
+    #[allow(dead_code)]
+    options: lv2_options::Options,
+    urids: Urids,
+    // Atom sequence input ports and the buffers they read from. All input ports receive the same
+    // events.
+    atom_input_ports: Vec<(PortIndex, Lv2AtomSequence)>,
+    // Scratch space for serializing events.
+    event_buffer: Vec<u8>,
+    // The transport for the next call to process.
+    transport: Option<olivia_core::plugin::TransportInfo>,
+    // The transport and number of frames of the last call to process.
+    previous_transport: Option<(olivia_core::plugin::TransportInfo, usize)>,
+    // Atom sequence output ports, the buffers they write to and whether they output MIDI.
+    atom_output_ports: Vec<(PortIndex, Lv2AtomSequence, bool)>,
+    has_midi_output: bool,
+    // The MIDI read from the atom output ports after the last run.
+    midi_output: Vec<olivia_core::TimedMidi<'static>>,
+    // The control input ports that are exposed as parameters.
+    parameters: Vec<ParameterInfo>,
     control_inputs: Vec<(PortIndex, f32)>,
-    out_left_port: Option<usize>,
-    out_right_port: Option<usize>,
-    other_output_ports: Vec<usize>,
+    // The index within `control_inputs` of the port that enables and disables the plugin.
+    enabled_input: Option<usize>,
+    // The control output port that the plugin reports its latency in frames on.
+    latency_output: Option<(PortIndex, Box<f32>)>,
+    // Audio ports and the buffers they are connected to.
+    audio_input_ports: Vec<(PortIndex, Vec<f32>)>,
+    audio_output_ports: Vec<(PortIndex, Vec<f32>)>,
+    audio_port_mapping: olivia_core::plugin::AudioPortMapping,
 }
 
 impl olivia_core::plugin::PluginInstance for LV2PluginInstance {
+    fn parameters(&self) -> Vec<ParameterInfo> {
+        self.parameters.clone()
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        if let Some((_, v)) = self.control_inputs.iter_mut().find(|(i, _)| *i == index) {
+            *v = value;
+        }
+    }
+
+    fn get_parameter(&self, index: usize) -> Option<f32> {
+        self.control_inputs
+            .iter()
+            .find(|(i, _)| *i == index)
+            .map(|(_, v)| *v)
+    }
+
+    fn set_enabled(&mut self, enabled: bool) -> bool {
+        match self.enabled_input {
+            Some(i) => {
+                self.control_inputs[i].1 = if enabled { 1.0 } else { 0.0 };
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn latency(&self) -> usize {
+        match self.latency_output.as_ref() {
+            Some((_, latency)) => latency.max(0.0) as usize,
+            None => 0,
+        }
+    }
+
+    fn set_transport(&mut self, transport: &olivia_core::plugin::TransportInfo) {
+        self.transport = Some(*transport);
+    }
+
     fn process(
         &mut self,
         midi: &[olivia_core::TimedMidi],
         out_left: &mut [f32],
         out_right: &mut [f32],
     ) {
-        let samples = out_left.len().min(out_right.len());
-        if let Some(p) = self.out_left_port {
-            unsafe { self.instance.connect_port(p, out_left.as_mut_ptr()) };
-        }
-        if let Some(p) = self.out_right_port {
-            unsafe { self.instance.connect_port(p, out_right.as_mut_ptr()) };
-        }
-        for p in self.other_output_ports.iter() {
+        self.process_with_inputs(&[], midi, out_left, out_right);
+    }
+
+    fn process_with_inputs(
+        &mut self,
+        inputs: &[&[f32]],
+        midi: &[olivia_core::TimedMidi],
+        out_left: &mut [f32],
+        out_right: &mut [f32],
+    ) {
+        let samples = out_left.len().min(out_right.len()).min(MAX_BUFFER_SIZE);
+        self.connect_audio_ports(inputs, &out_left[..samples], &out_right[..samples]);
+        self.write_atom_inputs(midi, samples);
+        for (port_index, sequence, _) in self.atom_output_ports.iter_mut() {
+            sequence.clear_for_output(self.urids.atom_chunk);
             unsafe {
                 self.instance
-                    .connect_port(*p, std::ptr::null_mut() as *mut f32)
+                    .connect_port(*port_index, sequence.as_mut_ptr())
+            };
+        }
+        self.instance.run(samples);
+        self.worker.end_run();
+        self.read_midi_output();
+        self.mix_audio_outputs(&mut out_left[..samples], &mut out_right[..samples]);
+    }
+
+    fn set_audio_port_mapping(&mut self, mapping: olivia_core::plugin::AudioPortMapping) {
+        self.audio_port_mapping = mapping;
+    }
+
+    fn midi_output(&self) -> Option<&[olivia_core::TimedMidi<'static>]> {
+        if self.has_midi_output {
+            Some(&self.midi_output)
+        } else {
+            None
+        }
+    }
+}
+
+impl LV2PluginInstance {
+    /// Fill the audio input buffers from their mapped channels and connect all audio ports to
+    /// their buffers. Unmapped inputs receive silence.
+    fn connect_audio_ports(&mut self, inputs: &[&[f32]], left: &[f32], right: &[f32]) {
+        use olivia_core::plugin::AudioChannel;
+        let samples = left.len();
+        for (i, (port_index, buffer)) in self.audio_input_ports.iter_mut().enumerate() {
+            let source = match self.audio_port_mapping.inputs.get(i).copied().flatten() {
+                Some(AudioChannel::Left) => Some(left),
+                Some(AudioChannel::Right) => Some(right),
+                Some(AudioChannel::Input(n)) => inputs.get(n).copied(),
+                None => None,
             };
+            let buffer = &mut buffer[..samples];
+            match source {
+                Some(source) if source.len() >= samples => {
+                    buffer.copy_from_slice(&source[..samples])
+                }
+                _ => buffer.iter_mut().for_each(|v| *v = 0.0),
+            }
+            unsafe { self.instance.connect_port(*port_index, buffer.as_mut_ptr()) };
+        }
+        for (port_index, buffer) in self.audio_output_ports.iter_mut() {
+            unsafe { self.instance.connect_port(*port_index, buffer.as_mut_ptr()) };
         }
-        if let Some(port_index) = self.midi_port {
-            self.midi_buffer.clear();
+    }
+
+    /// Replace the track channels with the sum of the outputs that are mapped to them. Channels
+    /// that no output is mapped to are left unchanged.
+    fn mix_audio_outputs(&self, out_left: &mut [f32], out_right: &mut [f32]) {
+        use olivia_core::plugin::AudioChannel;
+        let samples = out_left.len();
+        for (channel, out) in [
+            (AudioChannel::Left, out_left),
+            (AudioChannel::Right, out_right),
+        ]
+        .iter_mut()
+        {
+            let mut outputs = self
+                .audio_output_ports
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| self.audio_port_mapping.outputs.get(*i) == Some(&Some(*channel)))
+                .map(|(_, (_, buffer))| &buffer[..samples]);
+            if let Some(first) = outputs.next() {
+                out.copy_from_slice(first);
+                for buffer in outputs {
+                    for (o, v) in out.iter_mut().zip(buffer.iter()) {
+                        *o += v;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Fill the atom input ports with the MIDI events. A `time:Position` object is sent first if
+    /// the transport has changed in a way that plugins can't predict, such as starting, stopping,
+    /// relocating or changing tempo.
+    fn write_atom_inputs(&mut self, midi: &[olivia_core::TimedMidi], samples: usize) {
+        if self.atom_input_ports.is_empty() {
+            return;
+        }
+        let position_changed = match (self.transport, self.previous_transport) {
+            (Some(t), Some((previous, frames))) => {
+                let expected_frame = if previous.playing {
+                    previous.frame + frames as u64
+                } else {
+                    previous.frame
+                };
+                t.playing != previous.playing
+                    || t.frame != expected_frame
+                    || t.bpm != previous.bpm
+                    || t.beats_per_bar != previous.beats_per_bar
+            }
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        self.previous_transport = self.transport.map(|t| (t, samples));
+
+        let urids = self.urids;
+        for (port_index, sequence) in self.atom_input_ports.iter_mut() {
+            sequence.clear(urids.atom_sequence);
+            if let (true, Some(t)) = (position_changed, self.transport.as_ref()) {
+                let size = write_position(&mut self.event_buffer, &urids, t);
+                sequence.append_event(0, urids.atom_object, &self.event_buffer[..size]);
+            }
             for timed_midi in midi.iter() {
-                let mut event = Lv2AtomEvent::new(timed_midi.frame as i64, self.midi_uri);
-                if let Ok(size) = timed_midi.message.copy_to_slice(&mut event.buffer) {
-                    event.set_size(size);
-                    self.midi_buffer.append_event(&event);
+                if let Ok(size) = timed_midi.message.copy_to_slice(&mut self.event_buffer) {
+                    sequence.append_event(
+                        timed_midi.frame as i64,
+                        urids.midi_event,
+                        &self.event_buffer[..size],
+                    );
                 }
             }
             unsafe {
                 self.instance
-                    .connect_port(port_index, self.midi_buffer.as_mut_ptr())
+                    .connect_port(*port_index, sequence.as_mut_ptr())
             };
         }
-        self.instance.run(samples);
     }
+
+    /// Convert the MIDI events written to the MIDI output ports into `TimedMidi`. Events that are
+    /// not MIDI, and SysEx messages, are dropped.
+    fn read_midi_output(&mut self) {
+        self.midi_output.clear();
+        let (midi_uri, sequence_uri) = (self.urids.midi_event, self.urids.atom_sequence);
+        let midi_output = &mut self.midi_output;
+        let midi_ports = self
+            .atom_output_ports
+            .iter()
+            .filter(|(_, _, is_midi)| *is_midi);
+        for (_, sequence, _) in midi_ports {
+            sequence.for_each_event(sequence_uri, |frame, event_type, data| {
+                if event_type != midi_uri || midi_output.len() == midi_output.capacity() {
+                    return;
+                }
+                if let Ok(Some(message)) =
+                    wmidi::MidiMessage::try_from(data).map(wmidi::MidiMessage::drop_unowned_sysex)
+                {
+                    midi_output.push(olivia_core::TimedMidi {
+                        frame: frame.max(0) as usize,
+                        message,
+                    });
+                }
+            });
+        }
+        // Events from different ports on the same frame keep the order of their ports.
+        self.midi_output.sort_by_key(|m| m.frame);
+    }
+}
+
+/// Connect the first two audio inputs and outputs to the left and right channels of the track. A
+/// mono port is connected to the left channel.
+fn default_audio_port_mapping(
+    num_inputs: usize,
+    num_outputs: usize,
+) -> olivia_core::plugin::AudioPortMapping {
+    use olivia_core::plugin::AudioChannel;
+    let stereo = |n: usize| -> Vec<Option<AudioChannel>> {
+        (0..n)
+            .map(|i| match i {
+                0 => Some(AudioChannel::Left),
+                1 => Some(AudioChannel::Right),
+                _ => None,
+            })
+            .collect()
+    };
+    olivia_core::plugin::AudioPortMapping {
+        inputs: stereo(num_inputs),
+        outputs: stereo(num_outputs),
+    }
+}
+
+/// Serialize the body of a `time:Position` atom object into `buffer`. Returns the size of the
+/// body in bytes.
+fn write_position(
+    buffer: &mut [u8],
+    urids: &Urids,
+    transport: &olivia_core::plugin::TransportInfo,
+) -> usize {
+    let speed: f32 = if transport.playing { 1.0 } else { 0.0 };
+    // The object body has an id of 0, which marks it as blank, followed by the type.
+    buffer[0..4].copy_from_slice(&0u32.to_ne_bytes());
+    buffer[4..8].copy_from_slice(&urids.time_position.to_ne_bytes());
+    let properties: [(u32, u32, [u8; 8], usize); 7] = [
+        (
+            urids.time_frame,
+            urids.atom_long,
+            (transport.frame as i64).to_ne_bytes(),
+            8,
+        ),
+        (urids.time_speed, urids.atom_float, float_bytes(speed), 4),
+        (
+            urids.time_bar,
+            urids.atom_long,
+            i64::from(transport.bar).to_ne_bytes(),
+            8,
+        ),
+        (
+            urids.time_bar_beat,
+            urids.atom_float,
+            float_bytes(transport.bar_beat as f32),
+            4,
+        ),
+        (urids.time_beat_unit, urids.atom_int, int_bytes(4), 4),
+        (
+            urids.time_beats_per_bar,
+            urids.atom_float,
+            float_bytes(transport.beats_per_bar as f32),
+            4,
+        ),
+        (
+            urids.time_beats_per_minute,
+            urids.atom_float,
+            float_bytes(transport.bpm as f32),
+            4,
+        ),
+    ];
+    let mut offset = 8;
+    for (key, value_type, value, size) in properties.iter() {
+        // Each property is a key, a context, the value atom header and the padded value.
+        buffer[offset..offset + 4].copy_from_slice(&key.to_ne_bytes());
+        buffer[offset + 4..offset + 8].copy_from_slice(&0u32.to_ne_bytes());
+        buffer[offset + 8..offset + 12].copy_from_slice(&(*size as u32).to_ne_bytes());
+        buffer[offset + 12..offset + 16].copy_from_slice(&value_type.to_ne_bytes());
+        buffer[offset + 16..offset + 24].copy_from_slice(value);
+        offset += 24;
+    }
+    offset
+}
+
+fn float_bytes(v: f32) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&v.to_ne_bytes());
+    bytes
+}
+
+fn int_bytes(v: i32) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&v.to_ne_bytes());
+    bytes
 }
 
 struct Lv2Resources {
@@ -201,7 +1008,12 @@ struct Lv2Resources {
     control_port: lilv::Node,
     input_port: lilv::Node,
     output_port: lilv::Node,
+    enabled_designation: lilv::Node,
+    midi_event: lilv::Node,
+    unit: lilv::Node,
+    unit_symbol: lilv::Node,
     urid_map: UridMapFeature<'static>,
+    log: lv2_log::LogFeature,
 }
 
 impl Lv2Resources {
@@ -217,6 +1029,11 @@ impl Lv2Resources {
         port.classes().contains(&self.control_port)
     }
 
+    /// Returns true if the port is an atom port that supports MIDI events.
+    fn is_midi(&self, port: &lilv::Port) -> bool {
+        self.is_atom(port) && port.supports_event(&self.midi_event)
+    }
+
     fn is_output(&self, port: &lilv::Port) -> bool {
         port.classes().contains(&self.output_port)
     }
@@ -224,89 +1041,127 @@ impl Lv2Resources {
     fn is_input(&self, port: &lilv::Port) -> bool {
         port.classes().contains(&self.input_port)
     }
-}
 
-/// The underlying buffer backing the data for an atom event.
-type Lv2AtomEventBuffer = [u8; 16];
-
-/// An single atom event.
-#[repr(packed)]
-struct Lv2AtomEvent {
-    header: lv2_raw::LV2AtomEvent,
-    pub buffer: Lv2AtomEventBuffer,
-}
-
-impl Lv2AtomEvent {
-    /// Create a new atom event with the given time and type. The event can be filled in by setting
-    /// the bytes in buffer and calling `set_size`.
-    fn new(time_in_frames: i64, my_type: u32) -> Lv2AtomEvent {
-        let mut event: Lv2AtomEvent = unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-        event.header.time_in_frames = time_in_frames;
-        event.header.body.mytype = my_type;
-        event.header.body.size = 0;
-        event
-    }
-
-    /// Set the size of the atom. Must be less than or equal to the size of the buffer.
-    fn set_size(&mut self, size: usize) {
-        debug_assert!(size < self.buffer.len(), "{} < {}", size, self.buffer.len());
-        self.header.body.size = size as u32;
-    }
-
-    /// Return a pointer to the header of the atom.
-    #[allow(safe_packed_borrows)]
-    fn as_ptr(&self) -> *const lv2_raw::LV2AtomEvent {
-        &self.header
+    /// Returns true if the port is of a type that can be connected to a buffer.
+    fn is_connectable(&self, port: &lilv::Port) -> bool {
+        (self.is_audio(port) || self.is_atom(port) || self.is_control(port))
+            && (self.is_input(port) || self.is_output(port))
     }
 }
 
-/// An atom sequence.
+/// An atom sequence. The buffer is made of 64 bit words since LV2 requires events to be 64 bit
+/// aligned.
 struct Lv2AtomSequence {
-    buffer: Vec<lv2_raw::LV2AtomSequence>,
+    buffer: Vec<u64>,
 }
 
 impl Lv2AtomSequence {
     /// Create a new sequence that can hold about desired_capacity bytes.
     fn new(desired_capacity: usize) -> Lv2AtomSequence {
-        let len = desired_capacity / std::mem::size_of::<lv2_raw::LV2AtomSequence>();
-        let mut buffer = Vec::with_capacity(len);
-        buffer.resize_with(len, || lv2_raw::LV2AtomSequence {
-            atom: lv2_raw::LV2Atom { size: 0, mytype: 0 },
-            body: lv2_raw::LV2AtomSequenceBody { unit: 0, pad: 0 },
-        });
-        let mut seq = Lv2AtomSequence { buffer };
-        seq.clear();
+        let header_words = std::mem::size_of::<lv2_raw::LV2AtomSequence>() / 8;
+        let len = (desired_capacity / 8).max(header_words);
+        let mut seq = Lv2AtomSequence {
+            buffer: vec![0; len],
+        };
+        seq.clear(0);
         seq
     }
 
-    /// Clear all events in the sequence.
+    fn header(&self) -> &lv2_raw::LV2AtomSequence {
+        unsafe { &*(self.buffer.as_ptr() as *const lv2_raw::LV2AtomSequence) }
+    }
+
+    fn header_mut(&mut self) -> &mut lv2_raw::LV2AtomSequence {
+        unsafe { &mut *(self.buffer.as_mut_ptr() as *mut lv2_raw::LV2AtomSequence) }
+    }
+
+    /// Clear all events in the sequence and mark it as an atom sequence.
     #[inline(always)]
-    fn clear(&mut self) {
-        unsafe { lv2_raw::atomutils::lv2_atom_sequence_clear(self.as_mut_ptr()) }
+    fn clear(&mut self, sequence_uri: u32) {
+        let header = self.header_mut();
+        header.atom.mytype = sequence_uri;
+        header.atom.size = std::mem::size_of::<lv2_raw::LV2AtomSequenceBody>() as u32;
+        header.body.unit = 0;
+        header.body.pad = 0;
     }
 
     /// Append an event to the sequence. If there is no capacity for it, then it will not be
-    /// appended.
-    #[inline(always)]
-    fn append_event(&mut self, event: &Lv2AtomEvent) {
+    /// appended and false is returned.
+    fn append_event(&mut self, time_in_frames: i64, event_type: u32, data: &[u8]) -> bool {
+        let atom_header_size = std::mem::size_of::<lv2_raw::LV2Atom>();
+        let event_header_size = std::mem::size_of::<lv2_raw::LV2AtomEvent>();
+        let size = self.header().atom.size as usize;
+        // Events are always padded so the body size stays a multiple of 8.
+        let offset = atom_header_size + size;
+        let total_size = event_header_size + data.len();
+        if offset + total_size > self.capacity() {
+            return false;
+        }
         unsafe {
-            lv2_raw::atomutils::lv2_atom_sequence_append_event(
-                self.as_mut_ptr(),
-                self.capacity() as u32,
-                event.as_ptr(),
-            )
-        };
+            let event = (self.buffer.as_mut_ptr() as *mut u8).add(offset);
+            std::ptr::write(
+                event as *mut lv2_raw::LV2AtomEvent,
+                lv2_raw::LV2AtomEvent {
+                    time_in_frames,
+                    body: lv2_raw::LV2Atom {
+                        size: data.len() as u32,
+                        mytype: event_type,
+                    },
+                },
+            );
+            std::ptr::copy_nonoverlapping(data.as_ptr(), event.add(event_header_size), data.len());
+        }
+        let padded_size = lv2_raw::atomutils::lv2_atom_pad_size(total_size as u32) as usize;
+        self.header_mut().atom.size = (size + padded_size) as u32;
+        true
+    }
+
+    /// Prepare the sequence to be used as an output port. The plugin is told that it may use the
+    /// whole capacity of the buffer.
+    fn clear_for_output(&mut self, chunk_uri: u32) {
+        let size = self.capacity() - std::mem::size_of::<lv2_raw::LV2Atom>();
+        let atom = &mut self.header_mut().atom;
+        atom.size = size as u32;
+        atom.mytype = chunk_uri;
+    }
+
+    /// Call `f` with the time in frames, type and body of each event in the sequence. Nothing is
+    /// done if the sequence has not been written as an atom sequence.
+    fn for_each_event<F: FnMut(i64, u32, &[u8])>(&self, sequence_uri: u32, mut f: F) {
+        let header = self.header();
+        if header.atom.mytype != sequence_uri {
+            return;
+        }
+        let atom_header_size = std::mem::size_of::<lv2_raw::LV2Atom>();
+        let size = (header.atom.size as usize).min(self.capacity() - atom_header_size) as u32;
+        let end = unsafe { (self.buffer.as_ptr() as *const u8).add(self.capacity()) };
+        unsafe {
+            let body = &header.body as *const lv2_raw::LV2AtomSequenceBody;
+            let mut event = lv2_raw::atomutils::lv2_atom_sequence_begin(body);
+            while !lv2_raw::atomutils::lv2_atom_sequence_is_end(body, size, event) {
+                let data = event.add(1) as *const u8;
+                let data_size = (*event).body.size as usize;
+                if data.add(data_size) > end {
+                    break;
+                }
+                f(
+                    (*event).time_in_frames,
+                    (*event).body.mytype,
+                    std::slice::from_raw_parts(data, data_size),
+                );
+                event = lv2_raw::atomutils::lv2_atom_sequence_next(event);
+            }
+        }
     }
 
     /// Return a mutable pointer to the underlying data.
     fn as_mut_ptr(&mut self) -> *mut lv2_raw::LV2AtomSequence {
-        self.buffer.as_mut_ptr()
+        self.buffer.as_mut_ptr() as *mut lv2_raw::LV2AtomSequence
     }
 
-    /// Get the capacity of the sequence.
+    /// Get the capacity of the sequence in bytes.
     fn capacity(&self) -> usize {
-        let slice: &[lv2_raw::LV2AtomSequence] = &self.buffer;
-        std::mem::size_of_val(slice)
+        std::mem::size_of_val(self.buffer.as_slice())
     }
 }
 
@@ -346,9 +1201,18 @@ impl<'a> UridMapFeatureImpl<'a> {
 struct UridMapFeature<'a> {
     feature: lv2_raw::LV2Feature,
     data: Option<Box<lv2_raw::LV2UridMap>>,
+    // The urid unmap feature. Only available for the native implementation.
+    unmap: Option<(lv2_raw::LV2Feature, Box<Lv2UridUnmap>)>,
     urid_map_impl: UridMapFeatureImpl<'a>,
 }
 
+/// LV2_URID_Unmap.
+#[repr(C)]
+struct Lv2UridUnmap {
+    handle: *mut std::ffi::c_void,
+    unmap: extern "C" fn(handle: *mut std::ffi::c_void, urid: u32) -> *const std::os::raw::c_char,
+}
+
 unsafe impl Send for UridMapFeature<'static> {}
 unsafe impl Sync for UridMapFeature<'static> {}
 
@@ -362,7 +1226,17 @@ impl Default for UridMapFeature<'static> {
                 as *mut std::ffi::c_void,
             map: urid_map_feature_native_impl_map,
         });
+        let mut unmap_data = Box::new(Lv2UridUnmap {
+            handle: urid_map_impl.as_mut() as *mut UridMapFeatureNativeImpl
+                as *mut std::ffi::c_void,
+            unmap: urid_map_feature_native_impl_unmap,
+        });
+        let unmap_feature = lv2_raw::LV2Feature {
+            uri: UridMapFeature::UNMAP_URI.as_ptr() as *const ::std::os::raw::c_char,
+            data: unmap_data.as_mut() as *mut Lv2UridUnmap as *mut std::ffi::c_void,
+        };
         UridMapFeature {
+            unmap: Some((unmap_feature, unmap_data)),
             feature: lv2_raw::LV2Feature {
                 uri: UridMapFeature::URI.as_ptr() as *const ::std::os::raw::c_char,
                 data: data.as_mut() as *mut lv2_raw::LV2UridMap as *mut std::ffi::c_void,
@@ -389,15 +1263,49 @@ extern "C" fn urid_map_feature_native_impl_map(
     }
 }
 
+extern "C" fn urid_map_feature_native_impl_unmap(
+    handle: *mut std::ffi::c_void, /*Type is UridMapFeatureNativeImpl*/
+    urid: u32,
+) -> *const std::os::raw::c_char {
+    let self_ptr = handle as *const UridMapFeatureNativeImpl;
+    match unsafe { self_ptr.as_ref() } {
+        Some(self_ref) => self_ref.unmap(urid),
+        None => {
+            error!("URID Unmap had null handle for UridMapFeatureNativeImpl.");
+            std::ptr::null()
+        }
+    }
+}
+
 impl<'a> UridMapFeature<'a> {
     /// The URI for the urid map LV2 feature.
     const URI: &'static str = "http://lv2plug.in/ns/ext/urid#map\0";
 
+    /// The URI for the urid unmap LV2 feature.
+    const UNMAP_URI: &'static str = "http://lv2plug.in/ns/ext/urid#unmap\0";
+
     /// Get the urid map as an LV2_feature.
     fn as_lv2_feature(&self) -> &lv2_raw::LV2Feature {
         &self.feature
     }
 
+    /// Get the urid unmap as an LV2_feature. Returns `None` if the map is not implemented
+    /// natively.
+    fn as_unmap_lv2_feature(&self) -> Option<&lv2_raw::LV2Feature> {
+        self.unmap.as_ref().map(|(f, _)| f)
+    }
+
+    /// Like `map`, but for a nul terminated URI constant.
+    fn map_static(&self, uri: &'static str) -> u32 {
+        match CStr::from_bytes_with_nul(uri.as_bytes()) {
+            Ok(uri) => self.map(uri),
+            Err(e) => {
+                error!("Could not build URI CStr from {:?}: {:?}", uri, e);
+                0
+            }
+        }
+    }
+
     /// Get the id for the given uri. If the uri does not have an ID, it will be registered
     /// with a new one.
     ///
@@ -416,6 +1324,7 @@ impl<'a> From<&'a lv2_raw::LV2UridMap> for UridMapFeature<'a> {
                 data: map as *const lv2_raw::LV2UridMap as *mut std::ffi::c_void,
             },
             data: None, /*The data is borrowed from map*/
+            unmap: None,
             urid_map_impl: UridMapFeatureImpl::Abstract(map),
         }
     }
@@ -445,6 +1354,8 @@ impl<'a> TryFrom<&'a lv2_raw::LV2Feature> for UridMapFeature<'a> {
 /// Implementation for uri map LV2 feature.
 struct UridMapFeatureNativeImpl {
     map: RwLock<HashMap<CString, u32>>,
+    // The reverse of map. The strings are never removed so pointers to them stay valid.
+    unmap: RwLock<HashMap<u32, CString>>,
     next_id: AtomicU32,
 }
 
@@ -453,6 +1364,7 @@ impl Default for UridMapFeatureNativeImpl {
     fn default() -> UridMapFeatureNativeImpl {
         UridMapFeatureNativeImpl {
             map: RwLock::default(),
+            unmap: RwLock::default(),
             next_id: AtomicU32::new(1),
         }
     }
@@ -477,8 +1389,18 @@ impl UridMapFeatureNativeImpl {
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
         info!("Mapped URI {:?} to {}.", uri, id);
         map.insert(CString::from(uri), id);
+        self.unmap.write().unwrap().insert(id, CString::from(uri));
         id
     }
+
+    /// Get the URI for the given ID or null if the ID has not been mapped. The returned string is
+    /// valid for the lifetime of the map.
+    fn unmap(&self, id: u32) -> *const std::os::raw::c_char {
+        match self.unmap.read().unwrap().get(&id) {
+            Some(uri) => uri.as_ptr(),
+            None => std::ptr::null(),
+        }
+    }
 }
 
 fn create_id(uri: &str) -> String {
@@ -497,4 +1419,88 @@ mod tests {
             "lv2_ec91337841c3308d790e6387353f5690835925f1230b8471682856e1733625d8",
         );
     }
+
+    #[test]
+    fn find_bundles_returns_lv2_directories() {
+        let dir = std::env::temp_dir().join(format!("olivia_lv2_path_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("a.lv2")).unwrap();
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        std::fs::write(dir.join("c.lv2"), b"").unwrap();
+        let bundles = find_bundles(&[dir.clone(), dir.join("missing")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(bundles, [dir.join("a.lv2")].iter().cloned().collect());
+    }
+
+    #[test]
+    fn unmap_returns_mapped_uri() {
+        let urid_map = UridMapFeature::default();
+        let id = urid_map.map_static("http://lv2plug.in/ns/ext/atom#Int\0");
+        let unmap = urid_map.as_unmap_lv2_feature().unwrap();
+        let unmap = unsafe { &*(unmap.data as *const Lv2UridUnmap) };
+        let uri = unsafe { CStr::from_ptr((unmap.unmap)(unmap.handle, id)) };
+        assert_eq!(uri.to_str(), Ok("http://lv2plug.in/ns/ext/atom#Int"));
+        assert!((unmap.unmap)(unmap.handle, id + 1).is_null());
+    }
+
+    #[test]
+    fn default_audio_port_mapping_uses_first_two_ports() {
+        use olivia_core::plugin::AudioChannel;
+        let mapping = default_audio_port_mapping(1, 3);
+        assert_eq!(mapping.inputs, vec![Some(AudioChannel::Left)]);
+        assert_eq!(
+            mapping.outputs,
+            vec![Some(AudioChannel::Left), Some(AudioChannel::Right), None]
+        );
+    }
+
+    #[test]
+    fn atom_sequence_holds_large_events() {
+        let mut seq = Lv2AtomSequence::new(1024);
+        seq.clear(1);
+        let sysex: Vec<u8> = (0..100).collect();
+        assert!(seq.append_event(3, 2, &[0x90, 60, 127]));
+        assert!(seq.append_event(5, 3, &sysex));
+        assert!(!seq.append_event(6, 3, &[0; 1024]));
+        let mut events = Vec::new();
+        seq.for_each_event(1, |frame, event_type, data| {
+            events.push((frame, event_type, data.to_vec()))
+        });
+        assert_eq!(events, vec![(3, 2, vec![0x90, 60, 127]), (5, 3, sysex)]);
+    }
+
+    #[test]
+    fn position_has_all_properties() {
+        let urids = Urids {
+            midi_event: 1,
+            atom_chunk: 2,
+            atom_sequence: 3,
+            atom_object: 4,
+            atom_float: 5,
+            atom_int: 6,
+            atom_long: 7,
+            time_position: 8,
+            time_frame: 9,
+            time_speed: 10,
+            time_bar: 11,
+            time_bar_beat: 12,
+            time_beat_unit: 13,
+            time_beats_per_bar: 14,
+            time_beats_per_minute: 15,
+        };
+        let transport = olivia_core::plugin::TransportInfo {
+            playing: true,
+            frame: 100,
+            bpm: 120.0,
+            beats_per_bar: 4,
+            bar: 2,
+            bar_beat: 1.5,
+        };
+        let mut buffer = [0; 256];
+        let size = write_position(&mut buffer, &urids, &transport);
+        assert_eq!(size, 8 + 7 * 24);
+        assert_eq!(buffer[4..8], 8u32.to_ne_bytes());
+        // The first property is the frame.
+        assert_eq!(buffer[8..12], 9u32.to_ne_bytes());
+        assert_eq!(buffer[24..32], 100i64.to_ne_bytes());
+    }
 }
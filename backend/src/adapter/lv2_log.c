@@ -0,0 +1,33 @@
+/* The functions of LV2_Log_Log. They are written in C since Rust can not define variadic
+ * functions. The formatted message is passed to olivia_lv2_log_write in lv2_log.rs. */
+#include <stdarg.h>
+#include <stdint.h>
+#include <stdio.h>
+
+/* The largest formatted message including the terminating null. Longer messages are
+ * truncated. Must match MAX_MESSAGE_SIZE in lv2_log.rs. */
+#define MAX_MESSAGE_SIZE 1024
+
+void olivia_lv2_log_write(void *handle, uint32_t log_type, const char *message);
+
+int olivia_lv2_log_vprintf(void *handle, uint32_t log_type, const char *format, va_list ap) {
+    char message[MAX_MESSAGE_SIZE];
+    int size;
+    if (format == NULL) {
+        return 0;
+    }
+    size = vsnprintf(message, sizeof(message), format, ap);
+    if (size >= 0) {
+        olivia_lv2_log_write(handle, log_type, message);
+    }
+    return size;
+}
+
+int olivia_lv2_log_printf(void *handle, uint32_t log_type, const char *format, ...) {
+    va_list ap;
+    int size;
+    va_start(ap, format);
+    size = olivia_lv2_log_vprintf(handle, log_type, format, ap);
+    va_end(ap);
+    return size;
+}
@@ -0,0 +1,290 @@
+//! The LV2 log feature. Plugins may log from `run`, so messages are formatted into a preallocated
+//! ring and forwarded to the `log` crate by a separate thread. See http://lv2plug.in/ns/ext/log.
+use std::ffi::{c_void, CStr};
+use std::os::raw::{c_char, c_int};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The URI for the log LV2 feature.
+pub const LOG_URI: &str = "http://lv2plug.in/ns/ext/log#log\0";
+
+const ERROR_URI: &str = "http://lv2plug.in/ns/ext/log#Error\0";
+const WARNING_URI: &str = "http://lv2plug.in/ns/ext/log#Warning\0";
+const NOTE_URI: &str = "http://lv2plug.in/ns/ext/log#Note\0";
+const TRACE_URI: &str = "http://lv2plug.in/ns/ext/log#Trace\0";
+
+/// The largest formatted message including the terminating null. Longer messages are truncated.
+/// Must match `MAX_MESSAGE_SIZE` in lv2_log.c.
+const MAX_MESSAGE_SIZE: usize = 1024;
+
+/// The capacity in bytes of the message ring. Messages are dropped while it is full.
+const RING_SIZE: usize = 65536;
+
+/// The size of the header that precedes every message in the ring, the level and the size.
+const HEADER_SIZE: usize = 2 * std::mem::size_of::<u32>();
+
+/// How often the log thread forwards messages.
+const LOG_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+// Defined in lv2_log.c.
+extern "C" {
+    fn olivia_lv2_log_printf(
+        handle: *mut c_void,
+        log_type: u32,
+        format: *const c_char,
+        ...
+    ) -> c_int;
+    fn olivia_lv2_log_vprintf(
+        handle: *mut c_void,
+        log_type: u32,
+        format: *const c_char,
+        ap: *mut c_void,
+    ) -> c_int;
+}
+
+/// LV2_Log_Log.
+#[repr(C)]
+struct Lv2Log {
+    handle: *mut c_void,
+    printf: unsafe extern "C" fn(
+        handle: *mut c_void,
+        log_type: u32,
+        format: *const c_char,
+        ...
+    ) -> c_int,
+    vprintf: unsafe extern "C" fn(
+        handle: *mut c_void,
+        log_type: u32,
+        format: *const c_char,
+        ap: *mut c_void,
+    ) -> c_int,
+}
+
+/// The URIDs of the message types.
+#[derive(Debug)]
+struct LogTypes {
+    error: u32,
+    warning: u32,
+    note: u32,
+    trace: u32,
+}
+
+impl LogTypes {
+    fn level(&self, log_type: u32) -> log::Level {
+        match log_type {
+            t if t == self.error => log::Level::Error,
+            t if t == self.warning => log::Level::Warn,
+            t if t == self.note => log::Level::Info,
+            t if t == self.trace => log::Level::Trace,
+            _ => log::Level::Debug,
+        }
+    }
+}
+
+/// Writes messages to the ring without allocating or blocking. Plugins may log from several
+/// threads, so the ring is locked, and messages that arrive while it is locked are dropped.
+struct LogWriter {
+    types: LogTypes,
+    ring: Mutex<ringbuf::Producer<u8>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl LogWriter {
+    fn write(&self, log_type: u32, message: &[u8]) {
+        let message = &message[..message.len().min(MAX_MESSAGE_SIZE)];
+        let size = HEADER_SIZE + message.len();
+        let mut buffer = [0; HEADER_SIZE + MAX_MESSAGE_SIZE];
+        let level = self.types.level(log_type) as u32;
+        buffer[..HEADER_SIZE / 2].copy_from_slice(&level.to_ne_bytes());
+        buffer[HEADER_SIZE / 2..HEADER_SIZE].copy_from_slice(&(message.len() as u32).to_ne_bytes());
+        buffer[HEADER_SIZE..size].copy_from_slice(message);
+        let written = match self.ring.try_lock() {
+            Ok(mut ring) if ring.remaining() >= size => ring.push_slice(&buffer[..size]) == size,
+            _ => false,
+        };
+        if !written {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Reads the messages written by a `LogWriter`.
+struct LogReader {
+    ring: ringbuf::Consumer<u8>,
+    dropped: Arc<AtomicUsize>,
+    buffer: Vec<u8>,
+}
+
+impl LogReader {
+    /// Read the next message. Messages are pushed whole so a message is always complete once its
+    /// header is visible.
+    fn read(&mut self) -> Option<(log::Level, &str)> {
+        if self.ring.len() < HEADER_SIZE {
+            return None;
+        }
+        let mut header = [0; HEADER_SIZE];
+        self.ring.pop_slice(&mut header);
+        let mut level = [0; HEADER_SIZE / 2];
+        level.copy_from_slice(&header[..HEADER_SIZE / 2]);
+        let mut size = [0; HEADER_SIZE / 2];
+        size.copy_from_slice(&header[HEADER_SIZE / 2..]);
+        let size = self
+            .ring
+            .pop_slice(&mut self.buffer[..u32::from_ne_bytes(size) as usize]);
+        let level = match u32::from_ne_bytes(level) {
+            1 => log::Level::Error,
+            2 => log::Level::Warn,
+            3 => log::Level::Info,
+            4 => log::Level::Debug,
+            _ => log::Level::Trace,
+        };
+        let message = std::str::from_utf8(&self.buffer[..size]).unwrap_or("<invalid UTF-8>");
+        Some((level, message.trim_end()))
+    }
+
+    /// Forward all messages in the ring to the `log` crate.
+    fn forward(&mut self) {
+        while let Some((level, message)) = self.read() {
+            log!(target: "lv2", level, "{}", message);
+        }
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!(target: "lv2", "Dropped {} log messages.", dropped);
+        }
+    }
+}
+
+fn new_log(types: LogTypes) -> (LogWriter, LogReader) {
+    let (producer, consumer) = ringbuf::RingBuffer::new(RING_SIZE).split();
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let writer = LogWriter {
+        types,
+        ring: Mutex::new(producer),
+        dropped: dropped.clone(),
+    };
+    let reader = LogReader {
+        ring: consumer,
+        dropped,
+        buffer: vec![0; MAX_MESSAGE_SIZE],
+    };
+    (writer, reader)
+}
+
+/// Provides the log feature for LV2.
+pub struct LogFeature {
+    // The fields are referenced as void ptrs within feature and data.
+    #[allow(dead_code)]
+    writer: Box<LogWriter>,
+    #[allow(dead_code)]
+    data: Box<Lv2Log>,
+    feature: lv2_raw::LV2Feature,
+    thread: Option<(Arc<AtomicBool>, std::thread::JoinHandle<()>)>,
+}
+
+unsafe impl Send for LogFeature {}
+unsafe impl Sync for LogFeature {}
+
+impl LogFeature {
+    /// Create the log feature. `map` returns the URID of a URI.
+    pub fn new(map: &dyn Fn(&'static str) -> u32) -> LogFeature {
+        let (writer, mut reader) = new_log(LogTypes {
+            error: map(ERROR_URI),
+            warning: map(WARNING_URI),
+            note: map(NOTE_URI),
+            trace: map(TRACE_URI),
+        });
+        let mut writer = Box::new(writer);
+        let mut data = Box::new(Lv2Log {
+            handle: writer.as_mut() as *mut LogWriter as *mut c_void,
+            printf: olivia_lv2_log_printf,
+            vprintf: olivia_lv2_log_vprintf,
+        });
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let thread = std::thread::Builder::new()
+            .name("olivia_lv2_log".to_string())
+            .spawn(move || loop {
+                let running = thread_running.load(Ordering::SeqCst);
+                reader.forward();
+                if !running {
+                    break;
+                }
+                std::thread::park_timeout(LOG_INTERVAL);
+            });
+        let thread = match thread {
+            Ok(t) => Some((running, t)),
+            Err(e) => {
+                error!(
+                    "Could not start LV2 log thread, plugin messages are dropped: {}",
+                    e
+                );
+                None
+            }
+        };
+        LogFeature {
+            feature: lv2_raw::LV2Feature {
+                uri: LOG_URI.as_ptr() as *const c_char,
+                data: data.as_mut() as *mut Lv2Log as *mut c_void,
+            },
+            writer,
+            data,
+            thread,
+        }
+    }
+
+    /// Get the log as an LV2_feature.
+    pub fn as_lv2_feature(&self) -> &lv2_raw::LV2Feature {
+        &self.feature
+    }
+}
+
+impl Drop for LogFeature {
+    fn drop(&mut self) {
+        if let Some((running, join_handle)) = self.thread.take() {
+            running.store(false, Ordering::SeqCst);
+            join_handle.thread().unpark();
+            if join_handle.join().is_err() {
+                error!("LV2 log thread panicked.");
+            }
+        }
+    }
+}
+
+/// Called by the functions in lv2_log.c with the formatted message.
+#[no_mangle]
+extern "C" fn olivia_lv2_log_write(
+    handle: *mut c_void, /*Type is LogWriter*/
+    log_type: u32,
+    message: *const c_char,
+) {
+    let writer = match unsafe { (handle as *const LogWriter).as_ref() } {
+        Some(w) => w,
+        None => return,
+    };
+    if message.is_null() {
+        return;
+    }
+    writer.write(log_type, unsafe { CStr::from_ptr(message) }.to_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printf_formats_arguments() {
+        let (mut writer, mut reader) = new_log(LogTypes {
+            error: 1,
+            warning: 2,
+            note: 3,
+            trace: 4,
+        });
+        let handle = &mut writer as *mut LogWriter as *mut c_void;
+        let format = "%s has %d ports\n\0".as_ptr() as *const c_char;
+        let name = "amp\0".as_ptr() as *const c_char;
+        let size = unsafe { olivia_lv2_log_printf(handle, 2, format, name, 3 as c_int) };
+        assert_eq!(size, 16);
+        assert_eq!(reader.read(), Some((log::Level::Warn, "amp has 3 ports")));
+        assert_eq!(reader.read(), None);
+    }
+}
@@ -0,0 +1,180 @@
+//! The LV2 options feature, used to tell plugins the sample rate and block lengths. See
+//! http://lv2plug.in/ns/ext/options and http://lv2plug.in/ns/ext/buf-size.
+use crate::plugin_factory::AudioSettings;
+use std::ffi::c_void;
+
+/// The URI for the options LV2 feature.
+pub const OPTIONS_URI: &str = "http://lv2plug.in/ns/ext/options#options\0";
+
+/// The URI for the bounded block length LV2 feature. The feature has no data, it declares that
+/// the min and max block length options are provided.
+pub const BOUNDED_BLOCK_LENGTH_URI: &str = "http://lv2plug.in/ns/ext/buf-size#boundedBlockLength\0";
+
+const MIN_BLOCK_LENGTH_URI: &str = "http://lv2plug.in/ns/ext/buf-size#minBlockLength\0";
+const MAX_BLOCK_LENGTH_URI: &str = "http://lv2plug.in/ns/ext/buf-size#maxBlockLength\0";
+const NOMINAL_BLOCK_LENGTH_URI: &str = "http://lv2plug.in/ns/ext/buf-size#nominalBlockLength\0";
+const SAMPLE_RATE_URI: &str = "http://lv2plug.in/ns/ext/parameters#sampleRate\0";
+const ATOM_INT_URI: &str = "http://lv2plug.in/ns/ext/atom#Int\0";
+const ATOM_FLOAT_URI: &str = "http://lv2plug.in/ns/ext/atom#Float\0";
+
+/// LV2_OPTIONS_INSTANCE.
+const CONTEXT_INSTANCE: u32 = 0;
+
+/// LV2_Options_Option.
+#[repr(C)]
+struct Lv2OptionsOption {
+    context: u32,
+    subject: u32,
+    key: u32,
+    size: u32,
+    value_type: u32,
+    value: *const c_void,
+}
+
+/// The values that the options point to.
+#[derive(Debug, Default)]
+struct Values {
+    min_block_length: i32,
+    max_block_length: i32,
+    nominal_block_length: i32,
+    sample_rate: f32,
+}
+
+/// Provides the options feature for a single plugin instance. The options must outlive the plugin
+/// instance since plugins may keep a reference to them.
+pub struct Options {
+    // The fields are referenced as void ptrs within feature and options.
+    #[allow(dead_code)]
+    values: Box<Values>,
+    options: Vec<Lv2OptionsOption>,
+    feature: lv2_raw::LV2Feature,
+    bounded_block_length_feature: lv2_raw::LV2Feature,
+}
+
+// The options are only read by the plugin.
+unsafe impl Send for Options {}
+
+impl Options {
+    /// Create the options for `settings`. `max_block_length` is used as the max block length if
+    /// the buffer size is not known. `map` returns the URID of a URI.
+    pub fn new(
+        settings: AudioSettings,
+        max_block_length: usize,
+        map: &dyn Fn(&'static str) -> u32,
+    ) -> Options {
+        let max_block_length = match settings.buffer_size {
+            0 => max_block_length,
+            n => n.min(max_block_length),
+        };
+        let values = Box::new(Values {
+            min_block_length: 0,
+            max_block_length: max_block_length as i32,
+            nominal_block_length: settings.buffer_size as i32,
+            sample_rate: settings.sample_rate as f32,
+        });
+        let atom_int = map(ATOM_INT_URI);
+        let atom_float = map(ATOM_FLOAT_URI);
+        let option = |key: &'static str, value_type: u32, value: *const c_void, size: usize| {
+            Lv2OptionsOption {
+                context: CONTEXT_INSTANCE,
+                subject: 0,
+                key: map(key),
+                size: size as u32,
+                value_type,
+                value,
+            }
+        };
+        let int_size = std::mem::size_of::<i32>();
+        let mut options = vec![
+            option(
+                MIN_BLOCK_LENGTH_URI,
+                atom_int,
+                &values.min_block_length as *const i32 as *const c_void,
+                int_size,
+            ),
+            option(
+                MAX_BLOCK_LENGTH_URI,
+                atom_int,
+                &values.max_block_length as *const i32 as *const c_void,
+                int_size,
+            ),
+            option(
+                SAMPLE_RATE_URI,
+                atom_float,
+                &values.sample_rate as *const f32 as *const c_void,
+                std::mem::size_of::<f32>(),
+            ),
+        ];
+        if settings.buffer_size > 0 {
+            options.push(option(
+                NOMINAL_BLOCK_LENGTH_URI,
+                atom_int,
+                &values.nominal_block_length as *const i32 as *const c_void,
+                int_size,
+            ));
+        }
+        // The list is terminated by an option with a zero key and null value.
+        options.push(Lv2OptionsOption {
+            context: CONTEXT_INSTANCE,
+            subject: 0,
+            key: 0,
+            size: 0,
+            value_type: 0,
+            value: std::ptr::null(),
+        });
+        let feature = lv2_raw::LV2Feature {
+            uri: OPTIONS_URI.as_ptr() as *const ::std::os::raw::c_char,
+            data: options.as_ptr() as *mut c_void,
+        };
+        Options {
+            values,
+            options,
+            feature,
+            bounded_block_length_feature: lv2_raw::LV2Feature {
+                uri: BOUNDED_BLOCK_LENGTH_URI.as_ptr() as *const ::std::os::raw::c_char,
+                data: std::ptr::null_mut(),
+            },
+        }
+    }
+
+    /// Get the options as an LV2_feature.
+    pub fn as_lv2_feature(&self) -> &lv2_raw::LV2Feature {
+        &self.feature
+    }
+
+    /// Get the bounded block length LV2_feature.
+    pub fn bounded_block_length_feature(&self) -> &lv2_raw::LV2Feature {
+        &self.bounded_block_length_feature
+    }
+}
+
+impl std::fmt::Debug for Options {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Options")
+            .field("values", &self.values)
+            .field("len", &self.options.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_are_terminated_and_point_to_values() {
+        let settings = AudioSettings {
+            sample_rate: 48000.0,
+            buffer_size: 256,
+        };
+        let options = Options::new(settings, 8192, &|uri| uri.len() as u32);
+        let ptr = options.as_lv2_feature().data as *const Lv2OptionsOption;
+        let options = unsafe { std::slice::from_raw_parts(ptr, 5) };
+        let max = unsafe { *(options[1].value as *const i32) };
+        let sample_rate = unsafe { *(options[2].value as *const f32) };
+        let nominal = unsafe { *(options[3].value as *const i32) };
+        assert_eq!((max, sample_rate, nominal), (256, 48000.0, 256));
+        assert_eq!(options[4].key, 0);
+        assert!(options[4].value.is_null());
+    }
+}
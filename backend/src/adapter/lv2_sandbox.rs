@@ -0,0 +1,865 @@
+//! Runs LV2 plugins in a child process so that a crashing plugin does not take down the backend.
+//! The child is this executable started with `--plugin-host`. Audio, MIDI and the transport are
+//! exchanged through a block of shared memory. If the child exits, the plugin instance outputs
+//! silence and sets its failure flag.
+//!
+//! The audio thread never waits for the child. Each block is handed to the child and its output
+//! is collected on the next call to `process`, so sandboxed plugins have one block of extra
+//! latency.
+use olivia_core::plugin::{
+    AudioChannel, AudioPortMapping, FailureFlag, ParameterInfo, PluginInstance, TransportInfo,
+};
+use olivia_core::plugin_abi::MidiEvent;
+use std::ffi::c_void;
+use std::os::raw::c_int;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// The command line flag that starts the plugin host.
+pub const PLUGIN_HOST_FLAG: &str = "--plugin-host";
+
+/// The largest number of frames that can be processed at once.
+const MAX_FRAMES: usize = 8192;
+
+/// The largest number of MIDI events per block. Extra events are dropped.
+const MAX_MIDI_EVENTS: usize = 1024;
+
+/// The largest number of parameter changes per block. Extra changes are sent with the next block.
+const MAX_PARAMETER_CHANGES: usize = 256;
+
+/// The largest number of audio inputs or outputs of a sandboxed plugin.
+pub const MAX_AUDIO_PORTS: usize = 16;
+
+/// The largest number of processor inputs, such as sidechains, that a sandboxed plugin can read.
+/// Mappings to further inputs are treated as unmapped.
+const MAX_SIDECHAINS: usize = 4;
+
+/// The codes for the track channels in an encoded audio port mapping. Other codes are the index of
+/// a sidechain.
+const CHANNEL_NONE: i32 = -1;
+const CHANNEL_LEFT: i32 = -2;
+const CHANNEL_RIGHT: i32 = -3;
+
+/// How long to wait for the child to load the plugin.
+const STARTUP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How long the child sleeps between checks for a new block after spinning for a while.
+const CHILD_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_micros(20);
+
+/// The number of times the child checks for a new block before it starts sleeping.
+const CHILD_SPIN_COUNT: usize = 1000;
+
+/// How often the child is checked for having exited.
+const MONITOR_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const MAP_SHARED: c_int = 1;
+
+/// A change to the value of a parameter.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SharedParameter {
+    index: u32,
+    value: f32,
+}
+
+/// The memory shared between the backend and the plugin host. All fields are valid when zeroed.
+#[repr(C)]
+struct SharedBlock {
+    /// Set by the child once the plugin has been built.
+    ready: AtomicU32,
+    /// Set by the parent when the child should exit.
+    shutdown: AtomicU32,
+    /// Incremented by the parent once the inputs for a block have been written.
+    request: AtomicU32,
+    /// Set to `request` by the child once the outputs for the block have been written.
+    response: AtomicU32,
+    /// The latency reported by the plugin after the last block.
+    latency: AtomicU32,
+    frames: u32,
+    /// Set while the plugin is disabled by bypassing it.
+    disabled: u32,
+    /// Incremented when the audio port mapping changes. The plugin's default mapping is used
+    /// while 0.
+    mapping_version: u32,
+    mapping_inputs_len: u32,
+    mapping_inputs: [i32; MAX_AUDIO_PORTS],
+    mapping_outputs_len: u32,
+    mapping_outputs: [i32; MAX_AUDIO_PORTS],
+    midi_len: u32,
+    has_transport: u32,
+    playing: u32,
+    beats_per_bar: u32,
+    bar: u32,
+    frame: u64,
+    bpm: f64,
+    bar_beat: f64,
+    midi: [MidiEvent; MAX_MIDI_EVENTS],
+    parameters_len: u32,
+    parameters: [SharedParameter; MAX_PARAMETER_CHANGES],
+    inputs: [[f32; MAX_FRAMES]; 2],
+    sidechains_len: u32,
+    sidechains: [[f32; MAX_FRAMES]; MAX_SIDECHAINS],
+    outputs: [[f32; MAX_FRAMES]; 2],
+    midi_output_len: u32,
+    midi_output: [MidiEvent; MAX_MIDI_EVENTS],
+}
+
+/// A `SharedBlock` mapped from a file.
+struct SharedMemory {
+    block: NonNull<SharedBlock>,
+    // The file is removed when the memory is dropped if it was created by this process.
+    owned_path: Option<PathBuf>,
+}
+
+// The block is only accessed through atomics or by the side whose turn it is.
+unsafe impl Send for SharedMemory {}
+
+impl SharedMemory {
+    /// Create a new zeroed block backed by a new file that only this user can access.
+    fn create() -> std::io::Result<SharedMemory> {
+        static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+        let dir = Path::new("/dev/shm");
+        let dir = if dir.is_dir() {
+            dir.to_path_buf()
+        } else {
+            std::env::temp_dir()
+        };
+        let path = dir.join(format!(
+            "olivia_plugin_{}_{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        file.set_len(std::mem::size_of::<SharedBlock>() as u64)?;
+        let mut memory = SharedMemory::map(&file)?;
+        memory.owned_path = Some(path);
+        Ok(memory)
+    }
+
+    /// Map the block created by another process.
+    fn open(path: &Path) -> std::io::Result<SharedMemory> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        SharedMemory::map(&file)
+    }
+
+    fn map(file: &std::fs::File) -> std::io::Result<SharedMemory> {
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                std::mem::size_of::<SharedBlock>(),
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        // MAP_FAILED is -1.
+        if ptr as isize == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        match NonNull::new(ptr as *mut SharedBlock) {
+            Some(block) => Ok(SharedMemory {
+                block,
+                owned_path: None,
+            }),
+            None => Err(std::io::Error::other("mmap returned null")),
+        }
+    }
+
+    fn path(&self) -> Option<&Path> {
+        self.owned_path.as_deref()
+    }
+
+    /// Remove the file once the other process has mapped it. The memory stays mapped.
+    fn unlink(&mut self) {
+        if let Some(path) = self.owned_path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    fn block(&self) -> &SharedBlock {
+        unsafe { self.block.as_ref() }
+    }
+
+    fn block_mut(&mut self) -> &mut SharedBlock {
+        unsafe { self.block.as_mut() }
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(
+                self.block.as_ptr() as *mut c_void,
+                std::mem::size_of::<SharedBlock>(),
+            )
+        };
+        if let Some(path) = self.owned_path.as_ref() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A block that has been handed to the child.
+#[derive(Copy, Clone, Debug)]
+struct InFlight {
+    frames: usize,
+    // The frame, counted from the first block, at which the block starts.
+    start: u64,
+    // Set once silence has been output in place of the block because the child was too slow.
+    late: bool,
+}
+
+/// A plugin instance that runs in a child process.
+pub struct SandboxedPluginInstance {
+    uri: String,
+    pid: u32,
+    memory: SharedMemory,
+    // Tells the monitor thread to kill the child.
+    shutdown: Arc<AtomicBool>,
+    // The last request sent to the child.
+    request: u32,
+    in_flight: Option<InFlight>,
+    // The number of frames processed so far.
+    frame: u64,
+    // The delay of the output, one block.
+    delay: usize,
+    // The output of the child, delayed by `delay` frames.
+    delayed_left: (ringbuf::Producer<f32>, ringbuf::Consumer<f32>),
+    delayed_right: (ringbuf::Producer<f32>, ringbuf::Consumer<f32>),
+    // The MIDI output of the child and the frames at which to output it, in order.
+    pending_midi: Vec<(u64, MidiEvent)>,
+    midi_output: Option<Vec<olivia_core::TimedMidi<'static>>>,
+    transport: Option<TransportInfo>,
+    failure: FailureFlag,
+    handles_bypass: bool,
+    disabled: bool,
+    // The encoded audio port mapping and the processor input of each sidechain, if a mapping has
+    // been set.
+    mapping_version: u32,
+    mapping_inputs: Vec<i32>,
+    mapping_outputs: Vec<i32>,
+    sidechains: Vec<usize>,
+    parameters: Vec<ParameterInfo>,
+    // The value of each parameter, in the same order as `parameters`.
+    values: Vec<f32>,
+    // The positions within `parameters` of the values that have not been sent to the child yet.
+    pending_parameters: Vec<usize>,
+}
+
+impl std::fmt::Debug for SandboxedPluginInstance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SandboxedPluginInstance")
+            .field("uri", &self.uri)
+            .field("child", &self.pid)
+            .field("failed", &self.failure.is_set())
+            .finish()
+    }
+}
+
+/// What the backend needs to know about the plugin that runs in the child.
+#[derive(Clone, Debug, Default)]
+pub struct SandboxedPlugin {
+    pub parameters: Vec<ParameterInfo>,
+    /// If the plugin passes its input through by itself while disabled.
+    pub handles_bypass: bool,
+    pub has_midi_output: bool,
+}
+
+impl SandboxedPluginInstance {
+    /// Start a plugin host for the LV2 plugin with `uri` and wait for it to load the plugin.
+    pub fn spawn(
+        uri: &str,
+        sample_rate: f64,
+        buffer_size: usize,
+        lv2_path: &[PathBuf],
+        plugin: SandboxedPlugin,
+    ) -> std::io::Result<SandboxedPluginInstance> {
+        let mut memory = SharedMemory::create()?;
+        let mut command = std::process::Command::new(std::env::current_exe()?);
+        command
+            .arg(PLUGIN_HOST_FLAG)
+            .arg(uri)
+            .arg(memory.path().unwrap_or_else(|| Path::new("")))
+            .arg(sample_rate.to_string())
+            .arg(buffer_size.to_string());
+        if !lv2_path.is_empty() {
+            command.env(
+                "LV2_PATH",
+                std::env::join_paths(lv2_path).unwrap_or_default(),
+            );
+        }
+        let mut child = command.spawn()?;
+        info!("Started plugin host {} for {}.", child.id(), uri);
+        let deadline = std::time::Instant::now() + STARTUP_TIMEOUT;
+        while memory.block().ready.load(Ordering::Acquire) == 0 {
+            if let Some(status) = child.try_wait()? {
+                return Err(std::io::Error::other(format!(
+                    "plugin host exited with {}",
+                    status
+                )));
+            }
+            if std::time::Instant::now() > deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "plugin host did not start",
+                ));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        memory.unlink();
+        let pid = child.id();
+        let failure = FailureFlag::default();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        {
+            let failure = failure.clone();
+            let shutdown = shutdown.clone();
+            std::thread::Builder::new()
+                .name("olivia_plugin_host_monitor".to_string())
+                .spawn(move || monitor_child(child, failure, shutdown))?;
+        }
+        let delay = buffer_size.min(MAX_FRAMES);
+        let delay_line = || {
+            let (mut producer, consumer) = ringbuf::RingBuffer::new(delay + 2 * MAX_FRAMES).split();
+            producer.push_iter(&mut std::iter::repeat_n(0.0, delay));
+            (producer, consumer)
+        };
+        Ok(SandboxedPluginInstance {
+            uri: uri.to_string(),
+            pid,
+            memory,
+            shutdown,
+            request: 0,
+            in_flight: None,
+            frame: 0,
+            delay,
+            delayed_left: delay_line(),
+            delayed_right: delay_line(),
+            pending_midi: Vec::with_capacity(2 * MAX_MIDI_EVENTS),
+            midi_output: if plugin.has_midi_output {
+                Some(Vec::with_capacity(MAX_MIDI_EVENTS))
+            } else {
+                None
+            },
+            transport: None,
+            failure,
+            handles_bypass: plugin.handles_bypass,
+            disabled: false,
+            mapping_version: 0,
+            mapping_inputs: Vec::with_capacity(MAX_AUDIO_PORTS),
+            mapping_outputs: Vec::with_capacity(MAX_AUDIO_PORTS),
+            sidechains: Vec::with_capacity(MAX_SIDECHAINS),
+            values: plugin.parameters.iter().map(|p| p.default).collect(),
+            pending_parameters: Vec::with_capacity(plugin.parameters.len()),
+            parameters: plugin.parameters,
+        })
+    }
+
+    /// Collect the output of the block that was handed to the child. If the child has not
+    /// finished it, silence is output in its place.
+    fn collect_output(&mut self) {
+        let in_flight = match self.in_flight {
+            Some(f) => f,
+            None => return,
+        };
+        let block = self.memory.block();
+        if block.response.load(Ordering::Acquire) == self.request {
+            self.in_flight = None;
+            if in_flight.late {
+                return;
+            }
+            self.delayed_left
+                .0
+                .push_slice(&block.outputs[0][..in_flight.frames]);
+            self.delayed_right
+                .0
+                .push_slice(&block.outputs[1][..in_flight.frames]);
+            let midi_len = (block.midi_output_len as usize).min(MAX_MIDI_EVENTS);
+            let space = self.pending_midi.capacity() - self.pending_midi.len();
+            let delay = self.delay as u64;
+            self.pending_midi.extend(
+                block.midi_output[..midi_len]
+                    .iter()
+                    .map(|m| (in_flight.start + u64::from(m.frame) + delay, *m))
+                    .take(space),
+            );
+        } else if !in_flight.late {
+            push_silence(&mut self.delayed_left.0, in_flight.frames);
+            push_silence(&mut self.delayed_right.0, in_flight.frames);
+            self.in_flight = Some(InFlight {
+                late: true,
+                ..in_flight
+            });
+        }
+    }
+
+    fn write_inputs(
+        &mut self,
+        inputs: &[&[f32]],
+        midi: &[olivia_core::TimedMidi],
+        left: &[f32],
+        right: &[f32],
+    ) {
+        let transport = self.transport;
+        let disabled = self.disabled;
+        let block = self.memory.block_mut();
+        let frames = left.len();
+        block.frames = frames as u32;
+        block.disabled = disabled as u32;
+        block.inputs[0][..frames].copy_from_slice(left);
+        block.inputs[1][..frames].copy_from_slice(right);
+        if block.mapping_version != self.mapping_version {
+            block.mapping_version = self.mapping_version;
+            block.mapping_inputs_len = self.mapping_inputs.len() as u32;
+            block.mapping_inputs[..self.mapping_inputs.len()].copy_from_slice(&self.mapping_inputs);
+            block.mapping_outputs_len = self.mapping_outputs.len() as u32;
+            block.mapping_outputs[..self.mapping_outputs.len()]
+                .copy_from_slice(&self.mapping_outputs);
+        }
+        block.sidechains_len = self.sidechains.len() as u32;
+        for (shared, input) in block.sidechains.iter_mut().zip(self.sidechains.iter()) {
+            match inputs.get(*input) {
+                Some(input) if input.len() >= frames => {
+                    shared[..frames].copy_from_slice(&input[..frames])
+                }
+                _ => shared[..frames].iter_mut().for_each(|v| *v = 0.0),
+            }
+        }
+        let mut midi_len = 0;
+        for m in midi.iter().filter(|m| m.frame < frames) {
+            if midi_len == MAX_MIDI_EVENTS {
+                break;
+            }
+            if let Some(shared) = MidiEvent::new(m) {
+                block.midi[midi_len] = shared;
+                midi_len += 1;
+            }
+        }
+        block.midi_len = midi_len as u32;
+        let parameters_len = self.pending_parameters.len().min(MAX_PARAMETER_CHANGES);
+        for (shared, position) in block
+            .parameters
+            .iter_mut()
+            .zip(self.pending_parameters.drain(..parameters_len))
+        {
+            *shared = SharedParameter {
+                index: self.parameters[position].index as u32,
+                value: self.values[position],
+            };
+        }
+        block.parameters_len = parameters_len as u32;
+        match transport {
+            Some(t) => {
+                block.has_transport = 1;
+                block.playing = t.playing as u32;
+                block.frame = t.frame;
+                block.bpm = t.bpm;
+                block.beats_per_bar = t.beats_per_bar;
+                block.bar = t.bar;
+                block.bar_beat = t.bar_beat;
+            }
+            None => block.has_transport = 0,
+        }
+    }
+
+    /// Move the MIDI that is due within the next `frames` frames to the MIDI output.
+    fn output_midi(&mut self, frames: usize) {
+        let midi_output = match self.midi_output.as_mut() {
+            Some(m) => m,
+            None => return,
+        };
+        midi_output.clear();
+        let end = self.frame + frames as u64;
+        let due = self.pending_midi.partition_point(|(frame, _)| *frame < end);
+        for (frame, m) in self.pending_midi.drain(..due) {
+            if let Some(mut m) = m.to_timed_midi() {
+                m.frame = frame.saturating_sub(self.frame) as usize;
+                midi_output.push(m);
+            }
+        }
+    }
+}
+
+impl PluginInstance for SandboxedPluginInstance {
+    fn set_transport(&mut self, transport: &TransportInfo) {
+        self.transport = Some(*transport);
+    }
+
+    fn parameters(&self) -> Vec<ParameterInfo> {
+        self.parameters.clone()
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        if let Some(position) = self.parameters.iter().position(|p| p.index == index) {
+            self.values[position] = value;
+            if !self.pending_parameters.contains(&position) {
+                self.pending_parameters.push(position);
+            }
+        }
+    }
+
+    fn get_parameter(&self, index: usize) -> Option<f32> {
+        let position = self.parameters.iter().position(|p| p.index == index)?;
+        Some(self.values[position])
+    }
+
+    fn set_enabled(&mut self, enabled: bool) -> bool {
+        self.disabled = !enabled;
+        self.handles_bypass
+    }
+
+    /// Processor inputs beyond the first `MAX_SIDECHAINS` that are mapped are treated as
+    /// unmapped.
+    fn set_audio_port_mapping(&mut self, mapping: AudioPortMapping) {
+        encode_mapping(
+            &mapping,
+            &mut self.mapping_inputs,
+            &mut self.mapping_outputs,
+            &mut self.sidechains,
+        );
+        self.mapping_version = self.mapping_version.wrapping_add(1).max(1);
+    }
+
+    fn midi_output(&self) -> Option<&[olivia_core::TimedMidi<'static>]> {
+        self.midi_output.as_deref()
+    }
+
+    fn process(
+        &mut self,
+        midi: &[olivia_core::TimedMidi],
+        out_left: &mut [f32],
+        out_right: &mut [f32],
+    ) {
+        self.process_with_inputs(&[], midi, out_left, out_right);
+    }
+
+    fn process_with_inputs(
+        &mut self,
+        inputs: &[&[f32]],
+        midi: &[olivia_core::TimedMidi],
+        out_left: &mut [f32],
+        out_right: &mut [f32],
+    ) {
+        let frames = out_left.len().min(out_right.len()).min(MAX_FRAMES);
+        self.collect_output();
+        // The child is still working on an earlier block or has exited.
+        if self.in_flight.is_some() || self.failure.is_set() {
+            push_silence(&mut self.delayed_left.0, frames);
+            push_silence(&mut self.delayed_right.0, frames);
+        } else {
+            self.write_inputs(inputs, midi, &out_left[..frames], &out_right[..frames]);
+            self.request = self.request.wrapping_add(1);
+            self.memory
+                .block()
+                .request
+                .store(self.request, Ordering::Release);
+            self.in_flight = Some(InFlight {
+                frames,
+                start: self.frame,
+                late: false,
+            });
+        }
+        pop_or_silence(&mut self.delayed_left.1, out_left);
+        pop_or_silence(&mut self.delayed_right.1, out_right);
+        self.output_midi(frames);
+        self.frame += frames as u64;
+    }
+
+    fn latency(&self) -> usize {
+        self.delay + self.memory.block().latency.load(Ordering::Relaxed) as usize
+    }
+
+    fn failure_flag(&self) -> Option<FailureFlag> {
+        Some(self.failure.clone())
+    }
+}
+
+impl Drop for SandboxedPluginInstance {
+    fn drop(&mut self) {
+        self.memory.block().shutdown.store(1, Ordering::Release);
+        self.shutdown.store(true, Ordering::Release);
+    }
+}
+
+/// Wait for the child to exit and set the failure flag if it exits on its own. The child is
+/// killed once `shutdown` is set.
+fn monitor_child(mut child: std::process::Child, failure: FailureFlag, shutdown: Arc<AtomicBool>) {
+    loop {
+        if shutdown.load(Ordering::Acquire) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return;
+        }
+        match child.try_wait() {
+            Ok(None) => std::thread::sleep(MONITOR_INTERVAL),
+            Ok(Some(status)) => {
+                warn!("Plugin host {} exited with {}.", child.id(), status);
+                failure.set();
+                return;
+            }
+            Err(e) => {
+                error!("Failed to wait for plugin host {}: {}", child.id(), e);
+                failure.set();
+                return;
+            }
+        }
+    }
+}
+
+fn push_silence(producer: &mut ringbuf::Producer<f32>, frames: usize) {
+    producer.push_iter(&mut std::iter::repeat_n(0.0, frames));
+}
+
+/// Fill `out` from `consumer`, with silence for the frames that are not available.
+fn pop_or_silence(consumer: &mut ringbuf::Consumer<f32>, out: &mut [f32]) {
+    let len = consumer.pop_slice(out);
+    out[len..].iter_mut().for_each(|v| *v = 0.0);
+}
+
+/// Encode an audio port mapping for the shared block. The processor inputs that the mapping uses
+/// are assigned to sidechains, which are stored in `sidechains`.
+fn encode_mapping(
+    mapping: &AudioPortMapping,
+    inputs: &mut Vec<i32>,
+    outputs: &mut Vec<i32>,
+    sidechains: &mut Vec<usize>,
+) {
+    sidechains.clear();
+    let mut encode = |channel: &Option<AudioChannel>| match channel {
+        Some(AudioChannel::Left) => CHANNEL_LEFT,
+        Some(AudioChannel::Right) => CHANNEL_RIGHT,
+        Some(AudioChannel::Input(n)) => match sidechains.iter().position(|s| s == n) {
+            Some(i) => i as i32,
+            None if sidechains.len() < MAX_SIDECHAINS => {
+                sidechains.push(*n);
+                sidechains.len() as i32 - 1
+            }
+            None => CHANNEL_NONE,
+        },
+        None => CHANNEL_NONE,
+    };
+    inputs.clear();
+    inputs.extend(mapping.inputs.iter().take(MAX_AUDIO_PORTS).map(&mut encode));
+    outputs.clear();
+    outputs.extend(
+        mapping
+            .outputs
+            .iter()
+            .take(MAX_AUDIO_PORTS)
+            .map(&mut encode),
+    );
+}
+
+/// Decode an audio port mapping from the shared block.
+fn decode_mapping(inputs: &[i32], outputs: &[i32]) -> AudioPortMapping {
+    let decode = |code: &i32| match *code {
+        CHANNEL_LEFT => Some(AudioChannel::Left),
+        CHANNEL_RIGHT => Some(AudioChannel::Right),
+        n if n >= 0 => Some(AudioChannel::Input(n as usize)),
+        _ => None,
+    };
+    AudioPortMapping {
+        inputs: inputs.iter().map(decode).collect(),
+        outputs: outputs.iter().map(decode).collect(),
+    }
+}
+
+/// Run the plugin host. `args` are the arguments after `--plugin-host`: the plugin URI, the path
+/// of the shared memory, the sample rate and the buffer size. `build` builds the plugin.
+pub fn run_plugin_host<F>(args: &[String], build: F) -> Result<(), String>
+where
+    F: FnOnce(&str, f64, usize) -> Result<Box<dyn PluginInstance>, String>,
+{
+    let (uri, path, sample_rate, buffer_size) = match args {
+        [uri, path, sample_rate, buffer_size, ..] => (
+            uri,
+            path,
+            sample_rate.parse::<f64>().map_err(|e| e.to_string())?,
+            buffer_size.parse::<usize>().map_err(|e| e.to_string())?,
+        ),
+        _ => return Err("expected <uri> <shared memory> <sample rate> <buffer size>".into()),
+    };
+    let mut memory = SharedMemory::open(Path::new(path)).map_err(|e| e.to_string())?;
+    let mut plugin = build(uri, sample_rate, buffer_size)?;
+    let parent = std::os::unix::process::parent_id();
+    let mut left = vec![0.0; MAX_FRAMES];
+    let mut right = vec![0.0; MAX_FRAMES];
+    let mut midi = Vec::with_capacity(MAX_MIDI_EVENTS);
+    memory.block().ready.store(1, Ordering::Release);
+
+    let mut last_request = 0;
+    let mut mapping_version = 0;
+    let mut disabled = false;
+    let mut idle = 0;
+    loop {
+        let request = memory.block().request.load(Ordering::Acquire);
+        if request == last_request {
+            if memory.block().shutdown.load(Ordering::Acquire) != 0
+                || std::os::unix::process::parent_id() != parent
+            {
+                return Ok(());
+            }
+            idle += 1;
+            if idle < CHILD_SPIN_COUNT {
+                std::hint::spin_loop();
+            } else {
+                std::thread::sleep(CHILD_POLL_INTERVAL);
+            }
+            continue;
+        }
+        idle = 0;
+        last_request = request;
+
+        let block = memory.block_mut();
+        let frames = (block.frames as usize).min(MAX_FRAMES);
+        midi.clear();
+        midi.extend(
+            block.midi[..(block.midi_len as usize).min(MAX_MIDI_EVENTS)]
+                .iter()
+                .filter_map(|m| m.to_timed_midi()),
+        );
+        if block.mapping_version != mapping_version {
+            mapping_version = block.mapping_version;
+            plugin.set_audio_port_mapping(decode_mapping(
+                &block.mapping_inputs[..(block.mapping_inputs_len as usize).min(MAX_AUDIO_PORTS)],
+                &block.mapping_outputs[..(block.mapping_outputs_len as usize).min(MAX_AUDIO_PORTS)],
+            ));
+        }
+        if (block.disabled != 0) != disabled {
+            disabled = block.disabled != 0;
+            plugin.set_enabled(!disabled);
+        }
+        for p in
+            block.parameters[..(block.parameters_len as usize).min(MAX_PARAMETER_CHANGES)].iter()
+        {
+            plugin.set_parameter(p.index as usize, p.value);
+        }
+        if block.has_transport != 0 {
+            plugin.set_transport(&TransportInfo {
+                playing: block.playing != 0,
+                frame: block.frame,
+                bpm: block.bpm,
+                beats_per_bar: block.beats_per_bar,
+                bar: block.bar,
+                bar_beat: block.bar_beat,
+            });
+        }
+        left[..frames].copy_from_slice(&block.inputs[0][..frames]);
+        right[..frames].copy_from_slice(&block.inputs[1][..frames]);
+        let sidechains: Vec<&[f32]> = block.sidechains
+            [..(block.sidechains_len as usize).min(MAX_SIDECHAINS)]
+            .iter()
+            .map(|s| &s[..frames])
+            .collect();
+        plugin.process_with_inputs(
+            &sidechains,
+            &midi,
+            &mut left[..frames],
+            &mut right[..frames],
+        );
+        block.outputs[0][..frames].copy_from_slice(&left[..frames]);
+        block.outputs[1][..frames].copy_from_slice(&right[..frames]);
+        let mut midi_output_len = 0;
+        for m in plugin.midi_output().unwrap_or_default().iter() {
+            if midi_output_len == MAX_MIDI_EVENTS {
+                break;
+            }
+            if let Some(shared) = MidiEvent::new(m) {
+                block.midi_output[midi_output_len] = shared;
+                midi_output_len += 1;
+            }
+        }
+        block.midi_output_len = midi_output_len as u32;
+        block
+            .latency
+            .store(plugin.latency() as u32, Ordering::Relaxed);
+        block.response.store(request, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_memory_is_shared_between_mappings() {
+        let parent = SharedMemory::create().unwrap();
+        let path = parent.path().unwrap().to_path_buf();
+        let mut child = SharedMemory::open(&path).unwrap();
+        child.block_mut().outputs[1][7] = 0.5;
+        child.block().response.store(3, Ordering::Release);
+        assert_eq!(parent.block().outputs[1][7], 0.5);
+        assert_eq!(parent.block().response.load(Ordering::Acquire), 3);
+        drop(child);
+        drop(parent);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn shared_memory_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let memory = SharedMemory::create().unwrap();
+        let mode = std::fs::metadata(memory.path().unwrap())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn processor_inputs_are_mapped_to_sidechains() {
+        let mapping = AudioPortMapping {
+            inputs: vec![
+                Some(AudioChannel::Right),
+                Some(AudioChannel::Input(5)),
+                Some(AudioChannel::Input(2)),
+                Some(AudioChannel::Input(5)),
+                None,
+            ],
+            outputs: vec![Some(AudioChannel::Left)],
+        };
+        let (mut inputs, mut outputs, mut sidechains) = (Vec::new(), Vec::new(), Vec::new());
+        encode_mapping(&mapping, &mut inputs, &mut outputs, &mut sidechains);
+        assert_eq!(sidechains, vec![5, 2]);
+        let decoded = decode_mapping(&inputs, &outputs);
+        assert_eq!(
+            decoded.inputs,
+            vec![
+                Some(AudioChannel::Right),
+                Some(AudioChannel::Input(0)),
+                Some(AudioChannel::Input(1)),
+                Some(AudioChannel::Input(0)),
+                None,
+            ]
+        );
+        assert_eq!(decoded.outputs, mapping.outputs);
+    }
+}
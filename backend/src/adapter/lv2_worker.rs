@@ -0,0 +1,321 @@
+//! The LV2 worker extension. Plugins schedule non-realtime work, such as loading samples, from
+//! `run`. The work is performed on a dedicated thread and the responses are delivered back to the
+//! plugin on the audio thread. See http://lv2plug.in/ns/ext/worker.
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The URI for the worker schedule LV2 feature.
+pub const SCHEDULE_URI: &str = "http://lv2plug.in/ns/ext/worker#schedule\0";
+
+/// The URI for the worker interface extension data.
+const INTERFACE_URI: &str = "http://lv2plug.in/ns/ext/worker#interface";
+
+/// The largest request or response in bytes.
+const MAX_MESSAGE_SIZE: usize = 8192;
+
+/// The capacity in bytes of the request and response rings.
+const RING_SIZE: usize = 65536;
+
+/// The size of the header that precedes every message in a ring.
+const HEADER_SIZE: usize = std::mem::size_of::<u32>();
+
+/// How long the worker thread sleeps when it has not been woken up.
+const IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// LV2_Worker_Status.
+type Status = u32;
+const STATUS_SUCCESS: Status = 0;
+const STATUS_ERR_UNKNOWN: Status = 1;
+const STATUS_ERR_NO_SPACE: Status = 2;
+
+type RespondFunction = extern "C" fn(handle: *mut c_void, size: u32, data: *const c_void) -> Status;
+
+/// LV2_Worker_Schedule.
+#[repr(C)]
+struct Lv2WorkerSchedule {
+    handle: *mut c_void,
+    schedule_work: extern "C" fn(handle: *mut c_void, size: u32, data: *const c_void) -> Status,
+}
+
+/// LV2_Worker_Interface.
+#[repr(C)]
+struct Lv2WorkerInterface {
+    work: extern "C" fn(
+        instance: lv2_raw::LV2Handle,
+        respond: RespondFunction,
+        handle: *mut c_void,
+        size: u32,
+        data: *const c_void,
+    ) -> Status,
+    work_response:
+        extern "C" fn(instance: lv2_raw::LV2Handle, size: u32, body: *const c_void) -> Status,
+    end_run: Option<extern "C" fn(instance: lv2_raw::LV2Handle) -> Status>,
+}
+
+/// Provides the worker schedule feature for a single plugin instance and runs the work that the
+/// plugin schedules.
+pub struct Worker {
+    // The fields are referenced as void ptrs within feature and schedule.
+    #[allow(dead_code)]
+    requests: Box<MessageWriter>,
+    #[allow(dead_code)]
+    schedule: Box<Lv2WorkerSchedule>,
+    feature: lv2_raw::LV2Feature,
+    // Requests and responses move to the worker thread once it is started.
+    pending: Option<(ringbuf::Consumer<u8>, MessageWriter)>,
+    responses: ringbuf::Consumer<u8>,
+    // Scratch space for reading responses.
+    response_buffer: Vec<u8>,
+    // The plugin instance and its worker interface. Set once the worker thread has started.
+    interface: Option<(lv2_raw::LV2Handle, *const Lv2WorkerInterface)>,
+    thread: Option<(Arc<AtomicBool>, std::thread::JoinHandle<()>)>,
+}
+
+// The raw pointers are only dereferenced on the audio thread, or on the worker thread which is
+// joined before the worker is dropped.
+unsafe impl Send for Worker {}
+
+impl Default for Worker {
+    fn default() -> Worker {
+        let (request_producer, request_consumer) = ringbuf::RingBuffer::new(RING_SIZE).split();
+        let (response_producer, response_consumer) = ringbuf::RingBuffer::new(RING_SIZE).split();
+        let mut requests = Box::new(MessageWriter::new(request_producer));
+        let mut schedule = Box::new(Lv2WorkerSchedule {
+            handle: requests.as_mut() as *mut MessageWriter as *mut c_void,
+            schedule_work: schedule_work_impl,
+        });
+        Worker {
+            feature: lv2_raw::LV2Feature {
+                uri: SCHEDULE_URI.as_ptr() as *const ::std::os::raw::c_char,
+                data: schedule.as_mut() as *mut Lv2WorkerSchedule as *mut c_void,
+            },
+            requests,
+            schedule,
+            pending: Some((request_consumer, MessageWriter::new(response_producer))),
+            responses: response_consumer,
+            response_buffer: vec![0; MAX_MESSAGE_SIZE],
+            interface: None,
+            thread: None,
+        }
+    }
+}
+
+impl Worker {
+    /// Get the worker schedule as an LV2_feature.
+    pub fn as_lv2_feature(&self) -> &lv2_raw::LV2Feature {
+        &self.feature
+    }
+
+    /// Start the worker thread for `instance` if it implements the worker interface. Does nothing
+    /// if the instance does not implement the interface.
+    ///
+    /// # Safety
+    /// The worker must be dropped before `instance`.
+    pub unsafe fn start(&mut self, instance: &lilv::Instance) -> std::io::Result<()> {
+        let interface = match instance.extension_data::<Lv2WorkerInterface>(INTERFACE_URI) {
+            Some(i) => i.as_ptr() as *const Lv2WorkerInterface,
+            None => return Ok(()),
+        };
+        let (requests, responses) = match self.pending.take() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let handle = instance.handle();
+        let running = Arc::new(AtomicBool::new(true));
+        let mut thread = WorkerThread {
+            handle,
+            interface,
+            requests,
+            responses,
+            request_buffer: vec![0; MAX_MESSAGE_SIZE],
+            running: running.clone(),
+        };
+        let join_handle = std::thread::Builder::new()
+            .name("olivia_lv2_worker".to_string())
+            .spawn(move || thread.run())?;
+        self.requests.wake = Some(join_handle.thread().clone());
+        self.interface = Some((handle, interface));
+        self.thread = Some((running, join_handle));
+        Ok(())
+    }
+
+    /// Deliver the responses from the worker thread to the plugin and signal the end of the run
+    /// cycle. Must be called on the audio thread after each run.
+    pub fn end_run(&mut self) {
+        let (handle, interface) = match self.interface {
+            Some(i) => i,
+            None => return,
+        };
+        let interface = unsafe { &*interface };
+        while let Some(size) = read_message(&mut self.responses, &mut self.response_buffer) {
+            (interface.work_response)(
+                handle,
+                size as u32,
+                self.response_buffer.as_ptr() as *const c_void,
+            );
+        }
+        if let Some(end_run) = interface.end_run {
+            end_run(handle);
+        }
+    }
+}
+
+// Joining the thread blocks until any work in progress finishes, so plugin instances with a worker
+// must not be dropped on the audio thread. The thread can not be detached instead since it uses the
+// plugin instance, which is freed right after the worker.
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if let Some((running, join_handle)) = self.thread.take() {
+            running.store(false, Ordering::SeqCst);
+            join_handle.thread().unpark();
+            if join_handle.join().is_err() {
+                error!("LV2 worker thread panicked.");
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Worker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Worker")
+            .field("running", &self.thread.is_some())
+            .finish()
+    }
+}
+
+/// Performs the work requested by a plugin.
+struct WorkerThread {
+    handle: lv2_raw::LV2Handle,
+    interface: *const Lv2WorkerInterface,
+    requests: ringbuf::Consumer<u8>,
+    responses: MessageWriter,
+    request_buffer: Vec<u8>,
+    running: Arc<AtomicBool>,
+}
+
+unsafe impl Send for WorkerThread {}
+
+impl WorkerThread {
+    fn run(&mut self) {
+        let interface = unsafe { &*self.interface };
+        while self.running.load(Ordering::SeqCst) {
+            while let Some(size) = read_message(&mut self.requests, &mut self.request_buffer) {
+                let status = (interface.work)(
+                    self.handle,
+                    respond_impl,
+                    &mut self.responses as *mut MessageWriter as *mut c_void,
+                    size as u32,
+                    self.request_buffer.as_ptr() as *const c_void,
+                );
+                if status != STATUS_SUCCESS {
+                    warn!("LV2 worker failed with status {}.", status);
+                }
+            }
+            std::thread::park_timeout(IDLE_TIMEOUT);
+        }
+    }
+}
+
+/// Writes size prefixed messages to a ring without allocating.
+struct MessageWriter {
+    ring: ringbuf::Producer<u8>,
+    // Scratch space so that the header and body are pushed at once.
+    buffer: Vec<u8>,
+    // The thread to wake up after writing a message.
+    wake: Option<std::thread::Thread>,
+}
+
+impl MessageWriter {
+    fn new(ring: ringbuf::Producer<u8>) -> MessageWriter {
+        MessageWriter {
+            ring,
+            buffer: vec![0; HEADER_SIZE + MAX_MESSAGE_SIZE],
+            wake: None,
+        }
+    }
+
+    /// Write the message. Nothing is written if the message is too large or the ring does not
+    /// have space for it.
+    fn write(&mut self, data: &[u8]) -> Status {
+        if data.len() > MAX_MESSAGE_SIZE {
+            return STATUS_ERR_UNKNOWN;
+        }
+        let size = HEADER_SIZE + data.len();
+        if self.ring.remaining() < size {
+            return STATUS_ERR_NO_SPACE;
+        }
+        self.buffer[..HEADER_SIZE].copy_from_slice(&(data.len() as u32).to_ne_bytes());
+        self.buffer[HEADER_SIZE..size].copy_from_slice(data);
+        self.ring.push_slice(&self.buffer[..size]);
+        if let Some(t) = self.wake.as_ref() {
+            t.unpark();
+        }
+        STATUS_SUCCESS
+    }
+}
+
+/// Read the next message into `buffer` and return its size. Messages are pushed whole so a
+/// message is always complete once its header is visible.
+fn read_message(ring: &mut ringbuf::Consumer<u8>, buffer: &mut [u8]) -> Option<usize> {
+    if ring.len() < HEADER_SIZE {
+        return None;
+    }
+    let mut header = [0; HEADER_SIZE];
+    ring.pop_slice(&mut header);
+    let size = u32::from_ne_bytes(header) as usize;
+    Some(ring.pop_slice(&mut buffer[..size]))
+}
+
+/// Write a message from the C callback arguments.
+fn write_raw(handle: *mut c_void, size: u32, data: *const c_void) -> Status {
+    let writer = match unsafe { (handle as *mut MessageWriter).as_mut() } {
+        Some(w) => w,
+        None => return STATUS_ERR_UNKNOWN,
+    };
+    if size > 0 && data.is_null() {
+        return STATUS_ERR_UNKNOWN;
+    }
+    let data = if size == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(data as *const u8, size as usize) }
+    };
+    writer.write(data)
+}
+
+extern "C" fn schedule_work_impl(
+    handle: *mut c_void, /*Type is MessageWriter*/
+    size: u32,
+    data: *const c_void,
+) -> Status {
+    write_raw(handle, size, data)
+}
+
+extern "C" fn respond_impl(
+    handle: *mut c_void, /*Type is MessageWriter*/
+    size: u32,
+    data: *const c_void,
+) -> Status {
+    write_raw(handle, size, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let (producer, mut consumer) = ringbuf::RingBuffer::new(64).split();
+        let mut writer = MessageWriter::new(producer);
+        assert_eq!(writer.write(&[1, 2, 3]), STATUS_SUCCESS);
+        assert_eq!(writer.write(&[]), STATUS_SUCCESS);
+        assert_eq!(writer.write(&[0; 64]), STATUS_ERR_NO_SPACE);
+
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        assert_eq!(read_message(&mut consumer, &mut buffer), Some(3));
+        assert_eq!(&buffer[..3], &[1, 2, 3]);
+        assert_eq!(read_message(&mut consumer, &mut buffer), Some(0));
+        assert_eq!(read_message(&mut consumer, &mut buffer), None);
+    }
+}
@@ -1,4 +1,11 @@
 pub mod actix_server;
 pub mod dummy_io;
 pub mod jack;
+pub mod jack_transport;
 pub mod lilv;
+pub mod lv2_log;
+pub mod lv2_options;
+pub mod lv2_sandbox;
+pub mod lv2_worker;
+pub mod native_library;
+pub mod sampler;
@@ -0,0 +1,465 @@
+//! Loads native plugins from shared libraries that were built with
+//! `olivia_core::export_plugins!`. See `olivia_core::plugin_abi` for the ABI.
+use crate::config::NativePluginConfig;
+use crate::plugin_factory::{
+    AudioSettings, PluginBuilder, PluginBuilderError, PluginKind, PluginMetadata, PluginScan,
+    PluginSource, PortSummary, UnsupportedPlugin,
+};
+use crate::plugin_files::{self, PluginFiles};
+use olivia_core::plugin::{ParameterInfo, PluginInstance, TransportInfo};
+use olivia_core::plugin_abi::{self, MidiEvent, PluginDescriptor, PluginLibrary};
+use std::collections::HashSet;
+use std::ffi::c_void;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The plugins in the shared libraries of the configured directories. Libraries that are added,
+/// replaced or removed are picked up by `rescan`. Libraries should be replaced by a new file
+/// rather than overwritten in place, since the old library may still be in use.
+pub struct NativeLibrarySource {
+    config: NativePluginConfig,
+    // The libraries that have been reported by `rescan`, or why they are unsupported.
+    libraries: PluginFiles<Result<LoadedLibrary, UnsupportedPlugin>>,
+    // Libraries that were removed but may still be used by plugin instances. Instances are dropped
+    // on the audio thread, so the source closes libraries once nothing else holds them.
+    unloading: Vec<Arc<Library>>,
+}
+
+struct LoadedLibrary {
+    library: Arc<Library>,
+    // The ids of the plugins that were registered from the library.
+    ids: Vec<String>,
+}
+
+impl NativeLibrarySource {
+    pub fn new(config: NativePluginConfig) -> NativeLibrarySource {
+        NativeLibrarySource {
+            config,
+            libraries: PluginFiles::new(),
+            unloading: Vec::new(),
+        }
+    }
+
+    /// Load the library at `path` and add builders for its plugins to `scan`.
+    fn load(
+        path: &Path,
+        owned_ids: &mut HashSet<String>,
+        scan: &mut PluginScan,
+    ) -> Result<LoadedLibrary, NativeLibraryError> {
+        let library = Arc::new(Library::open(path)?);
+        info!("Loaded plugin library {:?}.", path);
+        let ids = add_plugins(&library, owned_ids, scan);
+        Ok(LoadedLibrary { library, ids })
+    }
+}
+
+/// Add builders for the plugins of `library` to `scan` and return their ids. Plugins that can not
+/// be registered, because their metadata is invalid or their id is already owned by another
+/// library, are skipped so that the library only owns the ids that it registered.
+fn add_plugins(
+    library: &Arc<Library>,
+    owned_ids: &mut HashSet<String>,
+    scan: &mut PluginScan,
+) -> Vec<String> {
+    let mut ids = Vec::new();
+    for builder in LibraryPluginBuilder::all(library) {
+        let id = &builder.metadata.id;
+        if let Err(e) = builder.metadata.validate() {
+            warn!("Skipping native plugin {}: {:?}", id, e);
+        } else if !owned_ids.insert(id.clone()) {
+            warn!("Skipping native plugin {}, the id is already in use.", id);
+        } else {
+            ids.push(id.clone());
+            scan.added.push(Box::new(builder));
+        }
+    }
+    ids
+}
+
+impl PluginSource for NativeLibrarySource {
+    fn rescan(&mut self) -> PluginScan {
+        let mut scan = PluginScan::default();
+        let mut paths = Vec::new();
+        for dir in self.config.paths.iter() {
+            find_libraries(dir, &mut paths);
+        }
+        // Libraries that were not found again have been removed. Replaced libraries are removed
+        // and loaded again.
+        for loaded in self.libraries.remove_stale(&paths).into_iter().flatten() {
+            scan.removed.extend(loaded.ids);
+            self.unloading.push(loaded.library);
+        }
+        let mut owned_ids: HashSet<String> = self
+            .libraries
+            .values()
+            .filter_map(|l| l.as_ref().ok())
+            .flat_map(|l| l.ids.iter().cloned())
+            .collect();
+        for path in paths {
+            if !self.libraries.contains(&path) {
+                let loaded =
+                    NativeLibrarySource::load(&path, &mut owned_ids, &mut scan).map_err(|e| {
+                        warn!("Could not load plugin library {:?}: {}", path, e);
+                        let id = plugin_files::create_id("native", &path);
+                        plugin_files::unsupported_file(id, &path)
+                    });
+                self.libraries.insert(path, loaded);
+            }
+        }
+        scan.unsupported = self
+            .libraries
+            .values()
+            .filter_map(|l| l.as_ref().err().cloned())
+            .collect();
+        self.unloading.retain(|l| Arc::strong_count(l) > 1);
+        scan
+    }
+}
+
+/// Add the shared libraries in `dir` to `paths`.
+fn find_libraries(dir: &Path, paths: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    paths.extend(
+        entries
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == "so")),
+    );
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NativeLibraryError {
+    Open(String),
+    MissingEntryPoint,
+    IncompatibleAbi(u32),
+}
+
+impl std::error::Error for NativeLibraryError {}
+
+impl std::fmt::Display for NativeLibraryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// A loaded plugin library. The library is unloaded when it is dropped.
+struct Library {
+    // Not set if the library is part of this executable.
+    _library: Option<libloading::Library>,
+    library: *const PluginLibrary,
+}
+
+// The plugin library is immutable and its functions may be called from any thread.
+unsafe impl Send for Library {}
+unsafe impl Sync for Library {}
+
+impl Library {
+    fn open(path: &Path) -> Result<Library, NativeLibraryError> {
+        // Loading a library runs its initialization code. Plugin libraries are trusted like any
+        // other plugin.
+        let library = unsafe {
+            libloading::os::unix::Library::open(Some(path), libloading::os::unix::RTLD_NOW)
+        }
+        .map_err(|e| NativeLibraryError::Open(e.to_string()))?;
+        let plugin_library = {
+            let entry_point = unsafe {
+                library.get::<extern "C" fn() -> *const PluginLibrary>(
+                    plugin_abi::ENTRY_POINT.as_bytes(),
+                )
+            }
+            .map_err(|_| NativeLibraryError::MissingEntryPoint)?;
+            entry_point()
+        };
+        unsafe { Library::new(Some(library.into()), plugin_library) }
+    }
+
+    /// # Safety
+    /// `library` must be null or stay valid until `handle` is dropped.
+    unsafe fn new(
+        handle: Option<libloading::Library>,
+        library: *const PluginLibrary,
+    ) -> Result<Library, NativeLibraryError> {
+        let library = Library {
+            _library: handle,
+            library,
+        };
+        if library.library.is_null() {
+            return Err(NativeLibraryError::MissingEntryPoint);
+        }
+        let abi_version = (*library.library).abi_version;
+        if abi_version != plugin_abi::ABI_VERSION {
+            return Err(NativeLibraryError::IncompatibleAbi(abi_version));
+        }
+        Ok(library)
+    }
+
+    fn descriptors(&self) -> &[PluginDescriptor] {
+        let library = unsafe { &*self.library };
+        if library.plugins.is_null() {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(library.plugins, library.plugins_len as usize) }
+    }
+}
+
+/// Builds instances of a plugin in a plugin library.
+struct LibraryPluginBuilder {
+    metadata: PluginMetadata,
+    library: Arc<Library>,
+    // The index of the plugin within the library.
+    index: usize,
+    sample_rate: f64,
+}
+
+impl LibraryPluginBuilder {
+    /// Builders for the plugins of `library`. Plugins without an id are skipped.
+    fn all(library: &Arc<Library>) -> Vec<LibraryPluginBuilder> {
+        library
+            .descriptors()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, d)| {
+                let string = |s| unsafe { plugin_abi::from_c_string(s) };
+                let id = string(d.id)?;
+                let ports = PortSummary {
+                    audio_inputs: d.audio_inputs as usize,
+                    audio_outputs: d.audio_outputs as usize,
+                    midi_inputs: d.midi_inputs as usize,
+                    controls: d.controls as usize,
+                };
+                let metadata = PluginMetadata {
+                    id: format!("native_{}", id),
+                    display_name: string(d.display_name).unwrap_or(id),
+                    class: string(d.class),
+                    author: string(d.author),
+                    kind: PluginKind::from_ports(&ports),
+                    ports,
+                    ..PluginMetadata::default()
+                };
+                Some(LibraryPluginBuilder {
+                    metadata,
+                    library: library.clone(),
+                    index,
+                    sample_rate: AudioSettings::default().sample_rate,
+                })
+            })
+            .collect()
+    }
+}
+
+impl PluginBuilder for LibraryPluginBuilder {
+    fn metadata(&self) -> PluginMetadata {
+        self.metadata.clone()
+    }
+
+    fn build(&self) -> Result<Box<dyn PluginInstance>, PluginBuilderError> {
+        let descriptor: *const PluginDescriptor = &self.library.descriptors()[self.index];
+        let handle = unsafe { ((*descriptor).instantiate)(descriptor, self.sample_rate) };
+        if handle.is_null() {
+            return Err(PluginBuilderError::GenericError(
+                "Failed to instantiate native plugin.",
+            ));
+        }
+        Ok(Box::new(LibraryPluginInstance {
+            id: self.metadata.id.clone(),
+            descriptor,
+            handle,
+            midi: Vec::with_capacity(plugin_abi::MAX_MIDI_EVENTS),
+            library: self.library.clone(),
+        }))
+    }
+
+    fn set_audio_settings(&mut self, settings: AudioSettings) {
+        self.sample_rate = settings.sample_rate;
+    }
+}
+
+/// An instance of a plugin in a plugin library.
+struct LibraryPluginInstance {
+    id: String,
+    // Points into the library.
+    descriptor: *const PluginDescriptor,
+    handle: *mut c_void,
+    // Scratch space for converting MIDI events.
+    midi: Vec<MidiEvent>,
+    // Must be dropped after the instance is destroyed. The source holds on to the library too, so
+    // dropping the instance on the audio thread never closes the library.
+    #[allow(dead_code)]
+    library: Arc<Library>,
+}
+
+// Instances are only used by one thread at a time.
+unsafe impl Send for LibraryPluginInstance {}
+
+impl std::fmt::Debug for LibraryPluginInstance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LibraryPluginInstance")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl LibraryPluginInstance {
+    fn descriptor(&self) -> &PluginDescriptor {
+        unsafe { &*self.descriptor }
+    }
+}
+
+impl PluginInstance for LibraryPluginInstance {
+    fn set_transport(&mut self, transport: &TransportInfo) {
+        let transport = plugin_abi::Transport::from(transport);
+        unsafe { (self.descriptor().set_transport)(self.handle, &transport) };
+    }
+
+    fn process(
+        &mut self,
+        midi: &[olivia_core::TimedMidi],
+        out_left: &mut [f32],
+        out_right: &mut [f32],
+    ) {
+        let frames = out_left.len().min(out_right.len());
+        self.midi.clear();
+        self.midi.extend(
+            midi.iter()
+                .filter(|m| m.frame < frames)
+                .filter_map(MidiEvent::new)
+                .take(plugin_abi::MAX_MIDI_EVENTS),
+        );
+        unsafe {
+            (self.descriptor().process)(
+                self.handle,
+                self.midi.as_ptr(),
+                self.midi.len() as u32,
+                out_left.as_mut_ptr(),
+                out_right.as_mut_ptr(),
+                frames as u32,
+            )
+        };
+    }
+
+    fn parameters(&self) -> Vec<ParameterInfo> {
+        let mut len = 0;
+        let parameters = unsafe { (self.descriptor().parameters)(self.handle, &mut len) };
+        if parameters.is_null() {
+            return Vec::new();
+        }
+        unsafe { std::slice::from_raw_parts(parameters, len as usize) }
+            .iter()
+            .map(|p| unsafe { p.to_info() })
+            .collect()
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        unsafe { (self.descriptor().set_parameter)(self.handle, index as u32, value) };
+    }
+
+    fn get_parameter(&self, index: usize) -> Option<f32> {
+        let mut value = 0.0;
+        match unsafe { (self.descriptor().get_parameter)(self.handle, index as u32, &mut value) } {
+            0 => None,
+            _ => Some(value),
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) -> bool {
+        unsafe { (self.descriptor().set_enabled)(self.handle, enabled as u32) != 0 }
+    }
+
+    fn latency(&self) -> usize {
+        unsafe { (self.descriptor().latency)(self.handle) as usize }
+    }
+}
+
+impl Drop for LibraryPluginInstance {
+    fn drop(&mut self) {
+        unsafe { (self.descriptor().destroy)(self.handle) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use olivia_core::effects::utility::{Utility, UtilityParameter};
+    use olivia_core::plugin_abi::{ExportedLibrary, ExportedPlugin};
+
+    fn utility_plugin(id: &str) -> ExportedPlugin {
+        ExportedPlugin {
+            id: id.to_string(),
+            display_name: "Utility".to_string(),
+            class: Some("Utility".to_string()),
+            author: None,
+            audio_inputs: 2,
+            audio_outputs: 2,
+            midi_inputs: 0,
+            controls: UtilityParameter::ALL.len(),
+            new_instance: |_| Box::new(Utility::default()),
+        }
+    }
+
+    fn exported_utility() -> ExportedLibrary {
+        ExportedLibrary::new(vec![utility_plugin("utility")])
+    }
+
+    #[test]
+    fn library_plugins_behave_like_the_plugin() {
+        let exported = exported_utility();
+        let library = unsafe { Library::new(None, exported.library()) }.unwrap();
+        let builders = LibraryPluginBuilder::all(&Arc::new(library));
+        assert_eq!(builders.len(), 1);
+        let metadata = builders[0].metadata();
+        assert_eq!(metadata.id, "native_utility");
+        assert_eq!(metadata.kind, PluginKind::Effect);
+        assert_eq!(metadata.ports.controls, UtilityParameter::ALL.len());
+
+        let mut instance = builders[0].build().unwrap();
+        assert_eq!(instance.parameters(), Utility::default().parameters());
+        let gain = UtilityParameter::Gain as usize;
+        instance.set_parameter(gain, 100.0);
+        assert_eq!(instance.get_parameter(gain), Some(24.0));
+        assert_eq!(instance.get_parameter(100), None);
+
+        instance.set_parameter(UtilityParameter::InvertLeft as usize, 1.0);
+        instance.set_parameter(gain, 0.0);
+        let (mut left, mut right) = ([0.5, 0.25], [0.5, 0.25]);
+        instance.process(&[], &mut left, &mut right);
+        assert_eq!((left, right), ([-0.5, -0.25], [0.5, 0.25]));
+    }
+
+    #[test]
+    fn only_registrable_plugins_are_owned() {
+        let exported = ExportedLibrary::new(vec![
+            utility_plugin("utility"),
+            utility_plugin("Invalid Id"),
+            utility_plugin("utility"),
+            utility_plugin("taken"),
+        ]);
+        let library = unsafe { Library::new(None, exported.library()) }.unwrap();
+        let mut owned_ids = std::iter::once("native_taken".to_string()).collect();
+        let mut scan = PluginScan::default();
+        let ids = add_plugins(&Arc::new(library), &mut owned_ids, &mut scan);
+        assert_eq!(ids, vec!["native_utility".to_string()]);
+        assert_eq!(scan.added.len(), 1);
+        assert_eq!(owned_ids.len(), 2);
+    }
+
+    #[test]
+    fn incompatible_libraries_are_rejected() {
+        let exported = exported_utility();
+        let mut library = unsafe { std::ptr::read(exported.library()) };
+        library.abi_version = plugin_abi::ABI_VERSION + 1;
+        let error = unsafe { Library::new(None, &library) }.err();
+        assert_eq!(
+            error,
+            Some(NativeLibraryError::IncompatibleAbi(
+                plugin_abi::ABI_VERSION + 1
+            ))
+        );
+        assert!(matches!(
+            Library::open(Path::new("/does/not/exist.so")),
+            Err(NativeLibraryError::Open(_))
+        ));
+    }
+}
@@ -0,0 +1,676 @@
+use crate::config::SamplerConfig;
+use crate::plugin_factory::{
+    AudioSettings, PluginBuilder, PluginBuilderError, PluginKind, PluginMetadata, PluginScan,
+    PluginSource, PortSummary, UnsupportedPlugin,
+};
+use crate::plugin_files::{self, PluginFiles};
+use olivia_core::plugin::PluginInstance;
+use olivia_core::sampler::{Sample, Sampler, Zone};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The samplers defined by `.sfz` and `.json` files in the configured directories. Definition
+/// files that are added, changed or removed are picked up by `rescan`.
+pub struct SamplerSource {
+    config: SamplerConfig,
+    // The definition files that have been reported by `rescan` and why they are unsupported, if
+    // they are.
+    definitions: PluginFiles<(String, Option<UnsupportedPlugin>)>,
+}
+
+impl SamplerSource {
+    pub fn new(config: SamplerConfig) -> SamplerSource {
+        SamplerSource {
+            config,
+            definitions: PluginFiles::new(),
+        }
+    }
+}
+
+impl PluginSource for SamplerSource {
+    fn rescan(&mut self) -> PluginScan {
+        let mut scan = PluginScan::default();
+        let mut paths = Vec::new();
+        for dir in self.config.paths.iter() {
+            find_definitions(dir, &mut paths);
+        }
+        // Definitions that were not found again have been removed. Changed definitions are
+        // removed and added again.
+        scan.removed = self
+            .definitions
+            .remove_stale(&paths)
+            .into_iter()
+            .filter(|(_, unsupported)| unsupported.is_none())
+            .map(|(id, _)| id)
+            .collect();
+        for path in paths {
+            if self.definitions.contains(&path) {
+                continue;
+            }
+            let id = create_id(&path);
+            let unsupported = match SamplerBuilder::new(&path) {
+                Ok(builder) => {
+                    scan.added.push(Box::new(builder));
+                    None
+                }
+                Err(e) => {
+                    warn!("Could not load sampler definition {:?}: {}", path, e);
+                    Some(plugin_files::unsupported_file(id.clone(), &path))
+                }
+            };
+            self.definitions.insert(path, (id, unsupported));
+        }
+        scan.unsupported = self
+            .definitions
+            .values()
+            .filter_map(|(_, unsupported)| unsupported.clone())
+            .collect();
+        scan
+    }
+}
+
+/// Add the sampler definition files in `dir` and its subdirectories to `paths`.
+fn find_definitions(dir: &Path, paths: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.is_dir() {
+            find_definitions(&path, paths);
+        } else if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("sfz") || e.eq_ignore_ascii_case("json"))
+        {
+            paths.push(path);
+        }
+    }
+}
+
+fn create_id(path: &Path) -> String {
+    plugin_files::create_id("sampler", path)
+}
+
+/// Builds samplers from a definition file. The samples are loaded when the first instance is
+/// built and shared by all instances.
+struct SamplerBuilder {
+    metadata: PluginMetadata,
+    definition: SamplerDefinition,
+    // The directory that sample paths are relative to.
+    directory: PathBuf,
+    sample_rate: f32,
+    zones: Mutex<Option<Arc<Vec<Zone>>>>,
+}
+
+impl SamplerBuilder {
+    fn new(path: &Path) -> Result<SamplerBuilder, SamplerError> {
+        let definition = SamplerDefinition::load(path)?;
+        let metadata = PluginMetadata {
+            id: create_id(path),
+            display_name: definition
+                .name
+                .clone()
+                .unwrap_or_else(|| plugin_files::display_name(path)),
+            class: Some("Sampler".to_string()),
+            ports: PortSummary {
+                audio_outputs: 2,
+                midi_inputs: 1,
+                ..PortSummary::default()
+            },
+            kind: PluginKind::Instrument,
+            ..PluginMetadata::default()
+        };
+        Ok(SamplerBuilder {
+            metadata,
+            definition,
+            directory: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            sample_rate: AudioSettings::default().sample_rate as f32,
+            zones: Mutex::new(None),
+        })
+    }
+}
+
+impl PluginBuilder for SamplerBuilder {
+    fn metadata(&self) -> PluginMetadata {
+        self.metadata.clone()
+    }
+
+    fn build(&self) -> Result<Box<dyn PluginInstance>, PluginBuilderError> {
+        let mut zones = self.zones.lock().unwrap();
+        let zones = match zones.as_ref() {
+            Some(z) => z.clone(),
+            None => {
+                let loaded = self.definition.load_zones(&self.directory).map_err(|e| {
+                    error!("Could not load samples for {}: {}", self.metadata.id, e);
+                    PluginBuilderError::GenericError("could not load samples")
+                })?;
+                zones.insert(Arc::new(loaded)).clone()
+            }
+        };
+        Ok(Box::new(Sampler::new(self.sample_rate, zones)))
+    }
+
+    fn set_audio_settings(&mut self, settings: AudioSettings) {
+        self.sample_rate = settings.sample_rate as f32;
+    }
+}
+
+/// A sampler read from a JSON definition file or an SFZ file.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct SamplerDefinition {
+    /// The name of the sampler. Defaults to the name of the file.
+    pub name: Option<String>,
+    pub zones: Vec<ZoneDefinition>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct ZoneDefinition {
+    /// The path of the WAV file, relative to the definition file.
+    pub sample: PathBuf,
+    pub low_key: u8,
+    pub high_key: u8,
+    pub low_velocity: u8,
+    pub high_velocity: u8,
+    /// The key that plays the sample at its original pitch.
+    pub root_key: u8,
+    /// The first frame of the loop. The zone loops if either loop point is set.
+    pub loop_start: Option<usize>,
+    /// The frame after the end of the loop. Defaults to the end of the sample.
+    pub loop_end: Option<usize>,
+    /// If true, the loop only plays while the key is held and the sample plays through to its end
+    /// after the key is released.
+    pub loop_sustain: bool,
+    /// The time in seconds to fade out after the key is released.
+    pub release: f32,
+    /// The level in decibels.
+    pub volume: f32,
+}
+
+impl Default for ZoneDefinition {
+    fn default() -> ZoneDefinition {
+        ZoneDefinition {
+            sample: PathBuf::new(),
+            low_key: 0,
+            high_key: 127,
+            low_velocity: 0,
+            high_velocity: 127,
+            root_key: 60,
+            loop_start: None,
+            loop_end: None,
+            loop_sustain: false,
+            release: 0.0,
+            volume: 0.0,
+        }
+    }
+}
+
+impl SamplerDefinition {
+    /// Read a definition from a `.sfz` file, or a JSON file with any other extension.
+    pub fn load(path: &Path) -> Result<SamplerDefinition, SamplerError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| SamplerError::Io(e.to_string()))?;
+        let definition = if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("sfz"))
+        {
+            parse_sfz(&contents)?
+        } else {
+            serde_json::from_str(&contents).map_err(|e| SamplerError::Parse(e.to_string()))?
+        };
+        definition.validate()?;
+        Ok(definition)
+    }
+
+    fn validate(&self) -> Result<(), SamplerError> {
+        if self.zones.is_empty() {
+            return Err(SamplerError::NoZones);
+        }
+        for (i, z) in self.zones.iter().enumerate() {
+            let is_valid = !z.sample.as_os_str().is_empty()
+                && z.low_key <= z.high_key
+                && z.high_key < 128
+                && z.low_velocity <= z.high_velocity
+                && z.root_key < 128
+                && z.release.is_finite()
+                && z.volume.is_finite();
+            if !is_valid {
+                return Err(SamplerError::InvalidZone(i));
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the samples of all zones. Sample paths are relative to `directory`.
+    pub fn load_zones(&self, directory: &Path) -> Result<Vec<Zone>, SamplerError> {
+        let mut samples: HashMap<PathBuf, Arc<Sample>> = HashMap::new();
+        let mut zones = Vec::with_capacity(self.zones.len());
+        for z in self.zones.iter() {
+            let path = directory.join(&z.sample);
+            let sample = match samples.get(&path) {
+                Some(s) => s.clone(),
+                None => {
+                    let s = Arc::new(load_wav(&path)?);
+                    samples.insert(path, s.clone());
+                    s
+                }
+            };
+            let loop_points = match (z.loop_start, z.loop_end) {
+                (None, None) => None,
+                (start, end) => Some((start.unwrap_or(0), end.unwrap_or_else(|| sample.len()))),
+            };
+            zones.push(Zone {
+                sample,
+                low_key: z.low_key,
+                high_key: z.high_key,
+                low_velocity: z.low_velocity,
+                high_velocity: z.high_velocity,
+                root_key: z.root_key,
+                loop_points,
+                loop_sustain: z.loop_sustain,
+                release: z.release.max(0.0),
+                gain: 10f32.powf(z.volume / 20.0),
+            });
+        }
+        Ok(zones)
+    }
+}
+
+/// Read a WAV file. Only the first two channels are used.
+fn load_wav(path: &Path) -> Result<Sample, SamplerError> {
+    let wav_error = |e: hound::Error| SamplerError::Wav(path.to_path_buf(), e.to_string());
+    let mut reader = hound::WavReader::open(path).map_err(wav_error)?;
+    let spec = reader.spec();
+    let data: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 * scale))
+                .collect::<Result<_, _>>()
+        }
+    }
+    .map_err(wav_error)?;
+    let channels = usize::from(spec.channels.max(1));
+    let left: Vec<f32> = data.iter().step_by(channels).copied().collect();
+    let right = if channels > 1 {
+        data.iter().skip(1).step_by(channels).copied().collect()
+    } else {
+        left.clone()
+    };
+    Ok(Sample {
+        sample_rate: spec.sample_rate as f32,
+        left,
+        right,
+    })
+}
+
+/// Parse the subset of SFZ made of `<global>`, `<group>`, `<region>` and `<control>` headers with
+/// the `sample`, `key`, `lokey`, `hikey`, `pitch_keycenter`, `lovel`, `hivel`, `loop_mode`,
+/// `loop_start`, `loop_end`, `ampeg_release`, `volume` and `default_path` opcodes. Other opcodes
+/// are ignored.
+pub fn parse_sfz(contents: &str) -> Result<SamplerDefinition, SamplerError> {
+    #[derive(Copy, Clone, PartialEq)]
+    enum Header {
+        None,
+        Control,
+        Global,
+        Group,
+        Region,
+        Other,
+    }
+    type Opcodes = HashMap<String, String>;
+
+    let mut header = Header::None;
+    let mut control = Opcodes::new();
+    let mut global = Opcodes::new();
+    let mut group = Opcodes::new();
+    let mut region = Opcodes::new();
+    // Regions start with the opcodes of their global and group headers.
+    let mut regions = Vec::new();
+    for line in contents.lines() {
+        let line = line.split("//").next().unwrap_or("");
+        // The opcode that values containing spaces are appended to.
+        let mut last_opcode: Option<String> = None;
+        for word in line.split_whitespace() {
+            if word.starts_with('<') && word.ends_with('>') {
+                if header == Header::Region {
+                    regions.push(std::mem::take(&mut region));
+                }
+                header = match word {
+                    "<control>" => Header::Control,
+                    "<global>" => {
+                        global.clear();
+                        group.clear();
+                        Header::Global
+                    }
+                    "<group>" => {
+                        group.clear();
+                        Header::Group
+                    }
+                    "<region>" => {
+                        region = global.clone();
+                        region.extend(group.iter().map(|(k, v)| (k.clone(), v.clone())));
+                        Header::Region
+                    }
+                    _ => Header::Other,
+                };
+                last_opcode = None;
+                continue;
+            }
+            let opcodes = match header {
+                Header::Control => &mut control,
+                Header::Global => &mut global,
+                Header::Group => &mut group,
+                Header::Region => &mut region,
+                Header::None | Header::Other => continue,
+            };
+            match (word.split_once('='), last_opcode.as_ref()) {
+                (Some((opcode, value)), _) => {
+                    opcodes.insert(opcode.to_string(), value.to_string());
+                    last_opcode = Some(opcode.to_string());
+                }
+                (None, Some(opcode)) => {
+                    if let Some(value) = opcodes.get_mut(opcode) {
+                        value.push(' ');
+                        value.push_str(word);
+                    }
+                }
+                (None, None) => return Err(SamplerError::Parse(format!("unexpected {}", word))),
+            }
+        }
+    }
+    if header == Header::Region {
+        regions.push(region);
+    }
+    let zones = regions
+        .iter()
+        .map(|r| sfz_zone(r, &control))
+        .collect::<Result<_, _>>()?;
+    Ok(SamplerDefinition { name: None, zones })
+}
+
+fn sfz_zone(
+    opcodes: &HashMap<String, String>,
+    control: &HashMap<String, String>,
+) -> Result<ZoneDefinition, SamplerError> {
+    let get = |opcode: &str| opcodes.get(opcode).map(String::as_str);
+    let invalid = |opcode: &str| {
+        SamplerError::Parse(format!(
+            "invalid value for {}: {:?}",
+            opcode,
+            get(opcode).unwrap_or("")
+        ))
+    };
+    let key = |opcode: &str| -> Result<Option<u8>, SamplerError> {
+        get(opcode)
+            .map(|v| parse_sfz_key(v).ok_or_else(|| invalid(opcode)))
+            .transpose()
+    };
+    let number = |opcode: &str| -> Result<Option<f32>, SamplerError> {
+        get(opcode)
+            .map(|v| v.parse::<f32>().map_err(|_| invalid(opcode)))
+            .transpose()
+    };
+    let frame = |opcode: &str| -> Result<Option<usize>, SamplerError> {
+        get(opcode)
+            .map(|v| v.parse::<usize>().map_err(|_| invalid(opcode)))
+            .transpose()
+    };
+
+    let mut zone = ZoneDefinition::default();
+    let sample = get("sample").ok_or_else(|| invalid("sample"))?;
+    let default_path = control
+        .get("default_path")
+        .map(String::as_str)
+        .unwrap_or("");
+    zone.sample = PathBuf::from(format!("{}{}", default_path, sample).replace('\\', "/"));
+    if let Some(k) = key("key")? {
+        zone.low_key = k;
+        zone.high_key = k;
+        zone.root_key = k;
+    }
+    zone.low_key = key("lokey")?.unwrap_or(zone.low_key);
+    zone.high_key = key("hikey")?.unwrap_or(zone.high_key);
+    zone.root_key = key("pitch_keycenter")?.unwrap_or(zone.root_key);
+    zone.low_velocity = number("lovel")?.map_or(zone.low_velocity, |v| v as u8);
+    zone.high_velocity = number("hivel")?.map_or(zone.high_velocity, |v| v as u8);
+    zone.release = number("ampeg_release")?.unwrap_or(zone.release);
+    zone.volume = number("volume")?.unwrap_or(zone.volume);
+    let looped = match get("loop_mode") {
+        None => get("loop_start").is_some() || get("loop_end").is_some(),
+        Some("loop_continuous") => true,
+        Some("loop_sustain") => {
+            zone.loop_sustain = true;
+            true
+        }
+        Some("no_loop") | Some("one_shot") => false,
+        Some(_) => return Err(invalid("loop_mode")),
+    };
+    if looped {
+        zone.loop_start = Some(frame("loop_start")?.unwrap_or(0));
+        // The SFZ loop end is the last frame of the loop.
+        zone.loop_end = frame("loop_end")?.map(|end| end + 1);
+    }
+    Ok(zone)
+}
+
+/// Parse a key given as a MIDI note number or a note name such as "c#4", where "c4" is 60.
+fn parse_sfz_key(value: &str) -> Option<u8> {
+    if let Ok(n) = value.parse::<u8>() {
+        return Some(n);
+    }
+    let value = value.to_ascii_lowercase();
+    let mut chars = value.chars();
+    let mut key: i32 = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let octave = if let Some(rest) = rest.strip_prefix('#') {
+        key += 1;
+        rest
+    } else if let Some(rest) = rest.strip_prefix('b') {
+        key -= 1;
+        rest
+    } else {
+        rest
+    };
+    key += (octave.parse::<i32>().ok()? + 1) * 12;
+    u8::try_from(key).ok().filter(|k| *k < 128)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SamplerError {
+    Io(String),
+    Parse(String),
+    Wav(PathBuf, String),
+    NoZones,
+    InvalidZone(usize),
+}
+
+impl std::error::Error for SamplerError {}
+
+impl std::fmt::Display for SamplerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_wav(path: &Path, channels: u16, data: &[i16]) {
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate: 22050,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for s in data {
+            writer.write_sample(*s).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn parse_sfz_regions() {
+        let sfz = r"
+            // A comment.
+            <control> default_path=samples\
+            <global> ampeg_release=0.5
+            <group> lovel=0 hivel=63 volume=-6
+            <region> sample=soft c.wav lokey=c4 hikey=b4 pitch_keycenter=c4
+            <region> sample=soft c5.wav key=72 loop_mode=loop_continuous loop_start=10 loop_end=99
+            <group> lovel=64
+            <region> sample=loud.wav loop_mode=loop_sustain
+        ";
+        let definition = parse_sfz(sfz).unwrap();
+        assert_eq!(
+            definition.zones,
+            vec![
+                ZoneDefinition {
+                    sample: PathBuf::from("samples/soft c.wav"),
+                    low_key: 60,
+                    high_key: 71,
+                    high_velocity: 63,
+                    release: 0.5,
+                    volume: -6.0,
+                    ..ZoneDefinition::default()
+                },
+                ZoneDefinition {
+                    sample: PathBuf::from("samples/soft c5.wav"),
+                    low_key: 72,
+                    high_key: 72,
+                    root_key: 72,
+                    high_velocity: 63,
+                    loop_start: Some(10),
+                    loop_end: Some(100),
+                    release: 0.5,
+                    volume: -6.0,
+                    ..ZoneDefinition::default()
+                },
+                ZoneDefinition {
+                    sample: PathBuf::from("samples/loud.wav"),
+                    low_velocity: 64,
+                    loop_start: Some(0),
+                    loop_sustain: true,
+                    release: 0.5,
+                    ..ZoneDefinition::default()
+                },
+            ]
+        );
+        assert_eq!(parse_sfz_key("c#-1"), Some(1));
+        assert_eq!(parse_sfz_key("h4"), None);
+        assert!(parse_sfz("<region> lokey=60").is_err());
+    }
+
+    #[test]
+    fn json_definitions_load_samples() {
+        let dir = std::env::temp_dir().join(format!("olivia_sampler_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_wav(&dir.join("mono.wav"), 1, &[0, 16384, -16384]);
+        write_wav(&dir.join("stereo.wav"), 2, &[0, 16384, -16384, 0]);
+        let path = dir.join("piano.json");
+        std::fs::write(
+            &path,
+            r#"{
+                "name": "Piano",
+                "zones": [
+                    {"sample": "mono.wav", "high_key": 59, "loop_start": 1},
+                    {"sample": "stereo.wav", "low_key": 60, "root_key": 64, "volume": -20}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let definition = SamplerDefinition::load(&path);
+        let zones = definition
+            .as_ref()
+            .map(|d| d.load_zones(&dir))
+            .unwrap()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(definition.unwrap().name.as_deref(), Some("Piano"));
+        assert_eq!(zones.len(), 2);
+        assert_eq!(zones[0].sample.left, vec![0.0, 0.5, -0.5]);
+        assert_eq!(zones[0].sample.right, zones[0].sample.left);
+        assert_eq!(zones[0].sample.sample_rate, 22050.0);
+        assert_eq!(zones[0].loop_points, Some((1, 3)));
+        assert_eq!(zones[1].sample.left, vec![0.0, -0.5]);
+        assert_eq!(zones[1].sample.right, vec![0.5, 0.0]);
+        assert_eq!(zones[1].loop_points, None);
+        assert!((zones[1].gain - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rescan_finds_added_and_removed_definitions() {
+        let dir = std::env::temp_dir().join(format!("olivia_sampler_scan_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("strings")).unwrap();
+        let good = dir.join("strings").join("violin.sfz");
+        let bad = dir.join("broken.json");
+        std::fs::write(&good, "<region> sample=violin.wav").unwrap();
+        std::fs::write(&bad, "{").unwrap();
+        let mut source = SamplerSource::new(SamplerConfig {
+            paths: vec![dir.clone()],
+        });
+
+        let scan = source.rescan();
+        let added: Vec<_> = scan.added.iter().map(|b| b.metadata()).collect();
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].id, create_id(&good));
+        assert_eq!(added[0].display_name, "violin");
+        assert_eq!(added[0].kind, PluginKind::Instrument);
+        assert_eq!(scan.unsupported.len(), 1);
+        assert!(scan.unsupported[0].verification_failed);
+
+        std::fs::remove_file(&good).unwrap();
+        let scan = source.rescan();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(scan.added.is_empty());
+        assert_eq!(scan.removed, vec![create_id(&good)]);
+    }
+
+    #[test]
+    fn rescan_reloads_changed_definitions() {
+        let dir =
+            std::env::temp_dir().join(format!("olivia_sampler_change_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("organ.json");
+        std::fs::write(
+            &path,
+            r#"{"name": "Organ", "zones": [{"sample": "a.wav"}]}"#,
+        )
+        .unwrap();
+        let mut source = SamplerSource::new(SamplerConfig {
+            paths: vec![dir.clone()],
+        });
+        assert_eq!(source.rescan().added.len(), 1);
+
+        std::fs::write(
+            &path,
+            r#"{"name": "Pipe organ", "zones": [{"sample": "b.wav"}]}"#,
+        )
+        .unwrap();
+        let scan = source.rescan();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(scan.removed, vec![create_id(&path)]);
+        let added: Vec<_> = scan.added.iter().map(|b| b.metadata()).collect();
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].id, create_id(&path));
+        assert_eq!(added[0].display_name, "Pipe organ");
+    }
+}
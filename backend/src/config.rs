@@ -0,0 +1,170 @@
+use crate::adapter::jack_transport::TransportSync;
+use std::path::{Path, PathBuf};
+
+/// The environment variable that holds the path to the config file if `--config` is not passed.
+const CONFIG_ENV_VAR: &str = "OLIVIA_CONFIG";
+
+/// Settings read from a JSON config file. Missing settings take their default value.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub lv2: Lv2Config,
+    pub sampler: SamplerConfig,
+    pub native_plugins: NativePluginConfig,
+    pub jack: JackConfig,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct JackConfig {
+    /// How the transport is synchronized with the JACK transport, one of `"internal"`,
+    /// `"follow"` or `"timebase_master"`.
+    pub transport_sync: TransportSync,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct NativePluginConfig {
+    /// The directories to search for plugin libraries, which are `.so` files built with
+    /// `olivia_core::export_plugins!`. Libraries run in the backend process, so only trusted
+    /// libraries should be placed in these directories.
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct SamplerConfig {
+    /// The directories to search for sampler definitions, which are `.sfz` or `.json` files.
+    /// Subdirectories are searched as well.
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Lv2Config {
+    /// The directories to search for LV2 bundles. If empty, `LV2_PATH` or the system default is
+    /// used.
+    pub paths: Vec<PathBuf>,
+    /// The URIs of plugins that are never loaded.
+    pub blocklist: Vec<String>,
+    /// If set, only the plugins with these URIs are loaded.
+    pub allowlist: Option<Vec<String>>,
+    /// If true, every plugin runs in a separate plugin host process.
+    pub sandbox_all: bool,
+    /// The URIs of plugins that run in a separate plugin host process.
+    pub sandboxed: Vec<String>,
+}
+
+impl Lv2Config {
+    /// Returns true if the plugin with `uri` may be loaded.
+    pub fn is_allowed(&self, uri: &str) -> bool {
+        if self.blocklist.iter().any(|u| u == uri) {
+            return false;
+        }
+        match self.allowlist.as_ref() {
+            Some(allowlist) => allowlist.iter().any(|u| u == uri),
+            None => true,
+        }
+    }
+
+    /// Returns true if the plugin with `uri` should run in a plugin host process.
+    pub fn is_sandboxed(&self, uri: &str) -> bool {
+        self.sandbox_all || self.sandboxed.iter().any(|u| u == uri)
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(e.to_string()))?;
+        serde_json::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+}
+
+/// The path of the config file, from `--config <path>` or the `OLIVIA_CONFIG` environment
+/// variable.
+pub fn config_path(args: &[String]) -> Option<PathBuf> {
+    if let Some(i) = args.iter().position(|a| a == "--config") {
+        return args.get(i + 1).map(PathBuf::from);
+    }
+    std::env::var_os(CONFIG_ENV_VAR).map(PathBuf::from)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    Io(String),
+    Parse(String),
+}
+
+impl std::error::Error for ConfigError {}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_settings_use_defaults() {
+        let config: Config =
+            serde_json::from_str(r#"{"lv2": {"blocklist": ["urn:crashy"]}}"#).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                lv2: Lv2Config {
+                    blocklist: vec!["urn:crashy".to_string()],
+                    ..Lv2Config::default()
+                },
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn transport_sync_is_configurable() {
+        let config: Config =
+            serde_json::from_str(r#"{"jack": {"transport_sync": "timebase_master"}}"#).unwrap();
+        assert_eq!(config.jack.transport_sync, TransportSync::TimebaseMaster);
+        assert_eq!(
+            Config::default().jack.transport_sync,
+            TransportSync::Internal
+        );
+    }
+
+    #[test]
+    fn blocklist_and_allowlist() {
+        let mut config = Lv2Config {
+            blocklist: vec!["urn:a".to_string()],
+            ..Lv2Config::default()
+        };
+        assert!(!config.is_allowed("urn:a"));
+        assert!(config.is_allowed("urn:b"));
+
+        config.allowlist = Some(vec!["urn:a".to_string(), "urn:b".to_string()]);
+        assert!(!config.is_allowed("urn:a"));
+        assert!(config.is_allowed("urn:b"));
+        assert!(!config.is_allowed("urn:c"));
+    }
+
+    #[test]
+    fn sandboxed_plugins() {
+        let mut config = Lv2Config {
+            sandboxed: vec!["urn:crashy".to_string()],
+            ..Lv2Config::default()
+        };
+        assert!(config.is_sandboxed("urn:crashy"));
+        assert!(!config.is_sandboxed("urn:stable"));
+
+        config.sandbox_all = true;
+        assert!(config.is_sandboxed("urn:stable"));
+    }
+
+    #[test]
+    fn config_path_from_args() {
+        let args: Vec<String> = vec!["olivia".into(), "--config".into(), "olivia.json".into()];
+        assert_eq!(config_path(&args), Some(PathBuf::from("olivia.json")));
+    }
+}
@@ -1,22 +1,415 @@
+use crate::history;
 use crate::plugin_factory;
+use crate::recorder;
 use olivia_core::TimedMidi;
 use plugin_factory::PluginFactory;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(
     Copy, Clone, Debug, Eq, Hash, PartialEq, Ord, PartialOrd, serde::Serialize, serde::Deserialize,
 )]
 pub struct IntId(pub usize);
 
+/// The number of undo steps that are kept.
+const MAX_UNDO_STEPS: usize = 256;
+
+/// The number of values the processor can send back to the controller before it has to free them
+/// itself.
+const GARBAGE_QUEUE_SIZE: usize = 1024;
+
+/// How long to wait for the processor to return the plugin instances of a removed track before
+/// they are rebuilt.
+const GARBAGE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+// Tracks are not boxed so that the audio thread does not free the box when it takes the track.
+#[allow(clippy::large_enum_variant)]
 enum Command {
-    AddTrack(olivia_core::processor::Track),
-    DeleteTrack(usize),
+    AddTrack(usize, olivia_core::processor::Track),
+    DeleteTrack(usize, DeletedPlugins),
+    SetTrackVolume(usize, f32),
+    SetTrackPan(usize, f32),
+    SetTrackAutomation(usize, Vec<olivia_core::automation::AutomationLane>),
+    SetMidiMap(Vec<olivia_core::midi_map::MidiMapping>),
+    SetMidiLearn(bool),
+    ReadParameters(ParameterSnapshot),
+    SetTrackInput {
+        track_index: usize,
+        input: Option<(usize, usize)>,
+        monitoring: bool,
+    },
+    SetTrackRecordSink(usize, Option<Box<dyn olivia_core::record::RecordSink>>),
+    SetTransport(Transport),
+    SetTempoMap(olivia_core::tempo_map::TempoMap),
+    SetMetronome(olivia_core::metronome::Metronome),
+    SetMidiSync {
+        generator: olivia_core::midi_sync::MidiSyncGenerator,
+        follower: Option<olivia_core::midi_sync::MidiSyncFollower>,
+    },
+    SetAudioPortMapping {
+        track_index: usize,
+        plugin_index: usize,
+        mapping: olivia_core::plugin::AudioPortMapping,
+    },
+    SetPluginBypass {
+        track_index: usize,
+        plugin_index: usize,
+        bypassed: bool,
+        mix: f32,
+    },
+    SetPluginParameter {
+        track_index: usize,
+        plugin_index: usize,
+        index: usize,
+        value: f32,
+    },
+    SetPluginDryDelay {
+        track_index: usize,
+        plugin_index: usize,
+        dry_delay: olivia_core::processor::DryDelay,
+    },
+}
+
+/// Values that the processor has removed or replaced. They are sent back to the controller so
+/// that they are freed outside of the audio thread.
+#[allow(clippy::large_enum_variant)]
+enum Garbage {
+    Track(olivia_core::processor::Track, DeletedPlugins),
+    MidiMap(Vec<olivia_core::midi_map::MidiMapping>),
+    Automation(Vec<olivia_core::automation::AutomationLane>),
+    DryDelay(olivia_core::processor::DryDelay),
+}
+
+/// A request for the processor to read the current values of plugin parameters. The processor
+/// fills in the values and sends the snapshot back to the controller.
+struct ParameterSnapshot {
+    // The value of `Controller::parameter_changes` when the snapshot was requested.
+    changes: u64,
+    readings: Vec<ParameterReading>,
+}
+
+struct ParameterReading {
+    plugin_instance_id: IntId,
+    track_index: usize,
+    plugin_index: usize,
+    index: usize,
+    value: Option<f32>,
+}
+
+/// What happens to the plugin instances of a track that the processor deletes.
+enum DeletedPlugins {
+    /// The plugin instances become unowned plugin instances with these ids, in track order.
+    Unowned(Vec<IntId>),
+    /// The plugin instances are kept for undoing the deletion.
+    Keep(PluginStash),
+}
+
+type PluginBoxes = Vec<Box<dyn olivia_core::plugin::PluginInstance>>;
+
+/// The index, metadata, plugin instances and automation lanes of a removed track.
+type RemovedTrack = (usize, Track, Vec<PluginInstance>, Vec<AutomationLane>);
+
+/// The plugin instances of a deleted track. They are kept so that undoing the deletion restores
+/// the plugin instances with their state, and freed once the undo step is forgotten.
+#[derive(Clone, Default)]
+struct PluginStash(std::sync::Arc<std::sync::Mutex<Option<PluginBoxes>>>);
+
+impl PluginStash {
+    fn put(&self, plugins: PluginBoxes) {
+        *self.0.lock().unwrap() = Some(plugins);
+    }
+
+    /// Take the plugin instances, or `None` if the processor has not returned them yet.
+    fn take(&self) -> Option<PluginBoxes> {
+        self.0.lock().unwrap().take()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_none()
+    }
+}
+
+impl std::fmt::Debug for PluginStash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PluginStash")
+            .field("returned", &!self.is_empty())
+            .finish()
+    }
+}
+
+impl PartialEq for PluginStash {
+    fn eq(&self, other: &PluginStash) -> bool {
+        std::sync::Arc::ptr_eq(&self.0, &other.0)
+    }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
 pub struct PluginInstance {
     pub id: IntId,
     pub plugin_id: String,
+    /// How the audio ports of the plugin are connected. If not set, the plugin decides.
+    #[serde(default)]
+    pub audio_port_mapping: Option<AudioPortMapping>,
+    #[serde(default)]
+    pub bypass: PluginBypass,
+    /// The values of the parameters that have been set, by parameter index. Other parameters
+    /// have their default value.
+    #[serde(default)]
+    pub parameters: BTreeMap<usize, f32>,
+}
+
+/// Whether a plugin instance is bypassed and how its output is mixed with its input.
+#[derive(Copy, Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct PluginBypass {
+    pub bypassed: bool,
+    /// The amount of plugin output in the mix, from 0, only the input, to 1, only the output.
+    pub mix: f32,
+}
+
+impl Default for PluginBypass {
+    fn default() -> PluginBypass {
+        PluginBypass {
+            bypassed: false,
+            mix: 1.0,
+        }
+    }
+}
+
+/// The latency of the output.
+#[derive(Copy, Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Latency {
+    pub frames: usize,
+    pub milliseconds: f32,
+}
+
+/// A point on an automation lane.
+#[derive(Copy, Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Breakpoint {
+    pub frame: u64,
+    pub value: f32,
+    /// The shape of the segment to the next breakpoint. 0 is a straight line, positive values
+    /// start slow and end fast and negative values start fast and end slow.
+    #[serde(default)]
+    pub curve: f32,
+}
+
+impl From<Breakpoint> for olivia_core::automation::Breakpoint {
+    fn from(b: Breakpoint) -> olivia_core::automation::Breakpoint {
+        olivia_core::automation::Breakpoint {
+            frame: b.frame,
+            value: b.value,
+            curve: b.curve,
+        }
+    }
+}
+
+/// The value that an automation lane controls.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AutomationTarget {
+    Volume,
+    Pan,
+    Parameter {
+        plugin_instance_id: IntId,
+        parameter: usize,
+    },
+}
+
+/// Changes a value of a track over time.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct AutomationLane {
+    pub id: IntId,
+    pub track_id: IntId,
+    pub target: AutomationTarget,
+    pub points: Vec<Breakpoint>,
+}
+
+/// A MIDI controller that can be mapped to a value. Channels are zero based.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MidiSource {
+    ControlChange { channel: u8, controller: u8 },
+    PitchBend { channel: u8 },
+    ChannelPressure { channel: u8 },
+}
+
+impl From<MidiSource> for olivia_core::midi_map::MidiSource {
+    fn from(s: MidiSource) -> olivia_core::midi_map::MidiSource {
+        match s {
+            MidiSource::ControlChange {
+                channel,
+                controller,
+            } => olivia_core::midi_map::MidiSource::ControlChange {
+                channel,
+                controller,
+            },
+            MidiSource::PitchBend { channel } => {
+                olivia_core::midi_map::MidiSource::PitchBend { channel }
+            }
+            MidiSource::ChannelPressure { channel } => {
+                olivia_core::midi_map::MidiSource::ChannelPressure { channel }
+            }
+        }
+    }
+}
+
+impl From<olivia_core::midi_map::MidiSource> for MidiSource {
+    fn from(s: olivia_core::midi_map::MidiSource) -> MidiSource {
+        match s {
+            olivia_core::midi_map::MidiSource::ControlChange {
+                channel,
+                controller,
+            } => MidiSource::ControlChange {
+                channel,
+                controller,
+            },
+            olivia_core::midi_map::MidiSource::PitchBend { channel } => {
+                MidiSource::PitchBend { channel }
+            }
+            olivia_core::midi_map::MidiSource::ChannelPressure { channel } => {
+                MidiSource::ChannelPressure { channel }
+            }
+        }
+    }
+}
+
+/// The value that a MIDI mapping controls.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MidiTarget {
+    TrackVolume {
+        track_id: IntId,
+    },
+    TrackPan {
+        track_id: IntId,
+    },
+    Parameter {
+        plugin_instance_id: IntId,
+        parameter: usize,
+    },
+}
+
+/// Binds a MIDI controller to a track control or plugin parameter. Changes made through MIDI are
+/// not reflected in the track volume and pan.
+#[derive(Copy, Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct MidiMapping {
+    pub id: IntId,
+    pub source: MidiSource,
+    pub target: MidiTarget,
+    /// The value for the lowest MIDI value. Defaults to the low end of the target's range.
+    #[serde(default)]
+    pub min: Option<f32>,
+    /// The value for the highest MIDI value. Defaults to the high end of the target's range.
+    #[serde(default)]
+    pub max: Option<f32>,
+}
+
+/// A request to map the next MIDI controller that is moved.
+#[derive(Copy, Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct MidiLearn {
+    /// The id of the mapping that is created.
+    pub mapping_id: IntId,
+    pub target: MidiTarget,
+    #[serde(default)]
+    pub min: Option<f32>,
+    #[serde(default)]
+    pub max: Option<f32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct MidiLearnStatus {
+    /// The request that is waiting for a MIDI controller, if any.
+    pub learning: Option<MidiLearn>,
+    /// The mapping that was created by the last completed request.
+    pub learned: Option<MidiMapping>,
+}
+
+/// The parts of a project that are saved to and loaded from a session. The tracks and plugin
+/// instances that the MIDI mappings refer to must exist before the session is loaded.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Session {
+    pub midi_mappings: Vec<MidiMapping>,
+}
+
+/// A value that an enumerated plugin parameter can take.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ParameterChoice {
+    pub value: f32,
+    pub label: String,
+}
+
+impl From<olivia_core::plugin::ParameterChoice> for ParameterChoice {
+    fn from(c: olivia_core::plugin::ParameterChoice) -> ParameterChoice {
+        ParameterChoice {
+            value: c.value,
+            label: c.label,
+        }
+    }
+}
+
+/// A parameter of a plugin instance, such as an LV2 control port, and its current value.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct PluginParameter {
+    pub index: usize,
+    pub id: String,
+    pub name: String,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub unit: Option<String>,
+    /// The values the parameter can take. Empty if any value in the range can be used.
+    pub enumeration: Vec<ParameterChoice>,
+    /// The current value. Changes made by automation and MIDI mappings show up with a delay, as
+    /// the values of plugins on tracks are read periodically.
+    pub value: f32,
+}
+
+/// The status of a running plugin instance.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct PluginInstanceStatus {
+    /// True if the plugin has failed, for example because its plugin host crashed. Failed plugins
+    /// output silence.
+    pub failed: bool,
+}
+
+/// A channel that an audio port of a plugin can be connected to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioChannel {
+    Left,
+    Right,
+    /// An audio input port, for example to feed a sidechain.
+    Input(usize),
+}
+
+impl From<AudioChannel> for olivia_core::plugin::AudioChannel {
+    fn from(c: AudioChannel) -> olivia_core::plugin::AudioChannel {
+        match c {
+            AudioChannel::Left => olivia_core::plugin::AudioChannel::Left,
+            AudioChannel::Right => olivia_core::plugin::AudioChannel::Right,
+            AudioChannel::Input(n) => olivia_core::plugin::AudioChannel::Input(n),
+        }
+    }
+}
+
+/// The channel of each audio input and output port of a plugin, in the order the plugin declares
+/// its ports. `None` leaves a port unconnected.
+#[derive(Clone, Debug, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct AudioPortMapping {
+    #[serde(default)]
+    pub inputs: Vec<Option<AudioChannel>>,
+    #[serde(default)]
+    pub outputs: Vec<Option<AudioChannel>>,
+}
+
+impl From<AudioPortMapping> for olivia_core::plugin::AudioPortMapping {
+    fn from(m: AudioPortMapping) -> olivia_core::plugin::AudioPortMapping {
+        olivia_core::plugin::AudioPortMapping {
+            inputs: m.inputs.into_iter().map(|c| c.map(Into::into)).collect(),
+            outputs: m.outputs.into_iter().map(|c| c.map(Into::into)).collect(),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
@@ -24,7 +417,244 @@ pub struct Track {
     pub id: IntId,
     pub name: String,
     pub volume: f32,
+    /// From -1 (left) to 1 (right).
+    #[serde(default)]
+    pub pan: f32,
     pub plugin_instances: Vec<IntId>,
+    #[serde(default)]
+    pub input: TrackInput,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct TrackInput {
+    // The audio input ports that feed the track. A single port is treated as mono and two ports
+    // are treated as left and right.
+    pub ports: Vec<usize>,
+    // If the input should be heard through the track output.
+    pub monitoring: bool,
+    // If the input should be recorded while the transport is recording.
+    pub armed: bool,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Transport {
+    pub playing: bool,
+    pub record_enabled: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct TempoChange {
+    pub bar: u32,
+    pub bpm: f64,
+    pub beats_per_bar: u32,
+}
+
+impl From<TempoChange> for olivia_core::tempo_map::TempoChange {
+    fn from(c: TempoChange) -> olivia_core::tempo_map::TempoChange {
+        olivia_core::tempo_map::TempoChange {
+            bar: c.bar,
+            bpm: c.bpm,
+            beats_per_bar: c.beats_per_bar,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClickSound {
+    Sine,
+    Square,
+    Noise,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetronomeOutput {
+    Master,
+    Dedicated,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Metronome {
+    pub enabled: bool,
+    pub sound: ClickSound,
+    pub volume: f32,
+    pub accent_volume: f32,
+    pub frequency: f32,
+    pub accent_frequency: f32,
+    pub count_in_bars: u32,
+    pub output: MetronomeOutput,
+}
+
+impl Default for Metronome {
+    fn default() -> Metronome {
+        Metronome::from(olivia_core::metronome::MetronomeSettings::default())
+    }
+}
+
+impl From<olivia_core::metronome::MetronomeSettings> for Metronome {
+    fn from(s: olivia_core::metronome::MetronomeSettings) -> Metronome {
+        use olivia_core::metronome;
+        Metronome {
+            enabled: s.enabled,
+            sound: match s.sound {
+                metronome::ClickSound::Sine => ClickSound::Sine,
+                metronome::ClickSound::Square => ClickSound::Square,
+                metronome::ClickSound::Noise => ClickSound::Noise,
+            },
+            volume: s.volume,
+            accent_volume: s.accent_volume,
+            frequency: s.frequency,
+            accent_frequency: s.accent_frequency,
+            count_in_bars: s.count_in_bars,
+            output: match s.output {
+                metronome::MetronomeOutput::Master => MetronomeOutput::Master,
+                metronome::MetronomeOutput::Dedicated => MetronomeOutput::Dedicated,
+            },
+        }
+    }
+}
+
+impl From<Metronome> for olivia_core::metronome::MetronomeSettings {
+    fn from(m: Metronome) -> olivia_core::metronome::MetronomeSettings {
+        use olivia_core::metronome;
+        metronome::MetronomeSettings {
+            enabled: m.enabled,
+            sound: match m.sound {
+                ClickSound::Sine => metronome::ClickSound::Sine,
+                ClickSound::Square => metronome::ClickSound::Square,
+                ClickSound::Noise => metronome::ClickSound::Noise,
+            },
+            volume: m.volume,
+            accent_volume: m.accent_volume,
+            frequency: m.frequency,
+            accent_frequency: m.accent_frequency,
+            count_in_bars: m.count_in_bars,
+            output: match m.output {
+                MetronomeOutput::Master => metronome::MetronomeOutput::Master,
+                MetronomeOutput::Dedicated => metronome::MetronomeOutput::Dedicated,
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MtcFrameRate {
+    Fps24,
+    Fps25,
+    Fps30,
+}
+
+impl From<MtcFrameRate> for olivia_core::midi_sync::MtcFrameRate {
+    fn from(r: MtcFrameRate) -> olivia_core::midi_sync::MtcFrameRate {
+        match r {
+            MtcFrameRate::Fps24 => olivia_core::midi_sync::MtcFrameRate::Fps24,
+            MtcFrameRate::Fps25 => olivia_core::midi_sync::MtcFrameRate::Fps25,
+            MtcFrameRate::Fps30 => olivia_core::midi_sync::MtcFrameRate::Fps30,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncSource {
+    MidiClock,
+    Mtc,
+}
+
+impl From<SyncSource> for olivia_core::midi_sync::SyncSource {
+    fn from(s: SyncSource) -> olivia_core::midi_sync::SyncSource {
+        match s {
+            SyncSource::MidiClock => olivia_core::midi_sync::SyncSource::MidiClock,
+            SyncSource::Mtc => olivia_core::midi_sync::SyncSource::Mtc,
+        }
+    }
+}
+
+/// MIDI clock and time code settings.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct MidiSync {
+    /// If MIDI clock, start, stop, continue and song position pointer messages are sent.
+    pub clock_output: bool,
+    /// The frame rate of the MIDI time code to send, if any.
+    pub mtc_output: Option<MtcFrameRate>,
+    /// The source on the MIDI input that drives the transport, if any.
+    pub follow: Option<SyncSource>,
+}
+
+/// An edit to the session that can be undone and redone.
+#[derive(Clone, Debug, PartialEq)]
+enum Edit {
+    AddTrack(Track),
+    DeleteTrack {
+        index: usize,
+        track: Track,
+        plugin_instances: Vec<PluginInstance>,
+        automation: Vec<AutomationLane>,
+        stash: PluginStash,
+    },
+    CreatePluginInstance(PluginInstance),
+    SetTrackVolume {
+        id: IntId,
+        old: f32,
+        new: f32,
+    },
+    SetTrackPan {
+        id: IntId,
+        old: f32,
+        new: f32,
+    },
+    SetAutomationLane {
+        id: IntId,
+        old: Option<AutomationLane>,
+        new: Option<AutomationLane>,
+    },
+    SetMidiMapping {
+        id: IntId,
+        old: Option<MidiMapping>,
+        new: Option<MidiMapping>,
+    },
+    SetTrackInput {
+        id: IntId,
+        old: TrackInput,
+        new: TrackInput,
+    },
+    SetTempoMap {
+        old: Vec<TempoChange>,
+        new: Vec<TempoChange>,
+    },
+    SetMetronome {
+        old: Metronome,
+        new: Metronome,
+    },
+    SetPluginBypass {
+        id: IntId,
+        old: PluginBypass,
+        new: PluginBypass,
+    },
+    SetPluginParameter {
+        id: IntId,
+        index: usize,
+        old: f32,
+        new: f32,
+    },
+}
+
+/// The descriptions of the steps that can be undone and redone, most recent last.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct History {
+    pub undo: Vec<String>,
+    pub redo: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Clip {
+    pub id: IntId,
+    pub track_id: IntId,
+    pub path: String,
+    pub start_frame: u64,
+    pub frames: u64,
 }
 
 pub struct Controller {
@@ -34,13 +664,60 @@ pub struct Controller {
     plugin_instances: Vec<PluginInstance>,
     // Plugin instances that don't belong to any tracks.
     unowned_plugin_instances: HashMap<IntId, Box<dyn olivia_core::plugin::PluginInstance>>,
+    // Failure flags for plugin instances that can fail, such as plugins running in a plugin host.
+    failure_flags: HashMap<IntId, olivia_core::plugin::FailureFlag>,
+    // The parameters declared by each plugin instance.
+    plugin_parameters: HashMap<IntId, Vec<olivia_core::plugin::ParameterInfo>>,
     // Factory containing plugin metadata as well as methods for building plugin
     // instances.
     plugin_factory: PluginFactory,
     // Buffer size.
     buffer_size: usize,
+    // Sample rate.
+    sample_rate: f32,
+    // The number of audio input ports provided by the IO backend.
+    num_audio_inputs: usize,
+    // Writes armed tracks to disk. Recording is unavailable if this is not set.
+    disk_writer: Option<recorder::DiskWriter>,
+    // The state of the transport.
+    transport: Transport,
+    // Audio clips that have been recorded.
+    clips: Vec<Clip>,
+    // Automation lanes of all tracks.
+    automation: Vec<AutomationLane>,
+    // Bindings of MIDI controllers to values.
+    midi_mappings: Vec<MidiMapping>,
+    // The MIDI learn request that is waiting for a MIDI controller.
+    midi_learn: Option<MidiLearn>,
+    // The id of the mapping created by the last completed MIDI learn request.
+    last_learned_mapping: Option<IntId>,
+    // MIDI controllers captured by the processor while learning.
+    learned_midi: crossbeam::channel::Receiver<olivia_core::midi_map::MidiSource>,
+    // Plugins whose latency has grown beyond their dry delay.
+    dry_delay_requests: crossbeam::channel::Receiver<olivia_core::processor::DryDelayRequest>,
+    // The values of plugin parameters on tracks, as last read by the processor.
+    parameter_values: HashMap<(IntId, usize), f32>,
+    // Counts the parameter changes made through the controller. Snapshots requested before the
+    // last change are out of date.
+    parameter_changes: u64,
+    // True while the processor is reading a parameter snapshot.
+    reading_parameters: bool,
+    // Parameter snapshots read by the processor.
+    parameter_snapshots: crossbeam::channel::Receiver<ParameterSnapshot>,
+    // The tempo changes that make up the tempo map.
+    tempo_map: Vec<TempoChange>,
+    // Metronome settings.
+    metronome: Metronome,
+    // MIDI clock and time code settings.
+    midi_sync: MidiSync,
+    // Edits that can be undone and redone.
+    history: history::History<Edit>,
     // Channel to send commands to audio processor.
     commands: crossbeam::channel::Sender<Command>,
+    // Values that the processor no longer uses.
+    garbage: crossbeam::channel::Receiver<Garbage>,
+    // The output latency in frames, updated by the processor.
+    latency: std::sync::Arc<std::sync::atomic::AtomicUsize>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -50,6 +727,18 @@ pub enum ControllerError {
     FailedToBuildPlugin(plugin_factory::PluginBuilderError),
     TrackAlreadyExists(IntId, Track),
     TrackDoesNotExist(IntId),
+    PluginInstanceDoesNotExist(IntId),
+    PluginParameterDoesNotExist(IntId, usize),
+    InvalidPluginParameterValue(IntId, usize, f32),
+    InvalidTrackInput(IntId, TrackInput),
+    InvalidAudioPortMapping(IntId, AudioPortMapping),
+    InvalidTempoChange(TempoChange),
+    InvalidAutomationLane(AutomationLane),
+    AutomationLaneDoesNotExist(IntId),
+    InvalidMidiMapping(MidiMapping),
+    MidiMappingDoesNotExist(IntId),
+    RecordingNotAvailable,
+    NoUndoGroupInProgress,
     TrackReferencesNonExistantPluginInstance {
         track_id: IntId,
         plugin_instance_id: IntId,
@@ -73,23 +762,202 @@ impl Controller {
     pub fn new(plugin_factory: PluginFactory) -> (Controller, Processor) {
         let command_queue_size = 1_000_000;
         let (tx, rx) = crossbeam::channel::bounded(command_queue_size);
+        let latency = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (learned_midi_tx, learned_midi_rx) = crossbeam::channel::bounded(16);
+        let (dry_delay_requests_tx, dry_delay_requests_rx) = crossbeam::channel::bounded(16);
+        let (garbage_tx, garbage_rx) = crossbeam::channel::bounded(GARBAGE_QUEUE_SIZE);
+        let (snapshots_tx, snapshots_rx) = crossbeam::channel::bounded(1);
         let controller = Controller {
             tracks: Vec::new(),
             plugin_instances: Vec::new(),
             unowned_plugin_instances: HashMap::new(),
+            failure_flags: HashMap::new(),
+            plugin_parameters: HashMap::new(),
             plugin_factory,
             buffer_size: 0,
+            sample_rate: 44100.0,
+            num_audio_inputs: 0,
+            disk_writer: None,
+            transport: Transport::default(),
+            clips: Vec::new(),
+            automation: Vec::new(),
+            midi_mappings: Vec::new(),
+            midi_learn: None,
+            last_learned_mapping: None,
+            learned_midi: learned_midi_rx,
+            dry_delay_requests: dry_delay_requests_rx,
+            parameter_values: HashMap::new(),
+            parameter_changes: 0,
+            reading_parameters: false,
+            parameter_snapshots: snapshots_rx,
+            tempo_map: vec![TempoChange {
+                bar: 0,
+                bpm: 120.0,
+                beats_per_bar: 4,
+            }],
+            metronome: Metronome::default(),
+            midi_sync: MidiSync::default(),
+            history: history::History::new(MAX_UNDO_STEPS),
             commands: tx,
+            garbage: garbage_rx,
+            latency: latency.clone(),
         };
         let processor = Processor {
             inner: olivia_core::processor::Processor::new(),
             commands: rx,
+            external_transport: false,
+            transport_request: None,
+            latency,
+            learned_midi: learned_midi_tx,
+            dry_delay_requests: dry_delay_requests_tx,
+            garbage: garbage_tx,
+            parameter_snapshots: snapshots_tx,
         };
         (controller, processor)
     }
 
+    /// Handle what the processor has sent back since the last update. This should be called
+    /// periodically, even while there are no requests.
+    pub fn update(&mut self) {
+        self.collect_garbage();
+        self.complete_midi_learn();
+        self.read_parameter_values();
+        self.resize_dry_delays();
+    }
+
+    /// Build larger dry delays for the plugins whose latency has grown. The dry delay lines the
+    /// plugin input up with its output, so they are only as long as the plugin latency.
+    fn resize_dry_delays(&mut self) {
+        while let Ok(request) = self.dry_delay_requests.try_recv() {
+            self.commands
+                .send(Command::SetPluginDryDelay {
+                    track_index: request.track_index,
+                    plugin_index: request.plugin_index,
+                    dry_delay: olivia_core::processor::DryDelay::new(request.latency),
+                })
+                .unwrap();
+        }
+    }
+
+    /// Store the parameter values from the last snapshot read by the processor and request the
+    /// next one.
+    fn read_parameter_values(&mut self) {
+        if let Ok(snapshot) = self.parameter_snapshots.try_recv() {
+            self.reading_parameters = false;
+            if snapshot.changes == self.parameter_changes {
+                self.parameter_values = snapshot
+                    .readings
+                    .into_iter()
+                    .filter_map(|r| Some(((r.plugin_instance_id, r.index), r.value?)))
+                    .collect();
+            }
+        }
+        if self.reading_parameters {
+            return;
+        }
+        let mut readings = Vec::new();
+        for (track_index, track) in self.tracks.iter().enumerate() {
+            for (plugin_index, id) in track.plugin_instances.iter().enumerate() {
+                let parameters = self.plugin_parameters.get(id).map(Vec::as_slice);
+                for p in parameters.unwrap_or_default() {
+                    readings.push(ParameterReading {
+                        plugin_instance_id: *id,
+                        track_index,
+                        plugin_index,
+                        index: p.index,
+                        value: None,
+                    });
+                }
+            }
+        }
+        self.reading_parameters = true;
+        self.commands
+            .send(Command::ReadParameters(ParameterSnapshot {
+                changes: self.parameter_changes,
+                readings,
+            }))
+            .unwrap();
+    }
+
+    /// Free the values that the processor no longer uses. Plugin instances of deleted tracks are
+    /// dropped here, which may block until their worker threads finish.
+    fn collect_garbage(&mut self) {
+        while let Ok(garbage) = self.garbage.try_recv() {
+            self.handle_garbage(garbage);
+        }
+    }
+
+    fn handle_garbage(&mut self, garbage: Garbage) {
+        match garbage {
+            Garbage::Track(mut track, DeletedPlugins::Unowned(ids)) => {
+                for (id, plugin) in ids.into_iter().zip(track.take_plugins()) {
+                    // The plugin instance may have been deleted or rebuilt in the meantime.
+                    if self.plugin_instance_by_id(id).is_some()
+                        && !self.unowned_plugin_instances.contains_key(&id)
+                    {
+                        self.unowned_plugin_instances.insert(id, plugin);
+                    }
+                }
+            }
+            Garbage::Track(mut track, DeletedPlugins::Keep(stash)) => {
+                stash.put(track.take_plugins())
+            }
+            Garbage::MidiMap(midi_map) => drop(midi_map),
+            Garbage::Automation(automation) => drop(automation),
+            Garbage::DryDelay(dry_delay) => drop(dry_delay),
+        }
+    }
+
+    /// Collect garbage until `done` returns true. Returns false if `done` is still false after
+    /// `GARBAGE_TIMEOUT`, for example because the processor is not running.
+    fn wait_for_garbage(&mut self, done: impl Fn(&Controller) -> bool) -> bool {
+        let deadline = std::time::Instant::now() + GARBAGE_TIMEOUT;
+        loop {
+            self.collect_garbage();
+            if done(self) {
+                return true;
+            }
+            let timeout = deadline.saturating_duration_since(std::time::Instant::now());
+            match self.garbage.recv_timeout(timeout) {
+                Ok(garbage) => self.handle_garbage(garbage),
+                Err(_) => return done(self),
+            }
+        }
+    }
+
     pub fn set_buffer_size(&mut self, buffer_size: usize) {
         self.buffer_size = buffer_size;
+        self.update_plugin_audio_settings();
+    }
+
+    /// Set the sample rate. The tempo map and metronome are updated to use the new sample rate.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.update_plugin_audio_settings();
+        self.commands
+            .send(Command::SetTempoMap(self.core_tempo_map()))
+            .unwrap();
+        if self.buffer_size > 0 {
+            self.commands
+                .send(Command::SetMetronome(self.core_metronome()))
+                .unwrap();
+        }
+    }
+
+    fn update_plugin_audio_settings(&mut self) {
+        self.plugin_factory
+            .set_audio_settings(plugin_factory::AudioSettings {
+                sample_rate: f64::from(self.sample_rate),
+                buffer_size: self.buffer_size,
+            });
+    }
+
+    pub fn set_num_audio_inputs(&mut self, num_audio_inputs: usize) {
+        self.num_audio_inputs = num_audio_inputs;
+    }
+
+    pub fn set_disk_writer(&mut self, disk_writer: recorder::DiskWriter) {
+        self.disk_writer = Some(disk_writer);
     }
 
     pub fn tracks(&self) -> impl Iterator<Item = &'_ Track> {
@@ -101,6 +969,15 @@ impl Controller {
     }
 
     pub fn add_track(&mut self, track: Track) -> Result<(), ControllerError> {
+        self.insert_track(self.tracks.len(), track.clone())?;
+        self.history.push(
+            &format!("Add track \"{}\"", track.name),
+            Edit::AddTrack(track),
+        );
+        Ok(())
+    }
+
+    fn insert_track(&mut self, index: usize, track: Track) -> Result<(), ControllerError> {
         if self.buffer_size == 0 {
             error!(
                 "Attempted to create track without setting buffer size. Track: {:?}.",
@@ -111,6 +988,7 @@ impl Controller {
         if let Some(t) = self.track_by_id(track.id) {
             return Err(ControllerError::TrackAlreadyExists(t.id, t.clone()));
         }
+        self.validate_track_input(track.id, &track.input)?;
         for plugin_instance in track.plugin_instances.iter() {
             if self.plugin_instance_by_id(*plugin_instance).is_none() {
                 return Err(ControllerError::TrackReferencesNonExistantPluginInstance {
@@ -132,47 +1010,757 @@ impl Controller {
         }
 
         info!("Creating track \"{}\".", track.name);
-        let mut core_track = olivia_core::processor::Track::new(self.buffer_size, 1.0);
+        let mut plugins = Vec::with_capacity(track.plugin_instances.len());
         for plugin_instance in track.plugin_instances.iter() {
-            core_track.add_plugin(
-                self.unowned_plugin_instances
-                    .remove(plugin_instance)
-                    .unwrap(),
-            );
+            match self.take_unowned_plugin(*plugin_instance) {
+                Ok(p) => plugins.push(p),
+                Err(e) => {
+                    for (id, p) in track.plugin_instances.iter().zip(plugins) {
+                        self.unowned_plugin_instances.insert(*id, p);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        let mut core_track = olivia_core::processor::Track::new(self.buffer_size, track.volume);
+        for (plugin_index, (plugin_instance, plugin)) in
+            track.plugin_instances.iter().zip(plugins).enumerate()
+        {
+            core_track.add_plugin(plugin);
+            if let Some(p) = self.plugin_instance_by_id(*plugin_instance) {
+                core_track.set_plugin_bypass(plugin_index, p.bypass.bypassed, p.bypass.mix);
+            }
+        }
+        core_track.set_pan(track.pan);
+        core_track.set_automation(self.core_automation(&track));
+        core_track.set_input(input_channels(&track.input));
+        core_track.set_monitoring(track.input.monitoring);
+        if track.input.armed {
+            core_track.set_record_sink(self.new_record_sink(track.id));
+        }
+        let index = index.min(self.tracks.len());
+        self.commands
+            .send(Command::AddTrack(index, core_track))
+            .unwrap();
+        self.tracks.insert(index, track);
+        self.sync_midi_map();
+        Ok(())
+    }
+
+    /// Take an unowned plugin instance. The plugin instances of a track that was just removed may
+    /// still be on their way back from the processor. They are rebuilt if they do not arrive in
+    /// time.
+    fn take_unowned_plugin(
+        &mut self,
+        id: IntId,
+    ) -> Result<Box<dyn olivia_core::plugin::PluginInstance>, ControllerError> {
+        if !self.unowned_plugin_instances.contains_key(&id) {
+            self.wait_for_garbage(|c| c.unowned_plugin_instances.contains_key(&id));
         }
-        self.commands.send(Command::AddTrack(core_track)).unwrap();
-        self.tracks.push(track);
+        if let Some(p) = self.unowned_plugin_instances.remove(&id) {
+            return Ok(p);
+        }
+        warn!(
+            "Plugin instance {:?} was not returned in time, rebuilding it.",
+            id
+        );
+        let metadata = match self.plugin_instance_by_id(id) {
+            Some(p) => p.clone(),
+            None => return Err(ControllerError::PluginInstanceDoesNotExist(id)),
+        };
+        self.instantiate_plugin(&metadata)
+    }
+
+    pub fn set_track_volume(&mut self, id: IntId, volume: f32) -> Result<(), ControllerError> {
+        let old = match self.track_by_id(id) {
+            Some(t) => t.volume,
+            None => return Err(ControllerError::TrackDoesNotExist(id)),
+        };
+        self.apply_track_volume(id, volume)?;
+        self.history.push(
+            "Change track volume",
+            Edit::SetTrackVolume {
+                id,
+                old,
+                new: volume,
+            },
+        );
         Ok(())
     }
 
-    pub fn delete_track(&mut self, id: IntId) -> Result<(), ControllerError> {
-        let track_index = match self.tracks.iter().enumerate().find(|(_, t)| t.id == id) {
-            Some((idx, _)) => idx,
+    fn apply_track_volume(&mut self, id: IntId, volume: f32) -> Result<(), ControllerError> {
+        let track_index = match self.tracks.iter().position(|t| t.id == id) {
+            Some(idx) => idx,
             None => return Err(ControllerError::TrackDoesNotExist(id)),
         };
-        for pid in self.tracks[track_index].plugin_instances.iter() {
-            self.plugin_instances.retain(|p| p.id != *pid);
-        }
-        self.tracks.retain(|t| t.id != id);
         self.commands
-            .send(Command::DeleteTrack(track_index))
+            .send(Command::SetTrackVolume(track_index, volume))
             .unwrap();
+        self.tracks[track_index].volume = volume;
         Ok(())
     }
 
-    pub fn plugin_factory(&self) -> &PluginFactory {
-        &self.plugin_factory
+    pub fn set_track_pan(&mut self, id: IntId, pan: f32) -> Result<(), ControllerError> {
+        let old = match self.track_by_id(id) {
+            Some(t) => t.pan,
+            None => return Err(ControllerError::TrackDoesNotExist(id)),
+        };
+        let pan = pan.clamp(-1.0, 1.0);
+        self.apply_track_pan(id, pan)?;
+        self.history
+            .push("Change track pan", Edit::SetTrackPan { id, old, new: pan });
+        Ok(())
     }
 
-    pub fn plugin_instances(&self) -> impl Iterator<Item = &'_ PluginInstance> {
-        self.plugin_instances.iter()
+    fn apply_track_pan(&mut self, id: IntId, pan: f32) -> Result<(), ControllerError> {
+        let track_index = match self.tracks.iter().position(|t| t.id == id) {
+            Some(idx) => idx,
+            None => return Err(ControllerError::TrackDoesNotExist(id)),
+        };
+        self.commands
+            .send(Command::SetTrackPan(track_index, pan))
+            .unwrap();
+        self.tracks[track_index].pan = pan;
+        Ok(())
     }
 
-    pub fn plugin_instance_by_id(&self, id: IntId) -> Option<&PluginInstance> {
-        self.plugin_instances().find(|p| p.id == id)
+    pub fn automation_lanes(&self) -> impl Iterator<Item = &'_ AutomationLane> {
+        self.automation.iter()
     }
 
-    pub fn create_plugin_instance(
+    pub fn automation_lane_by_id(&self, id: IntId) -> Option<&AutomationLane> {
+        self.automation.iter().find(|l| l.id == id)
+    }
+
+    /// Create an automation lane or replace the lane with the same id. Plugin parameters can only
+    /// be automated by lanes on the track the plugin instance belongs to. Curves are clamped to
+    /// `olivia_core::automation::MAX_CURVE`.
+    pub fn set_automation_lane(&mut self, mut lane: AutomationLane) -> Result<(), ControllerError> {
+        let track = match self.track_by_id(lane.track_id) {
+            Some(t) => t,
+            None => return Err(ControllerError::TrackDoesNotExist(lane.track_id)),
+        };
+        let is_valid = match lane.target {
+            AutomationTarget::Parameter {
+                plugin_instance_id, ..
+            } => track.plugin_instances.contains(&plugin_instance_id),
+            AutomationTarget::Volume | AutomationTarget::Pan => true,
+        };
+        let has_duplicate_target = self
+            .automation
+            .iter()
+            .any(|l| l.id != lane.id && l.track_id == lane.track_id && l.target == lane.target);
+        let is_finite = lane
+            .points
+            .iter()
+            .all(|p| p.value.is_finite() && p.curve.is_finite());
+        if !is_valid || has_duplicate_target || !is_finite {
+            return Err(ControllerError::InvalidAutomationLane(lane));
+        }
+        for p in lane.points.iter_mut() {
+            let max_curve = olivia_core::automation::MAX_CURVE;
+            p.curve = p.curve.clamp(-max_curve, max_curve);
+        }
+        let old = self.automation_lane_by_id(lane.id).cloned();
+        let description = if old.is_some() {
+            "Edit automation"
+        } else {
+            "Add automation"
+        };
+        self.apply_automation_lane(lane.id, Some(lane.clone()));
+        self.history.push(
+            description,
+            Edit::SetAutomationLane {
+                id: lane.id,
+                old,
+                new: Some(lane),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn delete_automation_lane(&mut self, id: IntId) -> Result<(), ControllerError> {
+        let old = match self.automation_lane_by_id(id) {
+            Some(l) => l.clone(),
+            None => return Err(ControllerError::AutomationLaneDoesNotExist(id)),
+        };
+        self.apply_automation_lane(id, None);
+        self.history.push(
+            "Delete automation",
+            Edit::SetAutomationLane {
+                id,
+                old: Some(old),
+                new: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Replace or remove the automation lane with `id` and update the tracks it belongs to.
+    fn apply_automation_lane(&mut self, id: IntId, lane: Option<AutomationLane>) {
+        let mut track_ids = Vec::with_capacity(2);
+        if let Some(i) = self.automation.iter().position(|l| l.id == id) {
+            track_ids.push(self.automation.remove(i).track_id);
+        }
+        if let Some(lane) = lane {
+            track_ids.push(lane.track_id);
+            self.automation.push(lane);
+        }
+        for track_id in track_ids {
+            if let Some(track_index) = self.tracks.iter().position(|t| t.id == track_id) {
+                let automation = self.core_automation(&self.tracks[track_index]);
+                self.commands
+                    .send(Command::SetTrackAutomation(track_index, automation))
+                    .unwrap();
+            }
+        }
+    }
+
+    /// The automation lanes of `track` for the processor.
+    fn core_automation(&self, track: &Track) -> Vec<olivia_core::automation::AutomationLane> {
+        self.automation
+            .iter()
+            .filter(|l| l.track_id == track.id)
+            .filter_map(|l| {
+                let target = match l.target {
+                    AutomationTarget::Volume => olivia_core::automation::AutomationTarget::Volume,
+                    AutomationTarget::Pan => olivia_core::automation::AutomationTarget::Pan,
+                    AutomationTarget::Parameter {
+                        plugin_instance_id,
+                        parameter,
+                    } => olivia_core::automation::AutomationTarget::Parameter {
+                        plugin_index: track
+                            .plugin_instances
+                            .iter()
+                            .position(|p| *p == plugin_instance_id)?,
+                        parameter,
+                    },
+                };
+                let points: Vec<olivia_core::automation::Breakpoint> =
+                    l.points.iter().map(|p| (*p).into()).collect();
+                Some(olivia_core::automation::AutomationLane {
+                    target,
+                    envelope: olivia_core::automation::Envelope::new(&points),
+                })
+            })
+            .collect()
+    }
+
+    pub fn midi_mappings(&self) -> impl Iterator<Item = &'_ MidiMapping> {
+        self.midi_mappings.iter()
+    }
+
+    pub fn midi_mapping_by_id(&self, id: IntId) -> Option<&MidiMapping> {
+        self.midi_mappings.iter().find(|m| m.id == id)
+    }
+
+    /// Create a MIDI mapping or replace the mapping with the same id.
+    pub fn set_midi_mapping(&mut self, mapping: MidiMapping) -> Result<(), ControllerError> {
+        if !self.is_valid_midi_mapping(&mapping) {
+            return Err(ControllerError::InvalidMidiMapping(mapping));
+        }
+        let old = self.midi_mapping_by_id(mapping.id).copied();
+        let description = if old.is_some() {
+            "Edit MIDI mapping"
+        } else {
+            "Add MIDI mapping"
+        };
+        self.apply_midi_mapping(mapping.id, Some(mapping));
+        self.history.push(
+            description,
+            Edit::SetMidiMapping {
+                id: mapping.id,
+                old,
+                new: Some(mapping),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn delete_midi_mapping(&mut self, id: IntId) -> Result<(), ControllerError> {
+        let old = match self.midi_mapping_by_id(id) {
+            Some(m) => *m,
+            None => return Err(ControllerError::MidiMappingDoesNotExist(id)),
+        };
+        self.apply_midi_mapping(id, None);
+        self.history.push(
+            "Delete MIDI mapping",
+            Edit::SetMidiMapping {
+                id,
+                old: Some(old),
+                new: None,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn session(&self) -> Session {
+        Session {
+            midi_mappings: self.midi_mappings.clone(),
+        }
+    }
+
+    /// Replace the MIDI mappings with the ones in `session`, as a single undo step. Nothing is
+    /// changed if any of the mappings is invalid.
+    pub fn load_session(&mut self, session: Session) -> Result<(), ControllerError> {
+        if let Some(m) = session
+            .midi_mappings
+            .iter()
+            .find(|m| !self.is_valid_midi_mapping(m))
+        {
+            return Err(ControllerError::InvalidMidiMapping(*m));
+        }
+        self.history.begin_group("Load session");
+        let old_ids: Vec<IntId> = self.midi_mappings.iter().map(|m| m.id).collect();
+        for id in old_ids {
+            if !session.midi_mappings.iter().any(|m| m.id == id) {
+                self.delete_midi_mapping(id)?;
+            }
+        }
+        for mapping in session.midi_mappings {
+            self.set_midi_mapping(mapping)?;
+        }
+        self.history.end_group();
+        Ok(())
+    }
+
+    fn is_valid_midi_mapping(&self, mapping: &MidiMapping) -> bool {
+        let target_exists = match mapping.target {
+            MidiTarget::TrackVolume { track_id } | MidiTarget::TrackPan { track_id } => {
+                self.track_by_id(track_id).is_some()
+            }
+            MidiTarget::Parameter {
+                plugin_instance_id, ..
+            } => self.plugin_location(plugin_instance_id).is_some(),
+        };
+        let is_finite = mapping
+            .min
+            .into_iter()
+            .chain(mapping.max)
+            .all(f32::is_finite);
+        target_exists && is_finite
+    }
+
+    /// Replace or remove the MIDI mapping with `id`.
+    fn apply_midi_mapping(&mut self, id: IntId, mapping: Option<MidiMapping>) {
+        self.midi_mappings.retain(|m| m.id != id);
+        if let Some(mapping) = mapping {
+            self.midi_mappings.push(mapping);
+        }
+        self.sync_midi_map();
+    }
+
+    /// Send the MIDI mappings to the processor. Mappings whose target no longer exists are kept
+    /// but ignored.
+    fn sync_midi_map(&self) {
+        let midi_map = self
+            .midi_mappings
+            .iter()
+            .filter_map(|m| {
+                let track_index = |id| self.tracks.iter().position(|t| t.id == id);
+                let target = match m.target {
+                    MidiTarget::TrackVolume { track_id } => {
+                        olivia_core::midi_map::MidiTarget::TrackVolume {
+                            track_index: track_index(track_id)?,
+                        }
+                    }
+                    MidiTarget::TrackPan { track_id } => {
+                        olivia_core::midi_map::MidiTarget::TrackPan {
+                            track_index: track_index(track_id)?,
+                        }
+                    }
+                    MidiTarget::Parameter {
+                        plugin_instance_id,
+                        parameter,
+                    } => {
+                        let (track_index, plugin_index) =
+                            self.plugin_location(plugin_instance_id)?;
+                        olivia_core::midi_map::MidiTarget::Parameter {
+                            track_index,
+                            plugin_index,
+                            parameter,
+                        }
+                    }
+                };
+                let (min, max) = self.default_midi_range(&m.target);
+                Some(olivia_core::midi_map::MidiMapping {
+                    source: m.source.into(),
+                    target,
+                    min: m.min.unwrap_or(min),
+                    max: m.max.unwrap_or(max),
+                })
+            })
+            .collect();
+        self.commands.send(Command::SetMidiMap(midi_map)).unwrap();
+    }
+
+    /// The range of values used if a mapping does not set one. Plugin parameters use their
+    /// declared range, or 0 to 1 if they do not declare one.
+    fn default_midi_range(&self, target: &MidiTarget) -> (f32, f32) {
+        match *target {
+            MidiTarget::TrackVolume { .. } => (0.0, 1.0),
+            MidiTarget::TrackPan { .. } => (-1.0, 1.0),
+            MidiTarget::Parameter {
+                plugin_instance_id,
+                parameter,
+            } => self
+                .plugin_parameter_info(plugin_instance_id, parameter)
+                .map(|p| (p.min, p.max))
+                .unwrap_or((0.0, 1.0)),
+        }
+    }
+
+    /// Map the next MIDI controller that is moved to `learn.target`. Replaces any earlier request
+    /// that has not completed.
+    pub fn start_midi_learn(&mut self, learn: MidiLearn) -> Result<(), ControllerError> {
+        let mapping = MidiMapping {
+            id: learn.mapping_id,
+            source: MidiSource::PitchBend { channel: 0 },
+            target: learn.target,
+            min: learn.min,
+            max: learn.max,
+        };
+        if !self.is_valid_midi_mapping(&mapping) {
+            return Err(ControllerError::InvalidMidiMapping(mapping));
+        }
+        while self.learned_midi.try_recv().is_ok() {}
+        self.midi_learn = Some(learn);
+        self.last_learned_mapping = None;
+        self.commands.send(Command::SetMidiLearn(true)).unwrap();
+        Ok(())
+    }
+
+    pub fn cancel_midi_learn(&mut self) {
+        if self.midi_learn.take().is_some() {
+            self.commands.send(Command::SetMidiLearn(false)).unwrap();
+        }
+    }
+
+    /// Create the mapping for the pending MIDI learn request once the processor has captured a
+    /// MIDI controller. Learning stops even if the mapping can not be created, for example because
+    /// its target was deleted in the meantime.
+    fn complete_midi_learn(&mut self) {
+        let learn = match self.midi_learn {
+            Some(learn) => learn,
+            None => return,
+        };
+        let source = match self.learned_midi.try_recv() {
+            Ok(source) => source,
+            Err(_) => return,
+        };
+        self.midi_learn = None;
+        let mapping = MidiMapping {
+            id: learn.mapping_id,
+            source: source.into(),
+            target: learn.target,
+            min: learn.min,
+            max: learn.max,
+        };
+        match self.set_midi_mapping(mapping) {
+            Ok(()) => self.last_learned_mapping = Some(learn.mapping_id),
+            Err(e) => warn!("Could not create learned MIDI mapping: {}", e),
+        }
+    }
+
+    pub fn midi_learn_status(&self) -> MidiLearnStatus {
+        MidiLearnStatus {
+            learning: self.midi_learn,
+            learned: self
+                .last_learned_mapping
+                .and_then(|id| self.midi_mapping_by_id(id))
+                .copied(),
+        }
+    }
+
+    pub fn set_track_input(&mut self, id: IntId, input: TrackInput) -> Result<(), ControllerError> {
+        let old = match self.track_by_id(id) {
+            Some(t) => t.input.clone(),
+            None => return Err(ControllerError::TrackDoesNotExist(id)),
+        };
+        self.apply_track_input(id, input.clone())?;
+        self.history.push(
+            "Change track input",
+            Edit::SetTrackInput {
+                id,
+                old,
+                new: input,
+            },
+        );
+        Ok(())
+    }
+
+    fn apply_track_input(&mut self, id: IntId, input: TrackInput) -> Result<(), ControllerError> {
+        let track_index = match self.tracks.iter().position(|t| t.id == id) {
+            Some(idx) => idx,
+            None => return Err(ControllerError::TrackDoesNotExist(id)),
+        };
+        self.validate_track_input(id, &input)?;
+        let was_armed = self.tracks[track_index].input.armed;
+        self.commands
+            .send(Command::SetTrackInput {
+                track_index,
+                input: input_channels(&input),
+                monitoring: input.monitoring,
+            })
+            .unwrap();
+        if input.armed != was_armed {
+            let sink = if input.armed {
+                self.new_record_sink(id)
+            } else {
+                if let Some(w) = self.disk_writer.as_ref() {
+                    w.disarm(id);
+                }
+                None
+            };
+            self.commands
+                .send(Command::SetTrackRecordSink(track_index, sink))
+                .unwrap();
+        }
+        self.tracks[track_index].input = input;
+        Ok(())
+    }
+
+    fn validate_track_input(&self, id: IntId, input: &TrackInput) -> Result<(), ControllerError> {
+        let invalid_ports = input.ports.len() > 2
+            || (input.armed && input.ports.is_empty())
+            || input.ports.iter().any(|p| *p >= self.num_audio_inputs);
+        if invalid_ports {
+            return Err(ControllerError::InvalidTrackInput(id, input.clone()));
+        }
+        if input.armed && self.disk_writer.is_none() {
+            return Err(ControllerError::RecordingNotAvailable);
+        }
+        Ok(())
+    }
+
+    fn new_record_sink(&self, id: IntId) -> Option<Box<dyn olivia_core::record::RecordSink>> {
+        let sink = self.disk_writer.as_ref()?.arm(id);
+        Some(Box::new(sink))
+    }
+
+    /// The latency added by plugins to the output. Tracks are delayed to match the track with the
+    /// most latency.
+    pub fn latency(&self) -> Latency {
+        let frames = self.latency.load(std::sync::atomic::Ordering::Relaxed);
+        Latency {
+            frames,
+            milliseconds: frames as f32 * 1000.0 / self.sample_rate,
+        }
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    pub fn set_transport(&mut self, transport: Transport) {
+        info!("Setting transport to {:?}.", transport);
+        self.transport = transport;
+        self.commands
+            .send(Command::SetTransport(transport))
+            .unwrap();
+    }
+
+    pub fn tempo_map(&self) -> impl Iterator<Item = &'_ TempoChange> {
+        self.tempo_map.iter()
+    }
+
+    /// Replace the tempo map. If no change starts on the first bar, the default tempo of 120 bpm
+    /// in 4/4 is used until the first change. Tempos must be finite and positive, and no two
+    /// changes may start on the same bar.
+    pub fn set_tempo_map(&mut self, mut changes: Vec<TempoChange>) -> Result<(), ControllerError> {
+        if let Some(c) = changes
+            .iter()
+            .find(|c| !c.bpm.is_finite() || c.bpm <= 0.0 || c.beats_per_bar == 0)
+        {
+            return Err(ControllerError::InvalidTempoChange(*c));
+        }
+        changes.sort_by_key(|c| c.bar);
+        if let Some(w) = changes.windows(2).find(|w| w[0].bar == w[1].bar) {
+            return Err(ControllerError::InvalidTempoChange(w[1]));
+        }
+        let old = self.tempo_map.clone();
+        self.apply_tempo_map(changes.clone());
+        self.history
+            .push("Change tempo map", Edit::SetTempoMap { old, new: changes });
+        Ok(())
+    }
+
+    fn apply_tempo_map(&mut self, changes: Vec<TempoChange>) {
+        self.tempo_map = changes;
+        self.commands
+            .send(Command::SetTempoMap(self.core_tempo_map()))
+            .unwrap();
+    }
+
+    fn core_tempo_map(&self) -> olivia_core::tempo_map::TempoMap {
+        let changes: Vec<olivia_core::tempo_map::TempoChange> =
+            self.tempo_map.iter().map(|c| (*c).into()).collect();
+        olivia_core::tempo_map::TempoMap::with_changes(f64::from(self.sample_rate), &changes)
+    }
+
+    pub fn metronome(&self) -> Metronome {
+        self.metronome
+    }
+
+    pub fn set_metronome(&mut self, metronome: Metronome) -> Result<(), ControllerError> {
+        if self.buffer_size == 0 {
+            return Err(ControllerError::BufferSizeHasNotBeenSet);
+        }
+        let old = self.metronome;
+        self.apply_metronome(metronome);
+        self.history.push(
+            "Change metronome",
+            Edit::SetMetronome {
+                old,
+                new: metronome,
+            },
+        );
+        Ok(())
+    }
+
+    fn apply_metronome(&mut self, metronome: Metronome) {
+        info!("Setting metronome to {:?}.", metronome);
+        self.metronome = metronome;
+        self.commands
+            .send(Command::SetMetronome(self.core_metronome()))
+            .unwrap();
+    }
+
+    fn core_metronome(&self) -> olivia_core::metronome::Metronome {
+        olivia_core::metronome::Metronome::new(
+            self.sample_rate,
+            self.buffer_size,
+            self.metronome.into(),
+        )
+    }
+
+    pub fn midi_sync(&self) -> MidiSync {
+        self.midi_sync
+    }
+
+    /// Configure sending and following MIDI clock and time code. While following, the transport
+    /// and tempo are driven by the MIDI input.
+    pub fn set_midi_sync(&mut self, midi_sync: MidiSync) {
+        info!("Setting MIDI sync to {:?}.", midi_sync);
+        self.midi_sync = midi_sync;
+        let generator = olivia_core::midi_sync::MidiSyncGenerator::new(
+            midi_sync.clock_output,
+            midi_sync.mtc_output.map(Into::into),
+        );
+        let follower = midi_sync
+            .follow
+            .map(|s| olivia_core::midi_sync::MidiSyncFollower::new(s.into()));
+        self.commands
+            .send(Command::SetMidiSync {
+                generator,
+                follower,
+            })
+            .unwrap();
+    }
+
+    /// Returns all recorded clips, including any that have finished recording since the last
+    /// call.
+    pub fn clips(&mut self) -> impl Iterator<Item = &'_ Clip> {
+        if let Some(w) = self.disk_writer.as_ref() {
+            for recorded in w.finished_clips() {
+                let id = IntId(self.clips.iter().map(|c| c.id.0 + 1).max().unwrap_or(0));
+                self.clips.push(Clip {
+                    id,
+                    track_id: recorded.track_id,
+                    path: recorded.path.to_string_lossy().to_string(),
+                    start_frame: recorded.start_frame,
+                    frames: recorded.frames,
+                });
+            }
+        }
+        self.clips.iter()
+    }
+
+    pub fn delete_track(&mut self, id: IntId) -> Result<(), ControllerError> {
+        let stash = PluginStash::default();
+        let (index, track, plugin_instances, automation) =
+            self.remove_track(id, Some(stash.clone()))?;
+        self.history.push(
+            &format!("Delete track \"{}\"", track.name),
+            Edit::DeleteTrack {
+                index,
+                track,
+                plugin_instances,
+                automation,
+                stash,
+            },
+        );
+        Ok(())
+    }
+
+    /// Remove a track and its automation lanes and return its index, metadata, plugin instances and
+    /// automation lanes. If `stash` is set, the plugin instances are deleted along with the track
+    /// and the processor returns them to the stash. Otherwise they become unowned plugin
+    /// instances.
+    fn remove_track(
+        &mut self,
+        id: IntId,
+        stash: Option<PluginStash>,
+    ) -> Result<RemovedTrack, ControllerError> {
+        let track_index = match self.tracks.iter().enumerate().find(|(_, t)| t.id == id) {
+            Some((idx, _)) => idx,
+            None => return Err(ControllerError::TrackDoesNotExist(id)),
+        };
+        let mut plugin_instances = Vec::new();
+        for pid in self.tracks[track_index].plugin_instances.iter() {
+            if let Some(p) = self.plugin_instance_by_id(*pid) {
+                plugin_instances.push(p.clone());
+            }
+        }
+        let deleted_plugins = match stash {
+            Some(stash) => {
+                for pid in self.tracks[track_index].plugin_instances.iter() {
+                    self.plugin_instances.retain(|p| p.id != *pid);
+                    self.failure_flags.remove(pid);
+                    self.plugin_parameters.remove(pid);
+                    self.parameter_values.retain(|(p, _), _| p != pid);
+                }
+                DeletedPlugins::Keep(stash)
+            }
+            None => DeletedPlugins::Unowned(self.tracks[track_index].plugin_instances.clone()),
+        };
+        if self.tracks[track_index].input.armed {
+            if let Some(w) = self.disk_writer.as_ref() {
+                w.disarm(id);
+            }
+        }
+        let (automation, kept) = std::mem::take(&mut self.automation)
+            .into_iter()
+            .partition(|l| l.track_id == id);
+        self.automation = kept;
+        let track = self.tracks.remove(track_index);
+        self.commands
+            .send(Command::DeleteTrack(track_index, deleted_plugins))
+            .unwrap();
+        self.sync_midi_map();
+        Ok((track_index, track, plugin_instances, automation))
+    }
+
+    pub fn plugin_factory(&self) -> &PluginFactory {
+        &self.plugin_factory
+    }
+
+    /// Look for plugins that were installed or removed since startup. Existing plugin instances
+    /// keep running even if their plugin was removed.
+    pub fn rescan_plugins(&mut self) -> plugin_factory::RescanReport {
+        info!("Rescanning plugins.");
+        self.plugin_factory.rescan()
+    }
+
+    pub fn plugin_instances(&self) -> impl Iterator<Item = &'_ PluginInstance> {
+        self.plugin_instances.iter()
+    }
+
+    pub fn plugin_instance_by_id(&self, id: IntId) -> Option<&PluginInstance> {
+        self.plugin_instances().find(|p| p.id == id)
+    }
+
+    pub fn create_plugin_instance(
         &mut self,
         metadata: PluginInstance,
     ) -> Result<(), ControllerError> {
@@ -182,34 +1770,836 @@ impl Controller {
                 p.clone(),
             ));
         }
-        let plugin_instance = self
-            .plugin_factory
-            .build(&metadata.plugin_id)
-            .map_err(ControllerError::FailedToBuildPlugin)?;
+        if let Some(m) = metadata.audio_port_mapping.as_ref() {
+            if !self.is_valid_audio_port_mapping(&metadata.plugin_id, m) {
+                return Err(ControllerError::InvalidAudioPortMapping(
+                    metadata.id,
+                    m.clone(),
+                ));
+            }
+        }
+        self.build_plugin_instance(metadata.clone())?;
+        self.history.push(
+            &format!("Create plugin instance of {}", metadata.plugin_id),
+            Edit::CreatePluginInstance(metadata),
+        );
+        Ok(())
+    }
+
+    fn build_plugin_instance(&mut self, metadata: PluginInstance) -> Result<(), ControllerError> {
+        let plugin_instance = self.instantiate_plugin(&metadata)?;
         self.unowned_plugin_instances
             .insert(metadata.id, plugin_instance);
         self.plugin_instances.push(metadata);
         Ok(())
     }
+
+    /// Build the plugin of a plugin instance and apply its audio port mapping and parameters.
+    fn instantiate_plugin(
+        &mut self,
+        metadata: &PluginInstance,
+    ) -> Result<Box<dyn olivia_core::plugin::PluginInstance>, ControllerError> {
+        let mut plugin_instance = self
+            .plugin_factory
+            .build(&metadata.plugin_id)
+            .map_err(ControllerError::FailedToBuildPlugin)?;
+        if let Some(m) = metadata.audio_port_mapping.as_ref() {
+            plugin_instance.set_audio_port_mapping(m.clone().into());
+        }
+        for (index, value) in metadata.parameters.iter() {
+            plugin_instance.set_parameter(*index, *value);
+        }
+        self.record_plugin_info(metadata.id, plugin_instance.as_ref());
+        Ok(plugin_instance)
+    }
+
+    /// Remember the failure flag and parameters of a plugin instance.
+    fn record_plugin_info(&mut self, id: IntId, plugin: &dyn olivia_core::plugin::PluginInstance) {
+        match plugin.failure_flag() {
+            Some(flag) => self.failure_flags.insert(id, flag),
+            None => self.failure_flags.remove(&id),
+        };
+        self.plugin_parameters.insert(id, plugin.parameters());
+    }
+
+    /// Restore the plugin instances of a deleted track as unowned plugin instances. The instances
+    /// kept in `stash` are used if the processor returns them in time, otherwise the plugin
+    /// instances are rebuilt.
+    fn restore_plugin_instances(
+        &mut self,
+        plugin_instances: &[PluginInstance],
+        stash: &PluginStash,
+    ) -> Result<(), ControllerError> {
+        if stash.is_empty() && !self.wait_for_garbage(|_| !stash.is_empty()) {
+            warn!("Plugin instances of deleted track were not returned in time, rebuilding them.");
+        }
+        let mut kept = stash.take().unwrap_or_default().into_iter();
+        for (i, metadata) in plugin_instances.iter().enumerate() {
+            let result = match kept.next() {
+                Some(plugin) => {
+                    self.record_plugin_info(metadata.id, plugin.as_ref());
+                    self.unowned_plugin_instances.insert(metadata.id, plugin);
+                    self.plugin_instances.push(metadata.clone());
+                    Ok(())
+                }
+                None => self.build_plugin_instance(metadata.clone()),
+            };
+            if let Err(e) = result {
+                self.stash_plugin_instances(&plugin_instances[..i], stash);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete unowned plugin instances and keep them in `stash`. Undoes
+    /// `restore_plugin_instances`.
+    fn stash_plugin_instances(&mut self, plugin_instances: &[PluginInstance], stash: &PluginStash) {
+        let plugins = plugin_instances
+            .iter()
+            .filter_map(|p| {
+                let plugin = self.unowned_plugin_instances.remove(&p.id);
+                self.remove_plugin_instance(p.id);
+                plugin
+            })
+            .collect();
+        stash.put(plugins);
+    }
+
+    /// Change which track channels or audio inputs the audio ports of a plugin instance are
+    /// connected to.
+    pub fn set_plugin_audio_port_mapping(
+        &mut self,
+        id: IntId,
+        mapping: AudioPortMapping,
+    ) -> Result<(), ControllerError> {
+        let plugin_id = match self.plugin_instance_by_id(id) {
+            Some(p) => &p.plugin_id,
+            None => return Err(ControllerError::PluginInstanceDoesNotExist(id)),
+        };
+        if !self.is_valid_audio_port_mapping(plugin_id, &mapping) {
+            return Err(ControllerError::InvalidAudioPortMapping(id, mapping));
+        }
+        let metadata = match self.plugin_instances.iter_mut().find(|p| p.id == id) {
+            Some(p) => p,
+            None => return Err(ControllerError::PluginInstanceDoesNotExist(id)),
+        };
+        info!(
+            "Setting audio port mapping of plugin instance {:?} to {:?}.",
+            id, mapping
+        );
+        metadata.audio_port_mapping = Some(mapping.clone());
+        if let Some(p) = self.unowned_plugin_instances.get_mut(&id) {
+            p.set_audio_port_mapping(mapping.into());
+            return Ok(());
+        }
+        if let Some((track_index, plugin_index)) = self.plugin_location(id) {
+            self.commands
+                .send(Command::SetAudioPortMapping {
+                    track_index,
+                    plugin_index,
+                    mapping: mapping.into(),
+                })
+                .unwrap();
+        }
+        Ok(())
+    }
+
+    /// Returns true if `mapping` only maps ports that the plugin has and only connects plugin
+    /// inputs to audio inputs that exist. The port counts are not checked if the plugin is no
+    /// longer available.
+    fn is_valid_audio_port_mapping(&self, plugin_id: &str, mapping: &AudioPortMapping) -> bool {
+        if let Some(plugin) = self.plugin_factory.metadata_by_id(plugin_id) {
+            if mapping.inputs.len() > plugin.ports.audio_inputs
+                || mapping.outputs.len() > plugin.ports.audio_outputs
+            {
+                return false;
+            }
+        }
+        let inputs_are_valid = mapping.inputs.iter().all(|c| match c {
+            Some(AudioChannel::Input(n)) => *n < self.num_audio_inputs,
+            _ => true,
+        });
+        let outputs_are_valid = mapping
+            .outputs
+            .iter()
+            .all(|c| !matches!(c, Some(AudioChannel::Input(_))));
+        inputs_are_valid && outputs_are_valid
+    }
+
+    /// Bypass a plugin instance or change its wet/dry mix.
+    pub fn set_plugin_bypass(
+        &mut self,
+        id: IntId,
+        bypass: PluginBypass,
+    ) -> Result<(), ControllerError> {
+        let old = match self.plugin_instance_by_id(id) {
+            Some(p) => p.bypass,
+            None => return Err(ControllerError::PluginInstanceDoesNotExist(id)),
+        };
+        self.apply_plugin_bypass(id, bypass)?;
+        let description = if bypass.bypassed != old.bypassed {
+            "Bypass plugin"
+        } else {
+            "Change plugin mix"
+        };
+        self.history.push(
+            description,
+            Edit::SetPluginBypass {
+                id,
+                old,
+                new: bypass,
+            },
+        );
+        Ok(())
+    }
+
+    fn apply_plugin_bypass(
+        &mut self,
+        id: IntId,
+        bypass: PluginBypass,
+    ) -> Result<(), ControllerError> {
+        let metadata = match self.plugin_instances.iter_mut().find(|p| p.id == id) {
+            Some(p) => p,
+            None => return Err(ControllerError::PluginInstanceDoesNotExist(id)),
+        };
+        metadata.bypass = bypass;
+        // Unowned plugin instances get their bypass settings when they are added to a track.
+        if let Some((track_index, plugin_index)) = self.plugin_location(id) {
+            self.commands
+                .send(Command::SetPluginBypass {
+                    track_index,
+                    plugin_index,
+                    bypassed: bypass.bypassed,
+                    mix: bypass.mix,
+                })
+                .unwrap();
+        }
+        Ok(())
+    }
+
+    /// The parameters of a plugin instance and their values. Values changed by automation and MIDI
+    /// mappings are read from the plugin instance periodically, see `update`.
+    pub fn plugin_parameters(&self, id: IntId) -> Result<Vec<PluginParameter>, ControllerError> {
+        let metadata = match self.plugin_instance_by_id(id) {
+            Some(p) => p,
+            None => return Err(ControllerError::PluginInstanceDoesNotExist(id)),
+        };
+        let parameters = self
+            .plugin_parameters
+            .get(&id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        Ok(parameters
+            .iter()
+            .map(|p| PluginParameter {
+                index: p.index,
+                id: p.id.clone(),
+                name: p.name.clone(),
+                min: p.min,
+                max: p.max,
+                default: p.default,
+                unit: p.unit.clone(),
+                enumeration: p.enumeration.iter().cloned().map(Into::into).collect(),
+                value: self
+                    .unowned_plugin_instances
+                    .get(&id)
+                    .and_then(|plugin| plugin.get_parameter(p.index))
+                    .or_else(|| self.parameter_values.get(&(id, p.index)).copied())
+                    .or_else(|| metadata.parameters.get(&p.index).copied())
+                    .unwrap_or(p.default),
+            })
+            .collect())
+    }
+
+    fn plugin_parameter_info(
+        &self,
+        id: IntId,
+        index: usize,
+    ) -> Option<&olivia_core::plugin::ParameterInfo> {
+        self.plugin_parameters
+            .get(&id)?
+            .iter()
+            .find(|p| p.index == index)
+    }
+
+    /// Set a parameter of a plugin instance. The value is clamped to the range of the parameter.
+    /// Returns the value that was set.
+    pub fn set_plugin_parameter(
+        &mut self,
+        id: IntId,
+        index: usize,
+        value: f32,
+    ) -> Result<f32, ControllerError> {
+        let metadata = match self.plugin_instance_by_id(id) {
+            Some(p) => p,
+            None => return Err(ControllerError::PluginInstanceDoesNotExist(id)),
+        };
+        let info = match self.plugin_parameter_info(id, index) {
+            Some(p) => p,
+            None => return Err(ControllerError::PluginParameterDoesNotExist(id, index)),
+        };
+        if !value.is_finite() {
+            return Err(ControllerError::InvalidPluginParameterValue(
+                id, index, value,
+            ));
+        }
+        let old = metadata
+            .parameters
+            .get(&index)
+            .copied()
+            .unwrap_or(info.default);
+        let new = info.clamp(value);
+        self.apply_plugin_parameter(id, index, new)?;
+        self.history.push(
+            "Change plugin parameter",
+            Edit::SetPluginParameter {
+                id,
+                index,
+                old,
+                new,
+            },
+        );
+        Ok(new)
+    }
+
+    fn apply_plugin_parameter(
+        &mut self,
+        id: IntId,
+        index: usize,
+        value: f32,
+    ) -> Result<(), ControllerError> {
+        let metadata = match self.plugin_instances.iter_mut().find(|p| p.id == id) {
+            Some(p) => p,
+            None => return Err(ControllerError::PluginInstanceDoesNotExist(id)),
+        };
+        metadata.parameters.insert(index, value);
+        self.parameter_values.insert((id, index), value);
+        self.parameter_changes += 1;
+        if let Some(p) = self.unowned_plugin_instances.get_mut(&id) {
+            p.set_parameter(index, value);
+            return Ok(());
+        }
+        if let Some((track_index, plugin_index)) = self.plugin_location(id) {
+            self.commands
+                .send(Command::SetPluginParameter {
+                    track_index,
+                    plugin_index,
+                    index,
+                    value,
+                })
+                .unwrap();
+        }
+        Ok(())
+    }
+
+    /// The index of the track that a plugin instance belongs to and its index within the track.
+    fn plugin_location(&self, id: IntId) -> Option<(usize, usize)> {
+        self.tracks.iter().enumerate().find_map(|(track_index, t)| {
+            t.plugin_instances
+                .iter()
+                .position(|p| *p == id)
+                .map(|plugin_index| (track_index, plugin_index))
+        })
+    }
+
+    /// Remove a plugin instance that does not belong to a track.
+    fn remove_plugin_instance(&mut self, id: IntId) {
+        self.unowned_plugin_instances.remove(&id);
+        self.plugin_instances.retain(|p| p.id != id);
+        self.failure_flags.remove(&id);
+        self.plugin_parameters.remove(&id);
+        self.parameter_values.retain(|(p, _), _| *p != id);
+    }
+
+    /// Get whether a plugin instance is still running.
+    pub fn plugin_instance_status(
+        &self,
+        id: IntId,
+    ) -> Result<PluginInstanceStatus, ControllerError> {
+        if self.plugin_instance_by_id(id).is_none() {
+            return Err(ControllerError::PluginInstanceDoesNotExist(id));
+        }
+        Ok(PluginInstanceStatus {
+            failed: self.failure_flags.get(&id).is_some_and(|f| f.is_set()),
+        })
+    }
+
+    pub fn history(&self) -> History {
+        History {
+            undo: self.history.undo_descriptions().map(String::from).collect(),
+            redo: self.history.redo_descriptions().map(String::from).collect(),
+        }
+    }
+
+    /// Group all following edits into a single undo step until `end_undo_group` is called.
+    pub fn begin_undo_group(&mut self, description: &str) {
+        self.history.begin_group(description);
+    }
+
+    pub fn end_undo_group(&mut self) -> Result<(), ControllerError> {
+        if self.history.end_group() {
+            Ok(())
+        } else {
+            Err(ControllerError::NoUndoGroupInProgress)
+        }
+    }
+
+    /// Undo the most recent undo step. Returns false if there is nothing to undo.
+    pub fn undo(&mut self) -> Result<bool, ControllerError> {
+        let step = match self.history.take_undo() {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+        info!("Undoing \"{}\".", step.description);
+        for (i, edit) in step.edits.iter().enumerate().rev() {
+            if let Err(e) = self.revert_edit(edit) {
+                error!("Failed to undo \"{}\": {:?}", step.description, e);
+                // Redo the edits that were undone so the step is undone completely or not at all.
+                for edit in step.edits[i + 1..].iter() {
+                    if let Err(e) = self.apply_edit(edit) {
+                        error!("Failed to roll back {:?}: {:?}", edit, e);
+                    }
+                }
+                self.history.push_undo(step);
+                return Err(e);
+            }
+        }
+        self.history.push_redo(step);
+        Ok(true)
+    }
+
+    /// Redo the most recently undone step. Returns false if there is nothing to redo.
+    pub fn redo(&mut self) -> Result<bool, ControllerError> {
+        let step = match self.history.take_redo() {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+        info!("Redoing \"{}\".", step.description);
+        for (i, edit) in step.edits.iter().enumerate() {
+            if let Err(e) = self.apply_edit(edit) {
+                error!("Failed to redo \"{}\": {:?}", step.description, e);
+                // Undo the edits that were redone so the step is redone completely or not at all.
+                for edit in step.edits[..i].iter().rev() {
+                    if let Err(e) = self.revert_edit(edit) {
+                        error!("Failed to roll back {:?}: {:?}", edit, e);
+                    }
+                }
+                self.history.push_redo(step);
+                return Err(e);
+            }
+        }
+        self.history.push_undo(step);
+        Ok(true)
+    }
+
+    fn apply_edit(&mut self, edit: &Edit) -> Result<(), ControllerError> {
+        match edit {
+            Edit::AddTrack(track) => self.insert_track(self.tracks.len(), track.clone()),
+            Edit::DeleteTrack { track, stash, .. } => {
+                self.remove_track(track.id, Some(stash.clone())).map(|_| ())
+            }
+            Edit::CreatePluginInstance(p) => self.build_plugin_instance(p.clone()),
+            Edit::SetTrackVolume { id, new, .. } => self.apply_track_volume(*id, *new),
+            Edit::SetTrackPan { id, new, .. } => self.apply_track_pan(*id, *new),
+            Edit::SetAutomationLane { id, new, .. } => {
+                self.apply_automation_lane(*id, new.clone());
+                Ok(())
+            }
+            Edit::SetMidiMapping { id, new, .. } => {
+                self.apply_midi_mapping(*id, *new);
+                Ok(())
+            }
+            Edit::SetTrackInput { id, new, .. } => self.apply_track_input(*id, new.clone()),
+            Edit::SetTempoMap { new, .. } => {
+                self.apply_tempo_map(new.clone());
+                Ok(())
+            }
+            Edit::SetMetronome { new, .. } => {
+                self.apply_metronome(*new);
+                Ok(())
+            }
+            Edit::SetPluginBypass { id, new, .. } => self.apply_plugin_bypass(*id, *new),
+            Edit::SetPluginParameter { id, index, new, .. } => {
+                self.apply_plugin_parameter(*id, *index, *new)
+            }
+        }
+    }
+
+    fn revert_edit(&mut self, edit: &Edit) -> Result<(), ControllerError> {
+        match edit {
+            Edit::AddTrack(track) => self.remove_track(track.id, None).map(|_| ()),
+            Edit::DeleteTrack {
+                index,
+                track,
+                plugin_instances,
+                automation,
+                stash,
+            } => {
+                self.restore_plugin_instances(plugin_instances, stash)?;
+                self.automation.extend(automation.iter().cloned());
+                if let Err(e) = self.insert_track(*index, track.clone()) {
+                    self.automation.retain(|l| l.track_id != track.id);
+                    self.stash_plugin_instances(plugin_instances, stash);
+                    return Err(e);
+                }
+                Ok(())
+            }
+            Edit::CreatePluginInstance(p) => {
+                self.remove_plugin_instance(p.id);
+                Ok(())
+            }
+            Edit::SetTrackVolume { id, old, .. } => self.apply_track_volume(*id, *old),
+            Edit::SetTrackPan { id, old, .. } => self.apply_track_pan(*id, *old),
+            Edit::SetAutomationLane { id, old, .. } => {
+                self.apply_automation_lane(*id, old.clone());
+                Ok(())
+            }
+            Edit::SetMidiMapping { id, old, .. } => {
+                self.apply_midi_mapping(*id, *old);
+                Ok(())
+            }
+            Edit::SetTrackInput { id, old, .. } => self.apply_track_input(*id, old.clone()),
+            Edit::SetTempoMap { old, .. } => {
+                self.apply_tempo_map(old.clone());
+                Ok(())
+            }
+            Edit::SetMetronome { old, .. } => {
+                self.apply_metronome(*old);
+                Ok(())
+            }
+            Edit::SetPluginBypass { id, old, .. } => self.apply_plugin_bypass(*id, *old),
+            Edit::SetPluginParameter { id, index, old, .. } => {
+                self.apply_plugin_parameter(*id, *index, *old)
+            }
+        }
+    }
 }
 
 pub struct Processor {
     inner: olivia_core::processor::Processor,
     commands: crossbeam::channel::Receiver<Command>,
+    // If the transport is driven by the IO backend rather than the controller.
+    external_transport: bool,
+    // A change to the playing state that was requested by the controller but has to be forwarded
+    // to the external transport.
+    transport_request: Option<bool>,
+    // Shared with the controller.
+    latency: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    // Sends MIDI controllers captured while learning to the controller.
+    learned_midi: crossbeam::channel::Sender<olivia_core::midi_map::MidiSource>,
+    // Asks the controller for larger dry delays for plugins whose latency has grown.
+    dry_delay_requests: crossbeam::channel::Sender<olivia_core::processor::DryDelayRequest>,
+    // Sends removed and replaced values to the controller to be freed.
+    garbage: crossbeam::channel::Sender<Garbage>,
+    // Sends parameter snapshots back to the controller once they are filled in.
+    parameter_snapshots: crossbeam::channel::Sender<ParameterSnapshot>,
 }
 
 impl Processor {
-    pub fn process(&mut self, midi: &[TimedMidi], out_left: &mut [f32], out_right: &mut [f32]) {
+    pub fn process(
+        &mut self,
+        inputs: &[&[f32]],
+        midi: &[TimedMidi],
+        out_left: &mut [f32],
+        out_right: &mut [f32],
+    ) {
         self.handle_commands();
-        self.inner.process(midi, out_left, out_right);
+        self.inner.process(inputs, midi, out_left, out_right);
+        self.latency
+            .store(self.inner.latency(), std::sync::atomic::Ordering::Relaxed);
+        if let Some(source) = self.inner.take_learned_midi() {
+            let _ = self.learned_midi.try_send(source);
+        }
+        if let Some(request) = self.inner.take_dry_delay_request() {
+            let _ = self.dry_delay_requests.try_send(request);
+        }
+    }
+
+    /// Let the IO backend drive the transport. Transport changes from the controller are then
+    /// made available through `take_transport_request` instead of being applied directly.
+    pub fn set_external_transport(&mut self, external_transport: bool) {
+        self.external_transport = external_transport;
+    }
+
+    /// Take the playing state requested by the controller, if it has changed.
+    pub fn take_transport_request(&mut self) -> Option<bool> {
+        self.transport_request.take()
+    }
+
+    /// Set the transport to the state of the external transport. Count-in is not used when
+    /// following an external transport.
+    pub fn sync_transport(&mut self, playing: bool, frame: u64) {
+        let transport = self.inner.transport_mut();
+        transport.set_playing(playing);
+        transport.locate(frame);
+    }
+
+    /// Follow the tempo of an external tempo source. `beat` is the number of beats since the
+    /// start of the song at `frame`.
+    pub fn sync_tempo(&mut self, bpm: f64, beats_per_bar: u32, frame: u64, beat: f64) {
+        self.inner
+            .tempo_map_mut()
+            .follow(bpm, beats_per_bar, frame as f64, beat);
+    }
+
+    pub fn tempo_map(&self) -> &olivia_core::tempo_map::TempoMap {
+        self.inner.tempo_map()
+    }
+
+    /// The metronome output if the metronome is routed to a dedicated output.
+    pub fn metronome_output(&self) -> Option<&[f32]> {
+        let metronome = self.inner.metronome();
+        match metronome.settings().output {
+            olivia_core::metronome::MetronomeOutput::Master => None,
+            olivia_core::metronome::MetronomeOutput::Dedicated => Some(metronome.output()),
+        }
+    }
+
+    /// The MIDI produced by plugins during the last processed block.
+    pub fn midi_output(&self) -> &[TimedMidi<'static>] {
+        self.inner.midi_output()
+    }
+
+    /// The MIDI clock and time code messages generated during the last processed block.
+    pub fn midi_sync_output(&self) -> &[TimedMidi<'static>] {
+        self.inner.midi_sync_output()
+    }
+
+    /// Send `garbage` to the controller to be freed. It is only freed here if the controller has
+    /// fallen too far behind.
+    fn dispose(&self, garbage: Garbage) {
+        if self.garbage.try_send(garbage).is_err() {
+            warn!("Garbage queue is full, freeing on the audio thread.");
+        }
     }
 
     fn handle_commands(&mut self) {
         for command in self.commands.try_iter() {
             match command {
-                Command::AddTrack(t) => self.inner.add_track(t),
-                Command::DeleteTrack(track_index) => self.inner.delete_track(track_index),
+                Command::AddTrack(track_index, t) => self.inner.insert_track(track_index, t),
+                Command::DeleteTrack(track_index, deleted_plugins) => {
+                    let track = self.inner.delete_track(track_index);
+                    self.dispose(Garbage::Track(track, deleted_plugins));
+                }
+                Command::SetTrackVolume(track_index, volume) => {
+                    if let Some(t) = self.inner.tracks_mut().nth(track_index) {
+                        t.set_volume(volume);
+                    }
+                }
+                Command::SetTrackPan(track_index, pan) => {
+                    if let Some(t) = self.inner.tracks_mut().nth(track_index) {
+                        t.set_pan(pan);
+                    }
+                }
+                Command::SetTrackAutomation(track_index, automation) => {
+                    let old = match self.inner.tracks_mut().nth(track_index) {
+                        Some(t) => t.set_automation(automation),
+                        None => automation,
+                    };
+                    self.dispose(Garbage::Automation(old));
+                }
+                Command::SetMidiMap(midi_map) => {
+                    let old = self.inner.set_midi_map(midi_map);
+                    self.dispose(Garbage::MidiMap(old));
+                }
+                Command::SetMidiLearn(learn) => self.inner.set_midi_learn(learn),
+                Command::ReadParameters(mut snapshot) => {
+                    for r in snapshot.readings.iter_mut() {
+                        r.value = self
+                            .inner
+                            .tracks_mut()
+                            .nth(r.track_index)
+                            .and_then(|t| t.plugin_mut(r.plugin_index))
+                            .and_then(|p| p.get_parameter(r.index));
+                    }
+                    let _ = self.parameter_snapshots.try_send(snapshot);
+                }
+                Command::SetTrackInput {
+                    track_index,
+                    input,
+                    monitoring,
+                } => {
+                    if let Some(t) = self.inner.tracks_mut().nth(track_index) {
+                        t.set_input(input);
+                        t.set_monitoring(monitoring);
+                    }
+                }
+                Command::SetTrackRecordSink(track_index, sink) => {
+                    if let Some(t) = self.inner.tracks_mut().nth(track_index) {
+                        t.set_record_sink(sink);
+                    }
+                }
+                Command::SetTransport(transport) => {
+                    self.inner
+                        .transport_mut()
+                        .set_record_enabled(transport.record_enabled);
+                    if self.external_transport {
+                        self.transport_request = Some(transport.playing);
+                    } else {
+                        self.inner.set_playing(transport.playing);
+                    }
+                }
+                Command::SetTempoMap(tempo_map) => self.inner.set_tempo_map(tempo_map),
+                Command::SetMetronome(metronome) => self.inner.set_metronome(metronome),
+                Command::SetMidiSync {
+                    generator,
+                    follower,
+                } => {
+                    self.inner.set_midi_sync_generator(generator);
+                    self.inner.set_midi_sync_follower(follower);
+                }
+                Command::SetAudioPortMapping {
+                    track_index,
+                    plugin_index,
+                    mapping,
+                } => {
+                    if let Some(p) = self
+                        .inner
+                        .tracks_mut()
+                        .nth(track_index)
+                        .and_then(|t| t.plugin_mut(plugin_index))
+                    {
+                        p.set_audio_port_mapping(mapping);
+                    }
+                }
+                Command::SetPluginBypass {
+                    track_index,
+                    plugin_index,
+                    bypassed,
+                    mix,
+                } => {
+                    if let Some(t) = self.inner.tracks_mut().nth(track_index) {
+                        t.set_plugin_bypass(plugin_index, bypassed, mix);
+                    }
+                }
+                Command::SetPluginParameter {
+                    track_index,
+                    plugin_index,
+                    index,
+                    value,
+                } => {
+                    if let Some(p) = self
+                        .inner
+                        .tracks_mut()
+                        .nth(track_index)
+                        .and_then(|t| t.plugin_mut(plugin_index))
+                    {
+                        p.set_parameter(index, value);
+                    }
+                }
+                Command::SetPluginDryDelay {
+                    track_index,
+                    plugin_index,
+                    dry_delay,
+                } => {
+                    let old = match self.inner.tracks_mut().nth(track_index) {
+                        Some(t) => t.set_plugin_dry_delay(plugin_index, dry_delay),
+                        None => dry_delay,
+                    };
+                    self.dispose(Garbage::DryDelay(old));
+                }
             }
         }
     }
 }
+
+/// Convert the input ports of a track into the left and right input channels.
+fn input_channels(input: &TrackInput) -> Option<(usize, usize)> {
+    match input.ports.as_slice() {
+        [mono] => Some((*mono, *mono)),
+        [left, right] => Some((*left, *right)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Outputs a constant signal on both channels.
+    #[derive(Debug)]
+    struct Constant;
+
+    impl olivia_core::plugin::PluginInstance for Constant {
+        fn process(&mut self, _: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+            for o in out_left.iter_mut().chain(out_right.iter_mut()) {
+                *o = 1.0;
+            }
+        }
+    }
+
+    struct ConstantBuilder;
+
+    impl plugin_factory::PluginBuilder for ConstantBuilder {
+        fn metadata(&self) -> plugin_factory::PluginMetadata {
+            plugin_factory::PluginMetadata {
+                id: "constant".to_string(),
+                display_name: "Constant".to_string(),
+                ports: plugin_factory::PortSummary {
+                    audio_outputs: 2,
+                    ..plugin_factory::PortSummary::default()
+                },
+                ..plugin_factory::PluginMetadata::default()
+            }
+        }
+
+        fn build(
+            &self,
+        ) -> Result<Box<dyn olivia_core::plugin::PluginInstance>, plugin_factory::PluginBuilderError>
+        {
+            Ok(Box::new(Constant))
+        }
+    }
+
+    fn process(processor: &mut Processor) -> Vec<f32> {
+        let mut left = vec![0.0; 64];
+        let mut right = vec![0.0; 64];
+        // Process a few blocks so that volume changes have settled.
+        for _ in 0..4 {
+            processor.process(&[], &[], &mut left, &mut right);
+        }
+        left
+    }
+
+    #[test]
+    fn undoing_track_deletion_keeps_volume() {
+        let mut factory = PluginFactory::new();
+        factory.register(ConstantBuilder).unwrap();
+        let (mut controller, mut processor) = Controller::new(factory);
+        controller.set_buffer_size(64);
+        controller
+            .create_plugin_instance(PluginInstance {
+                id: IntId(1),
+                plugin_id: "constant".to_string(),
+                audio_port_mapping: None,
+                bypass: PluginBypass::default(),
+                parameters: BTreeMap::new(),
+            })
+            .unwrap();
+        controller
+            .add_track(Track {
+                id: IntId(2),
+                name: "Track".to_string(),
+                volume: 0.5,
+                pan: 0.0,
+                plugin_instances: vec![IntId(1)],
+                input: TrackInput::default(),
+            })
+            .unwrap();
+        let before = process(&mut processor);
+        assert!(before.iter().any(|s| *s != 0.0));
+
+        controller.delete_track(IntId(2)).unwrap();
+        assert!(process(&mut processor).iter().all(|s| *s == 0.0));
+        assert_eq!(controller.undo(), Ok(true));
+        assert_eq!(process(&mut processor), before);
+        assert_eq!(controller.undo(), Ok(true));
+        assert_eq!(controller.redo(), Ok(true));
+        assert_eq!(process(&mut processor), before);
+
+        controller.set_track_volume(IntId(2), 1.0).unwrap();
+        let full: Vec<f32> = process(&mut processor).iter().map(|s| s * 0.5).collect();
+        assert_eq!(before, full);
+    }
+}
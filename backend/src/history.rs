@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+
+/// A single undo step. A step contains one or more edits that are undone and redone together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step<E> {
+    pub description: String,
+    pub edits: Vec<E>,
+}
+
+/// Records edits so that they can be undone and redone. At most `max_steps` undo steps are kept,
+/// the oldest steps are forgotten first.
+#[derive(Debug)]
+pub struct History<E> {
+    max_steps: usize,
+    undo: VecDeque<Step<E>>,
+    redo: Vec<Step<E>>,
+    // The group that edits are currently added to and the number of times it has been opened.
+    group: Option<(Step<E>, usize)>,
+}
+
+impl<E> History<E> {
+    pub fn new(max_steps: usize) -> History<E> {
+        History {
+            max_steps,
+            undo: VecDeque::with_capacity(max_steps),
+            redo: Vec::new(),
+            group: None,
+        }
+    }
+
+    /// Record an edit that has been applied. The redo history is cleared. If a group is open, the
+    /// edit is added to the group, otherwise it becomes its own undo step.
+    pub fn push(&mut self, description: &str, edit: E) {
+        self.redo.clear();
+        match self.group.as_mut() {
+            Some((step, _)) => step.edits.push(edit),
+            None => self.push_step(Step {
+                description: description.to_string(),
+                edits: vec![edit],
+            }),
+        }
+    }
+
+    /// Start grouping edits into a single undo step. Groups may be nested, in which case the edits
+    /// are added to the outermost group.
+    pub fn begin_group(&mut self, description: &str) {
+        match self.group.as_mut() {
+            Some((_, depth)) => *depth += 1,
+            None => {
+                self.group = Some((
+                    Step {
+                        description: description.to_string(),
+                        edits: Vec::new(),
+                    },
+                    1,
+                ))
+            }
+        }
+    }
+
+    /// Finish the group started by the matching `begin_group`. Returns false if no group is open.
+    pub fn end_group(&mut self) -> bool {
+        match self.group.take() {
+            Some((step, 1)) => {
+                if !step.edits.is_empty() {
+                    self.push_step(step);
+                }
+                true
+            }
+            Some((step, depth)) => {
+                self.group = Some((step, depth - 1));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take the most recent undo step. The caller should revert its edits, last edit first, and
+    /// then pass it to `push_redo`. Any open group is closed first.
+    pub fn take_undo(&mut self) -> Option<Step<E>> {
+        self.close_group();
+        self.undo.pop_back()
+    }
+
+    /// Take the most recently undone step. The caller should apply its edits in order and then
+    /// pass it to `push_undo`.
+    pub fn take_redo(&mut self) -> Option<Step<E>> {
+        self.close_group();
+        self.redo.pop()
+    }
+
+    pub fn push_redo(&mut self, step: Step<E>) {
+        self.redo.push(step);
+    }
+
+    pub fn push_undo(&mut self, step: Step<E>) {
+        self.push_step(step);
+    }
+
+    /// The descriptions of the steps that can be undone, most recent last.
+    pub fn undo_descriptions(&self) -> impl Iterator<Item = &'_ str> {
+        self.undo.iter().map(|s| s.description.as_str())
+    }
+
+    /// The descriptions of the steps that can be redone, most recent last.
+    pub fn redo_descriptions(&self) -> impl Iterator<Item = &'_ str> {
+        self.redo.iter().map(|s| s.description.as_str())
+    }
+
+    fn push_step(&mut self, step: Step<E>) {
+        if self.max_steps == 0 {
+            return;
+        }
+        while self.undo.len() >= self.max_steps {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(step);
+    }
+
+    fn close_group(&mut self) {
+        if let Some((step, _)) = self.group.take() {
+            if !step.edits.is_empty() {
+                self.push_step(step);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undo_and_redo_steps() {
+        let mut h = History::new(10);
+        h.push("a", 1);
+        h.push("b", 2);
+        let step = h.take_undo().unwrap();
+        assert_eq!(step.edits, vec![2]);
+        h.push_redo(step);
+        assert_eq!(h.redo_descriptions().collect::<Vec<_>>(), vec!["b"]);
+
+        let step = h.take_redo().unwrap();
+        h.push_undo(step);
+        assert_eq!(h.undo_descriptions().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert!(h.take_redo().is_none());
+    }
+
+    #[test]
+    fn new_edits_clear_redo() {
+        let mut h = History::new(10);
+        h.push("a", 1);
+        let step = h.take_undo().unwrap();
+        h.push_redo(step);
+        h.push("b", 2);
+        assert!(h.take_redo().is_none());
+    }
+
+    #[test]
+    fn groups_become_a_single_step() {
+        let mut h = History::new(10);
+        h.begin_group("group");
+        h.push("a", 1);
+        h.begin_group("inner");
+        h.push("b", 2);
+        assert!(h.end_group());
+        h.push("c", 3);
+        assert!(h.end_group());
+        assert!(!h.end_group());
+        let step = h.take_undo().unwrap();
+        assert_eq!(step.description, "group");
+        assert_eq!(step.edits, vec![1, 2, 3]);
+        assert!(h.take_undo().is_none());
+    }
+
+    #[test]
+    fn oldest_steps_are_forgotten() {
+        let mut h = History::new(2);
+        h.push("a", 1);
+        h.push("b", 2);
+        h.push("c", 3);
+        assert_eq!(h.undo_descriptions().collect::<Vec<_>>(), vec!["b", "c"]);
+    }
+}
@@ -2,5 +2,6 @@ pub trait IoBackend {
     fn name(&self) -> &'static str;
     fn buffer_size(&self) -> usize;
     fn sample_rate(&self) -> f32;
+    fn num_audio_inputs(&self) -> usize;
     fn run_process_loop(self);
 }
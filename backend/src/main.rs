@@ -2,28 +2,82 @@
 extern crate log;
 
 mod adapter;
+mod config;
 mod controller;
+mod history;
 mod io_backend;
 mod plugin_factory;
+mod plugin_files;
 mod plugin_registry;
+mod recorder;
 
 use io_backend::IoBackend;
 
+/// The number of audio input ports to request from the IO backend.
+const NUM_AUDIO_INPUTS: usize = 2;
+
+/// How often the controller handles what the processor sent back, like captured MIDI controllers
+/// and values to free.
+const UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some(adapter::lv2_sandbox::PLUGIN_HOST_FLAG) {
+        run_plugin_host(&args[2..]);
+        return Ok(());
+    }
+    let config = match config::config_path(&args) {
+        Some(path) => {
+            info!("Loading config from {:?}.", path);
+            config::Config::load(&path).unwrap()
+        }
+        None => config::Config::default(),
+    };
+
     info!("Loading audio plugins.");
-    let plugin_factory = plugin_registry::new_plugin_factory();
+    let plugin_factory = plugin_registry::new_plugin_factory(&config);
+    let report_path = std::env::temp_dir().join("olivia_unsupported_plugins.json");
+    match plugin_registry::save_compatibility_report(&plugin_factory, &report_path) {
+        Ok(()) => info!("Wrote plugin compatibility report to {:?}.", report_path),
+        Err(e) => warn!("Failed to write plugin compatibility report: {:?}", e),
+    }
+    if args.iter().any(|arg| arg == "--check-plugins") {
+        let mut num_unsupported = 0;
+        for plugin in plugin_factory.unsupported() {
+            println!("{}", plugin);
+            num_unsupported += 1;
+        }
+        println!(
+            "{} plugins supported, {} plugins unsupported.",
+            plugin_factory.metadata().count(),
+            num_unsupported
+        );
+        return Ok(());
+    }
 
     info!("Creating Olivia processor.");
     let (mut controller, processor) = controller::Controller::new(plugin_factory);
 
     // Uncomment the backend you want to use.
-    let backend = adapter::jack::JackBackend::new(processor).unwrap();
+    let backend =
+        adapter::jack::JackBackend::new(processor, NUM_AUDIO_INPUTS, config.jack.transport_sync)
+            .unwrap();
     // let backend = adapter::dummy_io::DummyBackend(processor);
     info!("Running Olivia with {} backend.", backend.name());
     controller.set_buffer_size(backend.buffer_size());
+    controller.set_sample_rate(backend.sample_rate());
+    controller.set_num_audio_inputs(backend.num_audio_inputs());
+    let recordings_dir = std::env::temp_dir().join("olivia_recordings");
+    match recorder::DiskWriter::new(&recordings_dir, backend.sample_rate() as u32) {
+        Ok(w) => controller.set_disk_writer(w),
+        Err(e) => error!(
+            "Recording is disabled, could not start disk writer: {:?}",
+            e
+        ),
+    }
     let _process_thread = std::thread::spawn(move || {
         let backend_name = backend.name();
         backend.run_process_loop();
@@ -34,31 +88,47 @@ async fn main() -> std::io::Result<()> {
     controller
         .create_plugin_instance(controller::PluginInstance {
             id: controller::IntId(0),
-            plugin_id: "builtin_sine".to_string(),
+            plugin_id: "builtin_synth".to_string(),
+            audio_port_mapping: None,
+            bypass: controller::PluginBypass::default(),
+            parameters: std::collections::BTreeMap::new(),
         })
         .unwrap();
     let initial_track = controller::Track {
         id: controller::IntId(1),
         name: "Track 01".to_string(),
         volume: 0.5,
+        pan: 0.0,
         plugin_instances: vec![controller::IntId(0)],
+        input: controller::TrackInput::default(),
     };
     controller.add_track(initial_track).unwrap();
 
+    let handler = actix_web::web::Data::new(std::sync::Mutex::new(
+        adapter::actix_server::Handler::new(controller),
+    ));
+    let update_handler = handler.clone();
+    let _update_thread = std::thread::spawn(move || loop {
+        std::thread::sleep(UPDATE_INTERVAL);
+        update_handler.lock().unwrap().update();
+    });
+
     info!("Starting actix webserver.");
-    let controller = std::sync::Arc::new(std::sync::Mutex::new(Some(controller)));
     actix_web::HttpServer::new(move || {
-        let controller_arc = controller.clone();
-        let mut some_controller = controller_arc.lock().unwrap();
-        // Since we only have a single worker thread, we should only ever take the value once ensuring that some
-        // controller is indeed Some(controller) rather than None by this point.
-        let handler = adapter::actix_server::Handler::new(some_controller.take().unwrap());
         actix_web::App::new()
-            .data(std::sync::Mutex::new(handler))
+            .app_data(handler.clone())
             .route(
                 "/plugins",
                 actix_web::web::get().to(adapter::actix_server::get_plugins),
             )
+            .route(
+                "/plugins/rescan",
+                actix_web::web::post().to(adapter::actix_server::post_rescan_plugins),
+            )
+            .route(
+                "/plugins/unsupported",
+                actix_web::web::get().to(adapter::actix_server::get_unsupported_plugins),
+            )
             .route(
                 "/plugin_instances",
                 actix_web::web::get().to(adapter::actix_server::get_plugin_instances),
@@ -71,6 +141,26 @@ async fn main() -> std::io::Result<()> {
                 "/plugin_instances/{plugin_instance_id}",
                 actix_web::web::put().to(adapter::actix_server::put_plugin_instance),
             )
+            .route(
+                "/plugin_instances/{plugin_instance_id}/bypass",
+                actix_web::web::put().to(adapter::actix_server::put_plugin_bypass),
+            )
+            .route(
+                "/plugin_instances/{plugin_instance_id}/parameters",
+                actix_web::web::get().to(adapter::actix_server::get_plugin_parameters),
+            )
+            .route(
+                "/plugin_instances/{plugin_instance_id}/parameters/{index}",
+                actix_web::web::put().to(adapter::actix_server::put_plugin_parameter),
+            )
+            .route(
+                "/plugin_instances/{plugin_instance_id}/status",
+                actix_web::web::get().to(adapter::actix_server::get_plugin_instance_status),
+            )
+            .route(
+                "/plugin_instances/{plugin_instance_id}/audio_ports",
+                actix_web::web::put().to(adapter::actix_server::put_plugin_audio_ports),
+            )
             .route(
                 "/tracks",
                 actix_web::web::get().to(adapter::actix_server::get_tracks),
@@ -87,9 +177,147 @@ async fn main() -> std::io::Result<()> {
                 "/tracks/{track_id}",
                 actix_web::web::delete().to(adapter::actix_server::delete_track),
             )
+            .route(
+                "/tracks/{track_id}/input",
+                actix_web::web::put().to(adapter::actix_server::put_track_input),
+            )
+            .route(
+                "/tracks/{track_id}/pan",
+                actix_web::web::put().to(adapter::actix_server::put_track_pan),
+            )
+            .route(
+                "/tracks/{track_id}/volume",
+                actix_web::web::put().to(adapter::actix_server::put_track_volume),
+            )
+            .route(
+                "/automation",
+                actix_web::web::get().to(adapter::actix_server::get_automation_lanes),
+            )
+            .route(
+                "/automation/{lane_id}",
+                actix_web::web::get().to(adapter::actix_server::get_automation_lane),
+            )
+            .route(
+                "/automation/{lane_id}",
+                actix_web::web::put().to(adapter::actix_server::put_automation_lane),
+            )
+            .route(
+                "/automation/{lane_id}",
+                actix_web::web::delete().to(adapter::actix_server::delete_automation_lane),
+            )
+            .route(
+                "/midi_mappings",
+                actix_web::web::get().to(adapter::actix_server::get_midi_mappings),
+            )
+            .route(
+                "/midi_mappings/{mapping_id}",
+                actix_web::web::put().to(adapter::actix_server::put_midi_mapping),
+            )
+            .route(
+                "/midi_mappings/{mapping_id}",
+                actix_web::web::delete().to(adapter::actix_server::delete_midi_mapping),
+            )
+            .route(
+                "/midi_learn",
+                actix_web::web::get().to(adapter::actix_server::get_midi_learn),
+            )
+            .route(
+                "/midi_learn",
+                actix_web::web::put().to(adapter::actix_server::put_midi_learn),
+            )
+            .route(
+                "/midi_learn",
+                actix_web::web::delete().to(adapter::actix_server::delete_midi_learn),
+            )
+            .route(
+                "/session",
+                actix_web::web::get().to(adapter::actix_server::get_session),
+            )
+            .route(
+                "/session",
+                actix_web::web::put().to(adapter::actix_server::put_session),
+            )
+            .route(
+                "/history",
+                actix_web::web::get().to(adapter::actix_server::get_history),
+            )
+            .route(
+                "/history/undo",
+                actix_web::web::post().to(adapter::actix_server::post_undo),
+            )
+            .route(
+                "/history/redo",
+                actix_web::web::post().to(adapter::actix_server::post_redo),
+            )
+            .route(
+                "/history/group",
+                actix_web::web::put().to(adapter::actix_server::put_undo_group),
+            )
+            .route(
+                "/history/group",
+                actix_web::web::delete().to(adapter::actix_server::delete_undo_group),
+            )
+            .route(
+                "/latency",
+                actix_web::web::get().to(adapter::actix_server::get_latency),
+            )
+            .route(
+                "/transport",
+                actix_web::web::get().to(adapter::actix_server::get_transport),
+            )
+            .route(
+                "/transport",
+                actix_web::web::put().to(adapter::actix_server::put_transport),
+            )
+            .route(
+                "/tempo_map",
+                actix_web::web::get().to(adapter::actix_server::get_tempo_map),
+            )
+            .route(
+                "/tempo_map",
+                actix_web::web::put().to(adapter::actix_server::put_tempo_map),
+            )
+            .route(
+                "/metronome",
+                actix_web::web::get().to(adapter::actix_server::get_metronome),
+            )
+            .route(
+                "/metronome",
+                actix_web::web::put().to(adapter::actix_server::put_metronome),
+            )
+            .route(
+                "/midi_sync",
+                actix_web::web::get().to(adapter::actix_server::get_midi_sync),
+            )
+            .route(
+                "/midi_sync",
+                actix_web::web::put().to(adapter::actix_server::put_midi_sync),
+            )
+            .route(
+                "/clips",
+                actix_web::web::get().to(adapter::actix_server::get_clips),
+            )
     })
     .workers(1)
     .bind("127.0.0.1:8080")?
     .run()
     .await
 }
+
+/// Run a single LV2 plugin for a sandboxed plugin instance in another process.
+fn run_plugin_host(args: &[String]) {
+    let source = adapter::lilv::Lv2PluginSource::new(config::Lv2Config::default());
+    let result = adapter::lv2_sandbox::run_plugin_host(args, |uri, sample_rate, buffer_size| {
+        source.build_unsandboxed(
+            uri,
+            plugin_factory::AudioSettings {
+                sample_rate,
+                buffer_size,
+            },
+        )
+    });
+    if let Err(e) = result {
+        error!("Plugin host failed: {}", e);
+        std::process::exit(1);
+    }
+}
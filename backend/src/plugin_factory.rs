@@ -4,39 +4,125 @@ use std::collections::HashMap;
 #[derive(Default)]
 pub struct PluginFactory {
     builders: HashMap<String, (PluginMetadata, Box<dyn PluginBuilder>)>,
+    // Sources of plugins and the plugins they found but could not support.
+    sources: Vec<(Box<dyn PluginSource>, Vec<UnsupportedPlugin>)>,
+    audio_settings: AudioSettings,
 }
 
 impl PluginFactory {
     pub fn new() -> PluginFactory {
         PluginFactory {
             builders: HashMap::new(),
+            sources: Vec::new(),
+            audio_settings: AudioSettings::default(),
         }
     }
 
+    /// Register all plugins from `source`. The source is scanned again on `rescan`.
+    pub fn add_source<S: 'static + PluginSource>(&mut self, source: S) -> RescanReport {
+        self.sources.push((Box::new(source), Vec::new()));
+        self.rescan_source(self.sources.len() - 1)
+    }
+
+    /// Scan all sources for added and removed plugins. Plugin instances that have already been
+    /// built are not affected.
+    pub fn rescan(&mut self) -> RescanReport {
+        let mut report = RescanReport::default();
+        for index in 0..self.sources.len() {
+            let r = self.rescan_source(index);
+            report.added.extend(r.added);
+            report.removed.extend(r.removed);
+        }
+        report
+    }
+
+    fn rescan_source(&mut self, index: usize) -> RescanReport {
+        let scan = self.sources[index].0.rescan();
+        let mut report = RescanReport::default();
+        for id in scan.removed {
+            if self.builders.remove(&id).is_some() {
+                info!("Unregistered plugin {}.", id);
+                report.removed.push(id);
+            }
+        }
+        for builder in scan.added {
+            match self.register_boxed(builder) {
+                Ok(id) => report.added.push(id),
+                Err(e) => warn!("Failed to register plugin: {:?}", e),
+            }
+        }
+        self.sources[index].1 = scan.unsupported;
+        report
+    }
+
+    /// The plugins that were found but could not be registered.
+    pub fn unsupported(&self) -> impl Iterator<Item = &'_ UnsupportedPlugin> {
+        self.sources.iter().flat_map(|(_, u)| u.iter())
+    }
+
     pub fn register<B: 'static + PluginBuilder>(
         &mut self,
         builder: B,
     ) -> Result<(), PluginRegistrationError> {
+        self.register_boxed(Box::new(builder)).map(|_| ())
+    }
+
+    /// Register a plugin and return its id.
+    fn register_boxed(
+        &mut self,
+        mut builder: Box<dyn PluginBuilder>,
+    ) -> Result<String, PluginRegistrationError> {
         let metadata = builder.metadata();
         if let Err(e) = metadata.validate() {
-            return Err(PluginRegistrationError::InvalidMetadata(metadata, e));
+            return Err(PluginRegistrationError::InvalidMetadata(
+                Box::new(metadata),
+                e,
+            ));
         }
         if self.builders.contains_key(&metadata.id) {
             return Err(PluginRegistrationError::PluginAlreadyRegistered(
                 metadata.id.clone(),
-                metadata,
+                Box::new(metadata),
             ));
         }
         info!("Registered plugin {}: {:?}.", metadata.id, metadata);
-        self.builders
-            .insert(metadata.id.clone(), (metadata, Box::new(builder)));
-        Ok(())
+        builder.set_audio_settings(self.audio_settings);
+        let id = metadata.id.clone();
+        self.builders.insert(id.clone(), (metadata, builder));
+        Ok(id)
+    }
+
+    /// Set the audio settings that plugins are built for. Only affects plugins that are built
+    /// afterwards.
+    pub fn set_audio_settings(&mut self, settings: AudioSettings) {
+        self.audio_settings = settings;
+        for (_, builder) in self.builders.values_mut() {
+            builder.set_audio_settings(settings);
+        }
     }
 
     pub fn metadata(&self) -> impl Iterator<Item = &'_ PluginMetadata> {
         self.builders.values().map(|(m, _)| m)
     }
 
+    pub fn metadata_by_id(&self, plugin_id: &str) -> Option<&PluginMetadata> {
+        self.builders.get(plugin_id).map(|(m, _)| m)
+    }
+
+    /// Find the plugins that match `query`, sorted by display name and then id. Returns the
+    /// total number of matches and the requested page of matches.
+    pub fn search(&self, query: &PluginQuery) -> (usize, Vec<&'_ PluginMetadata>) {
+        let mut matches: Vec<_> = self.metadata().filter(|m| query.matches(m)).collect();
+        matches.sort_by_cached_key(|m| (m.display_name.to_lowercase(), m.id.clone()));
+        let total = matches.len();
+        let page = matches
+            .into_iter()
+            .skip(query.offset.unwrap_or(0))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect();
+        (total, page)
+    }
+
     pub fn build(&self, plugin_id: &str) -> Result<Box<dyn PluginInstance>, PluginBuilderError> {
         match self.builders.get(plugin_id) {
             Some((_, builder)) => builder.build(),
@@ -50,24 +136,238 @@ impl PluginFactory {
 pub trait PluginBuilder: Send {
     fn metadata(&self) -> PluginMetadata;
     fn build(&self) -> Result<Box<dyn PluginInstance>, PluginBuilderError>;
+
+    /// Called when the sample rate or buffer size changes.
+    fn set_audio_settings(&mut self, _settings: AudioSettings) {}
+}
+
+/// Finds plugins, for example the LV2 plugins installed on the system.
+pub trait PluginSource: Send {
+    /// Return the plugins that were added or removed since the last scan and all the plugins that
+    /// can not be supported. The first scan adds every plugin.
+    fn rescan(&mut self) -> PluginScan;
+}
+
+#[derive(Default)]
+pub struct PluginScan {
+    pub added: Vec<Box<dyn PluginBuilder>>,
+    /// The ids of the plugins that were removed.
+    pub removed: Vec<String>,
+    pub unsupported: Vec<UnsupportedPlugin>,
 }
 
-#[derive(Clone, Eq, PartialEq, Debug, serde::Serialize)]
+/// The ids of the plugins that were registered and unregistered by a rescan.
+#[derive(Clone, Debug, Default, Eq, PartialEq, serde::Serialize)]
+pub struct RescanReport {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// The audio settings of the backend that plugins run in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AudioSettings {
+    pub sample_rate: f64,
+    /// The number of frames in each call to process, or 0 if it is not known yet.
+    pub buffer_size: usize,
+}
+
+impl Default for AudioSettings {
+    fn default() -> AudioSettings {
+        AudioSettings {
+            sample_rate: 44100.0,
+            buffer_size: 0,
+        }
+    }
+}
+
+/// The longest allowed plugin id.
+const MAX_ID_LENGTH: usize = 128;
+
+#[derive(Clone, Default, Eq, PartialEq, Debug, serde::Serialize)]
 pub struct PluginMetadata {
+    /// A unique id made of lowercase ASCII letters, digits and underscores.
     pub id: String,
     pub display_name: String,
+    /// The URI of the plugin, if it is an LV2 plugin.
+    pub uri: Option<String>,
+    /// The label of the plugin class, for example "Reverb".
+    pub class: Option<String>,
+    /// The URIs of the plugin class and its parent classes, most specific first.
+    pub class_hierarchy: Vec<String>,
+    pub author: Option<String>,
+    pub project: Option<String>,
+    pub ports: PortSummary,
+    /// True if the plugin reports the latency it introduces.
+    pub has_latency: bool,
+    pub kind: PluginKind,
+}
+
+/// The number of ports of each type that a plugin has.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug, serde::Serialize)]
+pub struct PortSummary {
+    pub audio_inputs: usize,
+    pub audio_outputs: usize,
+    pub midi_inputs: usize,
+    pub controls: usize,
+}
+
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginKind {
+    /// Produces audio from MIDI.
+    Instrument,
+    /// Processes audio.
+    Effect,
+    /// Measures audio without changing it.
+    Analyzer,
+    #[default]
+    Other,
+}
+
+impl PluginKind {
+    /// Guess the kind of a plugin from its ports.
+    pub fn from_ports(ports: &PortSummary) -> PluginKind {
+        match ports {
+            PortSummary {
+                audio_outputs: 0, ..
+            } => PluginKind::Other,
+            PortSummary {
+                audio_inputs: 0,
+                midi_inputs: 0,
+                ..
+            } => PluginKind::Other,
+            PortSummary {
+                audio_inputs: 0, ..
+            } => PluginKind::Instrument,
+            _ => PluginKind::Effect,
+        }
+    }
+}
+
+/// A plugin that was found but can not be loaded.
+#[derive(Clone, Eq, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+pub struct UnsupportedPlugin {
+    pub id: String,
+    pub uri: String,
+    pub display_name: String,
+    /// The required features that are not provided by the host.
+    pub missing_features: Vec<String>,
+    /// The symbols of the ports that can not be connected.
+    pub unsupported_ports: Vec<String>,
+    /// True if the plugin description is malformed.
+    pub verification_failed: bool,
+}
+
+impl std::fmt::Display for UnsupportedPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.display_name, self.uri)?;
+        if !self.missing_features.is_empty() {
+            write!(
+                f,
+                "\n  missing features: {}",
+                self.missing_features.join(", ")
+            )?;
+        }
+        if !self.unsupported_ports.is_empty() {
+            write!(
+                f,
+                "\n  unsupported ports: {}",
+                self.unsupported_ports.join(", ")
+            )?;
+        }
+        if self.verification_failed {
+            write!(f, "\n  failed verification")?;
+        }
+        Ok(())
+    }
+}
+
+/// Filters for searching the plugin catalog. All text matching is case insensitive.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+pub struct PluginQuery {
+    /// Text to find in the display name, id, URI, class or author.
+    pub q: Option<String>,
+    pub kind: Option<PluginKind>,
+    /// Text to find in the class label or class URIs.
+    pub class: Option<String>,
+    /// Text to find in the author.
+    pub author: Option<String>,
+    pub audio_inputs: Option<usize>,
+    pub audio_outputs: Option<usize>,
+    pub midi_inputs: Option<usize>,
+    /// The number of matches to skip.
+    pub offset: Option<usize>,
+    /// The largest number of matches to return.
+    pub limit: Option<usize>,
+}
+
+impl PluginQuery {
+    pub fn matches(&self, metadata: &PluginMetadata) -> bool {
+        fn contains(haystack: Option<&str>, needle: &str) -> bool {
+            haystack.is_some_and(|h| h.to_lowercase().contains(needle))
+        }
+        if let Some(q) = self.q.as_ref().map(|q| q.to_lowercase()) {
+            let found = contains(Some(&metadata.display_name), &q)
+                || contains(Some(&metadata.id), &q)
+                || contains(metadata.uri.as_deref(), &q)
+                || contains(metadata.class.as_deref(), &q)
+                || contains(metadata.author.as_deref(), &q);
+            if !found {
+                return false;
+            }
+        }
+        if let Some(class) = self.class.as_ref().map(|c| c.to_lowercase()) {
+            let found = contains(metadata.class.as_deref(), &class)
+                || metadata
+                    .class_hierarchy
+                    .iter()
+                    .any(|c| contains(Some(c), &class));
+            if !found {
+                return false;
+            }
+        }
+        if let Some(author) = self.author.as_ref().map(|a| a.to_lowercase()) {
+            if !contains(metadata.author.as_deref(), &author) {
+                return false;
+            }
+        }
+        let ports = &metadata.ports;
+        self.kind.is_none_or(|k| k == metadata.kind)
+            && self.audio_inputs.is_none_or(|n| n == ports.audio_inputs)
+            && self.audio_outputs.is_none_or(|n| n == ports.audio_outputs)
+            && self.midi_inputs.is_none_or(|n| n == ports.midi_inputs)
+    }
 }
 
 impl PluginMetadata {
     pub fn validate(&self) -> Result<(), MetadataError> {
-        // TODO(wmedrano): Do proper checks once LV2 plugins are registered
-        // with proper IDs.
+        if self.id.is_empty() {
+            return Err(MetadataError::EmptyId);
+        }
+        if self.id.len() > MAX_ID_LENGTH {
+            return Err(MetadataError::IdTooLong(self.id.len()));
+        }
+        if let Some(c) = self
+            .id
+            .chars()
+            .find(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '_'))
+        {
+            return Err(MetadataError::InvalidIdCharacter(c));
+        }
+        if self.display_name.trim().is_empty() {
+            return Err(MetadataError::EmptyDisplayName);
+        }
         Ok(())
     }
 }
 
 #[derive(Clone, Eq, PartialEq, Debug)]
-pub enum MetadataError {}
+pub enum MetadataError {
+    EmptyId,
+    IdTooLong(usize),
+    InvalidIdCharacter(char),
+    EmptyDisplayName,
+}
 
 impl std::error::Error for MetadataError {}
 
@@ -79,8 +379,8 @@ impl std::fmt::Display for MetadataError {
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub enum PluginRegistrationError {
-    PluginAlreadyRegistered(String, PluginMetadata),
-    InvalidMetadata(PluginMetadata, MetadataError),
+    PluginAlreadyRegistered(String, Box<PluginMetadata>),
+    InvalidMetadata(Box<PluginMetadata>, MetadataError),
 }
 
 impl std::error::Error for PluginRegistrationError {}
@@ -104,3 +404,126 @@ impl std::fmt::Display for PluginBuilderError {
         write!(f, "{:?}", self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(id: &str, display_name: &str) -> PluginMetadata {
+        PluginMetadata {
+            id: id.to_string(),
+            display_name: display_name.to_string(),
+            ..PluginMetadata::default()
+        }
+    }
+
+    #[test]
+    fn validate_ids() {
+        assert_eq!(metadata("builtin_sine", "Sine").validate(), Ok(()));
+        assert_eq!(metadata("lv2_0a1f", "Reverb").validate(), Ok(()));
+        assert_eq!(metadata("", "Sine").validate(), Err(MetadataError::EmptyId));
+        assert_eq!(
+            metadata("Sine", "Sine").validate(),
+            Err(MetadataError::InvalidIdCharacter('S'))
+        );
+        assert_eq!(
+            metadata("a b", "Sine").validate(),
+            Err(MetadataError::InvalidIdCharacter(' '))
+        );
+        assert_eq!(
+            metadata(&"a".repeat(MAX_ID_LENGTH + 1), "Sine").validate(),
+            Err(MetadataError::IdTooLong(MAX_ID_LENGTH + 1))
+        );
+        assert_eq!(
+            metadata("sine", " ").validate(),
+            Err(MetadataError::EmptyDisplayName)
+        );
+    }
+
+    #[test]
+    fn search_filters_sorts_and_pages() {
+        let mut factory = PluginFactory::new();
+        for (id, name, author, kind) in [
+            ("reverb", "Reverb", "Alice", PluginKind::Effect),
+            ("piano", "piano", "Bob", PluginKind::Instrument),
+            ("delay", "Delay", "alice", PluginKind::Effect),
+        ]
+        .iter()
+        {
+            let metadata = PluginMetadata {
+                author: Some(author.to_string()),
+                kind: *kind,
+                ..metadata(id, name)
+            };
+            factory.register(TestPluginBuilder(metadata)).unwrap();
+        }
+        let ids = |query: &PluginQuery| -> (usize, Vec<String>) {
+            let (total, page) = factory.search(query);
+            (total, page.into_iter().map(|m| m.id.clone()).collect())
+        };
+
+        assert_eq!(
+            ids(&PluginQuery::default()),
+            (3, vec!["delay".into(), "piano".into(), "reverb".into()])
+        );
+        assert_eq!(
+            ids(&PluginQuery {
+                author: Some("ALICE".into()),
+                ..PluginQuery::default()
+            }),
+            (2, vec!["delay".into(), "reverb".into()])
+        );
+        assert_eq!(
+            ids(&PluginQuery {
+                kind: Some(PluginKind::Instrument),
+                ..PluginQuery::default()
+            }),
+            (1, vec!["piano".into()])
+        );
+        assert_eq!(
+            ids(&PluginQuery {
+                q: Some("ver".into()),
+                ..PluginQuery::default()
+            }),
+            (1, vec!["reverb".into()])
+        );
+        assert_eq!(
+            ids(&PluginQuery {
+                offset: Some(1),
+                limit: Some(1),
+                ..PluginQuery::default()
+            }),
+            (3, vec!["piano".into()])
+        );
+    }
+
+    struct TestPluginBuilder(PluginMetadata);
+
+    impl PluginBuilder for TestPluginBuilder {
+        fn metadata(&self) -> PluginMetadata {
+            self.0.clone()
+        }
+
+        fn build(&self) -> Result<Box<dyn PluginInstance>, PluginBuilderError> {
+            Ok(Box::new(olivia_core::example_plugin::Silence))
+        }
+    }
+
+    #[test]
+    fn kind_from_ports() {
+        let ports = |audio_inputs, audio_outputs, midi_inputs| PortSummary {
+            audio_inputs,
+            audio_outputs,
+            midi_inputs,
+            controls: 0,
+        };
+        assert_eq!(
+            PluginKind::from_ports(&ports(0, 2, 1)),
+            PluginKind::Instrument
+        );
+        assert_eq!(PluginKind::from_ports(&ports(2, 2, 0)), PluginKind::Effect);
+        assert_eq!(PluginKind::from_ports(&ports(1, 2, 1)), PluginKind::Effect);
+        assert_eq!(PluginKind::from_ports(&ports(0, 2, 0)), PluginKind::Other);
+        assert_eq!(PluginKind::from_ports(&ports(1, 0, 1)), PluginKind::Other);
+    }
+}
@@ -0,0 +1,126 @@
+//! Bookkeeping shared by the plugin sources that load plugins from files, like sampler
+//! definitions and native plugin libraries.
+use crate::plugin_factory::UnsupportedPlugin;
+use sha3::Digest;
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Create a plugin id for the file at `path`. The id stays the same as long as the path does.
+pub fn create_id(prefix: &str, path: &Path) -> String {
+    let hash = hex::encode(sha3::Sha3_256::digest(path.to_string_lossy().as_bytes()));
+    format!("{}_{}", prefix, hash)
+}
+
+/// The name of the file at `path` without its extension.
+pub fn display_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string_lossy().into_owned())
+}
+
+/// Describes a plugin file that could not be loaded.
+pub fn unsupported_file(id: String, path: &Path) -> UnsupportedPlugin {
+    UnsupportedPlugin {
+        id,
+        uri: path.to_string_lossy().into_owned(),
+        display_name: display_name(path),
+        missing_features: Vec::new(),
+        unsupported_ports: Vec::new(),
+        verification_failed: true,
+    }
+}
+
+/// Identifies the contents of a file. Writing to the file or replacing it changes its version.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct FileVersion {
+    modified: Option<std::time::SystemTime>,
+    len: u64,
+    inode: u64,
+}
+
+impl FileVersion {
+    fn of(path: &Path) -> Option<FileVersion> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(FileVersion {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            inode: metadata.ino(),
+        })
+    }
+}
+
+/// The plugin files found by the previous scan of a plugin source and what was loaded from each
+/// of them.
+pub struct PluginFiles<T> {
+    files: HashMap<PathBuf, (Option<FileVersion>, T)>,
+}
+
+impl<T> PluginFiles<T> {
+    pub fn new() -> PluginFiles<T> {
+        PluginFiles {
+            files: HashMap::new(),
+        }
+    }
+
+    /// Forget the files that are not in `paths` or that changed since they were loaded, and
+    /// return what was loaded from them. Changed files should be loaded again.
+    pub fn remove_stale(&mut self, paths: &[PathBuf]) -> Vec<T> {
+        let stale: Vec<PathBuf> = self
+            .files
+            .iter()
+            .filter(|(path, (version, _))| {
+                !paths.contains(path) || FileVersion::of(path) != *version
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        stale
+            .iter()
+            .filter_map(|path| self.files.remove(path))
+            .map(|(_, loaded)| loaded)
+            .collect()
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    /// Record what was loaded from the current version of the file at `path`.
+    pub fn insert(&mut self, path: PathBuf, loaded: T) {
+        let version = FileVersion::of(&path);
+        self.files.insert(path, (version, loaded));
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &'_ T> {
+        self.files.values().map(|(_, loaded)| loaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_and_removed_files_are_stale() {
+        let dir = std::env::temp_dir().join(format!("olivia_plugin_files_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.json"), dir.join("b.json"));
+        std::fs::write(&a, "a").unwrap();
+        std::fs::write(&b, "b").unwrap();
+        let mut files = PluginFiles::new();
+        files.insert(a.clone(), 'a');
+        files.insert(b.clone(), 'b');
+        let paths = vec![a.clone(), b.clone()];
+        assert_eq!(files.remove_stale(&paths), Vec::<char>::new());
+
+        std::fs::write(&a, "changed").unwrap();
+        let stale = files.remove_stale(&paths);
+        assert_eq!(stale, vec!['a']);
+        assert!(!files.contains(&a));
+
+        let stale = files.remove_stale(&[]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(stale, vec!['b']);
+        assert_eq!(files.values().count(), 0);
+    }
+}
@@ -1,42 +1,52 @@
 use crate::adapter;
+use crate::config::Config;
 use olivia_core::plugin::PluginInstance;
 use plugin_factory::PluginBuilder;
 
 use crate::plugin_factory;
 use crate::plugin_factory::PluginFactory;
 
-pub fn new_plugin_factory() -> PluginFactory {
+pub fn new_plugin_factory(config: &Config) -> PluginFactory {
     let mut factory = PluginFactory::new();
     if let Err(e) = factory.register(CloneablePluginBuilder::new(
         plugin_factory::PluginMetadata {
             id: "builtin_silence".to_string(),
             display_name: "Empty".to_string(),
+            ports: plugin_factory::PortSummary {
+                audio_outputs: 2,
+                ..plugin_factory::PortSummary::default()
+            },
+            ..plugin_factory::PluginMetadata::default()
         },
         olivia_core::example_plugin::Silence,
     )) {
         warn!("Failed to register plugin: {:?}", e);
     };
-    if let Err(e) = factory.register(CloneablePluginBuilder::new(
-        plugin_factory::PluginMetadata {
-            id: "builtin_sine".to_string(),
-            display_name: "Sine".to_string(),
-        },
-        // We are assuming a 44100 sample rate which may not be true.
-        // The right thing to do is to create a proper builder that
-        // takes sample rate as a parameter when constructing.
-        olivia_core::example_plugin::Sine::new(44100.0),
-    )) {
-        warn!("Failed to register plugin: {:?}", e);
-    };
-    for plugin_builder in adapter::lilv::load_plugins().drain(..) {
-        if let Err(e) = factory.register(plugin_builder) {
-            warn!("Failed to register LV2 plugin: {:?}", e);
+    for builder in native_plugins() {
+        if let Err(e) = factory.register(builder) {
+            warn!("Failed to register plugin: {:?}", e);
         };
     }
+    factory.add_source(adapter::lilv::Lv2PluginSource::new(config.lv2.clone()));
+    factory.add_source(adapter::sampler::SamplerSource::new(config.sampler.clone()));
+    factory.add_source(adapter::native_library::NativeLibrarySource::new(
+        config.native_plugins.clone(),
+    ));
 
     factory
 }
 
+/// Write the plugins that could not be loaded to `path` as JSON.
+pub fn save_compatibility_report(
+    factory: &PluginFactory,
+    path: &std::path::Path,
+) -> std::io::Result<()> {
+    let unsupported: Vec<_> = factory.unsupported().collect();
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, &unsupported)?;
+    Ok(())
+}
+
 struct CloneablePluginBuilder<P> {
     metadata: plugin_factory::PluginMetadata,
     plugin_instance: P,
@@ -66,3 +76,118 @@ where
         Ok(Box::new(self.plugin_instance.clone()))
     }
 }
+
+/// Builds a native plugin at the current sample rate.
+struct NativePluginBuilder {
+    metadata: plugin_factory::PluginMetadata,
+    sample_rate: f32,
+    new_instance: fn(f32) -> Box<dyn PluginInstance>,
+}
+
+impl NativePluginBuilder {
+    fn new(
+        metadata: plugin_factory::PluginMetadata,
+        new_instance: fn(f32) -> Box<dyn PluginInstance>,
+    ) -> NativePluginBuilder {
+        NativePluginBuilder {
+            metadata,
+            sample_rate: plugin_factory::AudioSettings::default().sample_rate as f32,
+            new_instance,
+        }
+    }
+
+    /// A builder for a stereo effect with `controls` parameters.
+    fn effect(
+        id: &str,
+        display_name: &str,
+        class: &str,
+        controls: usize,
+        new_instance: fn(f32) -> Box<dyn PluginInstance>,
+    ) -> NativePluginBuilder {
+        let metadata = plugin_factory::PluginMetadata {
+            id: id.to_string(),
+            display_name: display_name.to_string(),
+            class: Some(class.to_string()),
+            ports: plugin_factory::PortSummary {
+                audio_inputs: 2,
+                audio_outputs: 2,
+                controls,
+                ..plugin_factory::PortSummary::default()
+            },
+            kind: plugin_factory::PluginKind::Effect,
+            ..plugin_factory::PluginMetadata::default()
+        };
+        NativePluginBuilder::new(metadata, new_instance)
+    }
+}
+
+impl PluginBuilder for NativePluginBuilder {
+    fn metadata(&self) -> plugin_factory::PluginMetadata {
+        self.metadata.clone()
+    }
+
+    fn build(&self) -> Result<Box<dyn PluginInstance>, plugin_factory::PluginBuilderError> {
+        Ok((self.new_instance)(self.sample_rate))
+    }
+
+    fn set_audio_settings(&mut self, settings: plugin_factory::AudioSettings) {
+        self.sample_rate = settings.sample_rate as f32;
+    }
+}
+
+/// The plugins that are implemented in `olivia_core`.
+fn native_plugins() -> Vec<NativePluginBuilder> {
+    use olivia_core::effects::*;
+    vec![
+        NativePluginBuilder::new(
+            plugin_factory::PluginMetadata {
+                id: "builtin_synth".to_string(),
+                display_name: "Synth".to_string(),
+                ports: plugin_factory::PortSummary {
+                    audio_outputs: 2,
+                    midi_inputs: 1,
+                    controls: olivia_core::synth::SynthParameter::ALL.len(),
+                    ..plugin_factory::PortSummary::default()
+                },
+                kind: plugin_factory::PluginKind::Instrument,
+                ..plugin_factory::PluginMetadata::default()
+            },
+            |sample_rate| Box::new(olivia_core::synth::Synth::new(sample_rate)),
+        ),
+        NativePluginBuilder::effect(
+            "builtin_eq",
+            "Equalizer",
+            "EQ",
+            eq::NUM_BANDS * eq::BandParameter::ALL.len(),
+            |sample_rate| Box::new(eq::Equalizer::new(sample_rate)),
+        ),
+        NativePluginBuilder::effect(
+            "builtin_compressor",
+            "Compressor",
+            "Compressor",
+            compressor::CompressorParameter::ALL.len(),
+            |sample_rate| Box::new(compressor::Compressor::new(sample_rate)),
+        ),
+        NativePluginBuilder::effect(
+            "builtin_delay",
+            "Delay",
+            "Delay",
+            delay::DelayParameter::ALL.len(),
+            |sample_rate| Box::new(delay::Delay::new(sample_rate)),
+        ),
+        NativePluginBuilder::effect(
+            "builtin_reverb",
+            "Reverb",
+            "Reverb",
+            reverb::ReverbParameter::ALL.len(),
+            |sample_rate| Box::new(reverb::Reverb::new(sample_rate)),
+        ),
+        NativePluginBuilder::effect(
+            "builtin_utility",
+            "Utility",
+            "Utility",
+            utility::UtilityParameter::ALL.len(),
+            |_| Box::new(utility::Utility::default()),
+        ),
+    ]
+}
@@ -0,0 +1,529 @@
+use crate::controller::IntId;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The number of seconds of stereo audio that can be buffered per track before the disk writer
+/// has to catch up.
+const RING_BUFFER_SECONDS: usize = 10;
+
+/// How often the disk writer thread checks for new audio.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// A recording that has been written to disk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedClip {
+    pub track_id: IntId,
+    pub path: PathBuf,
+    pub start_frame: u64,
+    pub frames: u64,
+}
+
+enum WriterCommand {
+    Arm {
+        track_id: IntId,
+        samples: ringbuf::Consumer<f32>,
+    },
+    Disarm {
+        track_id: IntId,
+    },
+}
+
+#[derive(Copy, Clone, Debug)]
+enum SinkEvent {
+    Start {
+        track_id: IntId,
+        frame: u64,
+    },
+    Stop {
+        track_id: IntId,
+        frame: u64,
+    },
+    /// `frames` frames were dropped at `position`, counted in frames since the track was armed.
+    Dropped {
+        track_id: IntId,
+        position: u64,
+        frames: u64,
+    },
+}
+
+/// Streams the audio of armed tracks to WAV files on a dedicated thread.
+pub struct DiskWriter {
+    sample_rate: u32,
+    commands: crossbeam::channel::Sender<WriterCommand>,
+    events: crossbeam::channel::Sender<SinkEvent>,
+    // The number of events that sinks failed to send since the disk writer thread last checked.
+    failed_events: Arc<AtomicUsize>,
+    clips: crossbeam::channel::Receiver<RecordedClip>,
+}
+
+impl DiskWriter {
+    /// Create a new disk writer that writes WAV files to `directory`. The directory is created if
+    /// it does not exist.
+    pub fn new(directory: &Path, sample_rate: u32) -> std::io::Result<DiskWriter> {
+        std::fs::create_dir_all(directory)?;
+        let (commands_tx, commands_rx) = crossbeam::channel::unbounded();
+        // Events are sent from the realtime thread so the channel must be bounded.
+        let (events_tx, events_rx) = crossbeam::channel::bounded(1024);
+        let (clips_tx, clips_rx) = crossbeam::channel::unbounded();
+        let failed_events = Arc::new(AtomicUsize::new(0));
+        let mut thread = DiskWriterThread {
+            directory: directory.to_path_buf(),
+            sample_rate,
+            commands: commands_rx,
+            events: events_rx,
+            failed_events: failed_events.clone(),
+            clips: clips_tx,
+            tracks: HashMap::new(),
+            next_take: 1,
+        };
+        std::thread::Builder::new()
+            .name("olivia_disk_writer".to_string())
+            .spawn(move || thread.run())?;
+        info!("Recording audio to {:?}.", directory);
+        Ok(DiskWriter {
+            sample_rate,
+            commands: commands_tx,
+            events: events_tx,
+            failed_events,
+            clips: clips_rx,
+        })
+    }
+
+    /// Arm a track for recording. The returned sink should be given to the track in the
+    /// processor.
+    pub fn arm(&self, track_id: IntId) -> TrackRecordSink {
+        let capacity = 2 * RING_BUFFER_SECONDS * self.sample_rate as usize;
+        let (producer, consumer) = ringbuf::RingBuffer::new(capacity).split();
+        self.commands
+            .send(WriterCommand::Arm {
+                track_id,
+                samples: consumer,
+            })
+            .unwrap();
+        TrackRecordSink {
+            track_id,
+            samples: producer,
+            events: self.events.clone(),
+            failed_events: self.failed_events.clone(),
+            position: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Disarm a track. Any recording in progress for the track is still finished.
+    pub fn disarm(&self, track_id: IntId) {
+        self.commands
+            .send(WriterCommand::Disarm { track_id })
+            .unwrap();
+    }
+
+    /// Returns all clips that have finished recording since the last call.
+    pub fn finished_clips(&self) -> impl Iterator<Item = RecordedClip> + '_ {
+        self.clips.try_iter()
+    }
+}
+
+/// The realtime side of a track recording. Audio is interleaved into a ring buffer which is read
+/// by the disk writer thread.
+pub struct TrackRecordSink {
+    track_id: IntId,
+    samples: ringbuf::Producer<f32>,
+    events: crossbeam::channel::Sender<SinkEvent>,
+    failed_events: Arc<AtomicUsize>,
+    // The number of frames written or reported as dropped since the track was armed.
+    position: u64,
+    // The number of frames dropped since `position` that have not been reported yet.
+    dropped: u64,
+}
+
+impl std::fmt::Debug for TrackRecordSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TrackRecordSink")
+            .field("track_id", &self.track_id)
+            .field("capacity", &self.samples.capacity())
+            .finish()
+    }
+}
+
+impl TrackRecordSink {
+    /// Send an event to the disk writer thread. Failures are counted and logged by the disk
+    /// writer thread since this is called from the realtime thread.
+    fn send_event(&self, event: SinkEvent) -> bool {
+        let sent = self.events.try_send(event).is_ok();
+        if !sent {
+            self.failed_events.fetch_add(1, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// Report the dropped frames so that the disk writer can replace them with silence.
+    fn report_dropped(&mut self) -> bool {
+        let reported = self.send_event(SinkEvent::Dropped {
+            track_id: self.track_id,
+            position: self.position,
+            frames: self.dropped,
+        });
+        if reported {
+            self.position += self.dropped;
+            self.dropped = 0;
+        }
+        reported
+    }
+}
+
+impl olivia_core::record::RecordSink for TrackRecordSink {
+    fn start(&mut self, frame: u64) {
+        self.send_event(SinkEvent::Start {
+            track_id: self.track_id,
+            frame,
+        });
+    }
+
+    fn write(&mut self, left: &[f32], right: &[f32]) {
+        let frames = left.len().min(right.len()) as u64;
+        // Audio after unreported dropped frames would end up too early in the file, so it is
+        // dropped as well.
+        if self.dropped > 0 && !self.report_dropped() {
+            self.dropped += frames;
+            return;
+        }
+        for (written, (l, r)) in left.iter().zip(right.iter()).enumerate() {
+            // If the disk writer has fallen behind, frames are dropped rather than blocking the
+            // realtime thread.
+            if self.samples.remaining() < 2 {
+                self.dropped += frames - written as u64;
+                return;
+            }
+            self.samples.push(*l).ok();
+            self.samples.push(*r).ok();
+            self.position += 1;
+        }
+    }
+
+    fn stop(&mut self, frame: u64) {
+        if self.dropped > 0 {
+            self.report_dropped();
+        }
+        self.send_event(SinkEvent::Stop {
+            track_id: self.track_id,
+            frame,
+        });
+    }
+}
+
+type WavWriter = hound::WavWriter<std::io::BufWriter<std::fs::File>>;
+
+struct Take {
+    path: PathBuf,
+    start_frame: u64,
+    // Not set if the file could not be created, in which case the audio is discarded.
+    writer: Option<WavWriter>,
+    // The number of frames read from the ring buffer.
+    frames: u64,
+}
+
+/// Frames that were dropped by the sink and are written as silence.
+struct Gap {
+    position: u64,
+    frames: u64,
+}
+
+struct ArmedTrack {
+    samples: ringbuf::Consumer<f32>,
+    // The number of samples that may be read from the ring buffer. Only audio that was written
+    // before the last events were received is read, so that no dropped frames are missed.
+    available: usize,
+    // The number of frames read from the ring buffer or written as silence since the track was
+    // armed.
+    position: u64,
+    gaps: VecDeque<Gap>,
+    take: Option<Take>,
+    disarmed: bool,
+}
+
+struct DiskWriterThread {
+    directory: PathBuf,
+    sample_rate: u32,
+    commands: crossbeam::channel::Receiver<WriterCommand>,
+    events: crossbeam::channel::Receiver<SinkEvent>,
+    failed_events: Arc<AtomicUsize>,
+    clips: crossbeam::channel::Sender<RecordedClip>,
+    tracks: HashMap<IntId, ArmedTrack>,
+    next_take: usize,
+}
+
+impl DiskWriterThread {
+    fn run(&mut self) {
+        let mut buffer = vec![0f32; 2 * self.sample_rate as usize];
+        loop {
+            loop {
+                match self.commands.try_recv() {
+                    Ok(command) => self.handle_command(command),
+                    Err(crossbeam::channel::TryRecvError::Empty) => break,
+                    Err(crossbeam::channel::TryRecvError::Disconnected) => {
+                        info!("Disk writer has been dropped, stopping disk writer thread.");
+                        return;
+                    }
+                }
+            }
+            self.write_events_and_audio(&mut buffer);
+            let failed_events = self.failed_events.swap(0, Ordering::Relaxed);
+            if failed_events > 0 {
+                error!("Failed to send {} recording events.", failed_events);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Handle the events from the sinks and write the audio they produced.
+    fn write_events_and_audio(&mut self, buffer: &mut [f32]) {
+        // Sinks report dropped frames before writing the audio that follows them.
+        for track in self.tracks.values_mut() {
+            track.available = track.samples.len();
+        }
+        while let Ok(event) = self.events.try_recv() {
+            self.handle_event(event, buffer);
+        }
+        for (track_id, track) in self.tracks.iter_mut() {
+            write_available(*track_id, track, buffer, u64::MAX);
+        }
+    }
+
+    fn handle_command(&mut self, command: WriterCommand) {
+        match command {
+            WriterCommand::Arm { track_id, samples } => {
+                self.tracks.insert(
+                    track_id,
+                    ArmedTrack {
+                        samples,
+                        available: 0,
+                        position: 0,
+                        gaps: VecDeque::new(),
+                        take: None,
+                        disarmed: false,
+                    },
+                );
+            }
+            WriterCommand::Disarm { track_id } => {
+                let remove = match self.tracks.get_mut(&track_id) {
+                    Some(t) => {
+                        t.disarmed = true;
+                        t.take.is_none()
+                    }
+                    None => false,
+                };
+                if remove {
+                    self.tracks.remove(&track_id);
+                }
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: SinkEvent, buffer: &mut [f32]) {
+        match event {
+            SinkEvent::Start { track_id, frame } => {
+                let take = self.next_take;
+                self.next_take += 1;
+                let path = self
+                    .directory
+                    .join(format!("track_{}_take_{}.wav", track_id.0, take));
+                let spec = hound::WavSpec {
+                    channels: 2,
+                    sample_rate: self.sample_rate,
+                    bits_per_sample: 32,
+                    sample_format: hound::SampleFormat::Float,
+                };
+                let track = match self.tracks.get_mut(&track_id) {
+                    Some(t) => t,
+                    None => {
+                        warn!("Received recording start for unarmed track {:?}.", track_id);
+                        return;
+                    }
+                };
+                let writer = match WavWriter::create(&path, spec) {
+                    Ok(writer) => {
+                        info!("Recording track {:?} to {:?}.", track_id, path);
+                        Some(writer)
+                    }
+                    Err(e) => {
+                        error!("Failed to create {:?}: {:?}", path, e);
+                        None
+                    }
+                };
+                track.take = Some(Take {
+                    path,
+                    start_frame: frame,
+                    writer,
+                    frames: 0,
+                });
+            }
+            SinkEvent::Stop { track_id, frame } => {
+                let track = match self.tracks.get_mut(&track_id) {
+                    Some(t) => t,
+                    None => return,
+                };
+                let frames = match track.take.as_ref() {
+                    Some(take) => frame.saturating_sub(take.start_frame),
+                    None => 0,
+                };
+                // All audio of the take was written before it stopped. Audio after the end of the
+                // take belongs to the next take.
+                track.available = track.samples.len();
+                write_available(track_id, track, buffer, frames);
+                if let Some(Take {
+                    path,
+                    start_frame,
+                    writer: Some(writer),
+                    ..
+                }) = track.take.take()
+                {
+                    match writer.finalize() {
+                        Ok(()) => {
+                            info!("Finished recording {:?}.", path);
+                            let clip = RecordedClip {
+                                track_id,
+                                path,
+                                start_frame,
+                                frames,
+                            };
+                            self.clips.send(clip).ok();
+                        }
+                        Err(e) => error!("Failed to finalize {:?}: {:?}", path, e),
+                    }
+                }
+                if track.disarmed {
+                    self.tracks.remove(&track_id);
+                }
+            }
+            SinkEvent::Dropped {
+                track_id,
+                position,
+                frames,
+            } => {
+                if let Some(track) = self.tracks.get_mut(&track_id) {
+                    warn!(
+                        "Dropped {} frames while recording track {:?}, they are replaced with silence.",
+                        frames, track_id
+                    );
+                    track.gaps.push_back(Gap { position, frames });
+                }
+            }
+        }
+    }
+}
+
+/// Write the audio available in the track's ring buffer to its current take, until the take has
+/// `max_frames` frames. Dropped frames are written as silence. If the take has not started yet, the
+/// audio is left in the ring buffer.
+fn write_available(track_id: IntId, track: &mut ArmedTrack, buffer: &mut [f32], max_frames: u64) {
+    let take = match track.take.as_mut() {
+        Some(t) => t,
+        None => return,
+    };
+    loop {
+        let remaining = max_frames.saturating_sub(take.frames);
+        let position = track.position;
+        let len = match track.gaps.front_mut() {
+            Some(gap) if gap.position <= position => {
+                let frames = gap.frames.min(remaining).min(buffer.len() as u64 / 2);
+                gap.position += frames;
+                gap.frames -= frames;
+                if gap.frames == 0 {
+                    track.gaps.pop_front();
+                }
+                let len = 2 * frames as usize;
+                buffer[..len].iter_mut().for_each(|s| *s = 0.0);
+                len
+            }
+            gap => {
+                let until_gap = gap.map_or(u64::MAX, |g| g.position - position);
+                let samples = remaining.min(until_gap).saturating_mul(2);
+                let len = buffer
+                    .len()
+                    .min(track.available)
+                    .min(samples.min(usize::MAX as u64) as usize);
+                let len = track.samples.pop_slice(&mut buffer[..len]);
+                track.available -= len;
+                len
+            }
+        };
+        if len == 0 {
+            return;
+        }
+        track.position += len as u64 / 2;
+        take.frames += len as u64 / 2;
+        if let Some(writer) = take.writer.as_mut() {
+            for sample in buffer[..len].iter() {
+                if let Err(e) = writer.write_sample(*sample) {
+                    error!("Failed to write audio for track {:?}: {:?}", track_id, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use olivia_core::record::RecordSink;
+
+    #[test]
+    fn dropped_frames_are_written_as_silence() {
+        let directory =
+            std::env::temp_dir().join(format!("olivia_recorder_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let (_commands_tx, commands_rx) = crossbeam::channel::unbounded();
+        let (events_tx, events_rx) = crossbeam::channel::bounded(16);
+        let (clips_tx, clips_rx) = crossbeam::channel::unbounded();
+        let failed_events = Arc::new(AtomicUsize::new(0));
+        let mut thread = DiskWriterThread {
+            directory: directory.clone(),
+            sample_rate: 44100,
+            commands: commands_rx,
+            events: events_rx,
+            failed_events: failed_events.clone(),
+            clips: clips_tx,
+            tracks: HashMap::new(),
+            next_take: 1,
+        };
+        // Room for 2 frames.
+        let (producer, consumer) = ringbuf::RingBuffer::new(4).split();
+        thread.handle_command(WriterCommand::Arm {
+            track_id: IntId(1),
+            samples: consumer,
+        });
+        let mut sink = TrackRecordSink {
+            track_id: IntId(1),
+            samples: producer,
+            events: events_tx,
+            failed_events,
+            position: 0,
+            dropped: 0,
+        };
+        let mut buffer = vec![0.0; 16];
+
+        sink.start(10);
+        sink.write(&[1.0, 2.0, 3.0, 4.0], &[-1.0, -2.0, -3.0, -4.0]);
+        thread.write_events_and_audio(&mut buffer);
+        sink.write(&[5.0], &[-5.0]);
+        sink.stop(15);
+        thread.write_events_and_audio(&mut buffer);
+
+        let clip = clips_rx.try_recv().unwrap();
+        assert_eq!(clip.start_frame, 10);
+        assert_eq!(clip.frames, 5);
+        let samples: Vec<f32> = hound::WavReader::open(&clip.path)
+            .unwrap()
+            .samples::<f32>()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            samples,
+            vec![1.0, -1.0, 2.0, -2.0, 0.0, 0.0, 0.0, 0.0, 5.0, -5.0]
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+/// A point on an envelope.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub frame: u64,
+    pub value: f32,
+    /// The shape of the segment to the next breakpoint. 0 is a straight line, positive values
+    /// start slow and end fast and negative values start fast and end slow.
+    pub curve: f32,
+}
+
+/// A value that changes over time, made of segments between breakpoints.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Envelope {
+    points: Vec<Breakpoint>,
+}
+
+/// Curves closer to 0 than this are treated as straight lines.
+const MIN_CURVE: f32 = 1e-3;
+
+/// The steepest curve. Steeper curves are clamped to it.
+pub const MAX_CURVE: f32 = 50.0;
+
+impl Envelope {
+    /// Create an envelope from breakpoints in any order. If several breakpoints are at the same
+    /// frame, only the first one is kept. Curves are clamped to `MAX_CURVE`.
+    pub fn new(points: &[Breakpoint]) -> Envelope {
+        let mut points = points.to_vec();
+        for p in points.iter_mut() {
+            p.curve = p.curve.clamp(-MAX_CURVE, MAX_CURVE);
+        }
+        points.sort_by_key(|p| p.frame);
+        points.dedup_by_key(|p| p.frame);
+        Envelope { points }
+    }
+
+    pub fn points(&self) -> &[Breakpoint] {
+        &self.points
+    }
+
+    /// The value at `frame`. Before the first breakpoint and after the last one, the value of the
+    /// closest breakpoint is used. Returns `None` if there are no breakpoints.
+    pub fn value_at(&self, frame: u64) -> Option<f32> {
+        let next = self.points.partition_point(|p| p.frame <= frame);
+        self.value_between(next, frame)
+    }
+
+    /// Write the values for the frames starting at `frame` to `out`. `out` is not changed if there
+    /// are no breakpoints.
+    pub fn render(&self, frame: u64, out: &mut [f32]) {
+        if self.points.is_empty() {
+            return;
+        }
+        let mut next = self.points.partition_point(|p| p.frame <= frame);
+        for (f, o) in (frame..).zip(out.iter_mut()) {
+            while next < self.points.len() && self.points[next].frame <= f {
+                next += 1;
+            }
+            if let Some(v) = self.value_between(next, f) {
+                *o = v;
+            }
+        }
+    }
+
+    /// The value at `frame`, where `next` is the index of the first breakpoint after `frame`.
+    fn value_between(&self, next: usize, frame: u64) -> Option<f32> {
+        let first = self.points.first()?;
+        let last = self.points.last()?;
+        if next == 0 {
+            return Some(first.value);
+        }
+        if next == self.points.len() {
+            return Some(last.value);
+        }
+        let (a, b) = (&self.points[next - 1], &self.points[next]);
+        let t = (frame - a.frame) as f64 / (b.frame - a.frame) as f64;
+        let t = if a.curve.abs() < MIN_CURVE {
+            t
+        } else {
+            let curve = f64::from(a.curve);
+            ((curve * t).exp() - 1.0) / (curve.exp() - 1.0)
+        };
+        Some(a.value + (b.value - a.value) * t as f32)
+    }
+}
+
+/// The value that an automation lane controls.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AutomationTarget {
+    /// The track volume. Replaces the volume set on the track.
+    Volume,
+    /// The track pan, from -1 (left) to 1 (right). Replaces the pan set on the track.
+    Pan,
+    /// A parameter of a plugin on the track.
+    Parameter {
+        plugin_index: usize,
+        parameter: usize,
+    },
+}
+
+/// An envelope that controls a value of a track.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutomationLane {
+    pub target: AutomationTarget,
+    pub envelope: Envelope,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(frame: u64, value: f32, curve: f32) -> Breakpoint {
+        Breakpoint {
+            frame,
+            value,
+            curve,
+        }
+    }
+
+    #[test]
+    fn empty_envelope_has_no_value() {
+        let envelope = Envelope::default();
+        assert_eq!(envelope.value_at(0), None);
+        let mut out = [0.5; 2];
+        envelope.render(0, &mut out);
+        assert_eq!(out, [0.5; 2]);
+    }
+
+    #[test]
+    fn linear_segments_are_interpolated() {
+        let envelope = Envelope::new(&[point(200, 0.0, 0.0), point(100, 1.0, 0.0)]);
+        assert_eq!(envelope.value_at(0), Some(1.0));
+        assert_eq!(envelope.value_at(100), Some(1.0));
+        assert_eq!(envelope.value_at(150), Some(0.5));
+        assert_eq!(envelope.value_at(200), Some(0.0));
+        assert_eq!(envelope.value_at(1000), Some(0.0));
+    }
+
+    #[test]
+    fn curved_segments_bend_towards_the_curve() {
+        let slow_start = Envelope::new(&[point(0, 0.0, 4.0), point(100, 1.0, 0.0)]);
+        let fast_start = Envelope::new(&[point(0, 0.0, -4.0), point(100, 1.0, 0.0)]);
+        assert!(slow_start.value_at(50).unwrap() < 0.5);
+        assert!(fast_start.value_at(50).unwrap() > 0.5);
+        assert_eq!(slow_start.value_at(100), Some(1.0));
+        assert_eq!(fast_start.value_at(0), Some(0.0));
+    }
+
+    #[test]
+    fn steep_curves_stay_finite() {
+        for curve in [200.0, -200.0, f32::MAX, f32::MIN].iter() {
+            let envelope = Envelope::new(&[point(0, 0.0, *curve), point(100, 1.0, 0.0)]);
+            for frame in 0..=100 {
+                let value = envelope.value_at(frame).unwrap();
+                assert!(
+                    (0.0..=1.0).contains(&value),
+                    "curve {} frame {}",
+                    curve,
+                    frame
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn render_matches_value_at() {
+        let envelope = Envelope::new(&[
+            point(10, 0.0, 0.0),
+            point(20, 1.0, 2.0),
+            point(30, 0.25, 0.0),
+        ]);
+        let mut out = [0.0; 40];
+        envelope.render(5, &mut out);
+        for (frame, value) in (5..).zip(out.iter()) {
+            assert_eq!(Some(*value), envelope.value_at(frame), "frame {}", frame);
+        }
+    }
+}
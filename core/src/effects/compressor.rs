@@ -0,0 +1,178 @@
+use super::{db_to_gain, gain_to_db, smoothing_coefficient};
+use crate::plugin::{ParameterInfo, PluginInstance};
+use crate::TimedMidi;
+
+/// The parameters of the compressor, in the order of their indices for `set_parameter`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CompressorParameter {
+    /// The level in decibels above which the signal is compressed.
+    Threshold,
+    /// How much the signal above the threshold is reduced, from 1 (not at all) to 100, which acts
+    /// as a limiter.
+    Ratio,
+    /// The time in seconds for the gain reduction to react to a louder signal.
+    Attack,
+    /// The time in seconds for the gain reduction to recover.
+    Release,
+    /// The gain in decibels applied after compression.
+    Makeup,
+}
+
+impl CompressorParameter {
+    pub const ALL: [CompressorParameter; 5] = [
+        CompressorParameter::Threshold,
+        CompressorParameter::Ratio,
+        CompressorParameter::Attack,
+        CompressorParameter::Release,
+        CompressorParameter::Makeup,
+    ];
+
+    pub fn from_index(index: usize) -> Option<CompressorParameter> {
+        CompressorParameter::ALL.get(index).copied()
+    }
+
+    pub fn info(self) -> ParameterInfo {
+        let info = |id, name, min, max, default| {
+            ParameterInfo::new(self as usize, id, name, min, max, default)
+        };
+        match self {
+            CompressorParameter::Threshold => {
+                info("threshold", "Threshold", -60.0, 0.0, -18.0).with_unit("dB")
+            }
+            CompressorParameter::Ratio => info("ratio", "Ratio", 1.0, 100.0, 4.0),
+            CompressorParameter::Attack => info("attack", "Attack", 0.0, 1.0, 0.01).with_unit("s"),
+            CompressorParameter::Release => {
+                info("release", "Release", 0.0, 5.0, 0.1).with_unit("s")
+            }
+            CompressorParameter::Makeup => info("makeup", "Makeup", 0.0, 24.0, 0.0).with_unit("dB"),
+        }
+    }
+}
+
+/// A stereo linked peak compressor.
+#[derive(Clone, Debug)]
+pub struct Compressor {
+    sample_rate: f32,
+    threshold: f32,
+    ratio: f32,
+    attack: f32,
+    release: f32,
+    makeup: f32,
+    // The peak level of the input, which falls at the release rate.
+    envelope: f32,
+    // The current gain reduction in decibels.
+    reduction: f32,
+}
+
+impl Compressor {
+    pub fn new(sample_rate: f32) -> Compressor {
+        let default = |p: CompressorParameter| p.info().default;
+        Compressor {
+            sample_rate,
+            threshold: default(CompressorParameter::Threshold),
+            ratio: default(CompressorParameter::Ratio),
+            attack: default(CompressorParameter::Attack),
+            release: default(CompressorParameter::Release),
+            makeup: default(CompressorParameter::Makeup),
+            envelope: 0.0,
+            reduction: 0.0,
+        }
+    }
+
+    /// The current gain reduction in decibels.
+    pub fn reduction(&self) -> f32 {
+        self.reduction
+    }
+}
+
+impl PluginInstance for Compressor {
+    fn process(&mut self, _: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+        let attack = smoothing_coefficient(self.attack, self.sample_rate);
+        let release = smoothing_coefficient(self.release, self.sample_rate);
+        let slope = 1.0 - 1.0 / self.ratio;
+        for (l, r) in out_left.iter_mut().zip(out_right.iter_mut()) {
+            self.envelope = l.abs().max(r.abs()).max(self.envelope * release);
+            let target = (gain_to_db(self.envelope) - self.threshold).max(0.0) * slope;
+            let coefficient = if target > self.reduction {
+                attack
+            } else {
+                release
+            };
+            self.reduction = target + (self.reduction - target) * coefficient;
+            let gain = db_to_gain(self.makeup - self.reduction);
+            *l *= gain;
+            *r *= gain;
+        }
+    }
+
+    fn parameters(&self) -> Vec<ParameterInfo> {
+        CompressorParameter::ALL.iter().map(|p| p.info()).collect()
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match CompressorParameter::from_index(index) {
+            Some(CompressorParameter::Threshold) => self.threshold = value.clamp(-60.0, 0.0),
+            Some(CompressorParameter::Ratio) => self.ratio = value.clamp(1.0, 100.0),
+            Some(CompressorParameter::Attack) => self.attack = value.clamp(0.0, 1.0),
+            Some(CompressorParameter::Release) => self.release = value.clamp(0.0, 5.0),
+            Some(CompressorParameter::Makeup) => self.makeup = value.clamp(0.0, 24.0),
+            None => (),
+        }
+    }
+
+    fn get_parameter(&self, index: usize) -> Option<f32> {
+        match CompressorParameter::from_index(index)? {
+            CompressorParameter::Threshold => Some(self.threshold),
+            CompressorParameter::Ratio => Some(self.ratio),
+            CompressorParameter::Attack => Some(self.attack),
+            CompressorParameter::Release => Some(self.release),
+            CompressorParameter::Makeup => Some(self.makeup),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{peak, sine};
+    use super::*;
+
+    fn compress(compressor: &mut Compressor, amplitude: f32) -> f32 {
+        let input: Vec<f32> = sine(1000.0, 44100.0, 44100)
+            .into_iter()
+            .map(|s| s * amplitude)
+            .collect();
+        let mut left = input.clone();
+        let mut right = input;
+        compressor.process(&[], &mut left, &mut right);
+        peak(&left[22050..])
+    }
+
+    #[test]
+    fn signals_below_the_threshold_are_unchanged() {
+        let mut compressor = Compressor::new(44100.0);
+        let amplitude = db_to_gain(-24.0);
+        assert!((compress(&mut compressor, amplitude) - amplitude).abs() < 1e-4);
+        assert_eq!(compressor.reduction(), 0.0);
+    }
+
+    #[test]
+    fn signals_above_the_threshold_are_reduced_by_the_ratio() {
+        let mut compressor = Compressor::new(44100.0);
+        compressor.set_parameter(CompressorParameter::Threshold as usize, -12.0);
+        compressor.set_parameter(CompressorParameter::Ratio as usize, 4.0);
+        let out = gain_to_db(compress(&mut compressor, db_to_gain(0.0)));
+        assert!((out - -9.0).abs() < 0.5, "{} dB", out);
+
+        let mut limiter = Compressor::new(44100.0);
+        limiter.set_parameter(CompressorParameter::Threshold as usize, -6.0);
+        limiter.set_parameter(CompressorParameter::Ratio as usize, 100.0);
+        limiter.set_parameter(CompressorParameter::Attack as usize, 0.0);
+        let out = gain_to_db(compress(&mut limiter, db_to_gain(0.0)));
+        assert!((out - -6.0).abs() < 0.5, "{} dB", out);
+    }
+
+    #[test]
+    fn declared_parameters_match_the_settings() {
+        super::super::check_parameters(&mut Compressor::new(44100.0));
+    }
+}
@@ -0,0 +1,215 @@
+use super::{from_switch, is_on, smoothing_coefficient};
+use crate::plugin::{ParameterInfo, PluginInstance, TransportInfo};
+use crate::TimedMidi;
+
+/// The longest delay time.
+pub const MAX_DELAY_SECONDS: f32 = 4.0;
+
+/// How long the delay time takes to follow a change, to avoid clicks.
+const TIME_SMOOTHING_SECONDS: f32 = 0.05;
+
+/// The parameters of the delay, in the order of their indices for `set_parameter`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DelayParameter {
+    /// The delay time in seconds, used when not synced to the tempo.
+    Time,
+    /// 1 to use a delay time in beats of the current tempo, 0 to use the time in seconds.
+    Sync,
+    /// The delay time in beats, used when synced to the tempo.
+    Beats,
+    /// How much of the delayed signal is fed back, from 0 to 0.95.
+    Feedback,
+    /// The level of the delayed signal from 0 to 1. The level of the input is `1 - mix`.
+    Mix,
+}
+
+impl DelayParameter {
+    pub const ALL: [DelayParameter; 5] = [
+        DelayParameter::Time,
+        DelayParameter::Sync,
+        DelayParameter::Beats,
+        DelayParameter::Feedback,
+        DelayParameter::Mix,
+    ];
+
+    pub fn from_index(index: usize) -> Option<DelayParameter> {
+        DelayParameter::ALL.get(index).copied()
+    }
+
+    pub fn info(self) -> ParameterInfo {
+        let index = self as usize;
+        match self {
+            DelayParameter::Time => {
+                ParameterInfo::new(index, "time", "Time", 0.0, MAX_DELAY_SECONDS, 0.375)
+                    .with_unit("s")
+            }
+            DelayParameter::Sync => ParameterInfo::toggle(index, "sync", "Sync", false),
+            DelayParameter::Beats => {
+                ParameterInfo::new(index, "beats", "Beats", 0.0, 16.0, 0.75).with_unit("beats")
+            }
+            DelayParameter::Feedback => {
+                ParameterInfo::new(index, "feedback", "Feedback", 0.0, 0.95, 0.35)
+            }
+            DelayParameter::Mix => ParameterInfo::new(index, "mix", "Mix", 0.0, 1.0, 0.3),
+        }
+    }
+}
+
+/// A stereo feedback delay whose time can follow the tempo.
+#[derive(Clone, Debug)]
+pub struct Delay {
+    sample_rate: f32,
+    time: f32,
+    sync: bool,
+    beats: f32,
+    feedback: f32,
+    mix: f32,
+    bpm: f64,
+    left: Vec<f32>,
+    right: Vec<f32>,
+    write: usize,
+    // The delay in frames, which moves towards the target delay.
+    delay: f32,
+}
+
+impl Delay {
+    pub fn new(sample_rate: f32) -> Delay {
+        let len = (MAX_DELAY_SECONDS * sample_rate) as usize + 2;
+        let mut delay = Delay {
+            sample_rate,
+            time: DelayParameter::Time.info().default,
+            sync: false,
+            beats: DelayParameter::Beats.info().default,
+            feedback: DelayParameter::Feedback.info().default,
+            mix: DelayParameter::Mix.info().default,
+            bpm: 120.0,
+            left: vec![0.0; len],
+            right: vec![0.0; len],
+            write: 0,
+            delay: 0.0,
+        };
+        delay.delay = delay.target_delay();
+        delay
+    }
+
+    /// The delay in frames for the current settings.
+    fn target_delay(&self) -> f32 {
+        let seconds = if self.sync {
+            (f64::from(self.beats) * 60.0 / self.bpm) as f32
+        } else {
+            self.time
+        };
+        let max = (self.left.len() - 2) as f32;
+        (seconds * self.sample_rate).clamp(1.0, max)
+    }
+}
+
+impl PluginInstance for Delay {
+    fn set_transport(&mut self, transport: &TransportInfo) {
+        if transport.bpm > 0.0 {
+            self.bpm = transport.bpm;
+        }
+    }
+
+    fn process(&mut self, _: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+        let target = self.target_delay();
+        let smoothing = smoothing_coefficient(TIME_SMOOTHING_SECONDS, self.sample_rate);
+        let len = self.left.len();
+        for (l, r) in out_left.iter_mut().zip(out_right.iter_mut()) {
+            self.delay = target + (self.delay - target) * smoothing;
+            let read = self.write as f32 + len as f32 - self.delay;
+            let index = read as usize;
+            let fraction = read - index as f32;
+            let tap = |buffer: &[f32]| {
+                let a = buffer[index % len];
+                let b = buffer[(index + 1) % len];
+                a + (b - a) * fraction
+            };
+            let (delayed_left, delayed_right) = (tap(&self.left), tap(&self.right));
+            self.left[self.write] = *l + delayed_left * self.feedback;
+            self.right[self.write] = *r + delayed_right * self.feedback;
+            self.write = (self.write + 1) % len;
+            *l = *l * (1.0 - self.mix) + delayed_left * self.mix;
+            *r = *r * (1.0 - self.mix) + delayed_right * self.mix;
+        }
+    }
+
+    fn parameters(&self) -> Vec<ParameterInfo> {
+        DelayParameter::ALL.iter().map(|p| p.info()).collect()
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match DelayParameter::from_index(index) {
+            Some(DelayParameter::Time) => self.time = value.clamp(0.0, MAX_DELAY_SECONDS),
+            Some(DelayParameter::Sync) => self.sync = is_on(value),
+            Some(DelayParameter::Beats) => self.beats = value.clamp(0.0, 16.0),
+            Some(DelayParameter::Feedback) => self.feedback = value.clamp(0.0, 0.95),
+            Some(DelayParameter::Mix) => self.mix = value.clamp(0.0, 1.0),
+            None => (),
+        }
+    }
+
+    fn get_parameter(&self, index: usize) -> Option<f32> {
+        match DelayParameter::from_index(index)? {
+            DelayParameter::Time => Some(self.time),
+            DelayParameter::Sync => Some(from_switch(self.sync)),
+            DelayParameter::Beats => Some(self.beats),
+            DelayParameter::Feedback => Some(self.feedback),
+            DelayParameter::Mix => Some(self.mix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn impulse_response(delay: &mut Delay, frames: usize) -> Vec<f32> {
+        let mut left = vec![0.0; frames];
+        left[0] = 1.0;
+        let mut right = left.clone();
+        delay.process(&[], &mut left, &mut right);
+        assert_eq!(left, right);
+        left
+    }
+
+    #[test]
+    fn echoes_repeat_with_feedback() {
+        let mut delay = Delay::new(1000.0);
+        delay.set_parameter(DelayParameter::Time as usize, 0.1);
+        delay.set_parameter(DelayParameter::Feedback as usize, 0.5);
+        delay.set_parameter(DelayParameter::Mix as usize, 0.5);
+        delay.delay = delay.target_delay();
+        let out = impulse_response(&mut delay, 250);
+        assert_eq!(out[0], 0.5);
+        assert_eq!(out[100], 0.5);
+        assert_eq!(out[200], 0.25);
+        assert_eq!(out.iter().filter(|s| **s != 0.0).count(), 3);
+    }
+
+    #[test]
+    fn synced_time_follows_the_tempo() {
+        let mut delay = Delay::new(1000.0);
+        delay.set_parameter(DelayParameter::Sync as usize, 1.0);
+        delay.set_parameter(DelayParameter::Beats as usize, 0.5);
+        delay.set_parameter(DelayParameter::Feedback as usize, 0.0);
+        delay.set_parameter(DelayParameter::Mix as usize, 1.0);
+        delay.set_transport(&TransportInfo {
+            playing: true,
+            frame: 0,
+            bpm: 150.0,
+            beats_per_bar: 4,
+            bar: 0,
+            bar_beat: 0.0,
+        });
+        delay.delay = delay.target_delay();
+        let out = impulse_response(&mut delay, 400);
+        assert_eq!(out[200], 1.0);
+        assert_eq!(out.iter().filter(|s| **s != 0.0).count(), 1);
+    }
+
+    #[test]
+    fn declared_parameters_match_the_settings() {
+        super::super::check_parameters(&mut Delay::new(1000.0));
+    }
+}
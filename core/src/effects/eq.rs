@@ -0,0 +1,304 @@
+use crate::plugin::{ParameterInfo, PluginInstance};
+use crate::TimedMidi;
+
+/// The number of bands of the equalizer. The first band is a low shelf, the last band is a high
+/// shelf and the bands in between are peaks.
+pub const NUM_BANDS: usize = 4;
+
+/// The default frequency of each band in Hz.
+const DEFAULT_FREQUENCIES: [f32; NUM_BANDS] = [100.0, 500.0, 2000.0, 8000.0];
+
+/// The default Q of every band.
+const DEFAULT_Q: f32 = 0.707;
+
+/// The id and name prefix of each band.
+const BAND_NAMES: [(&str, &str); NUM_BANDS] = [
+    ("low", "Low"),
+    ("low_mid", "Low Mid"),
+    ("high_mid", "High Mid"),
+    ("high", "High"),
+];
+
+/// The parameters of each band. The index of a parameter is
+/// `band * BandParameter::ALL.len() + parameter`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BandParameter {
+    /// The center or corner frequency in Hz.
+    Frequency,
+    /// The boost or cut in decibels.
+    Gain,
+    /// The width of the band. Higher values are narrower.
+    Q,
+}
+
+impl BandParameter {
+    pub const ALL: [BandParameter; 3] = [
+        BandParameter::Frequency,
+        BandParameter::Gain,
+        BandParameter::Q,
+    ];
+
+    /// The band and parameter for a parameter index.
+    pub fn from_index(index: usize) -> Option<(usize, BandParameter)> {
+        let band = index / BandParameter::ALL.len();
+        let parameter = BandParameter::ALL[index % BandParameter::ALL.len()];
+        if band < NUM_BANDS {
+            Some((band, parameter))
+        } else {
+            None
+        }
+    }
+
+    /// Describe the parameter of `band`.
+    pub fn info(self, band: usize) -> ParameterInfo {
+        let index = band * BandParameter::ALL.len() + self as usize;
+        let (band_id, band_name) = BAND_NAMES[band];
+        let info = |id, name, min, max, default| {
+            let id = format!("{}_{}", band_id, id);
+            let name = format!("{} {}", band_name, name);
+            ParameterInfo::new(index, &id, &name, min, max, default)
+        };
+        match self {
+            BandParameter::Frequency => info(
+                "frequency",
+                "Frequency",
+                20.0,
+                20000.0,
+                DEFAULT_FREQUENCIES[band],
+            )
+            .with_unit("Hz"),
+            BandParameter::Gain => info("gain", "Gain", -24.0, 24.0, 0.0).with_unit("dB"),
+            BandParameter::Q => info("q", "Q", 0.1, 18.0, DEFAULT_Q),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Shape {
+    LowShelf,
+    Peak,
+    HighShelf,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Band {
+    shape: Shape,
+    frequency: f32,
+    gain: f32,
+    q: f32,
+}
+
+/// The coefficients of a biquad filter, normalized so that a0 is 1.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Coefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Coefficients {
+    /// The filter for `band`, from the Audio EQ Cookbook.
+    fn new(band: &Band, sample_rate: f32) -> Coefficients {
+        let a = 10f32.powf(band.gain / 40.0);
+        let w0 = 2.0 * std::f32::consts::PI * band.frequency.min(0.49 * sample_rate) / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * band.q);
+        let (b0, b1, b2, a0, a1, a2) = match band.shape {
+            Shape::Peak => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            Shape::LowShelf => {
+                let k = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos + k),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - k),
+                    (a + 1.0) + (a - 1.0) * cos + k,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - k,
+                )
+            }
+            Shape::HighShelf => {
+                let k = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos + k),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - k),
+                    (a + 1.0) - (a - 1.0) * cos + k,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - k,
+                )
+            }
+        };
+        Coefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+}
+
+/// The state of a biquad filter for one channel.
+#[derive(Copy, Clone, Debug, Default)]
+struct State {
+    z1: f32,
+    z2: f32,
+}
+
+impl State {
+    fn process(&mut self, c: &Coefficients, x: f32) -> f32 {
+        let y = c.b0 * x + self.z1;
+        self.z1 = c.b1 * x - c.a1 * y + self.z2;
+        self.z2 = c.b2 * x - c.a2 * y;
+        y
+    }
+}
+
+/// A stereo parametric equalizer with a low shelf, two peaks and a high shelf.
+#[derive(Clone, Debug)]
+pub struct Equalizer {
+    sample_rate: f32,
+    bands: [Band; NUM_BANDS],
+    coefficients: [Coefficients; NUM_BANDS],
+    states: [[State; 2]; NUM_BANDS],
+}
+
+impl Equalizer {
+    pub fn new(sample_rate: f32) -> Equalizer {
+        let band = |shape, index: usize| Band {
+            shape,
+            frequency: DEFAULT_FREQUENCIES[index],
+            gain: 0.0,
+            q: DEFAULT_Q,
+        };
+        let bands = [
+            band(Shape::LowShelf, 0),
+            band(Shape::Peak, 1),
+            band(Shape::Peak, 2),
+            band(Shape::HighShelf, 3),
+        ];
+        let mut coefficients = [Coefficients::new(&bands[0], sample_rate); NUM_BANDS];
+        for (c, b) in coefficients.iter_mut().zip(bands.iter()) {
+            *c = Coefficients::new(b, sample_rate);
+        }
+        Equalizer {
+            sample_rate,
+            bands,
+            coefficients,
+            states: [[State::default(); 2]; NUM_BANDS],
+        }
+    }
+}
+
+impl PluginInstance for Equalizer {
+    fn process(&mut self, _: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+        for (band, (c, state)) in self
+            .coefficients
+            .iter()
+            .zip(self.states.iter_mut())
+            .enumerate()
+        {
+            if self.bands[band].gain == 0.0 {
+                continue;
+            }
+            let [left, right] = state;
+            for (l, r) in out_left.iter_mut().zip(out_right.iter_mut()) {
+                *l = left.process(c, *l);
+                *r = right.process(c, *r);
+            }
+        }
+    }
+
+    fn parameters(&self) -> Vec<ParameterInfo> {
+        (0..NUM_BANDS)
+            .flat_map(|band| BandParameter::ALL.iter().map(move |p| p.info(band)))
+            .collect()
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        let (band_index, parameter) = match BandParameter::from_index(index) {
+            Some(p) => p,
+            None => return,
+        };
+        let band = &mut self.bands[band_index];
+        // Flat bands are skipped, so their state is stale.
+        if band.gain == 0.0 {
+            self.states[band_index] = [State::default(); 2];
+        }
+        match parameter {
+            BandParameter::Frequency => band.frequency = value.clamp(20.0, 20000.0),
+            BandParameter::Gain => band.gain = value.clamp(-24.0, 24.0),
+            BandParameter::Q => band.q = value.clamp(0.1, 18.0),
+        }
+        self.coefficients[band_index] = Coefficients::new(band, self.sample_rate);
+    }
+
+    fn get_parameter(&self, index: usize) -> Option<f32> {
+        let (band_index, parameter) = BandParameter::from_index(index)?;
+        let band = &self.bands[band_index];
+        match parameter {
+            BandParameter::Frequency => Some(band.frequency),
+            BandParameter::Gain => Some(band.gain),
+            BandParameter::Q => Some(band.q),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::db_to_gain;
+    use super::*;
+
+    /// The gain that `eq` applies to a sine at `frequency`, once it has settled.
+    fn response(eq: &Equalizer, frequency: f32) -> f32 {
+        let input = super::super::sine(frequency, eq.sample_rate, 8820);
+        let mut left = input.clone();
+        let mut right = input;
+        eq.clone().process(&[], &mut left, &mut right);
+        super::super::peak(&left[4410..])
+    }
+
+    #[test]
+    fn flat_bands_do_not_change_the_signal() {
+        let mut eq = Equalizer::new(44100.0);
+        let input = super::super::sine(440.0, 44100.0, 512);
+        let mut left = input.clone();
+        let mut right = input.clone();
+        eq.process(&[], &mut left, &mut right);
+        assert_eq!(left, input);
+        assert_eq!(right, input);
+    }
+
+    #[test]
+    fn bands_boost_and_cut_around_their_frequency() {
+        let index = |band: usize, parameter: BandParameter| {
+            band * BandParameter::ALL.len() + parameter as usize
+        };
+        let mut eq = Equalizer::new(44100.0);
+        eq.set_parameter(index(1, BandParameter::Frequency), 1000.0);
+        eq.set_parameter(index(1, BandParameter::Gain), 12.0);
+        eq.set_parameter(index(1, BandParameter::Q), 2.0);
+        assert!((response(&eq, 1000.0) - db_to_gain(12.0)).abs() < 0.1);
+        assert!((response(&eq, 100.0) - 1.0).abs() < 0.05);
+
+        let mut eq = Equalizer::new(44100.0);
+        eq.set_parameter(index(3, BandParameter::Gain), -12.0);
+        assert!(response(&eq, 16000.0) < db_to_gain(-10.0));
+        assert!((response(&eq, 100.0) - 1.0).abs() < 0.05);
+        assert_eq!(BandParameter::from_index(NUM_BANDS * 3), None);
+    }
+
+    #[test]
+    fn declared_parameters_match_the_settings() {
+        super::super::check_parameters(&mut Equalizer::new(44100.0));
+    }
+}
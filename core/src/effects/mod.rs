@@ -0,0 +1,82 @@
+//! Native audio effects. Effects process the track audio in place and take their settings through
+//! `PluginInstance::set_parameter`, using the indices of their parameter enum.
+
+pub mod compressor;
+pub mod delay;
+pub mod eq;
+pub mod reverb;
+pub mod utility;
+
+/// Convert a level in decibels to a gain.
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Convert a gain to a level in decibels. Silence is clamped to -120 dB.
+fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.max(1e-6).log10()
+}
+
+/// The coefficient of a one pole smoother that gets about 63% of the way to its target in
+/// `seconds`. Returns 0, which jumps straight to the target, for times shorter than a frame.
+fn smoothing_coefficient(seconds: f32, sample_rate: f32) -> f32 {
+    let frames = seconds * sample_rate;
+    if frames < 1.0 {
+        0.0
+    } else {
+        (-1.0 / frames).exp()
+    }
+}
+
+/// Interpret a parameter value as a switch.
+fn is_on(value: f32) -> bool {
+    value >= 0.5
+}
+
+/// The parameter value of a switch.
+fn from_switch(on: bool) -> f32 {
+    if on {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+fn sine(frequency: f32, sample_rate: f32, frames: usize) -> Vec<f32> {
+    (0..frames)
+        .map(|i| (2.0 * std::f32::consts::PI * frequency * i as f32 / sample_rate).sin())
+        .collect()
+}
+
+#[cfg(test)]
+fn peak(samples: &[f32]) -> f32 {
+    samples.iter().fold(0.0, |p, s| p.max(s.abs()))
+}
+
+/// Check that the declared parameters of `plugin` start at their default and are clamped to their
+/// range.
+#[cfg(test)]
+pub(crate) fn check_parameters(plugin: &mut dyn crate::plugin::PluginInstance) {
+    let parameters = plugin.parameters();
+    assert!(!parameters.is_empty());
+    for (index, info) in parameters.iter().enumerate() {
+        assert_eq!(info.index, index);
+        assert!(
+            info.min <= info.default && info.default <= info.max,
+            "{:?}",
+            info
+        );
+        assert_eq!(
+            plugin.get_parameter(index),
+            Some(info.default),
+            "{:?}",
+            info
+        );
+        plugin.set_parameter(index, info.min - 1000.0);
+        assert_eq!(plugin.get_parameter(index), Some(info.min), "{:?}", info);
+        plugin.set_parameter(index, info.max + 1000.0);
+        assert_eq!(plugin.get_parameter(index), Some(info.max), "{:?}", info);
+    }
+    assert_eq!(plugin.get_parameter(parameters.len()), None);
+}
@@ -0,0 +1,246 @@
+use crate::plugin::{ParameterInfo, PluginInstance};
+use crate::TimedMidi;
+
+/// The lengths of the comb filters at 44.1 kHz.
+const COMB_LENGTHS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+
+/// The lengths of the allpass filters at 44.1 kHz.
+const ALLPASS_LENGTHS: [usize; 4] = [556, 441, 341, 225];
+
+/// How much longer the filters of the right channel are, to decorrelate the channels.
+const STEREO_SPREAD: usize = 23;
+
+/// The gain of the input to the filters.
+const INPUT_GAIN: f32 = 0.015;
+
+/// The gain that makes the wet signal about as loud as the input.
+const WET_GAIN: f32 = 3.0;
+
+/// The parameters of the reverb, in the order of their indices for `set_parameter`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ReverbParameter {
+    /// The size of the room from 0 to 1. Larger rooms ring longer.
+    RoomSize,
+    /// How quickly high frequencies die out, from 0 to 1.
+    Damping,
+    /// The stereo width of the reverb from 0 (mono) to 1.
+    Width,
+    /// The level of the reverb from 0 to 1. The level of the input is `1 - mix`.
+    Mix,
+}
+
+impl ReverbParameter {
+    pub const ALL: [ReverbParameter; 4] = [
+        ReverbParameter::RoomSize,
+        ReverbParameter::Damping,
+        ReverbParameter::Width,
+        ReverbParameter::Mix,
+    ];
+
+    pub fn from_index(index: usize) -> Option<ReverbParameter> {
+        ReverbParameter::ALL.get(index).copied()
+    }
+
+    pub fn info(self) -> ParameterInfo {
+        let info =
+            |id, name, default| ParameterInfo::new(self as usize, id, name, 0.0, 1.0, default);
+        match self {
+            ReverbParameter::RoomSize => info("room_size", "Room Size", 0.5),
+            ReverbParameter::Damping => info("damping", "Damping", 0.5),
+            ReverbParameter::Width => info("width", "Width", 1.0),
+            ReverbParameter::Mix => info("mix", "Mix", 0.25),
+        }
+    }
+}
+
+/// A low pass feedback comb filter.
+#[derive(Clone, Debug)]
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    filtered: f32,
+}
+
+impl Comb {
+    fn new(len: usize) -> Comb {
+        Comb {
+            buffer: vec![0.0; len.max(1)],
+            index: 0,
+            filtered: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.filtered = output * (1.0 - damping) + self.filtered * damping;
+        self.buffer[self.index] = input + self.filtered * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn new(len: usize) -> Allpass {
+        Allpass {
+            buffer: vec![0.0; len.max(1)],
+            index: 0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.index];
+        self.buffer[self.index] = input + buffered * 0.5;
+        self.index = (self.index + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+/// The filters of one channel.
+#[derive(Clone, Debug)]
+struct Channel {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl Channel {
+    fn new(sample_rate: f32, spread: usize) -> Channel {
+        let scale = |len: usize| ((len + spread) as f32 * sample_rate / 44100.0) as usize;
+        Channel {
+            combs: COMB_LENGTHS.iter().map(|l| Comb::new(scale(*l))).collect(),
+            allpasses: ALLPASS_LENGTHS
+                .iter()
+                .map(|l| Allpass::new(scale(*l)))
+                .collect(),
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let mut output = 0.0;
+        for comb in self.combs.iter_mut() {
+            output += comb.process(input, feedback, damping);
+        }
+        for allpass in self.allpasses.iter_mut() {
+            output = allpass.process(output);
+        }
+        output
+    }
+}
+
+/// An algorithmic stereo reverb made of parallel comb filters followed by allpass filters, after
+/// the Freeverb design.
+#[derive(Clone, Debug)]
+pub struct Reverb {
+    room_size: f32,
+    damping: f32,
+    width: f32,
+    mix: f32,
+    left: Channel,
+    right: Channel,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: f32) -> Reverb {
+        let default = |p: ReverbParameter| p.info().default;
+        Reverb {
+            room_size: default(ReverbParameter::RoomSize),
+            damping: default(ReverbParameter::Damping),
+            width: default(ReverbParameter::Width),
+            mix: default(ReverbParameter::Mix),
+            left: Channel::new(sample_rate, 0),
+            right: Channel::new(sample_rate, STEREO_SPREAD),
+        }
+    }
+}
+
+impl PluginInstance for Reverb {
+    fn process(&mut self, _: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+        let feedback = 0.7 + 0.28 * self.room_size;
+        let damping = 0.4 * self.damping;
+        let wet = self.mix * WET_GAIN;
+        let wet_same = wet * (0.5 + 0.5 * self.width);
+        let wet_other = wet * (0.5 - 0.5 * self.width);
+        let dry = 1.0 - self.mix;
+        for (l, r) in out_left.iter_mut().zip(out_right.iter_mut()) {
+            let input = (*l + *r) * INPUT_GAIN;
+            let reverb_left = self.left.process(input, feedback, damping);
+            let reverb_right = self.right.process(input, feedback, damping);
+            *l = *l * dry + reverb_left * wet_same + reverb_right * wet_other;
+            *r = *r * dry + reverb_right * wet_same + reverb_left * wet_other;
+        }
+    }
+
+    fn parameters(&self) -> Vec<ParameterInfo> {
+        ReverbParameter::ALL.iter().map(|p| p.info()).collect()
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        let value = value.clamp(0.0, 1.0);
+        match ReverbParameter::from_index(index) {
+            Some(ReverbParameter::RoomSize) => self.room_size = value,
+            Some(ReverbParameter::Damping) => self.damping = value,
+            Some(ReverbParameter::Width) => self.width = value,
+            Some(ReverbParameter::Mix) => self.mix = value,
+            None => (),
+        }
+    }
+
+    fn get_parameter(&self, index: usize) -> Option<f32> {
+        match ReverbParameter::from_index(index)? {
+            ReverbParameter::RoomSize => Some(self.room_size),
+            ReverbParameter::Damping => Some(self.damping),
+            ReverbParameter::Width => Some(self.width),
+            ReverbParameter::Mix => Some(self.mix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::peak;
+    use super::*;
+
+    fn impulse_response(reverb: &mut Reverb, frames: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut left = vec![0.0; frames];
+        left[0] = 1.0;
+        let mut right = left.clone();
+        reverb.process(&[], &mut left, &mut right);
+        (left, right)
+    }
+
+    #[test]
+    fn impulses_leave_a_decaying_stereo_tail() {
+        let mut reverb = Reverb::new(44100.0);
+        reverb.set_parameter(ReverbParameter::Mix as usize, 1.0);
+        let (left, right) = impulse_response(&mut reverb, 88200);
+        assert!(left.iter().chain(right.iter()).all(|s| s.is_finite()));
+        assert!(peak(&left[4410..8820]) > 1e-3);
+        assert!(peak(&left[80000..]) < peak(&left[4410..8820]));
+        assert_ne!(left, right);
+
+        let mut larger = Reverb::new(44100.0);
+        larger.set_parameter(ReverbParameter::Mix as usize, 1.0);
+        larger.set_parameter(ReverbParameter::RoomSize as usize, 1.0);
+        let (larger_left, _) = impulse_response(&mut larger, 88200);
+        assert!(peak(&larger_left[80000..]) > peak(&left[80000..]));
+    }
+
+    #[test]
+    fn dry_signal_passes_without_reverb() {
+        let mut reverb = Reverb::new(44100.0);
+        reverb.set_parameter(ReverbParameter::Mix as usize, 0.0);
+        let (left, _) = impulse_response(&mut reverb, 1000);
+        assert_eq!(left[0], 1.0);
+        assert!(left[1..].iter().all(|s| *s == 0.0));
+    }
+
+    #[test]
+    fn declared_parameters_match_the_settings() {
+        super::super::check_parameters(&mut Reverb::new(44100.0));
+    }
+}
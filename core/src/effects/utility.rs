@@ -0,0 +1,149 @@
+use super::{db_to_gain, from_switch, is_on};
+use crate::plugin::{ParameterInfo, PluginInstance};
+use crate::TimedMidi;
+
+/// The parameters of the utility, in the order of their indices for `set_parameter`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UtilityParameter {
+    /// The gain in decibels.
+    Gain,
+    /// The balance from -1 (left) to 1 (right).
+    Pan,
+    /// The stereo width from 0 (mono) through 1 (unchanged) to 2 (extra wide).
+    Width,
+    /// 1 to invert the polarity of the left channel.
+    InvertLeft,
+    /// 1 to invert the polarity of the right channel.
+    InvertRight,
+}
+
+impl UtilityParameter {
+    pub const ALL: [UtilityParameter; 5] = [
+        UtilityParameter::Gain,
+        UtilityParameter::Pan,
+        UtilityParameter::Width,
+        UtilityParameter::InvertLeft,
+        UtilityParameter::InvertRight,
+    ];
+
+    pub fn from_index(index: usize) -> Option<UtilityParameter> {
+        UtilityParameter::ALL.get(index).copied()
+    }
+
+    pub fn info(self) -> ParameterInfo {
+        let index = self as usize;
+        match self {
+            UtilityParameter::Gain => {
+                ParameterInfo::new(index, "gain", "Gain", -60.0, 24.0, 0.0).with_unit("dB")
+            }
+            UtilityParameter::Pan => ParameterInfo::new(index, "pan", "Pan", -1.0, 1.0, 0.0),
+            UtilityParameter::Width => ParameterInfo::new(index, "width", "Width", 0.0, 2.0, 1.0),
+            UtilityParameter::InvertLeft => {
+                ParameterInfo::toggle(index, "invert_left", "Invert Left", false)
+            }
+            UtilityParameter::InvertRight => {
+                ParameterInfo::toggle(index, "invert_right", "Invert Right", false)
+            }
+        }
+    }
+}
+
+/// Gain, balance, stereo width and polarity.
+#[derive(Copy, Clone, Debug)]
+pub struct Utility {
+    gain: f32,
+    pan: f32,
+    width: f32,
+    invert_left: bool,
+    invert_right: bool,
+}
+
+impl Default for Utility {
+    fn default() -> Utility {
+        Utility {
+            gain: 0.0,
+            pan: 0.0,
+            width: 1.0,
+            invert_left: false,
+            invert_right: false,
+        }
+    }
+}
+
+impl PluginInstance for Utility {
+    fn process(&mut self, _: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+        let gain = db_to_gain(self.gain);
+        let polarity = |invert| if invert { -1.0 } else { 1.0 };
+        let left_gain = gain * (1.0 - self.pan).min(1.0) * polarity(self.invert_left);
+        let right_gain = gain * (1.0 + self.pan).min(1.0) * polarity(self.invert_right);
+        for (l, r) in out_left.iter_mut().zip(out_right.iter_mut()) {
+            let mid = 0.5 * (*l + *r);
+            let side = 0.5 * (*l - *r) * self.width;
+            *l = (mid + side) * left_gain;
+            *r = (mid - side) * right_gain;
+        }
+    }
+
+    fn parameters(&self) -> Vec<ParameterInfo> {
+        UtilityParameter::ALL.iter().map(|p| p.info()).collect()
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match UtilityParameter::from_index(index) {
+            Some(UtilityParameter::Gain) => self.gain = value.clamp(-60.0, 24.0),
+            Some(UtilityParameter::Pan) => self.pan = value.clamp(-1.0, 1.0),
+            Some(UtilityParameter::Width) => self.width = value.clamp(0.0, 2.0),
+            Some(UtilityParameter::InvertLeft) => self.invert_left = is_on(value),
+            Some(UtilityParameter::InvertRight) => self.invert_right = is_on(value),
+            None => (),
+        }
+    }
+
+    fn get_parameter(&self, index: usize) -> Option<f32> {
+        match UtilityParameter::from_index(index)? {
+            UtilityParameter::Gain => Some(self.gain),
+            UtilityParameter::Pan => Some(self.pan),
+            UtilityParameter::Width => Some(self.width),
+            UtilityParameter::InvertLeft => Some(from_switch(self.invert_left)),
+            UtilityParameter::InvertRight => Some(from_switch(self.invert_right)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(utility: &mut Utility, left: f32, right: f32) -> (f32, f32) {
+        let (mut l, mut r) = ([left], [right]);
+        utility.process(&[], &mut l, &mut r);
+        (l[0], r[0])
+    }
+
+    #[test]
+    fn default_settings_pass_the_signal() {
+        assert_eq!(process(&mut Utility::default(), 0.5, -0.25), (0.5, -0.25));
+    }
+
+    #[test]
+    fn gain_pan_width_and_polarity() {
+        let mut utility = Utility::default();
+        utility.set_parameter(UtilityParameter::Width as usize, 0.0);
+        assert_eq!(process(&mut utility, 1.0, 0.0), (0.5, 0.5));
+
+        let mut utility = Utility::default();
+        utility.set_parameter(UtilityParameter::Pan as usize, 0.5);
+        utility.set_parameter(UtilityParameter::InvertRight as usize, 1.0);
+        assert_eq!(process(&mut utility, 1.0, 1.0), (0.5, -1.0));
+
+        let mut utility = Utility::default();
+        utility.set_parameter(UtilityParameter::Gain as usize, 20.0);
+        let (l, r) = process(&mut utility, 0.1, 0.1);
+        assert!((l - 1.0).abs() < 1e-5 && (r - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn declared_parameters_match_the_settings() {
+        super::super::check_parameters(&mut Utility::default());
+    }
+}
@@ -1,8 +1,37 @@
+pub mod automation;
+pub mod effects;
 pub mod example_plugin;
+pub mod metronome;
+pub mod midi_map;
+pub mod midi_sync;
 pub mod plugin;
+pub mod plugin_abi;
 pub mod processor;
+pub mod record;
+pub mod sampler;
+pub mod synth;
+pub mod tempo_map;
+pub mod transport;
 
+#[derive(Clone, Debug)]
 pub struct TimedMidi<'a> {
     pub frame: usize,
     pub message: wmidi::MidiMessage<'a>,
 }
+
+/// Clear `buffer` and reuse its allocation for MIDI messages with a different lifetime. This lets
+/// a buffer of borrowed messages be kept between process calls without allocating.
+pub fn recycle_midi_buffer<'a, 'b>(mut buffer: Vec<TimedMidi<'a>>) -> Vec<TimedMidi<'b>> {
+    buffer.clear();
+    let mut buffer = std::mem::ManuallyDrop::new(buffer);
+    // SAFETY: The vector is empty, so no message that borrows for `'a` can be reached through the
+    // returned vector. Lifetimes do not change the layout of `TimedMidi`, so the allocation has the
+    // size and alignment that `Vec<TimedMidi<'b>>` expects for the same capacity.
+    unsafe {
+        Vec::from_raw_parts(
+            buffer.as_mut_ptr().cast::<TimedMidi<'b>>(),
+            0,
+            buffer.capacity(),
+        )
+    }
+}
@@ -0,0 +1,341 @@
+use crate::tempo_map::TempoMap;
+use crate::transport::Transport;
+
+/// How long each click lasts.
+const CLICK_SECONDS: f32 = 0.05;
+
+/// The time it takes for a click to decay to about a third of its initial level.
+const CLICK_DECAY_SECONDS: f32 = 0.01;
+
+/// The waveform used to generate clicks.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ClickSound {
+    Sine,
+    Square,
+    Noise,
+}
+
+/// Where the metronome output is sent.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MetronomeOutput {
+    /// Mix the metronome into the master output.
+    Master,
+    /// Keep the metronome out of the master output. The IO backend may send it to a dedicated
+    /// output.
+    Dedicated,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MetronomeSettings {
+    /// If the metronome should click while the transport is playing. Count-in clicks are played
+    /// regardless.
+    pub enabled: bool,
+    pub sound: ClickSound,
+    /// The level of regular beats.
+    pub volume: f32,
+    /// The level of the first beat of each bar.
+    pub accent_volume: f32,
+    /// The pitch of regular beats. Not used for noise clicks.
+    pub frequency: f32,
+    /// The pitch of the first beat of each bar. Not used for noise clicks.
+    pub accent_frequency: f32,
+    /// The number of bars to count in before recording starts.
+    pub count_in_bars: u32,
+    pub output: MetronomeOutput,
+}
+
+impl Default for MetronomeSettings {
+    fn default() -> MetronomeSettings {
+        MetronomeSettings {
+            enabled: false,
+            sound: ClickSound::Sine,
+            volume: 0.5,
+            accent_volume: 0.8,
+            frequency: 1000.0,
+            accent_frequency: 1500.0,
+            count_in_bars: 0,
+            output: MetronomeOutput::Master,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Click {
+    frame: usize,
+    accent: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct CountIn {
+    // Frames elapsed since the count-in started.
+    elapsed: u64,
+    // The frame, relative to the start of the count-in, of the first click.
+    first_click: f64,
+    frames_per_beat: f64,
+    beats_per_bar: u32,
+    // The number of the next count-in click to play.
+    next_beat: u32,
+    beats: u32,
+}
+
+/// Generates clicks on every beat of the tempo map.
+#[derive(Debug)]
+pub struct Metronome {
+    settings: MetronomeSettings,
+    sample_rate: f32,
+    buffer: Vec<f32>,
+    click: Option<Click>,
+    count_in: Option<CountIn>,
+    noise_state: u32,
+}
+
+impl Metronome {
+    pub fn new(sample_rate: f32, buffer_size: usize, settings: MetronomeSettings) -> Metronome {
+        Metronome {
+            settings,
+            sample_rate,
+            buffer: vec![0.0; buffer_size],
+            click: None,
+            count_in: None,
+            noise_state: 1,
+        }
+    }
+
+    pub fn settings(&self) -> &MetronomeSettings {
+        &self.settings
+    }
+
+    /// The output of the last processed block.
+    pub fn output(&self) -> &[f32] {
+        &self.buffer
+    }
+
+    /// Start counting in. The count-in lasts exactly `frames` frames with the clicks placed so
+    /// that the beat after the last click lands on the first frame after the count-in.
+    pub(crate) fn start_count_in(&mut self, tempo_map: &TempoMap, frame: u64, frames: u64) {
+        let tempo = tempo_map.tempo_at_frame(frame as f64);
+        let frames_per_beat = tempo_map.frames_per_beat_at_frame(frame as f64);
+        let beats = self.settings.count_in_bars * tempo.beats_per_bar;
+        self.count_in = Some(CountIn {
+            elapsed: 0,
+            first_click: frames as f64 - f64::from(beats) * frames_per_beat,
+            frames_per_beat,
+            beats_per_bar: tempo.beats_per_bar,
+            next_beat: 0,
+            beats,
+        });
+    }
+
+    /// The exact number of frames that the count-in lasts when starting at `frame`.
+    pub(crate) fn count_in_frames(&self, tempo_map: &TempoMap, frame: u64) -> f64 {
+        let tempo = tempo_map.tempo_at_frame(frame as f64);
+        let beats = self.settings.count_in_bars * tempo.beats_per_bar;
+        f64::from(beats) * tempo_map.frames_per_beat_at_frame(frame as f64)
+    }
+
+    /// Render the clicks for the next `frames` frames into the output buffer, starting `offset`
+    /// frames into the buffer. Blocks that are processed in parts render each part at its offset.
+    pub(crate) fn process(
+        &mut self,
+        transport: &Transport,
+        tempo_map: &TempoMap,
+        offset: usize,
+        frames: usize,
+    ) {
+        let offset = offset.min(self.buffer.len());
+        let frames = frames.min(self.buffer.len() - offset);
+        if transport.is_counting_in() {
+            self.process_count_in(offset, frames);
+        } else {
+            self.count_in = None;
+            if self.settings.enabled && transport.is_playing() {
+                self.process_beats(transport.frame(), tempo_map, offset, frames);
+            } else {
+                self.render(offset, frames, |_| None);
+            }
+        }
+    }
+
+    fn process_beats(&mut self, start: u64, tempo_map: &TempoMap, offset: usize, frames: usize) {
+        let start = start as f64;
+        let mut next_beat = tempo_map.beat_at_frame(start).ceil();
+        let mut next_click = tempo_map.frame_at_beat(next_beat) - start;
+        self.render(offset, frames, |frame| {
+            if frame as f64 >= next_click {
+                let accent = tempo_map.position_at_beat(next_beat).beat == 0;
+                next_beat += 1.0;
+                next_click = tempo_map.frame_at_beat(next_beat) - start;
+                Some(accent)
+            } else {
+                None
+            }
+        });
+    }
+
+    fn process_count_in(&mut self, offset: usize, frames: usize) {
+        let mut count_in = match self.count_in {
+            Some(c) => c,
+            None => return self.render(offset, frames, |_| None),
+        };
+        self.render(offset, frames, |frame| {
+            let elapsed = (count_in.elapsed + frame as u64) as f64;
+            let next_click =
+                count_in.first_click + f64::from(count_in.next_beat) * count_in.frames_per_beat;
+            if count_in.next_beat < count_in.beats && elapsed >= next_click {
+                let accent = count_in.next_beat % count_in.beats_per_bar == 0;
+                count_in.next_beat += 1;
+                Some(accent)
+            } else {
+                None
+            }
+        });
+        count_in.elapsed += frames as u64;
+        self.count_in = Some(count_in);
+    }
+
+    /// Render `frames` frames starting `offset` frames into the buffer. `trigger` is called for
+    /// each frame and returns `Some(accent)` if a click should start on that frame.
+    fn render<F: FnMut(usize) -> Option<bool>>(
+        &mut self,
+        offset: usize,
+        frames: usize,
+        mut trigger: F,
+    ) {
+        let click_frames = (CLICK_SECONDS * self.sample_rate) as usize;
+        for frame in 0..frames {
+            if let Some(accent) = trigger(frame) {
+                self.click = Some(Click { frame: 0, accent });
+            }
+            self.buffer[offset + frame] = match self.click {
+                Some(c) if c.frame < click_frames => {
+                    self.click = Some(Click {
+                        frame: c.frame + 1,
+                        accent: c.accent,
+                    });
+                    self.click_sample(c)
+                }
+                _ => {
+                    self.click = None;
+                    0.0
+                }
+            };
+        }
+    }
+
+    fn click_sample(&mut self, click: Click) -> f32 {
+        let (volume, frequency) = if click.accent {
+            (self.settings.accent_volume, self.settings.accent_frequency)
+        } else {
+            (self.settings.volume, self.settings.frequency)
+        };
+        let t = click.frame as f32 / self.sample_rate;
+        let envelope = (-t / CLICK_DECAY_SECONDS).exp();
+        let phase = 2.0 * std::f32::consts::PI * frequency * t;
+        let wave = match self.settings.sound {
+            ClickSound::Sine => phase.sin(),
+            ClickSound::Square => phase.sin().signum(),
+            ClickSound::Noise => self.next_noise(),
+        };
+        volume * envelope * wave
+    }
+
+    /// Generate white noise in the range [-1, 1] using a xorshift generator.
+    fn next_noise(&mut self) -> f32 {
+        let mut x = self.noise_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise_state = x;
+        (x as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+impl Default for Metronome {
+    fn default() -> Metronome {
+        Metronome::new(44100.0, 0, MetronomeSettings::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn click_starts(output: &[f32]) -> Vec<usize> {
+        output
+            .iter()
+            .enumerate()
+            .filter(|(i, v)| **v != 0.0 && (*i == 0 || output[i - 1] == 0.0))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn settings() -> MetronomeSettings {
+        MetronomeSettings {
+            enabled: true,
+            sound: ClickSound::Square,
+            ..MetronomeSettings::default()
+        }
+    }
+
+    #[test]
+    fn clicks_on_every_beat_while_playing() {
+        // 1000 frames per second at 120 bpm is 500 frames per beat. Clicks last 50 frames.
+        let tempo_map = TempoMap::new(1000.0, 120.0, 4);
+        let mut m = Metronome::new(1000.0, 2000, settings());
+        let mut t = Transport::new();
+        t.set_playing(true);
+        t.locate(250);
+        m.process(&t, &tempo_map, 0, 2000);
+        assert_eq!(click_starts(m.output()), vec![250, 750, 1250, 1750]);
+    }
+
+    #[test]
+    fn downbeats_are_accented() {
+        let tempo_map = TempoMap::new(1000.0, 120.0, 2);
+        let mut m = Metronome::new(1000.0, 1000, settings());
+        let mut t = Transport::new();
+        t.set_playing(true);
+        m.process(&t, &tempo_map, 0, 1000);
+        assert_eq!(m.output()[0], m.settings().accent_volume);
+        assert_eq!(m.output()[500], m.settings().volume);
+    }
+
+    #[test]
+    fn disabled_metronome_is_silent() {
+        let tempo_map = TempoMap::new(1000.0, 120.0, 4);
+        let mut m = Metronome::new(
+            1000.0,
+            1000,
+            MetronomeSettings {
+                enabled: false,
+                ..settings()
+            },
+        );
+        let mut t = Transport::new();
+        t.set_playing(true);
+        m.process(&t, &tempo_map, 0, 1000);
+        assert!(m.output().iter().all(|v| *v == 0.0));
+    }
+
+    #[test]
+    fn count_in_ends_on_beat() {
+        let tempo_map = TempoMap::new(1000.0, 120.0, 2);
+        let mut m = Metronome::new(
+            1000.0,
+            1200,
+            MetronomeSettings {
+                enabled: false,
+                count_in_bars: 1,
+                ..settings()
+            },
+        );
+        let mut t = Transport::new();
+        t.set_playing(true);
+        t.start_count_in(1200);
+        m.start_count_in(&tempo_map, 0, 1200);
+        m.process(&t, &tempo_map, 0, 1200);
+        // 2 beats of 500 frames, ending after 1200 frames.
+        assert_eq!(click_starts(m.output()), vec![200, 700]);
+        assert_eq!(m.output()[200], m.settings().accent_volume);
+    }
+}
@@ -0,0 +1,112 @@
+use wmidi::MidiMessage;
+
+/// A MIDI controller that can be mapped to a value. Channels are zero based.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MidiSource {
+    ControlChange { channel: u8, controller: u8 },
+    PitchBend { channel: u8 },
+    ChannelPressure { channel: u8 },
+}
+
+impl MidiSource {
+    /// The source of `message` and its value from 0 to 1, or `None` if the message can not be
+    /// mapped.
+    pub fn from_message(message: &MidiMessage<'_>) -> Option<(MidiSource, f32)> {
+        match message {
+            MidiMessage::ControlChange(channel, controller, value) => Some((
+                MidiSource::ControlChange {
+                    channel: channel.index(),
+                    controller: u8::from(*controller),
+                },
+                f32::from(u8::from(*value)) / 127.0,
+            )),
+            MidiMessage::PitchBendChange(channel, bend) => Some((
+                MidiSource::PitchBend {
+                    channel: channel.index(),
+                },
+                f32::from(u16::from(*bend)) / 16383.0,
+            )),
+            MidiMessage::ChannelPressure(channel, pressure) => Some((
+                MidiSource::ChannelPressure {
+                    channel: channel.index(),
+                },
+                f32::from(u8::from(*pressure)) / 127.0,
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The value that a MIDI mapping controls.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MidiTarget {
+    TrackVolume {
+        track_index: usize,
+    },
+    TrackPan {
+        track_index: usize,
+    },
+    Parameter {
+        track_index: usize,
+        plugin_index: usize,
+        parameter: usize,
+    },
+}
+
+/// Binds a MIDI controller to a value.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MidiMapping {
+    pub source: MidiSource,
+    pub target: MidiTarget,
+    /// The value for the lowest MIDI value.
+    pub min: f32,
+    /// The value for the highest MIDI value.
+    pub max: f32,
+}
+
+impl MidiMapping {
+    /// The target value for a MIDI value from 0 to 1.
+    pub fn value(&self, midi_value: f32) -> f32 {
+        self.min + (self.max - self.min) * midi_value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn sources_are_read_from_messages() {
+        let cc = MidiMessage::try_from([0xB2, 7, 127].as_ref()).unwrap();
+        let bend = MidiMessage::try_from([0xE0, 0x00, 0x40].as_ref()).unwrap();
+        let note = MidiMessage::try_from([0x90, 60, 100].as_ref()).unwrap();
+        assert_eq!(
+            MidiSource::from_message(&cc),
+            Some((
+                MidiSource::ControlChange {
+                    channel: 2,
+                    controller: 7
+                },
+                1.0
+            ))
+        );
+        let (source, value) = MidiSource::from_message(&bend).unwrap();
+        assert_eq!(source, MidiSource::PitchBend { channel: 0 });
+        assert!((value - 0.5).abs() < 0.001);
+        assert_eq!(MidiSource::from_message(&note), None);
+    }
+
+    #[test]
+    fn mapping_scales_to_range() {
+        let mapping = MidiMapping {
+            source: MidiSource::PitchBend { channel: 0 },
+            target: MidiTarget::TrackPan { track_index: 0 },
+            min: -1.0,
+            max: 1.0,
+        };
+        assert_eq!(mapping.value(0.0), -1.0);
+        assert_eq!(mapping.value(0.5), 0.0);
+        assert_eq!(mapping.value(1.0), 1.0);
+    }
+}
@@ -0,0 +1,510 @@
+use crate::tempo_map::TempoMap;
+use crate::transport::Transport;
+use crate::TimedMidi;
+use std::convert::TryFrom;
+
+/// The number of MIDI clock messages per beat.
+pub const CLOCKS_PER_BEAT: f64 = 24.0;
+
+/// The largest song position that can be sent in a song position pointer.
+const MAX_SONG_POSITION: u16 = 0x3fff;
+
+/// How much weight the previous tempo estimate has when receiving a new clock message.
+const TEMPO_SMOOTHING: f64 = 0.9;
+
+/// Frame rates for MIDI time code.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MtcFrameRate {
+    Fps24,
+    Fps25,
+    Fps30,
+}
+
+impl MtcFrameRate {
+    fn fps(self) -> u32 {
+        match self {
+            MtcFrameRate::Fps24 => 24,
+            MtcFrameRate::Fps25 => 25,
+            MtcFrameRate::Fps30 => 30,
+        }
+    }
+
+    /// The rate code sent in the last quarter frame message.
+    fn code(self) -> u8 {
+        match self {
+            MtcFrameRate::Fps24 => 0,
+            MtcFrameRate::Fps25 => 1,
+            MtcFrameRate::Fps30 => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<MtcFrameRate> {
+        match code {
+            0 => Some(MtcFrameRate::Fps24),
+            1 => Some(MtcFrameRate::Fps25),
+            3 => Some(MtcFrameRate::Fps30),
+            _ => None,
+        }
+    }
+}
+
+/// Generates MIDI clock, song position and MIDI time code messages that follow the transport.
+#[derive(Debug, Default)]
+pub struct MidiSyncGenerator {
+    clock: bool,
+    mtc: Option<MtcFrameRate>,
+    was_playing: bool,
+    last_frame: u64,
+}
+
+impl MidiSyncGenerator {
+    pub fn new(clock: bool, mtc: Option<MtcFrameRate>) -> MidiSyncGenerator {
+        MidiSyncGenerator {
+            clock,
+            mtc,
+            was_playing: false,
+            last_frame: 0,
+        }
+    }
+
+    /// Generate the sync messages for a block of `frames` frames that starts at the current
+    /// transport position. Messages are appended to `out` as long as it has capacity.
+    pub fn process(
+        &mut self,
+        transport: &Transport,
+        tempo_map: &TempoMap,
+        frames: usize,
+        out: &mut Vec<TimedMidi<'static>>,
+    ) {
+        let playing = transport.is_playing() && !transport.is_counting_in();
+        let frame = transport.frame();
+        let start_len = out.len();
+        if self.clock {
+            let beat = tempo_map.beat_at_frame(frame as f64);
+            if playing && !self.was_playing {
+                if frame == 0 {
+                    push(out, 0, wmidi::MidiMessage::Start);
+                } else {
+                    push(out, 0, song_position_pointer(beat));
+                    push(out, 0, wmidi::MidiMessage::Continue);
+                }
+            } else if !playing && self.was_playing {
+                push(out, 0, wmidi::MidiMessage::Stop);
+            } else if !playing && frame != self.last_frame {
+                push(out, 0, song_position_pointer(beat));
+            }
+            if playing {
+                let start = frame as f64;
+                let mut pulse = (beat * CLOCKS_PER_BEAT).ceil();
+                loop {
+                    let offset = tempo_map.frame_at_beat(pulse / CLOCKS_PER_BEAT) - start;
+                    if offset >= frames as f64 {
+                        break;
+                    }
+                    push(
+                        out,
+                        offset.max(0.0) as usize,
+                        wmidi::MidiMessage::TimingClock,
+                    );
+                    pulse += 1.0;
+                }
+            }
+        }
+        if let (Some(rate), true) = (self.mtc, playing) {
+            let sample_rate = tempo_map.sample_rate();
+            let quarter_frames_per_second = f64::from(rate.fps() * 4);
+            let start = frame as f64;
+            let mut quarter_frame = (start / sample_rate * quarter_frames_per_second).ceil() as u64;
+            loop {
+                let offset = quarter_frame as f64 / quarter_frames_per_second * sample_rate - start;
+                if offset >= frames as f64 {
+                    break;
+                }
+                let message = quarter_frame_message(rate, quarter_frame);
+                push(out, offset.max(0.0) as usize, message);
+                quarter_frame += 1;
+            }
+        }
+        // The clock and MTC messages are generated separately but MIDI outputs require messages in
+        // order.
+        sort_by_frame(&mut out[start_len..]);
+        self.was_playing = playing;
+        self.last_frame = frame;
+    }
+}
+
+fn push(out: &mut Vec<TimedMidi<'static>>, frame: usize, message: wmidi::MidiMessage<'static>) {
+    if out.len() < out.capacity() {
+        out.push(TimedMidi { frame, message });
+    }
+}
+
+/// Sort `out` by frame without allocating. Messages on the same frame keep their order.
+fn sort_by_frame(out: &mut [TimedMidi<'static>]) {
+    for i in 1..out.len() {
+        let mut j = i;
+        while j > 0 && out[j - 1].frame > out[j].frame {
+            out.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+}
+
+fn song_position_pointer(beat: f64) -> wmidi::MidiMessage<'static> {
+    // Song position is measured in 16th notes.
+    let position = (beat * 4.0).max(0.0).min(f64::from(MAX_SONG_POSITION)) as u16;
+    wmidi::MidiMessage::SongPositionPointer(wmidi::U14::try_from(position).unwrap())
+}
+
+/// Build the quarter frame message with the given index. Each group of 8 quarter frames carries
+/// the time code of the frame at which the group started.
+fn quarter_frame_message(rate: MtcFrameRate, quarter_frame: u64) -> wmidi::MidiMessage<'static> {
+    let piece = (quarter_frame % 8) as u8;
+    let fps = u64::from(rate.fps());
+    let frame = (quarter_frame - quarter_frame % 8) / 4;
+    let frames = (frame % fps) as u8;
+    let seconds = (frame / fps % 60) as u8;
+    let minutes = (frame / fps / 60 % 60) as u8;
+    let hours = (frame / fps / 3600 % 24) as u8;
+    let nibble = match piece {
+        0 => frames & 0xf,
+        1 => frames >> 4,
+        2 => seconds & 0xf,
+        3 => seconds >> 4,
+        4 => minutes & 0xf,
+        5 => minutes >> 4,
+        6 => hours & 0xf,
+        _ => (hours >> 4) | (rate.code() << 1),
+    };
+    wmidi::MidiMessage::MidiTimeCode(wmidi::U7::try_from((piece << 4) | nibble).unwrap())
+}
+
+/// The external source to follow.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SyncSource {
+    /// Follow MIDI clock, start, stop, continue and song position pointer messages.
+    MidiClock,
+    /// Follow the position of MIDI time code quarter frame messages.
+    Mtc,
+}
+
+/// Drives the transport from incoming MIDI clock or MIDI time code.
+#[derive(Debug)]
+pub struct MidiSyncFollower {
+    source: SyncSource,
+    // The number of frames that have been processed. Used to measure the time between clock
+    // messages.
+    elapsed: u64,
+    last_clock: Option<u64>,
+    frames_per_clock: Option<f64>,
+    // The beat at the last song position pointer or start.
+    start_beat: f64,
+    clocks: u64,
+    // The pieces of the time code being received.
+    mtc_pieces: [u8; 8],
+    mtc_received: u8,
+    // The frame at which the last quarter frame was received.
+    last_quarter_frame: Option<u64>,
+}
+
+impl MidiSyncFollower {
+    pub fn new(source: SyncSource) -> MidiSyncFollower {
+        MidiSyncFollower {
+            source,
+            elapsed: 0,
+            last_clock: None,
+            frames_per_clock: None,
+            start_beat: 0.0,
+            clocks: 0,
+            mtc_pieces: [0; 8],
+            mtc_received: 0,
+            last_quarter_frame: None,
+        }
+    }
+
+    pub fn source(&self) -> SyncSource {
+        self.source
+    }
+
+    /// Update the transport and tempo map from the sync messages in `midi`.
+    pub fn process(
+        &mut self,
+        midi: &[TimedMidi<'_>],
+        frames: usize,
+        transport: &mut Transport,
+        tempo_map: &mut TempoMap,
+    ) {
+        match self.source {
+            SyncSource::MidiClock => self.follow_clock(midi, transport, tempo_map),
+            SyncSource::Mtc => self.follow_mtc(midi, frames, transport, tempo_map),
+        }
+        self.elapsed += frames as u64;
+    }
+
+    fn follow_clock(
+        &mut self,
+        midi: &[TimedMidi<'_>],
+        transport: &mut Transport,
+        tempo_map: &mut TempoMap,
+    ) {
+        for m in midi.iter() {
+            match m.message {
+                wmidi::MidiMessage::Start => {
+                    self.start_beat = 0.0;
+                    self.clocks = 0;
+                    transport.locate(0);
+                    transport.set_playing(true);
+                }
+                wmidi::MidiMessage::Continue => transport.set_playing(true),
+                wmidi::MidiMessage::Stop => {
+                    self.last_clock = None;
+                    transport.set_playing(false);
+                }
+                wmidi::MidiMessage::SongPositionPointer(position) => {
+                    self.start_beat = f64::from(u16::from(position)) / 4.0;
+                    self.clocks = 0;
+                    let frame = tempo_map.frame_at_beat(self.start_beat).max(0.0);
+                    transport.locate(frame as u64);
+                }
+                wmidi::MidiMessage::TimingClock => {
+                    let now = self.elapsed + m.frame as u64;
+                    // Clocks on the same frame carry no tempo information.
+                    if let Some(last) = self.last_clock.filter(|last| *last < now) {
+                        let frames_per_clock = (now - last) as f64;
+                        let estimate = match self.frames_per_clock {
+                            Some(f) => {
+                                TEMPO_SMOOTHING * f + (1.0 - TEMPO_SMOOTHING) * frames_per_clock
+                            }
+                            None => frames_per_clock,
+                        };
+                        self.frames_per_clock = Some(estimate);
+                    }
+                    self.last_clock = Some(now);
+                    if transport.is_playing() {
+                        self.clocks += 1;
+                    }
+                    if let Some(frames_per_clock) = self.frames_per_clock {
+                        let bpm =
+                            60.0 * tempo_map.sample_rate() / (frames_per_clock * CLOCKS_PER_BEAT);
+                        let beats_per_bar = tempo_map
+                            .tempo_at_frame(transport.frame() as f64)
+                            .beats_per_bar;
+                        let beat = self.start_beat + self.clocks as f64 / CLOCKS_PER_BEAT;
+                        let frame = transport.frame() as f64 + m.frame as f64;
+                        if bpm.is_finite() && bpm > 0.0 {
+                            tempo_map.follow(bpm, beats_per_bar, frame, beat);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn follow_mtc(
+        &mut self,
+        midi: &[TimedMidi<'_>],
+        frames: usize,
+        transport: &mut Transport,
+        tempo_map: &TempoMap,
+    ) {
+        for m in midi.iter() {
+            if let wmidi::MidiMessage::MidiTimeCode(data) = m.message {
+                let data = u8::from(data);
+                let piece = (data >> 4) as usize;
+                if piece >= 8 {
+                    continue;
+                }
+                self.mtc_pieces[piece] = data & 0xf;
+                self.last_quarter_frame = Some(self.elapsed + m.frame as u64);
+                if piece == 0 {
+                    self.mtc_received = 1;
+                } else if self.mtc_received as usize == piece {
+                    self.mtc_received += 1;
+                }
+                if self.mtc_received == 8 {
+                    self.mtc_received = 0;
+                    if let Some(seconds) = mtc_seconds(&self.mtc_pieces) {
+                        // The time code refers to when piece 0 was sent, which was 7 quarter
+                        // frames ago.
+                        let rate = MtcFrameRate::from_code(self.mtc_pieces[7] >> 1).unwrap();
+                        let seconds = seconds + 7.0 / f64::from(4 * rate.fps());
+                        let frame = seconds * tempo_map.sample_rate() - m.frame as f64;
+                        transport.locate(frame.max(0.0) as u64);
+                        transport.set_playing(true);
+                    }
+                }
+            }
+        }
+        // Time code that has not been received for a while means the source has stopped.
+        let timeout = (tempo_map.sample_rate() / 10.0) as u64;
+        if let Some(last) = self.last_quarter_frame {
+            if self.elapsed + frames as u64 > last + timeout {
+                self.last_quarter_frame = None;
+                transport.set_playing(false);
+            }
+        }
+    }
+}
+
+/// Convert the 8 quarter frame pieces into seconds.
+fn mtc_seconds(pieces: &[u8; 8]) -> Option<f64> {
+    let rate = MtcFrameRate::from_code(pieces[7] >> 1)?;
+    let frames = pieces[0] | (pieces[1] << 4);
+    let seconds = pieces[2] | (pieces[3] << 4);
+    let minutes = pieces[4] | (pieces[5] << 4);
+    let hours = pieces[6] | ((pieces[7] & 1) << 4);
+    Some(
+        f64::from(hours) * 3600.0
+            + f64::from(minutes) * 60.0
+            + f64::from(seconds)
+            + f64::from(frames) / f64::from(rate.fps()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(out: &[TimedMidi<'static>]) -> Vec<(usize, wmidi::MidiMessage<'static>)> {
+        out.iter().map(|m| (m.frame, m.message.clone())).collect()
+    }
+
+    #[test]
+    fn clock_starts_and_pulses() {
+        // 1000 frames per second at 60 bpm is 1000 frames per beat or 41.6 frames per clock.
+        let tempo_map = TempoMap::new(1000.0, 60.0, 4);
+        let mut g = MidiSyncGenerator::new(true, None);
+        let mut t = Transport::new();
+        let mut out = Vec::with_capacity(64);
+        t.set_playing(true);
+        g.process(&t, &tempo_map, 100, &mut out);
+        assert_eq!(
+            messages(&out),
+            vec![
+                (0, wmidi::MidiMessage::Start),
+                (0, wmidi::MidiMessage::TimingClock),
+                (41, wmidi::MidiMessage::TimingClock),
+                (83, wmidi::MidiMessage::TimingClock),
+            ]
+        );
+
+        out.clear();
+        t.advance(100);
+        t.set_playing(false);
+        g.process(&t, &tempo_map, 100, &mut out);
+        assert_eq!(messages(&out), vec![(0, wmidi::MidiMessage::Stop)]);
+    }
+
+    #[test]
+    fn clock_continues_from_song_position() {
+        let tempo_map = TempoMap::new(1000.0, 60.0, 4);
+        let mut g = MidiSyncGenerator::new(true, None);
+        let mut t = Transport::new();
+        let mut out = Vec::with_capacity(64);
+        t.locate(2500);
+        t.set_playing(true);
+        g.process(&t, &tempo_map, 1, &mut out);
+        assert_eq!(
+            messages(&out)[..2],
+            [
+                (
+                    0,
+                    wmidi::MidiMessage::SongPositionPointer(wmidi::U14::try_from(10).unwrap())
+                ),
+                (0, wmidi::MidiMessage::Continue),
+            ]
+        );
+    }
+
+    #[test]
+    fn mtc_round_trips() {
+        let tempo_map = TempoMap::new(1000.0, 120.0, 4);
+        let mut g = MidiSyncGenerator::new(false, Some(MtcFrameRate::Fps25));
+        let mut t = Transport::new();
+        let mut out = Vec::with_capacity(1024);
+        // 1 hour, 2 minutes and 3 seconds.
+        t.locate(3_723_000);
+        t.set_playing(true);
+        g.process(&t, &tempo_map, 200, &mut out);
+        assert_eq!(out.len(), 20);
+
+        let mut f = MidiSyncFollower::new(SyncSource::Mtc);
+        let mut followed = Transport::new();
+        let mut followed_tempo = TempoMap::new(1000.0, 120.0, 4);
+        f.process(&out, 200, &mut followed, &mut followed_tempo);
+        assert!(followed.is_playing());
+        // The transport is located to the start of the block.
+        assert_eq!(followed.frame(), 3_723_000);
+    }
+
+    #[test]
+    fn clock_and_mtc_are_merged_in_order() {
+        let tempo_map = TempoMap::new(1000.0, 60.0, 4);
+        let mut g = MidiSyncGenerator::new(true, Some(MtcFrameRate::Fps25));
+        let mut t = Transport::new();
+        let mut out = Vec::with_capacity(64);
+        t.set_playing(true);
+        g.process(&t, &tempo_map, 100, &mut out);
+        let frames: Vec<usize> = out.iter().map(|m| m.frame).collect();
+        assert_eq!(
+            frames,
+            vec![0, 0, 0, 10, 20, 30, 40, 41, 50, 60, 70, 80, 83, 90]
+        );
+        assert_eq!(out[0].message, wmidi::MidiMessage::Start);
+        assert_eq!(out[1].message, wmidi::MidiMessage::TimingClock);
+    }
+
+    #[test]
+    fn follows_clock_tempo() {
+        let mut f = MidiSyncFollower::new(SyncSource::MidiClock);
+        let mut t = Transport::new();
+        let mut tempo_map = TempoMap::new(1000.0, 60.0, 4);
+        // 10 frames per clock is 250 bpm at 1000 frames per second.
+        let mut midi = vec![TimedMidi {
+            frame: 0,
+            message: wmidi::MidiMessage::Start,
+        }];
+        for i in 0..10 {
+            midi.push(TimedMidi {
+                frame: i * 10,
+                message: wmidi::MidiMessage::TimingClock,
+            });
+        }
+        f.process(&midi, 100, &mut t, &mut tempo_map);
+        assert!(t.is_playing());
+        assert_eq!(tempo_map.tempo_at_frame(0.0).bpm, 250.0);
+
+        f.process(
+            &[TimedMidi {
+                frame: 0,
+                message: wmidi::MidiMessage::Stop,
+            }],
+            100,
+            &mut t,
+            &mut tempo_map,
+        );
+        assert!(!t.is_playing());
+    }
+
+    #[test]
+    fn clocks_on_the_same_frame_are_ignored_for_tempo() {
+        let mut f = MidiSyncFollower::new(SyncSource::MidiClock);
+        let mut t = Transport::new();
+        let mut tempo_map = TempoMap::new(1000.0, 60.0, 4);
+        let midi: Vec<_> = [
+            (0, wmidi::MidiMessage::Start),
+            (5, wmidi::MidiMessage::TimingClock),
+            (5, wmidi::MidiMessage::TimingClock),
+        ]
+        .iter()
+        .map(|(frame, message)| TimedMidi {
+            frame: *frame,
+            message: message.clone(),
+        })
+        .collect();
+        f.process(&midi, 100, &mut t, &mut tempo_map);
+        assert_eq!(tempo_map.tempo_at_frame(0.0).bpm, 60.0);
+        assert!(tempo_map.beat_at_frame(50.0).is_finite());
+    }
+}
@@ -1,4 +1,183 @@
 use crate::TimedMidi;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The state of the transport at the start of a block, as seen by plugins.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TransportInfo {
+    /// True if the transport is rolling. The transport is not rolling while counting in.
+    pub playing: bool,
+    pub frame: u64,
+    pub bpm: f64,
+    pub beats_per_bar: u32,
+    /// The zero based bar.
+    pub bar: u32,
+    /// The beat within the bar, including the fractional part.
+    pub bar_beat: f64,
+}
+
+/// A channel that an audio port of a plugin can be connected to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AudioChannel {
+    /// The left channel of the track.
+    Left,
+    /// The right channel of the track.
+    Right,
+    /// An audio input of the processor, such as a sidechain. Only valid for plugin inputs.
+    Input(usize),
+}
+
+/// Which channel each audio port of a plugin is connected to. Ports are listed in the order the
+/// plugin declares them. Ports without a channel, including ports past the end of the lists, are
+/// not connected.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AudioPortMapping {
+    pub inputs: Vec<Option<AudioChannel>>,
+    pub outputs: Vec<Option<AudioChannel>>,
+}
+
+/// Set by a plugin instance when it fails, for example when the process hosting it crashes. The
+/// flag can be shared and read from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct FailureFlag(Arc<AtomicBool>);
+
+impl FailureFlag {
+    pub fn set(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// A value that an enumerated parameter can take, such as a waveform.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParameterChoice {
+    pub value: f32,
+    pub label: String,
+}
+
+/// Describes a parameter that can be changed with `PluginInstance::set_parameter`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParameterInfo {
+    /// The index passed to `set_parameter` and `get_parameter`.
+    pub index: usize,
+    /// An identifier that does not change between versions of the plugin, such as the symbol of
+    /// an LV2 port.
+    pub id: String,
+    pub name: String,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    /// The unit of the value, for example "dB" or "Hz".
+    pub unit: Option<String>,
+    /// The values the parameter can take. Empty if any value in the range can be used.
+    pub enumeration: Vec<ParameterChoice>,
+}
+
+impl ParameterInfo {
+    pub fn new(index: usize, id: &str, name: &str, min: f32, max: f32, default: f32) -> Self {
+        ParameterInfo {
+            index,
+            id: id.to_string(),
+            name: name.to_string(),
+            min,
+            max,
+            default,
+            unit: None,
+            enumeration: Vec::new(),
+        }
+    }
+
+    pub fn with_unit(mut self, unit: &str) -> Self {
+        self.unit = Some(unit.to_string());
+        self
+    }
+
+    /// Only allow the values of `choices`, given as values and labels.
+    pub fn with_enumeration(mut self, choices: &[(f32, &str)]) -> Self {
+        self.enumeration = choices
+            .iter()
+            .map(|(value, label)| ParameterChoice {
+                value: *value,
+                label: label.to_string(),
+            })
+            .collect();
+        self
+    }
+
+    /// A parameter that is either off (0) or on (1).
+    pub fn toggle(index: usize, id: &str, name: &str, default: bool) -> Self {
+        let default = if default { 1.0 } else { 0.0 };
+        ParameterInfo::new(index, id, name, 0.0, 1.0, default)
+            .with_enumeration(&[(0.0, "Off"), (1.0, "On")])
+    }
+
+    /// Clamp `value` to the range of the parameter.
+    pub fn clamp(&self, value: f32) -> f32 {
+        value.max(self.min).min(self.max)
+    }
+}
+
 pub trait PluginInstance: Send + std::fmt::Debug {
+    /// Called before every call to `process` with the state of the transport.
+    fn set_transport(&mut self, _transport: &TransportInfo) {}
+
     fn process(&mut self, midi: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]);
+
+    /// Like `process`, but with access to the audio inputs of the processor. Plugins that use
+    /// sidechain inputs should override this.
+    fn process_with_inputs(
+        &mut self,
+        _inputs: &[&[f32]],
+        midi: &[TimedMidi<'_>],
+        out_left: &mut [f32],
+        out_right: &mut [f32],
+    ) {
+        self.process(midi, out_left, out_right);
+    }
+
+    /// Change how the audio ports of the plugin are connected. Plugins with a fixed layout may
+    /// ignore this.
+    fn set_audio_port_mapping(&mut self, _mapping: AudioPortMapping) {}
+
+    /// The MIDI produced by the last call to `process`. Plugins without a MIDI output return
+    /// `None`, in which case the MIDI input is passed through to the next plugin unchanged.
+    fn midi_output(&self) -> Option<&[TimedMidi<'static>]> {
+        None
+    }
+
+    /// Describe the parameters of the plugin. Not called from the audio thread.
+    fn parameters(&self) -> Vec<ParameterInfo> {
+        Vec::new()
+    }
+
+    /// Set the value of a parameter. What the index refers to is up to the plugin, LV2 plugins use
+    /// the index of the control port.
+    fn set_parameter(&mut self, _index: usize, _value: f32) {}
+
+    /// The current value of a parameter, or `None` if there is no parameter with `index`. Must not
+    /// block or allocate.
+    fn get_parameter(&self, _index: usize) -> Option<f32> {
+        None
+    }
+
+    /// Enable or disable the plugin. Returns true if the plugin passes its input through by
+    /// itself while disabled, in which case it keeps being processed. Otherwise the track
+    /// bypasses the plugin.
+    fn set_enabled(&mut self, _enabled: bool) -> bool {
+        false
+    }
+
+    /// The latency of the plugin in frames. Plugins that delay their output should report it so
+    /// that the other tracks can be delayed to match.
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// A flag that is set if the plugin fails. Plugins that can not fail return `None`.
+    fn failure_flag(&self) -> Option<FailureFlag> {
+        None
+    }
 }
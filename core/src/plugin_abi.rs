@@ -0,0 +1,516 @@
+//! A C ABI for native plugins that are built as shared libraries and loaded by the backend at
+//! runtime. The Rust ABI is not stable between compiler versions, so only `#[repr(C)]` types and
+//! `extern "C"` functions cross the library boundary.
+//!
+//! A plugin library is a `cdylib` crate that depends on `olivia_core`, implements
+//! `PluginInstance` and exports its plugins with `export_plugins!`:
+//!
+//! ```ignore
+//! fn plugins() -> Vec<olivia_core::plugin_abi::ExportedPlugin> {
+//!     vec![olivia_core::plugin_abi::ExportedPlugin {
+//!         id: "fuzz".to_string(),
+//!         display_name: "Fuzz".to_string(),
+//!         class: Some("Distortion".to_string()),
+//!         author: None,
+//!         audio_inputs: 2,
+//!         audio_outputs: 2,
+//!         midi_inputs: 0,
+//!         controls: 1,
+//!         new_instance: |sample_rate| Box::new(Fuzz::new(sample_rate)),
+//!     }]
+//! }
+//!
+//! olivia_core::export_plugins!(plugins);
+//! ```
+//!
+//! The library and its host must agree on `ABI_VERSION`. Panics in a plugin abort the host.
+use crate::plugin::{ParameterChoice, ParameterInfo, PluginInstance, TransportInfo};
+use crate::TimedMidi;
+use std::convert::TryFrom;
+use std::ffi::{c_void, CStr, CString};
+use std::os::raw::c_char;
+
+/// The version of the ABI. Changes to any type in this module increment it.
+pub const ABI_VERSION: u32 = 1;
+
+/// The name of the function that returns the `PluginLibrary` of a shared library. It takes no
+/// arguments and is defined by `export_plugins!`.
+pub const ENTRY_POINT: &str = "olivia_plugin_library";
+
+/// The largest number of MIDI events per call to `process`. Extra events are dropped.
+pub const MAX_MIDI_EVENTS: usize = 1024;
+
+/// The plugins in a shared library. Lives as long as the library is loaded.
+#[repr(C)]
+pub struct PluginLibrary {
+    /// Must be checked against `ABI_VERSION` before any other field is read.
+    pub abi_version: u32,
+    pub plugins_len: u32,
+    pub plugins: *const PluginDescriptor,
+}
+
+/// Describes a plugin and holds the functions that operate on its instances. Instances are
+/// opaque pointers returned by `instantiate` and freed by `destroy`. Strings are nul terminated
+/// UTF-8, optional strings may be null.
+#[repr(C)]
+pub struct PluginDescriptor {
+    /// Made of lowercase ASCII letters, digits and underscores.
+    pub id: *const c_char,
+    pub display_name: *const c_char,
+    pub class: *const c_char,
+    pub author: *const c_char,
+    pub audio_inputs: u32,
+    pub audio_outputs: u32,
+    pub midi_inputs: u32,
+    pub controls: u32,
+    /// Data for the library's own use.
+    pub context: *const c_void,
+    /// Returns null if the plugin could not be instantiated.
+    pub instantiate:
+        unsafe extern "C" fn(descriptor: *const PluginDescriptor, sample_rate: f64) -> *mut c_void,
+    pub destroy: unsafe extern "C" fn(instance: *mut c_void),
+    /// Describe the parameters of the instance. The descriptors are valid until the next call or
+    /// until the instance is destroyed. Not called from the audio thread.
+    pub parameters:
+        unsafe extern "C" fn(instance: *mut c_void, len: *mut u32) -> *const ParameterDescriptor,
+    pub set_transport: unsafe extern "C" fn(instance: *mut c_void, transport: *const Transport),
+    /// Process `frames` frames in place. The buffers hold the input when called.
+    pub process: unsafe extern "C" fn(
+        instance: *mut c_void,
+        midi: *const MidiEvent,
+        midi_len: u32,
+        left: *mut f32,
+        right: *mut f32,
+        frames: u32,
+    ),
+    pub set_parameter: unsafe extern "C" fn(instance: *mut c_void, index: u32, value: f32),
+    /// Writes the value of the parameter to `value` and returns 1, or returns 0 if there is no
+    /// parameter with `index`.
+    pub get_parameter:
+        unsafe extern "C" fn(instance: *mut c_void, index: u32, value: *mut f32) -> u32,
+    /// Returns 1 if the plugin passes its input through by itself while disabled.
+    pub set_enabled: unsafe extern "C" fn(instance: *mut c_void, enabled: u32) -> u32,
+    pub latency: unsafe extern "C" fn(instance: *mut c_void) -> u32,
+}
+
+/// The C form of `ParameterInfo`.
+#[repr(C)]
+pub struct ParameterDescriptor {
+    pub index: u32,
+    pub id: *const c_char,
+    pub name: *const c_char,
+    pub unit: *const c_char,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub choices_len: u32,
+    pub choices: *const ChoiceDescriptor,
+}
+
+/// The C form of `ParameterChoice`.
+#[repr(C)]
+pub struct ChoiceDescriptor {
+    pub value: f32,
+    pub label: *const c_char,
+}
+
+/// A MIDI message of at most 3 bytes. SysEx is not sent to plugin libraries or sandboxed plugins.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MidiEvent {
+    pub frame: u32,
+    pub len: u32,
+    pub bytes: [u8; 4],
+}
+
+/// The C form of `TransportInfo`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transport {
+    pub playing: u32,
+    pub beats_per_bar: u32,
+    pub bar: u32,
+    pub frame: u64,
+    pub bpm: f64,
+    pub bar_beat: f64,
+}
+
+impl MidiEvent {
+    /// Returns `None` for messages that are longer than 3 bytes.
+    pub fn new(midi: &TimedMidi<'_>) -> Option<MidiEvent> {
+        let mut bytes = [0; 4];
+        let len = midi.message.copy_to_slice(&mut bytes[..3]).ok()?;
+        Some(MidiEvent {
+            frame: midi.frame as u32,
+            len: len as u32,
+            bytes,
+        })
+    }
+
+    pub fn to_timed_midi(self) -> Option<TimedMidi<'static>> {
+        let bytes = &self.bytes[..(self.len as usize).min(3)];
+        let message = wmidi::MidiMessage::try_from(bytes).ok()?;
+        Some(TimedMidi {
+            frame: self.frame as usize,
+            message: message.to_owned(),
+        })
+    }
+}
+
+impl From<&TransportInfo> for Transport {
+    fn from(t: &TransportInfo) -> Transport {
+        Transport {
+            playing: t.playing as u32,
+            beats_per_bar: t.beats_per_bar,
+            bar: t.bar,
+            frame: t.frame,
+            bpm: t.bpm,
+            bar_beat: t.bar_beat,
+        }
+    }
+}
+
+impl From<&Transport> for TransportInfo {
+    fn from(t: &Transport) -> TransportInfo {
+        TransportInfo {
+            playing: t.playing != 0,
+            frame: t.frame,
+            bpm: t.bpm,
+            beats_per_bar: t.beats_per_bar,
+            bar: t.bar,
+            bar_beat: t.bar_beat,
+        }
+    }
+}
+
+impl ParameterDescriptor {
+    /// # Safety
+    /// The strings and choices of the descriptor must be valid.
+    pub unsafe fn to_info(&self) -> ParameterInfo {
+        let choices = if self.choices.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(self.choices, self.choices_len as usize)
+        };
+        ParameterInfo {
+            index: self.index as usize,
+            id: from_c_string(self.id).unwrap_or_default(),
+            name: from_c_string(self.name).unwrap_or_default(),
+            min: self.min,
+            max: self.max,
+            default: self.default,
+            unit: from_c_string(self.unit),
+            enumeration: choices
+                .iter()
+                .map(|c| ParameterChoice {
+                    value: c.value,
+                    label: from_c_string(c.label).unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Copy a nul terminated string. Returns `None` for null.
+///
+/// # Safety
+/// `s` must be null or point to a nul terminated string.
+pub unsafe fn from_c_string(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+    }
+}
+
+/// A plugin exported by a plugin library.
+#[derive(Clone, Debug)]
+pub struct ExportedPlugin {
+    pub id: String,
+    pub display_name: String,
+    pub class: Option<String>,
+    pub author: Option<String>,
+    pub audio_inputs: usize,
+    pub audio_outputs: usize,
+    pub midi_inputs: usize,
+    pub controls: usize,
+    /// Build an instance at a sample rate.
+    pub new_instance: fn(f32) -> Box<dyn PluginInstance>,
+}
+
+/// The `PluginLibrary` of a plugin library and the data it points to. Built once by
+/// `export_plugins!`.
+pub struct ExportedLibrary {
+    library: PluginLibrary,
+    // Pointed to by the library.
+    #[allow(dead_code)]
+    descriptors: Vec<PluginDescriptor>,
+    // Pointed to by the context of each descriptor.
+    #[allow(dead_code)]
+    plugins: Vec<ExportedPlugin>,
+    // Pointed to by the descriptors.
+    #[allow(dead_code)]
+    strings: Vec<CString>,
+}
+
+// The pointers are only read after the library has been built.
+unsafe impl Send for ExportedLibrary {}
+unsafe impl Sync for ExportedLibrary {}
+
+impl ExportedLibrary {
+    pub fn new(plugins: Vec<ExportedPlugin>) -> ExportedLibrary {
+        let mut strings = Vec::new();
+        let descriptors: Vec<PluginDescriptor> = plugins
+            .iter()
+            .map(|p| PluginDescriptor {
+                id: store_string(&mut strings, Some(&p.id)),
+                display_name: store_string(&mut strings, Some(&p.display_name)),
+                class: store_string(&mut strings, p.class.as_deref()),
+                author: store_string(&mut strings, p.author.as_deref()),
+                audio_inputs: p.audio_inputs as u32,
+                audio_outputs: p.audio_outputs as u32,
+                midi_inputs: p.midi_inputs as u32,
+                controls: p.controls as u32,
+                context: p as *const ExportedPlugin as *const c_void,
+                instantiate: exported_instantiate,
+                destroy: exported_destroy,
+                parameters: exported_parameters,
+                set_transport: exported_set_transport,
+                process: exported_process,
+                set_parameter: exported_set_parameter,
+                get_parameter: exported_get_parameter,
+                set_enabled: exported_set_enabled,
+                latency: exported_latency,
+            })
+            .collect();
+        ExportedLibrary {
+            library: PluginLibrary {
+                abi_version: ABI_VERSION,
+                plugins_len: descriptors.len() as u32,
+                plugins: descriptors.as_ptr(),
+            },
+            descriptors,
+            plugins,
+            strings,
+        }
+    }
+
+    pub fn library(&self) -> *const PluginLibrary {
+        &self.library
+    }
+}
+
+/// Define the entry point of a plugin library. `$plugins` is a function that returns the
+/// `ExportedPlugin`s of the library. It is called once, when the library is loaded.
+#[macro_export]
+macro_rules! export_plugins {
+    ($plugins:path) => {
+        #[no_mangle]
+        pub extern "C" fn olivia_plugin_library() -> *const $crate::plugin_abi::PluginLibrary {
+            static LIBRARY: std::sync::OnceLock<$crate::plugin_abi::ExportedLibrary> =
+                std::sync::OnceLock::new();
+            LIBRARY
+                .get_or_init(|| $crate::plugin_abi::ExportedLibrary::new($plugins()))
+                .library()
+        }
+    };
+}
+
+/// Keep a copy of `s` in `strings` and return a pointer to it, or null for `None`. Interior nul
+/// bytes are removed.
+fn store_string(strings: &mut Vec<CString>, s: Option<&str>) -> *const c_char {
+    match s {
+        Some(s) => {
+            let s = CString::new(s.replace('\0', "")).unwrap_or_default();
+            // The contents of a `CString` do not move when the `CString` is moved.
+            let ptr = s.as_ptr();
+            strings.push(s);
+            ptr
+        }
+        None => std::ptr::null(),
+    }
+}
+
+/// An instance of an exported plugin.
+struct ExportedInstance {
+    plugin: Box<dyn PluginInstance>,
+    // Scratch space for converting MIDI events.
+    midi: Vec<TimedMidi<'static>>,
+    // The last descriptors returned by `parameters` and the data they point to.
+    parameters: Vec<ParameterDescriptor>,
+    choices: Vec<Vec<ChoiceDescriptor>>,
+    strings: Vec<CString>,
+}
+
+unsafe extern "C" fn exported_instantiate(
+    descriptor: *const PluginDescriptor,
+    sample_rate: f64,
+) -> *mut c_void {
+    let plugin = &*((*descriptor).context as *const ExportedPlugin);
+    let instance = ExportedInstance {
+        plugin: (plugin.new_instance)(sample_rate as f32),
+        midi: Vec::with_capacity(MAX_MIDI_EVENTS),
+        parameters: Vec::new(),
+        choices: Vec::new(),
+        strings: Vec::new(),
+    };
+    Box::into_raw(Box::new(instance)) as *mut c_void
+}
+
+unsafe extern "C" fn exported_destroy(instance: *mut c_void) {
+    drop(Box::from_raw(instance as *mut ExportedInstance));
+}
+
+unsafe extern "C" fn exported_parameters(
+    instance: *mut c_void,
+    len: *mut u32,
+) -> *const ParameterDescriptor {
+    let instance = &mut *(instance as *mut ExportedInstance);
+    let infos = instance.plugin.parameters();
+    let mut strings = Vec::new();
+    instance.choices = infos
+        .iter()
+        .map(|info| {
+            info.enumeration
+                .iter()
+                .map(|c| ChoiceDescriptor {
+                    value: c.value,
+                    label: store_string(&mut strings, Some(&c.label)),
+                })
+                .collect()
+        })
+        .collect();
+    instance.parameters = infos
+        .iter()
+        .zip(instance.choices.iter())
+        .map(|(info, choices)| ParameterDescriptor {
+            index: info.index as u32,
+            id: store_string(&mut strings, Some(&info.id)),
+            name: store_string(&mut strings, Some(&info.name)),
+            unit: store_string(&mut strings, info.unit.as_deref()),
+            min: info.min,
+            max: info.max,
+            default: info.default,
+            choices_len: choices.len() as u32,
+            choices: choices.as_ptr(),
+        })
+        .collect();
+    instance.strings = strings;
+    *len = instance.parameters.len() as u32;
+    instance.parameters.as_ptr()
+}
+
+unsafe extern "C" fn exported_set_transport(instance: *mut c_void, transport: *const Transport) {
+    let instance = &mut *(instance as *mut ExportedInstance);
+    instance.plugin.set_transport(&(&*transport).into());
+}
+
+unsafe extern "C" fn exported_process(
+    instance: *mut c_void,
+    midi: *const MidiEvent,
+    midi_len: u32,
+    left: *mut f32,
+    right: *mut f32,
+    frames: u32,
+) {
+    let instance = &mut *(instance as *mut ExportedInstance);
+    let midi = if midi.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(midi, (midi_len as usize).min(MAX_MIDI_EVENTS))
+    };
+    instance.midi.clear();
+    instance
+        .midi
+        .extend(midi.iter().filter_map(|m| m.to_timed_midi()));
+    let left = std::slice::from_raw_parts_mut(left, frames as usize);
+    let right = std::slice::from_raw_parts_mut(right, frames as usize);
+    instance.plugin.process(&instance.midi, left, right);
+}
+
+unsafe extern "C" fn exported_set_parameter(instance: *mut c_void, index: u32, value: f32) {
+    let instance = &mut *(instance as *mut ExportedInstance);
+    instance.plugin.set_parameter(index as usize, value);
+}
+
+unsafe extern "C" fn exported_get_parameter(
+    instance: *mut c_void,
+    index: u32,
+    value: *mut f32,
+) -> u32 {
+    let instance = &*(instance as *const ExportedInstance);
+    match instance.plugin.get_parameter(index as usize) {
+        Some(v) => {
+            *value = v;
+            1
+        }
+        None => 0,
+    }
+}
+
+unsafe extern "C" fn exported_set_enabled(instance: *mut c_void, enabled: u32) -> u32 {
+    let instance = &mut *(instance as *mut ExportedInstance);
+    instance.plugin.set_enabled(enabled != 0) as u32
+}
+
+unsafe extern "C" fn exported_latency(instance: *mut c_void) -> u32 {
+    let instance = &*(instance as *const ExportedInstance);
+    instance.plugin.latency() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn midi_and_transport_survive_the_conversion() {
+        let midi = TimedMidi {
+            frame: 7,
+            message: wmidi::MidiMessage::NoteOn(
+                wmidi::Channel::Ch2,
+                wmidi::Note::C4,
+                wmidi::Velocity::MAX,
+            ),
+        };
+        let event = MidiEvent::new(&midi).unwrap();
+        assert_eq!(event.len, 3);
+        let converted = event.to_timed_midi().unwrap();
+        assert_eq!(converted.frame, 7);
+        assert_eq!(converted.message, midi.message);
+
+        let transport = TransportInfo {
+            playing: true,
+            frame: 44100,
+            bpm: 140.0,
+            beats_per_bar: 3,
+            bar: 2,
+            bar_beat: 1.5,
+        };
+        assert_eq!(TransportInfo::from(&Transport::from(&transport)), transport);
+    }
+
+    #[test]
+    fn exported_plugins_are_described() {
+        let exported = ExportedLibrary::new(vec![ExportedPlugin {
+            id: "utility".to_string(),
+            display_name: "Utility".to_string(),
+            class: None,
+            author: Some("Olivia".to_string()),
+            audio_inputs: 2,
+            audio_outputs: 2,
+            midi_inputs: 0,
+            controls: 5,
+            new_instance: |_| Box::new(crate::effects::utility::Utility::default()),
+        }]);
+        let library = unsafe { &*exported.library() };
+        assert_eq!(library.abi_version, ABI_VERSION);
+        assert_eq!(library.plugins_len, 1);
+        let descriptor = unsafe { &*library.plugins };
+        unsafe {
+            assert_eq!(from_c_string(descriptor.id).as_deref(), Some("utility"));
+            assert_eq!(from_c_string(descriptor.class), None);
+            assert_eq!(from_c_string(descriptor.author).as_deref(), Some("Olivia"));
+        }
+        assert_eq!(descriptor.controls, 5);
+    }
+}
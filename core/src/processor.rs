@@ -1,10 +1,52 @@
+use crate::automation::{AutomationLane, AutomationTarget};
+use crate::metronome::{Metronome, MetronomeOutput};
+use crate::midi_map::{MidiMapping, MidiSource, MidiTarget};
+use crate::midi_sync::{MidiSyncFollower, MidiSyncGenerator};
 use crate::plugin;
+use crate::record::RecordSink;
+use crate::tempo_map::TempoMap;
+use crate::transport::Transport;
 use crate::TimedMidi;
 
+/// The largest delay, in frames, that is added to tracks to compensate for the latency of plugins
+/// on other tracks.
+pub const MAX_LATENCY_COMPENSATION: usize = 32768;
+
+/// The number of frames over which a plugin is faded in or out when it is bypassed or its wet/dry
+/// mix changes.
+const CROSSFADE_FRAMES: usize = 256;
+
+/// The number of frames between updates of automated plugin parameters.
+pub const AUTOMATION_INTERVAL: usize = 64;
+
+/// The largest number of audio inputs that are passed to tracks while automating plugin
+/// parameters.
+const MAX_AUDIO_INPUTS: usize = 64;
+
 #[derive(Debug)]
 pub struct Processor {
     tracks: Vec<Track>,
     volume: f32,
+    transport: Transport,
+    tempo_map: TempoMap,
+    metronome: Metronome,
+    // Set when the transport starts recording and a count-in should be started on the next
+    // process call.
+    count_in_pending: bool,
+    midi_sync_generator: MidiSyncGenerator,
+    midi_sync_follower: Option<MidiSyncFollower>,
+    midi_sync_output: Vec<TimedMidi<'static>>,
+    // The MIDI produced by all tracks during the last processed block.
+    midi_output: Vec<TimedMidi<'static>>,
+    // The MIDI input for the part of the block being processed when the block is split up. Kept
+    // empty between process calls so its allocation can hold messages that borrow the input.
+    block_midi: Vec<TimedMidi<'static>>,
+    // The latency of the output during the last processed block.
+    latency: usize,
+    midi_map: Vec<MidiMapping>,
+    // If set, the next mappable MIDI message is stored in `learned_midi`.
+    midi_learn: bool,
+    learned_midi: Option<MidiSource>,
 }
 
 impl Processor {
@@ -12,20 +54,212 @@ impl Processor {
         Processor {
             tracks: Vec::with_capacity(1024),
             volume: 1.0,
+            transport: Transport::new(),
+            tempo_map: TempoMap::default(),
+            metronome: Metronome::default(),
+            count_in_pending: false,
+            midi_sync_generator: MidiSyncGenerator::default(),
+            midi_sync_follower: None,
+            midi_sync_output: Vec::with_capacity(1024),
+            midi_output: Vec::with_capacity(4096),
+            block_midi: Vec::with_capacity(1024),
+            latency: 0,
+            midi_map: Vec::new(),
+            midi_learn: false,
+            learned_midi: None,
+        }
+    }
+
+    /// Process a single block of audio. `inputs` contains the audio input channels that tracks
+    /// may monitor and record from.
+    pub fn process(
+        &mut self,
+        inputs: &[&[f32]],
+        midi: &[TimedMidi<'_>],
+        out_left: &mut [f32],
+        out_right: &mut [f32],
+    ) {
+        self.midi_output.clear();
+        self.midi_sync_output.clear();
+        self.apply_midi_map(midi);
+        let frames = out_left.len().min(out_right.len());
+        if frames <= AUTOMATION_INTERVAL || !self.tracks.iter().any(Track::has_parameter_automation)
+        {
+            self.process_block(0, inputs, midi, out_left, out_right);
+            return;
+        }
+        // Automated plugin parameters are updated between smaller blocks.
+        let num_inputs = inputs.len().min(MAX_AUDIO_INPUTS);
+        let mut block_inputs: [&[f32]; MAX_AUDIO_INPUTS] = [&[]; MAX_AUDIO_INPUTS];
+        let mut start = 0;
+        while start < frames {
+            let end = (start + AUTOMATION_INTERVAL).min(frames);
+            for (block_input, input) in block_inputs.iter_mut().zip(inputs.iter()) {
+                *block_input = &input[start.min(input.len())..end.min(input.len())];
+            }
+            let mut block_midi = crate::recycle_midi_buffer(std::mem::take(&mut self.block_midi));
+            let capacity = block_midi.capacity();
+            block_midi.extend(
+                midi.iter()
+                    .filter(|m| m.frame >= start && m.frame < end)
+                    .map(|m| TimedMidi {
+                        frame: m.frame - start,
+                        message: m.message.clone(),
+                    })
+                    .take(capacity),
+            );
+            self.process_block(
+                start,
+                &block_inputs[..num_inputs],
+                &block_midi,
+                &mut out_left[start..end],
+                &mut out_right[start..end],
+            );
+            self.block_midi = crate::recycle_midi_buffer(block_midi);
+            start = end;
         }
     }
 
-    pub fn process(&mut self, midi: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+    /// Process the part of the block that starts `offset` frames into the block.
+    fn process_block(
+        &mut self,
+        offset: usize,
+        inputs: &[&[f32]],
+        midi: &[TimedMidi<'_>],
+        out_left: &mut [f32],
+        out_right: &mut [f32],
+    ) {
         zero_buffer(out_left);
         zero_buffer(out_right);
 
+        let frames = out_left.len();
+        if let Some(follower) = self.midi_sync_follower.as_mut() {
+            follower.process(midi, frames, &mut self.transport, &mut self.tempo_map);
+        }
+        if self.count_in_pending {
+            self.count_in_pending = false;
+            self.start_count_in(frames);
+        }
         let master_volume = self.volume;
-        for track in self.tracks_mut() {
-            track.process(midi);
-            let volume = track.volume * master_volume;
-            mix_into(out_left, &track.out_left, volume);
-            mix_into(out_right, &track.out_right, volume);
+        let transport = self.transport;
+        let transport_info = self.transport_info();
+        let midi_output_start = self.midi_output.len();
+        // Tracks with less latency than the slowest track are delayed so all tracks stay aligned.
+        self.latency = self
+            .tracks
+            .iter()
+            .map(Track::latency)
+            .max()
+            .unwrap_or(0)
+            .min(MAX_LATENCY_COMPENSATION);
+        for track in self.tracks.iter_mut() {
+            let delay = self.latency.saturating_sub(track.latency());
+            track.record(inputs, &transport, frames);
+            track.process(inputs, midi, &transport_info, frames);
+            track.compensate_latency(frames, delay);
+            track.mix_into(out_left, out_right, master_volume, transport.frame());
+            if let Some(track_midi) = track.midi_output() {
+                let space = self.midi_output.capacity() - self.midi_output.len();
+                let len = track_midi.len().min(space);
+                self.midi_output.extend_from_slice(&track_midi[..len]);
+            }
+        }
+        for m in self.midi_output[midi_output_start..].iter_mut() {
+            m.frame += offset;
+        }
+        self.midi_output.sort_by_key(|m| m.frame);
+        self.metronome
+            .process(&self.transport, &self.tempo_map, offset, frames);
+        if self.metronome.settings().output == MetronomeOutput::Master {
+            let clicks = &self.metronome.output()[offset.min(self.metronome.output().len())..];
+            mix_into(out_left, clicks, 1.0);
+            mix_into(out_right, clicks, 1.0);
+        }
+        let midi_sync_start = self.midi_sync_output.len();
+        self.midi_sync_generator.process(
+            &self.transport,
+            &self.tempo_map,
+            frames,
+            &mut self.midi_sync_output,
+        );
+        for m in self.midi_sync_output[midi_sync_start..].iter_mut() {
+            m.frame += offset;
+        }
+        self.transport.advance(frames);
+    }
+
+    /// Update the values that are mapped to the MIDI controllers in `midi`. Mapped messages are
+    /// still passed to the tracks.
+    fn apply_midi_map(&mut self, midi: &[TimedMidi<'_>]) {
+        for m in midi.iter() {
+            let (source, value) = match MidiSource::from_message(&m.message) {
+                Some(s) => s,
+                None => continue,
+            };
+            if self.midi_learn {
+                self.midi_learn = false;
+                self.learned_midi = Some(source);
+            }
+            for mapping in self.midi_map.iter().filter(|m| m.source == source) {
+                let value = mapping.value(value);
+                match mapping.target {
+                    MidiTarget::TrackVolume { track_index } => {
+                        if let Some(t) = self.tracks.get_mut(track_index) {
+                            t.set_volume(value);
+                        }
+                    }
+                    MidiTarget::TrackPan { track_index } => {
+                        if let Some(t) = self.tracks.get_mut(track_index) {
+                            t.set_pan(value);
+                        }
+                    }
+                    MidiTarget::Parameter {
+                        track_index,
+                        plugin_index,
+                        parameter,
+                    } => {
+                        if let Some(p) = self
+                            .tracks
+                            .get_mut(track_index)
+                            .and_then(|t| t.plugin_mut(plugin_index))
+                        {
+                            p.set_parameter(parameter, value);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn transport_info(&self) -> plugin::TransportInfo {
+        let frame = self.transport.frame();
+        let tempo = self.tempo_map.tempo_at_frame(frame as f64);
+        let position = self.tempo_map.position_at_frame(frame as f64);
+        plugin::TransportInfo {
+            playing: self.transport.is_playing() && !self.transport.is_counting_in(),
+            frame,
+            bpm: tempo.bpm,
+            beats_per_bar: tempo.beats_per_bar,
+            bar: position.bar,
+            bar_beat: f64::from(position.beat) + position.beat_fraction,
+        }
+    }
+
+    /// Start the count-in if the metronome has one. The count-in is rounded up to a whole number
+    /// of blocks so that recording starts on a block boundary.
+    fn start_count_in(&mut self, frames: usize) {
+        if frames == 0 || !self.transport.is_recording() {
+            return;
+        }
+        let frame = self.transport.frame();
+        let exact = self.metronome.count_in_frames(&self.tempo_map, frame);
+        if exact <= 0.0 {
+            return;
         }
+        let count_in = (exact / frames as f64).ceil() as u64 * frames as u64;
+        self.transport.start_count_in(count_in);
+        self.metronome
+            .start_count_in(&self.tempo_map, frame, count_in);
     }
 
     pub fn tracks_mut(&mut self) -> impl Iterator<Item = &'_ mut Track> {
@@ -36,13 +270,128 @@ impl Processor {
         self.tracks.push(track);
     }
 
-    pub fn delete_track(&mut self, track_index: usize) {
-        self.tracks.remove(track_index);
+    /// Insert a track at `track_index`. If the index is past the last track, the track is added
+    /// to the end.
+    pub fn insert_track(&mut self, track_index: usize, track: Track) {
+        let track_index = track_index.min(self.tracks.len());
+        self.tracks.insert(track_index, track);
+    }
+
+    /// Remove the track at `track_index` and return it so that the caller decides where it is
+    /// freed. A recording in progress on the track is stopped.
+    pub fn delete_track(&mut self, track_index: usize) -> Track {
+        let mut track = self.tracks.remove(track_index);
+        track.stop_recording();
+        track
     }
 
     pub fn set_volume(&mut self, volume: f32) {
         self.volume = volume;
     }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut Transport {
+        &mut self.transport
+    }
+
+    /// Start or stop the transport. If the transport starts while record enabled, the metronome
+    /// count-in is played before recording starts.
+    pub fn set_playing(&mut self, playing: bool) {
+        if playing && !self.transport.is_playing() && self.transport.is_record_enabled() {
+            self.count_in_pending = true;
+        }
+        self.transport.set_playing(playing);
+    }
+
+    pub fn tempo_map(&self) -> &TempoMap {
+        &self.tempo_map
+    }
+
+    pub fn tempo_map_mut(&mut self) -> &mut TempoMap {
+        &mut self.tempo_map
+    }
+
+    pub fn set_tempo_map(&mut self, tempo_map: TempoMap) {
+        self.tempo_map = tempo_map;
+    }
+
+    pub fn metronome(&self) -> &Metronome {
+        &self.metronome
+    }
+
+    pub fn set_metronome(&mut self, metronome: Metronome) {
+        self.metronome = metronome;
+    }
+
+    pub fn set_midi_sync_generator(&mut self, generator: MidiSyncGenerator) {
+        self.midi_sync_generator = generator;
+    }
+
+    /// Follow an external MIDI sync source. The follower takes control of the transport and tempo
+    /// map while set.
+    pub fn set_midi_sync_follower(&mut self, follower: Option<MidiSyncFollower>) {
+        self.midi_sync_follower = follower;
+    }
+
+    /// The MIDI produced by the plugins of all tracks during the last processed block, ordered by
+    /// frame.
+    pub fn midi_output(&self) -> &[TimedMidi<'static>] {
+        &self.midi_output
+    }
+
+    /// The latency of the output in frames. This is the latency of the track with the most
+    /// latency, the other tracks are delayed to match it.
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// Replace the MIDI mappings. The old mappings are returned so that they can be freed outside
+    /// of the audio thread.
+    pub fn set_midi_map(&mut self, midi_map: Vec<MidiMapping>) -> Vec<MidiMapping> {
+        std::mem::replace(&mut self.midi_map, midi_map)
+    }
+
+    /// Start or stop waiting for a MIDI controller to map. The source of the next mappable message
+    /// is returned by `take_learned_midi`.
+    pub fn set_midi_learn(&mut self, learn: bool) {
+        self.midi_learn = learn;
+        self.learned_midi = None;
+    }
+
+    /// Take the MIDI controller that was captured while learning.
+    pub fn take_learned_midi(&mut self) -> Option<MidiSource> {
+        self.learned_midi.take()
+    }
+
+    /// Take the next plugin whose latency has grown beyond its dry delay. The larger dry delay
+    /// should be allocated outside of the audio thread and set with `Track::set_plugin_dry_delay`.
+    /// Each latency is only requested once.
+    pub fn take_dry_delay_request(&mut self) -> Option<DryDelayRequest> {
+        self.tracks
+            .iter_mut()
+            .enumerate()
+            .find_map(|(track_index, track)| {
+                track
+                    .plugins
+                    .iter_mut()
+                    .enumerate()
+                    .find_map(|(plugin_index, slot)| {
+                        slot.dry_delay_request().map(|latency| DryDelayRequest {
+                            track_index,
+                            plugin_index,
+                            latency,
+                        })
+                    })
+            })
+    }
+
+    /// The MIDI clock and time code messages generated during the last processed block.
+    pub fn midi_sync_output(&self) -> &[TimedMidi<'static>] {
+        &self.midi_sync_output
+    }
 }
 
 impl Default for Processor {
@@ -51,12 +400,39 @@ impl Default for Processor {
     }
 }
 
+/// A plugin that needs a dry delay of at least `latency` frames.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DryDelayRequest {
+    pub track_index: usize,
+    pub plugin_index: usize,
+    pub latency: usize,
+}
+
 #[derive(Debug)]
 pub struct Track {
-    plugins: Vec<Box<dyn plugin::PluginInstance>>,
+    plugins: Vec<PluginSlot>,
     volume: f32,
+    pan: f32,
+    automation: Vec<AutomationLane>,
+    // The automated volume and pan of each frame.
+    volume_buffer: Vec<f32>,
+    pan_buffer: Vec<f32>,
     out_left: Vec<f32>,
     out_right: Vec<f32>,
+    // The input of the plugin being processed, used to mix in the dry signal.
+    dry_left: Vec<f32>,
+    dry_right: Vec<f32>,
+    // The indices of the left and right input channels.
+    input: Option<(usize, usize)>,
+    monitoring: bool,
+    record_sink: Option<Box<dyn RecordSink>>,
+    // The frame at which the current recording started, if the track is recording.
+    recording_since: Option<u64>,
+    // The transport frame after the last recorded block.
+    record_frame: u64,
+    // Delays the output to compensate for latency on other tracks.
+    delay_left: DelayLine,
+    delay_right: DelayLine,
 }
 
 impl Track {
@@ -64,24 +440,444 @@ impl Track {
         Track {
             plugins: Vec::with_capacity(128),
             volume,
+            pan: 0.0,
+            automation: Vec::new(),
+            volume_buffer: vec![0.0; buffer_size],
+            pan_buffer: vec![0.0; buffer_size],
             out_left: vec![0.0; buffer_size],
             out_right: vec![0.0; buffer_size],
+            dry_left: vec![0.0; buffer_size],
+            dry_right: vec![0.0; buffer_size],
+            input: None,
+            monitoring: false,
+            record_sink: None,
+            recording_since: None,
+            record_frame: 0,
+            delay_left: DelayLine::new(MAX_LATENCY_COMPENSATION),
+            delay_right: DelayLine::new(MAX_LATENCY_COMPENSATION),
         }
     }
 
     pub fn add_plugin(&mut self, plugin: Box<dyn plugin::PluginInstance>) {
-        self.plugins.push(plugin)
+        self.plugins.push(PluginSlot::new(plugin))
+    }
+
+    /// Remove all plugins from the track and return them in order.
+    pub fn take_plugins(&mut self) -> Vec<Box<dyn plugin::PluginInstance>> {
+        self.plugins.drain(..).map(|slot| slot.plugin).collect()
+    }
+
+    pub fn plugin_mut(&mut self, plugin_index: usize) -> Option<&mut dyn plugin::PluginInstance> {
+        match self.plugins.get_mut(plugin_index) {
+            Some(p) => Some(p.plugin.as_mut()),
+            None => None,
+        }
+    }
+
+    /// Bypass a plugin or change how much of its output is mixed with its input. `mix` goes from
+    /// 0, only the input, to 1, only the plugin output. Changes are crossfaded to avoid clicks.
+    pub fn set_plugin_bypass(&mut self, plugin_index: usize, bypassed: bool, mix: f32) {
+        if let Some(slot) = self.plugins.get_mut(plugin_index) {
+            slot.handles_bypass = slot.plugin.set_enabled(!bypassed);
+            slot.bypassed = bypassed;
+            slot.mix = mix.clamp(0.0, 1.0);
+        }
+    }
+
+    /// Replace the dry delay of a plugin with a larger one after its latency has grown. The old
+    /// dry delay is returned so that it can be freed outside of the audio thread, or `dry_delay`
+    /// if there is no such plugin or its dry delay is not smaller.
+    pub fn set_plugin_dry_delay(&mut self, plugin_index: usize, dry_delay: DryDelay) -> DryDelay {
+        match self.plugins.get_mut(plugin_index) {
+            Some(slot) if slot.dry_delay.max_delay() < dry_delay.max_delay() => {
+                std::mem::replace(&mut slot.dry_delay, dry_delay)
+            }
+            _ => dry_delay,
+        }
     }
 
     pub fn set_volume(&mut self, volume: f32) {
         self.volume = volume;
     }
 
-    fn process(&mut self, midi: &[TimedMidi<'_>]) {
-        for plugin in self.plugins.iter_mut() {
-            plugin.process(midi, &mut self.out_left, &mut self.out_right);
+    /// Set the pan, from -1 (left) to 1 (right).
+    pub fn set_pan(&mut self, pan: f32) {
+        self.pan = pan.clamp(-1.0, 1.0);
+    }
+
+    /// Replace the automation lanes of the track. Lanes for plugins that are not on the track are
+    /// ignored. The old lanes are returned so that they can be freed outside of the audio thread.
+    pub fn set_automation(&mut self, automation: Vec<AutomationLane>) -> Vec<AutomationLane> {
+        std::mem::replace(&mut self.automation, automation)
+    }
+
+    fn has_parameter_automation(&self) -> bool {
+        self.automation
+            .iter()
+            .any(|l| matches!(l.target, AutomationTarget::Parameter { .. }))
+    }
+
+    /// Mix the first `frames` of the output into `out_left` and `out_right`, where `frame` is the
+    /// transport frame at the start of the block.
+    fn mix_into(&mut self, out_left: &mut [f32], out_right: &mut [f32], volume: f32, frame: u64) {
+        let frames = out_left.len().min(out_right.len()).min(self.out_left.len());
+        let automation = &self.automation;
+        let lane = |target| {
+            automation
+                .iter()
+                .find(|l| l.target == target)
+                .map(|l| &l.envelope)
+        };
+        let volume_lane = lane(AutomationTarget::Volume);
+        let pan_lane = lane(AutomationTarget::Pan);
+        if volume_lane.is_none() && pan_lane.is_none() {
+            let (left, right) = pan_gains(self.pan);
+            mix_into(
+                out_left,
+                &self.out_left[..frames],
+                self.volume * volume * left,
+            );
+            mix_into(
+                out_right,
+                &self.out_right[..frames],
+                self.volume * volume * right,
+            );
+            return;
+        }
+        let volumes = &mut self.volume_buffer[..frames];
+        let pans = &mut self.pan_buffer[..frames];
+        fill_buffer(volumes, self.volume);
+        fill_buffer(pans, self.pan);
+        if let Some(envelope) = volume_lane {
+            envelope.render(frame, volumes);
+        }
+        if let Some(envelope) = pan_lane {
+            envelope.render(frame, pans);
+        }
+        for i in 0..frames {
+            let (left, right) = pan_gains(pans[i]);
+            out_left[i] += self.out_left[i] * volumes[i] * volume * left;
+            out_right[i] += self.out_right[i] * volumes[i] * volume * right;
+        }
+    }
+
+    /// Set the automated plugin parameters to their values at `frame`.
+    fn update_parameters(&mut self, frame: u64) {
+        for lane in self.automation.iter() {
+            if let AutomationTarget::Parameter {
+                plugin_index,
+                parameter,
+            } = lane.target
+            {
+                if let (Some(slot), Some(value)) = (
+                    self.plugins.get_mut(plugin_index),
+                    lane.envelope.value_at(frame),
+                ) {
+                    slot.plugin.set_parameter(parameter, value);
+                }
+            }
         }
     }
+
+    /// Set the input channels for the track. For mono inputs, left and right should be the same
+    /// channel.
+    pub fn set_input(&mut self, input: Option<(usize, usize)>) {
+        self.input = input;
+    }
+
+    /// If monitoring is enabled, the track input is mixed into the track output.
+    pub fn set_monitoring(&mut self, monitoring: bool) {
+        self.monitoring = monitoring;
+    }
+
+    /// Arm the track for recording by setting a sink for the track's input or disarm it by
+    /// passing `None`. If the track was in the middle of a recording, the previous sink is
+    /// stopped.
+    pub fn set_record_sink(&mut self, sink: Option<Box<dyn RecordSink>>) {
+        self.stop_recording();
+        self.record_sink = sink;
+    }
+
+    /// Stop the recording in progress, if any.
+    fn stop_recording(&mut self) {
+        if let Some(sink) = self.record_sink.as_mut() {
+            if self.recording_since.take().is_some() {
+                sink.stop(self.record_frame);
+            }
+        }
+    }
+
+    /// Returns true if the track has a record sink.
+    pub fn is_armed(&self) -> bool {
+        self.record_sink.is_some()
+    }
+
+    fn input<'a>(&self, inputs: &[&'a [f32]]) -> Option<(&'a [f32], &'a [f32])> {
+        let (left, right) = self.input?;
+        Some((inputs.get(left)?, inputs.get(right)?))
+    }
+
+    fn record(&mut self, inputs: &[&[f32]], transport: &Transport, frames: usize) {
+        let input = self.input(inputs);
+        let sink = match self.record_sink.as_mut() {
+            Some(s) => s,
+            None => return,
+        };
+        match (transport.is_recording(), self.recording_since) {
+            (true, recording_since) => {
+                if recording_since.is_none() {
+                    sink.start(transport.frame());
+                    self.recording_since = Some(transport.frame());
+                }
+                if let Some((left, right)) = input {
+                    sink.write(left, right);
+                }
+                self.record_frame = transport.frame() + frames as u64;
+            }
+            (false, Some(_)) => {
+                sink.stop(self.record_frame);
+                self.recording_since = None;
+            }
+            (false, None) => (),
+        }
+    }
+
+    /// The latency of the track in frames, the sum of the latency reported by its plugins.
+    pub fn latency(&self) -> usize {
+        self.plugins.iter().map(|p| p.latency()).sum()
+    }
+
+    /// Delay the first `frames` of the output by `delay` frames.
+    fn compensate_latency(&mut self, frames: usize, delay: usize) {
+        let frames = frames.min(self.out_left.len());
+        self.delay_left.process(&mut self.out_left[..frames], delay);
+        self.delay_right
+            .process(&mut self.out_right[..frames], delay);
+    }
+
+    /// The MIDI output of the last plugin on the track that produces MIDI, if any.
+    pub fn midi_output(&self) -> Option<&[TimedMidi<'static>]> {
+        self.plugins.iter().rev().find_map(|p| p.midi_output())
+    }
+
+    fn process(
+        &mut self,
+        inputs: &[&[f32]],
+        midi: &[TimedMidi<'_>],
+        transport: &plugin::TransportInfo,
+        frames: usize,
+    ) {
+        self.update_parameters(transport.frame);
+        let input = self.input(inputs);
+        let frames = frames.min(self.out_left.len());
+        let out_left = &mut self.out_left[..frames];
+        let out_right = &mut self.out_right[..frames];
+        let dry_left = &mut self.dry_left[..frames];
+        let dry_right = &mut self.dry_right[..frames];
+        zero_buffer(out_left);
+        zero_buffer(out_right);
+        // Each plugin receives the MIDI output of the closest plugin before it that produces MIDI,
+        // or the track MIDI input if there is none.
+        for i in 0..self.plugins.len() {
+            let (upstream, downstream) = self.plugins.split_at_mut(i);
+            let slot = &mut downstream[0];
+            // The dry signal is delayed by the plugin latency so it lines up with the plugin
+            // output. It is delayed even when unused so the delay lines never play stale audio.
+            dry_left.copy_from_slice(out_left);
+            dry_right.copy_from_slice(out_right);
+            slot.delay_dry(dry_left, dry_right);
+            if slot.is_bypassed() {
+                out_left.copy_from_slice(dry_left);
+                out_right.copy_from_slice(dry_right);
+                continue;
+            }
+            let plugin_midi = upstream
+                .iter()
+                .rev()
+                .find_map(|p| p.midi_output())
+                .unwrap_or(midi);
+            slot.plugin.set_transport(transport);
+            slot.plugin
+                .process_with_inputs(inputs, plugin_midi, out_left, out_right);
+            if slot.gain != 1.0 || slot.target_gain() != 1.0 {
+                slot.mix_dry(dry_left, dry_right, out_left, out_right);
+            }
+        }
+        if self.monitoring {
+            if let Some((left, right)) = input {
+                mix_into(out_left, left, 1.0);
+                mix_into(out_right, right, 1.0);
+            }
+        }
+    }
+}
+
+/// A plugin on a track along with its bypass and wet/dry settings.
+#[derive(Debug)]
+struct PluginSlot {
+    plugin: Box<dyn plugin::PluginInstance>,
+    bypassed: bool,
+    // The amount of plugin output in the mix, from 0 to 1.
+    mix: f32,
+    // True if the plugin passes its input through by itself when disabled.
+    handles_bypass: bool,
+    // The amount of plugin output in the mix at the end of the last block. Moves towards the
+    // target gain over `CROSSFADE_FRAMES`.
+    gain: f32,
+    // Delay the plugin input by the plugin latency before it is mixed with the plugin output.
+    dry_delay: DryDelay,
+    // The largest latency that a larger dry delay has been requested for.
+    requested_dry_delay: usize,
+}
+
+impl PluginSlot {
+    fn new(plugin: Box<dyn plugin::PluginInstance>) -> PluginSlot {
+        let dry_delay = DryDelay::new(plugin.latency());
+        PluginSlot {
+            plugin,
+            bypassed: false,
+            mix: 1.0,
+            handles_bypass: false,
+            gain: 1.0,
+            dry_delay,
+            requested_dry_delay: 0,
+        }
+    }
+
+    fn target_gain(&self) -> f32 {
+        if self.bypassed && !self.handles_bypass {
+            0.0
+        } else {
+            self.mix
+        }
+    }
+
+    /// Returns true if the plugin output is not used, in which case the plugin is not processed.
+    fn is_bypassed(&self) -> bool {
+        self.gain == 0.0 && self.target_gain() == 0.0
+    }
+
+    /// The latency of the plugin. Bypassed plugins report the same latency since their input is
+    /// delayed by it, which keeps the track latency constant when bypassing.
+    fn latency(&self) -> usize {
+        self.plugin.latency()
+    }
+
+    fn delay_dry(&mut self, dry_left: &mut [f32], dry_right: &mut [f32]) {
+        let latency = self.plugin.latency();
+        self.dry_delay.left.process(dry_left, latency);
+        self.dry_delay.right.process(dry_right, latency);
+    }
+
+    /// Returns the latency to build a larger dry delay for, if the plugin latency has grown
+    /// beyond the dry delay and no dry delay has been requested for it yet.
+    fn dry_delay_request(&mut self) -> Option<usize> {
+        let latency = self.plugin.latency().min(MAX_LATENCY_COMPENSATION);
+        if latency <= self.dry_delay.max_delay() || latency <= self.requested_dry_delay {
+            return None;
+        }
+        self.requested_dry_delay = latency;
+        Some(latency)
+    }
+
+    fn midi_output(&self) -> Option<&[TimedMidi<'static>]> {
+        if self.is_bypassed() {
+            None
+        } else {
+            self.plugin.midi_output()
+        }
+    }
+
+    /// Mix the plugin input into the plugin output according to the gain.
+    fn mix_dry(
+        &mut self,
+        dry_left: &[f32],
+        dry_right: &[f32],
+        out_left: &mut [f32],
+        out_right: &mut [f32],
+    ) {
+        let target = self.target_gain();
+        let step = 1.0 / CROSSFADE_FRAMES as f32;
+        let mut gain = self.gain;
+        let dry = dry_left.iter().zip(dry_right.iter());
+        let wet = out_left.iter_mut().zip(out_right.iter_mut());
+        for ((dl, dr), (l, r)) in dry.zip(wet) {
+            gain = if gain < target {
+                (gain + step).min(target)
+            } else {
+                (gain - step).max(target)
+            };
+            *l = dl + (*l - dl) * gain;
+            *r = dr + (*r - dr) * gain;
+        }
+        self.gain = gain;
+    }
+}
+
+/// Delays the input of a plugin by the plugin latency before it is mixed with the plugin output.
+#[derive(Debug)]
+pub struct DryDelay {
+    left: DelayLine,
+    right: DelayLine,
+}
+
+impl DryDelay {
+    /// Create a dry delay for plugins with a latency of up to `max_delay` frames. The delay is
+    /// limited to `MAX_LATENCY_COMPENSATION`.
+    pub fn new(max_delay: usize) -> DryDelay {
+        let max_delay = max_delay.min(MAX_LATENCY_COMPENSATION);
+        DryDelay {
+            left: DelayLine::new(max_delay),
+            right: DelayLine::new(max_delay),
+        }
+    }
+
+    fn max_delay(&self) -> usize {
+        self.left.buffer.len() - 1
+    }
+}
+
+/// Delays audio by a variable number of frames.
+#[derive(Debug)]
+struct DelayLine {
+    buffer: Vec<f32>,
+    // The index that the next frame is written to.
+    position: usize,
+}
+
+impl DelayLine {
+    /// Create a delay line that can delay by up to `max_delay` frames.
+    fn new(max_delay: usize) -> DelayLine {
+        DelayLine {
+            buffer: vec![0.0; max_delay + 1],
+            position: 0,
+        }
+    }
+
+    /// Delay `b` in place by `delay` frames. Every frame goes through the delay line, even without
+    /// a delay, so that changing the delay does not play stale audio.
+    fn process(&mut self, b: &mut [f32], delay: usize) {
+        let len = self.buffer.len();
+        let delay = delay.min(len - 1);
+        for v in b.iter_mut() {
+            self.buffer[self.position] = *v;
+            *v = self.buffer[(self.position + len - delay) % len];
+            self.position = (self.position + 1) % len;
+        }
+    }
+}
+
+/// The gains of the left and right channels for `pan`. The center pan leaves both channels
+/// unchanged.
+fn pan_gains(pan: f32) -> (f32, f32) {
+    let pan = pan.clamp(-1.0, 1.0);
+    ((1.0 - pan).min(1.0), (1.0 + pan).min(1.0))
+}
+
+fn fill_buffer(b: &mut [f32], value: f32) {
+    for o in b.iter_mut() {
+        *o = value;
+    }
 }
 
 fn zero_buffer(b: &mut [f32]) {
@@ -99,7 +895,11 @@ fn mix_into(dst: &mut [f32], src: &[f32], volume: f32) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::automation::{Breakpoint, Envelope};
     use crate::plugin::PluginInstance;
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     #[derive(Debug)]
     struct OnePluginInstance;
@@ -110,18 +910,21 @@ mod tests {
         }
     }
 
+    #[derive(Debug)]
+    struct SilencePluginInstance;
+    impl PluginInstance for SilencePluginInstance {
+        fn process(&mut self, _: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+            fill_buffer(out_left, 0.0);
+            fill_buffer(out_right, 0.0);
+        }
+    }
+
     fn new_track(volume: f32) -> Track {
         let mut t = Track::new(1024, volume);
         t.add_plugin(Box::new(OnePluginInstance));
         t
     }
 
-    fn fill_buffer(b: &mut [f32], scalar: f32) {
-        for o in b.iter_mut() {
-            *o = scalar;
-        }
-    }
-
     #[test]
     fn outputs_are_cleared() {
         let mut left = [1.0, 2.0];
@@ -129,7 +932,7 @@ mod tests {
 
         let mut p = Processor::new();
         assert_ne!([left, right], [[0.0, 0.0], [0.0, 0.0]]);
-        p.process(&[], &mut left, &mut right);
+        p.process(&[], &[], &mut left, &mut right);
         assert_eq!([left, right], [[0.0, 0.0], [0.0, 0.0]]);
     }
 
@@ -141,7 +944,7 @@ mod tests {
 
         let mut left = [0.0; 2];
         let mut right = [0.0; 2];
-        p.process(&[], &mut left, &mut right);
+        p.process(&[], &[], &mut left, &mut right);
 
         assert_eq!([left, right], [[0.75, 0.75], [0.75, 0.75]])
     }
@@ -155,7 +958,7 @@ mod tests {
 
         let mut left = [0.0; 2];
         let mut right = [0.0; 2];
-        p.process(&[], &mut left, &mut right);
+        p.process(&[], &[], &mut left, &mut right);
 
         assert_eq!([left, right], [[0.25, 0.25], [0.25, 0.25]])
     }
@@ -169,7 +972,7 @@ mod tests {
 
         let mut left = [0.0; 2];
         let mut right = [0.0; 2];
-        p.process(&[], &mut left, &mut right);
+        p.process(&[], &[], &mut left, &mut right);
 
         assert_eq!([left, right], [[0.5, 0.5], [0.5, 0.5]])
     }
@@ -182,8 +985,589 @@ mod tests {
 
         let mut left = [0.0; 2];
         let mut right = [0.0; 2];
-        p.process(&[], &mut left, &mut right);
+        p.process(&[], &[], &mut left, &mut right);
 
         assert_eq!([left, right], [[2.0, 2.0], [2.0, 2.0]])
     }
+
+    #[derive(Debug)]
+    struct ImpulsePluginInstance {
+        latency: usize,
+    }
+    impl PluginInstance for ImpulsePluginInstance {
+        fn process(&mut self, _: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+            out_left[0] = 1.0;
+            out_right[0] = 1.0;
+        }
+
+        fn latency(&self) -> usize {
+            self.latency
+        }
+    }
+
+    #[test]
+    fn bypassed_plugins_are_crossfaded() {
+        let mut t = Track::new(CROSSFADE_FRAMES * 2, 1.0);
+        t.add_plugin(Box::new(OnePluginInstance));
+        t.set_plugin_bypass(0, true, 1.0);
+        let transport = Processor::new().transport_info();
+
+        t.process(&[], &[], &transport, CROSSFADE_FRAMES * 2);
+        assert_eq!(t.out_left[0], 1.0 - 1.0 / CROSSFADE_FRAMES as f32);
+        assert_eq!(t.out_left[CROSSFADE_FRAMES / 2 - 1], 0.5);
+        assert_eq!(t.out_left[CROSSFADE_FRAMES..], [0.0; CROSSFADE_FRAMES][..]);
+
+        t.process(&[], &[], &transport, CROSSFADE_FRAMES * 2);
+        assert_eq!(t.out_left, vec![0.0; CROSSFADE_FRAMES * 2]);
+        assert_eq!(t.out_right, vec![0.0; CROSSFADE_FRAMES * 2]);
+    }
+
+    #[test]
+    fn plugins_mix_wet_and_dry() {
+        let mut t = Track::new(CROSSFADE_FRAMES, 1.0);
+        t.add_plugin(Box::new(OnePluginInstance));
+        t.add_plugin(Box::new(SilencePluginInstance));
+        t.set_plugin_bypass(1, false, 0.5);
+        let transport = Processor::new().transport_info();
+
+        // Let the mix settle.
+        t.process(&[], &[], &transport, CROSSFADE_FRAMES);
+        t.process(&[], &[], &transport, CROSSFADE_FRAMES);
+        assert_eq!(t.out_left, vec![0.5; CROSSFADE_FRAMES]);
+        assert_eq!(t.out_right, vec![0.5; CROSSFADE_FRAMES]);
+    }
+
+    #[test]
+    fn dry_signal_is_delayed_by_plugin_latency() {
+        let mut t = Track::new(4, 1.0);
+        t.add_plugin(Box::new(ImpulsePluginInstance { latency: 0 }));
+        t.add_plugin(Box::new(ImpulsePluginInstance { latency: 2 }));
+        t.set_plugin_bypass(1, false, 0.0);
+        let transport = Processor::new().transport_info();
+
+        // Let the mix settle.
+        for _ in 0..CROSSFADE_FRAMES / 2 {
+            t.process(&[], &[], &transport, 4);
+        }
+        assert_eq!(t.out_left, vec![0.0, 0.0, 1.0, 0.0]);
+        assert_eq!(t.out_right, vec![0.0, 0.0, 1.0, 0.0]);
+    }
+
+    #[derive(Debug)]
+    struct SharedLatencyPluginInstance(Arc<AtomicUsize>);
+    impl PluginInstance for SharedLatencyPluginInstance {
+        fn process(&mut self, _: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+            out_left[0] = 1.0;
+            out_right[0] = 1.0;
+        }
+
+        fn latency(&self) -> usize {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    fn grown_plugin_latency_requests_larger_dry_delay() {
+        let latency = Arc::new(AtomicUsize::new(0));
+        let mut p = Processor::new();
+        let mut t = Track::new(4, 1.0);
+        t.add_plugin(Box::new(ImpulsePluginInstance { latency: 0 }));
+        t.add_plugin(Box::new(SharedLatencyPluginInstance(latency.clone())));
+        t.set_plugin_bypass(1, false, 0.0);
+        p.add_track(t);
+        assert_eq!(p.take_dry_delay_request(), None);
+
+        latency.store(2, Ordering::Relaxed);
+        assert_eq!(
+            p.take_dry_delay_request(),
+            Some(DryDelayRequest {
+                track_index: 0,
+                plugin_index: 1,
+                latency: 2,
+            })
+        );
+        assert_eq!(p.take_dry_delay_request(), None);
+
+        let t = p.tracks_mut().next().unwrap();
+        t.set_plugin_dry_delay(1, DryDelay::new(2));
+        let transport = Processor::new().transport_info();
+        // Let the mix settle.
+        for _ in 0..CROSSFADE_FRAMES / 2 {
+            t.process(&[], &[], &transport, 4);
+        }
+        assert_eq!(t.out_left, vec![0.0, 0.0, 1.0, 0.0]);
+        assert_eq!(t.out_right, vec![0.0, 0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn bypassed_plugins_report_latency() {
+        let mut t = Track::new(4, 1.0);
+        t.add_plugin(Box::new(ImpulsePluginInstance { latency: 3 }));
+        t.set_plugin_bypass(0, true, 1.0);
+        let transport = Processor::new().transport_info();
+        for _ in 0..CROSSFADE_FRAMES / 2 {
+            t.process(&[], &[], &transport, 4);
+        }
+        assert_eq!(t.latency(), 3);
+    }
+
+    #[test]
+    fn midi_controllers_are_learned_and_mapped() {
+        let mut p = Processor::new();
+        p.add_track(new_track(1.0));
+        p.set_midi_learn(true);
+        let cc = |value: u8| TimedMidi {
+            frame: 0,
+            message: wmidi::MidiMessage::ControlChange(
+                wmidi::Channel::Ch2,
+                wmidi::ControlFunction(wmidi::U7::try_from(7).unwrap()),
+                wmidi::U7::try_from(value).unwrap(),
+            ),
+        };
+        let mut left = [0.0; 2];
+        let mut right = [0.0; 2];
+        p.process(&[], &[cc(127)], &mut left, &mut right);
+        let source = p.take_learned_midi().unwrap();
+        assert_eq!(
+            source,
+            MidiSource::ControlChange {
+                channel: 1,
+                controller: 7
+            }
+        );
+        assert_eq!(p.take_learned_midi(), None);
+
+        p.set_midi_map(vec![MidiMapping {
+            source,
+            target: MidiTarget::TrackVolume { track_index: 0 },
+            min: 0.0,
+            max: 0.5,
+        }]);
+        p.process(&[], &[cc(127)], &mut left, &mut right);
+        assert_eq!([left, right], [[0.5, 0.5], [0.5, 0.5]]);
+        assert_eq!(p.set_midi_map(Vec::new()).len(), 1);
+    }
+
+    #[test]
+    fn tracks_can_set_pan() {
+        let mut p = Processor::new();
+        let mut t = new_track(1.0);
+        t.set_pan(-0.5);
+        p.add_track(t);
+
+        let mut left = [0.0; 2];
+        let mut right = [0.0; 2];
+        p.process(&[], &[], &mut left, &mut right);
+
+        assert_eq!([left, right], [[1.0, 1.0], [0.5, 0.5]])
+    }
+
+    fn envelope(points: &[(u64, f32)]) -> Envelope {
+        let points: Vec<_> = points
+            .iter()
+            .map(|(frame, value)| Breakpoint {
+                frame: *frame,
+                value: *value,
+                curve: 0.0,
+            })
+            .collect();
+        Envelope::new(&points)
+    }
+
+    #[test]
+    fn volume_and_pan_are_automated_per_frame() {
+        let mut p = Processor::new();
+        let mut t = new_track(1.0);
+        t.set_automation(vec![
+            AutomationLane {
+                target: AutomationTarget::Volume,
+                envelope: envelope(&[(0, 0.0), (4, 1.0)]),
+            },
+            AutomationLane {
+                target: AutomationTarget::Pan,
+                envelope: envelope(&[(2, 0.0), (3, 1.0)]),
+            },
+        ]);
+        p.add_track(t);
+
+        let mut left = [0.0; 4];
+        let mut right = [0.0; 4];
+        p.process(&[], &[], &mut left, &mut right);
+
+        assert_eq!(left, [0.0, 0.25, 0.5, 0.0]);
+        assert_eq!(right, [0.0, 0.25, 0.5, 0.75]);
+    }
+
+    #[derive(Debug)]
+    struct ParameterPluginInstance {
+        value: f32,
+    }
+    impl PluginInstance for ParameterPluginInstance {
+        fn process(&mut self, _: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+            fill_buffer(out_left, self.value);
+            fill_buffer(out_right, self.value);
+        }
+
+        fn set_parameter(&mut self, index: usize, value: f32) {
+            if index == 3 {
+                self.value = value;
+            }
+        }
+    }
+
+    #[test]
+    fn parameters_are_automated_between_intervals() {
+        let mut p = Processor::new();
+        let mut t = Track::new(AUTOMATION_INTERVAL * 2, 1.0);
+        t.add_plugin(Box::new(ParameterPluginInstance { value: 0.0 }));
+        t.set_automation(vec![AutomationLane {
+            target: AutomationTarget::Parameter {
+                plugin_index: 0,
+                parameter: 3,
+            },
+            envelope: envelope(&[(0, 0.0), (AUTOMATION_INTERVAL as u64, 1.0)]),
+        }]);
+        p.add_track(t);
+        p.set_playing(true);
+
+        let mut left = vec![0.0; AUTOMATION_INTERVAL * 2];
+        let mut right = vec![0.0; AUTOMATION_INTERVAL * 2];
+        p.process(&[], &[], &mut left, &mut right);
+
+        assert_eq!(
+            left[..AUTOMATION_INTERVAL],
+            vec![0.0; AUTOMATION_INTERVAL][..]
+        );
+        assert_eq!(
+            left[AUTOMATION_INTERVAL..],
+            vec![1.0; AUTOMATION_INTERVAL][..]
+        );
+        assert_eq!(left, right);
+        assert_eq!(p.transport().frame(), AUTOMATION_INTERVAL as u64 * 2);
+    }
+
+    fn automated_track(frames: usize) -> Track {
+        let mut t = Track::new(frames, 1.0);
+        t.add_plugin(Box::new(ParameterPluginInstance { value: 0.0 }));
+        t.set_automation(vec![AutomationLane {
+            target: AutomationTarget::Parameter {
+                plugin_index: 0,
+                parameter: 3,
+            },
+            envelope: envelope(&[(0, 0.0)]),
+        }]);
+        t
+    }
+
+    #[test]
+    fn metronome_is_rendered_for_the_whole_automated_block() {
+        let mut p = Processor::new();
+        p.add_track(automated_track(1000));
+        p.set_tempo_map(TempoMap::new(1000.0, 120.0, 4));
+        p.set_metronome(Metronome::new(
+            1000.0,
+            1000,
+            crate::metronome::MetronomeSettings {
+                enabled: true,
+                sound: crate::metronome::ClickSound::Square,
+                output: MetronomeOutput::Dedicated,
+                ..Default::default()
+            },
+        ));
+        p.set_playing(true);
+
+        let mut left = [0.0; 1000];
+        let mut right = [0.0; 1000];
+        p.process(&[], &[], &mut left, &mut right);
+
+        let clicks = p.metronome().output();
+        assert_ne!(clicks[0], 0.0);
+        assert_eq!(clicks[499], 0.0);
+        assert_ne!(clicks[500], 0.0);
+    }
+
+    #[derive(Debug)]
+    struct SysExPluginInstance {
+        frames: std::sync::Arc<std::sync::Mutex<Vec<usize>>>,
+    }
+    impl PluginInstance for SysExPluginInstance {
+        fn process(&mut self, midi: &[TimedMidi<'_>], _: &mut [f32], _: &mut [f32]) {
+            for m in midi.iter() {
+                if let wmidi::MidiMessage::SysEx(_) = m.message {
+                    self.frames.lock().unwrap().push(m.frame);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sysex_is_passed_to_automated_tracks() {
+        let frames = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut t = automated_track(AUTOMATION_INTERVAL * 2);
+        t.add_plugin(Box::new(SysExPluginInstance {
+            frames: frames.clone(),
+        }));
+        let mut p = Processor::new();
+        p.add_track(t);
+
+        let data = [wmidi::U7::try_from(1).unwrap()];
+        let midi = [TimedMidi {
+            frame: AUTOMATION_INTERVAL + 3,
+            message: wmidi::MidiMessage::SysEx(&data),
+        }];
+        let mut left = vec![0.0; AUTOMATION_INTERVAL * 2];
+        let mut right = vec![0.0; AUTOMATION_INTERVAL * 2];
+        p.process(&[], &midi, &mut left, &mut right);
+        assert_eq!(*frames.lock().unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn tracks_are_delayed_to_compensate_for_latency() {
+        let mut p = Processor::new();
+        for (volume, latency) in [(1.0, 0), (0.5, 1), (0.25, 3)].iter() {
+            let mut t = Track::new(1024, *volume);
+            t.add_plugin(Box::new(ImpulsePluginInstance { latency: *latency }));
+            p.add_track(t);
+        }
+
+        let mut left = [0.0; 4];
+        let mut right = [0.0; 4];
+        p.process(&[], &[], &mut left, &mut right);
+
+        assert_eq!(p.latency(), 3);
+        assert_eq!(left, [0.25, 0.0, 0.5, 1.0]);
+        assert_eq!(right, [0.25, 0.0, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn delay_line_carries_audio_across_blocks() {
+        let mut d = DelayLine::new(4);
+        let mut first = [1.0, 2.0, 3.0];
+        let mut second = [4.0, 5.0, 6.0];
+        d.process(&mut first, 2);
+        d.process(&mut second, 2);
+        assert_eq!(first, [0.0, 0.0, 1.0]);
+        assert_eq!(second, [2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn metronome_counts_in_before_recording() {
+        let sink = FakeRecordSink::default();
+        let events = sink.0.clone();
+        let mut p = Processor::new();
+        let mut t = Track::new(250, 1.0);
+        t.set_input(Some((0, 0)));
+        t.set_record_sink(Some(Box::new(sink)));
+        p.add_track(t);
+        // One bar of two beats at 500 frames per beat.
+        p.set_tempo_map(TempoMap::new(1000.0, 120.0, 2));
+        p.set_metronome(Metronome::new(
+            1000.0,
+            250,
+            crate::metronome::MetronomeSettings {
+                count_in_bars: 1,
+                ..Default::default()
+            },
+        ));
+
+        let mut left = [0.0; 250];
+        let mut right = [0.0; 250];
+        p.transport_mut().set_record_enabled(true);
+        p.set_playing(true);
+        for _ in 0..4 {
+            p.process(&[&[1.0; 250]], &[], &mut left, &mut right);
+            assert!(events.lock().unwrap().is_empty());
+        }
+        p.process(&[&[1.0; 250]], &[], &mut left, &mut right);
+        assert_eq!(events.lock().unwrap()[0], RecordEvent::Start(0));
+    }
+
+    #[test]
+    fn monitored_inputs_are_played() {
+        let mut p = Processor::new();
+        let mut t = Track::new(2, 1.0);
+        t.set_input(Some((1, 0)));
+        t.set_monitoring(true);
+        p.add_track(t);
+
+        let mut left = [0.0; 2];
+        let mut right = [0.0; 2];
+        p.process(&[&[1.0, 2.0], &[3.0, 4.0]], &[], &mut left, &mut right);
+        assert_eq!([left, right], [[3.0, 4.0], [1.0, 2.0]]);
+
+        p.tracks_mut().next().unwrap().set_monitoring(false);
+        p.process(&[&[1.0, 2.0], &[3.0, 4.0]], &[], &mut left, &mut right);
+        assert_eq!([left, right], [[0.0, 0.0], [0.0, 0.0]]);
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum RecordEvent {
+        Start(u64),
+        Write(Vec<f32>, Vec<f32>),
+        Stop(u64),
+    }
+
+    #[derive(Debug, Default)]
+    struct FakeRecordSink(std::sync::Arc<std::sync::Mutex<Vec<RecordEvent>>>);
+
+    impl RecordSink for FakeRecordSink {
+        fn start(&mut self, frame: u64) {
+            self.0.lock().unwrap().push(RecordEvent::Start(frame));
+        }
+
+        fn write(&mut self, left: &[f32], right: &[f32]) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(RecordEvent::Write(left.to_vec(), right.to_vec()));
+        }
+
+        fn stop(&mut self, frame: u64) {
+            self.0.lock().unwrap().push(RecordEvent::Stop(frame));
+        }
+    }
+
+    #[test]
+    fn armed_tracks_are_recorded_while_transport_records() {
+        let sink = FakeRecordSink::default();
+        let events = sink.0.clone();
+        let mut p = Processor::new();
+        let mut t = Track::new(2, 1.0);
+        t.set_input(Some((0, 0)));
+        t.set_record_sink(Some(Box::new(sink)));
+        p.add_track(t);
+
+        let mut left = [0.0; 2];
+        let mut right = [0.0; 2];
+        p.transport_mut().set_record_enabled(true);
+        p.process(&[&[1.0, 2.0]], &[], &mut left, &mut right);
+        p.transport_mut().set_playing(true);
+        p.process(&[&[3.0, 4.0]], &[], &mut left, &mut right);
+        p.process(&[&[5.0, 6.0]], &[], &mut left, &mut right);
+        p.transport_mut().set_playing(false);
+        p.process(&[&[7.0, 8.0]], &[], &mut left, &mut right);
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                RecordEvent::Start(0),
+                RecordEvent::Write(vec![3.0, 4.0], vec![3.0, 4.0]),
+                RecordEvent::Write(vec![5.0, 6.0], vec![5.0, 6.0]),
+                RecordEvent::Stop(4),
+            ]
+        );
+    }
+
+    #[test]
+    fn disarming_track_stops_recording() {
+        let sink = FakeRecordSink::default();
+        let events = sink.0.clone();
+        let mut p = Processor::new();
+        let mut t = Track::new(2, 1.0);
+        t.set_input(Some((0, 0)));
+        t.set_record_sink(Some(Box::new(sink)));
+        p.add_track(t);
+
+        let mut left = [0.0; 2];
+        let mut right = [0.0; 2];
+        p.transport_mut().set_record_enabled(true);
+        p.transport_mut().set_playing(true);
+        p.process(&[&[1.0, 2.0]], &[], &mut left, &mut right);
+        p.tracks_mut().next().unwrap().set_record_sink(None);
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                RecordEvent::Start(0),
+                RecordEvent::Write(vec![1.0, 2.0], vec![1.0, 2.0]),
+                RecordEvent::Stop(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn deleting_track_stops_recording() {
+        let sink = FakeRecordSink::default();
+        let events = sink.0.clone();
+        let mut p = Processor::new();
+        let mut t = Track::new(2, 1.0);
+        t.set_input(Some((0, 0)));
+        t.set_record_sink(Some(Box::new(sink)));
+        p.add_track(t);
+
+        let mut left = [0.0; 2];
+        let mut right = [0.0; 2];
+        p.transport_mut().set_record_enabled(true);
+        p.transport_mut().set_playing(true);
+        p.process(&[&[1.0, 2.0]], &[], &mut left, &mut right);
+        p.delete_track(0);
+
+        assert_eq!(events.lock().unwrap().last(), Some(&RecordEvent::Stop(2)));
+    }
+
+    #[derive(Debug)]
+    struct TransposePluginInstance {
+        output: Vec<TimedMidi<'static>>,
+    }
+    impl PluginInstance for TransposePluginInstance {
+        fn process(&mut self, midi: &[TimedMidi<'_>], _: &mut [f32], _: &mut [f32]) {
+            self.output.clear();
+            for m in midi.iter() {
+                if let wmidi::MidiMessage::NoteOn(c, n, v) = m.message {
+                    let n = n.step(12).unwrap();
+                    self.output.push(TimedMidi {
+                        frame: m.frame,
+                        message: wmidi::MidiMessage::NoteOn(c, n, v),
+                    });
+                }
+            }
+        }
+
+        fn midi_output(&self) -> Option<&[TimedMidi<'static>]> {
+            Some(&self.output)
+        }
+    }
+
+    #[derive(Debug)]
+    struct NotesPluginInstance {
+        notes: std::sync::Arc<std::sync::Mutex<Vec<wmidi::Note>>>,
+    }
+    impl PluginInstance for NotesPluginInstance {
+        fn process(&mut self, midi: &[TimedMidi<'_>], _: &mut [f32], _: &mut [f32]) {
+            for m in midi.iter() {
+                if let wmidi::MidiMessage::NoteOn(_, n, _) = m.message {
+                    self.notes.lock().unwrap().push(n);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn midi_output_is_sent_to_downstream_plugins() {
+        let notes = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut t = Track::new(2, 1.0);
+        t.add_plugin(Box::new(TransposePluginInstance {
+            output: Vec::with_capacity(16),
+        }));
+        t.add_plugin(Box::new(NotesPluginInstance {
+            notes: notes.clone(),
+        }));
+        let mut p = Processor::new();
+        p.add_track(t);
+
+        let midi = [TimedMidi {
+            frame: 1,
+            message: wmidi::MidiMessage::NoteOn(
+                wmidi::Channel::Ch1,
+                wmidi::Note::C3,
+                wmidi::U7::MAX,
+            ),
+        }];
+        let mut left = [0.0; 2];
+        let mut right = [0.0; 2];
+        p.process(&[], &midi, &mut left, &mut right);
+        assert_eq!(*notes.lock().unwrap(), vec![wmidi::Note::C4]);
+        assert_eq!(p.midi_output().len(), 1);
+        assert_eq!(p.midi_output()[0].frame, 1);
+    }
 }
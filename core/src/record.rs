@@ -0,0 +1,16 @@
+/// Receives the audio input of an armed track while the transport is recording.
+///
+/// All methods are called from the realtime processing thread so implementations should not
+/// block or allocate.
+pub trait RecordSink: Send + std::fmt::Debug {
+    /// Called when recording starts. `frame` is the transport position of the first frame that
+    /// will be written.
+    fn start(&mut self, frame: u64);
+
+    /// Write a block of recorded audio.
+    fn write(&mut self, left: &[f32], right: &[f32]);
+
+    /// Called when recording stops. `frame` is the transport position after the last frame that
+    /// was written.
+    fn stop(&mut self, frame: u64);
+}
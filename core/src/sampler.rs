@@ -0,0 +1,422 @@
+use crate::plugin::PluginInstance;
+use crate::TimedMidi;
+use std::sync::Arc;
+
+/// The number of zones that can play at the same time.
+pub const MAX_VOICES: usize = 32;
+
+/// The shortest release, used to avoid clicks when a note stops. Voices that are stolen for a new
+/// note fade out this quickly.
+const MIN_RELEASE_SECONDS: f32 = 0.005;
+
+/// Releases below this level are considered silent.
+const SILENCE: f32 = 1e-4;
+
+/// Releases reach about 99% of the way to silence in their set time.
+const TIME_CONSTANTS_PER_RELEASE: f32 = 4.6;
+
+/// Audio loaded from a file. Mono samples have the same data in both channels.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sample {
+    pub sample_rate: f32,
+    pub left: Vec<f32>,
+    pub right: Vec<f32>,
+}
+
+impl Sample {
+    /// The number of frames in the sample.
+    pub fn len(&self) -> usize {
+        self.left.len().min(self.right.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A sample and the keys and velocities that play it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Zone {
+    pub sample: Arc<Sample>,
+    pub low_key: u8,
+    pub high_key: u8,
+    pub low_velocity: u8,
+    pub high_velocity: u8,
+    /// The key that plays the sample at its original pitch.
+    pub root_key: u8,
+    /// The frames to loop between while the zone plays. The end is exclusive. Zones without a loop
+    /// stop at the end of the sample.
+    pub loop_points: Option<(usize, usize)>,
+    /// If true, the loop only plays while the key is held. After the key is released the sample
+    /// plays through to its end.
+    pub loop_sustain: bool,
+    /// The time in seconds to fade out after the key is released.
+    pub release: f32,
+    pub gain: f32,
+}
+
+impl Zone {
+    fn contains(&self, key: u8, velocity: u8) -> bool {
+        (self.low_key..=self.high_key).contains(&key)
+            && (self.low_velocity..=self.high_velocity).contains(&velocity)
+    }
+
+    /// The loop points, if they are within the sample and not empty.
+    fn valid_loop(&self) -> Option<(f64, f64)> {
+        let (start, end) = self.loop_points?;
+        let end = end.min(self.sample.len());
+        if start < end {
+            Some((start as f64, end as f64))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Voice {
+    active: bool,
+    held: bool,
+    zone: usize,
+    channel: u8,
+    key: u8,
+    // When the voice was started, used to steal the oldest voice.
+    started: u64,
+    // The position in the sample in frames.
+    position: f64,
+    // The number of sample frames to advance for each output frame.
+    step: f64,
+    gain: f32,
+    level: f32,
+    release_coefficient: f32,
+}
+
+impl Voice {
+    /// The loop that the voice plays, if any.
+    fn loop_points(&self, zone: &Zone) -> Option<(f64, f64)> {
+        zone.valid_loop()
+            .filter(|_| self.held || !zone.loop_sustain)
+    }
+
+    /// Produce the next frame of the voice and advance it. The voice becomes inactive once it has
+    /// reached the end of the sample or faded out.
+    fn next_frame(&mut self, zone: &Zone) -> (f32, f32) {
+        let sample = &zone.sample;
+        let loop_points = self.loop_points(zone);
+        let index = self.position as usize;
+        let next = match loop_points {
+            Some((start, end)) if index + 1 >= end as usize => start as usize,
+            _ => index + 1,
+        };
+        let fraction = (self.position - index as f64) as f32;
+        let interpolate = |channel: &[f32]| {
+            let a = channel[index];
+            let b = channel.get(next).copied().unwrap_or(0.0);
+            a + (b - a) * fraction
+        };
+        let gain = self.gain * self.level;
+        let out = (
+            interpolate(&sample.left) * gain,
+            interpolate(&sample.right) * gain,
+        );
+
+        self.position += self.step;
+        if let Some((start, end)) = loop_points {
+            while self.position >= end {
+                self.position -= end - start;
+            }
+        }
+        if !self.held {
+            self.level *= self.release_coefficient;
+        }
+        if self.position as usize >= sample.len() || self.level < SILENCE {
+            self.active = false;
+        }
+        out
+    }
+}
+
+/// Plays samples mapped to zones of keys and velocities. Every zone that contains the key and
+/// velocity of a note is played, so zones can be layered.
+#[derive(Clone, Debug)]
+pub struct Sampler {
+    sample_rate: f32,
+    zones: Arc<Vec<Zone>>,
+    voices: [Voice; MAX_VOICES],
+    // Voices that were replaced by a new note and are fading out.
+    stolen_voices: [Voice; MAX_VOICES],
+    // The number of voices started so far.
+    voices_started: u64,
+}
+
+impl Sampler {
+    pub fn new(sample_rate: f32, zones: Arc<Vec<Zone>>) -> Sampler {
+        Sampler {
+            sample_rate,
+            zones,
+            voices: [Voice::default(); MAX_VOICES],
+            stolen_voices: [Voice::default(); MAX_VOICES],
+            voices_started: 0,
+        }
+    }
+
+    /// The number of voices that are making sound.
+    pub fn active_voices(&self) -> usize {
+        self.voices
+            .iter()
+            .chain(self.stolen_voices.iter())
+            .filter(|v| v.active)
+            .count()
+    }
+
+    fn handle_midi(&mut self, message: &wmidi::MidiMessage<'_>) {
+        match message {
+            wmidi::MidiMessage::NoteOn(channel, note, velocity) if u8::from(*velocity) > 0 => {
+                self.note_on(channel.index(), u8::from(*note), u8::from(*velocity))
+            }
+            wmidi::MidiMessage::NoteOn(channel, note, _)
+            | wmidi::MidiMessage::NoteOff(channel, note, _) => {
+                let (channel, key) = (channel.index(), u8::from(*note));
+                for v in self.voices.iter_mut() {
+                    if v.held && v.channel == channel && v.key == key {
+                        v.held = false;
+                    }
+                }
+            }
+            wmidi::MidiMessage::ControlChange(_, wmidi::ControlFunction::ALL_NOTES_OFF, _)
+            | wmidi::MidiMessage::ControlChange(_, wmidi::ControlFunction::ALL_SOUND_OFF, _) => {
+                for v in self.voices.iter_mut() {
+                    v.held = false;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        for zone_index in 0..self.zones.len() {
+            let zone = &self.zones[zone_index];
+            if !zone.contains(key, velocity) || zone.sample.is_empty() {
+                continue;
+            }
+            let semitones = f64::from(key) - f64::from(zone.root_key);
+            let release_samples = zone.release.max(MIN_RELEASE_SECONDS) * self.sample_rate;
+            let voice = Voice {
+                active: true,
+                held: true,
+                zone: zone_index,
+                channel,
+                key,
+                started: self.voices_started,
+                position: 0.0,
+                step: 2f64.powf(semitones / 12.0) * f64::from(zone.sample.sample_rate)
+                    / f64::from(self.sample_rate),
+                gain: zone.gain * f32::from(velocity) / 127.0,
+                level: 1.0,
+                release_coefficient: (-TIME_CONSTANTS_PER_RELEASE / release_samples).exp(),
+            };
+            let index = self.free_voice();
+            if self.voices[index].active {
+                self.steal_voice(index);
+            }
+            self.voices[index] = voice;
+            self.voices_started += 1;
+        }
+    }
+
+    /// An inactive voice, or the oldest voice if all are active.
+    fn free_voice(&self) -> usize {
+        if let Some(i) = self.voices.iter().position(|v| !v.active) {
+            return i;
+        }
+        self.voices
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, v)| (v.held, v.started))
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    }
+
+    /// Move the voice at `index` to the stolen voices so that it fades out quickly instead of
+    /// stopping with a click. If all stolen voices are still fading, the quietest is replaced.
+    fn steal_voice(&mut self, index: usize) {
+        let mut voice = self.voices[index];
+        voice.held = false;
+        voice.release_coefficient =
+            (-TIME_CONSTANTS_PER_RELEASE / (MIN_RELEASE_SECONDS * self.sample_rate)).exp();
+        let slot = match self.stolen_voices.iter().position(|v| !v.active) {
+            Some(i) => i,
+            None => self
+                .stolen_voices
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.level.total_cmp(&b.level))
+                .map(|(i, _)| i)
+                .unwrap_or(0),
+        };
+        self.stolen_voices[slot] = voice;
+    }
+}
+
+impl PluginInstance for Sampler {
+    fn process(&mut self, midi: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+        for (l, r) in out_left.iter_mut().zip(out_right.iter_mut()) {
+            *l = 0.0;
+            *r = 0.0;
+        }
+        let mut midi_iter = midi.iter().peekable();
+        for frame in 0..out_left.len().min(out_right.len()) {
+            while let Some(m) = midi_iter.next_if(|m| m.frame <= frame) {
+                self.handle_midi(&m.message);
+            }
+            let voices = self.voices.iter_mut().chain(self.stolen_voices.iter_mut());
+            for v in voices.filter(|v| v.active) {
+                let (left, right) = v.next_frame(&self.zones[v.zone]);
+                out_left[frame] += left;
+                out_right[frame] += right;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+
+    fn midi(frame: usize, bytes: &[u8]) -> TimedMidi<'static> {
+        TimedMidi {
+            frame,
+            message: wmidi::MidiMessage::try_from(bytes)
+                .unwrap()
+                .drop_unowned_sysex()
+                .unwrap(),
+        }
+    }
+
+    fn ramp(len: usize) -> Arc<Sample> {
+        let data: Vec<f32> = (0..len).map(|i| i as f32).collect();
+        Arc::new(Sample {
+            sample_rate: SAMPLE_RATE,
+            left: data.clone(),
+            right: data,
+        })
+    }
+
+    fn zone(sample: Arc<Sample>, low_key: u8, high_key: u8) -> Zone {
+        Zone {
+            sample,
+            low_key,
+            high_key,
+            low_velocity: 0,
+            high_velocity: 127,
+            root_key: 60,
+            loop_points: None,
+            loop_sustain: false,
+            release: 0.0,
+            gain: 1.0,
+        }
+    }
+
+    fn process(sampler: &mut Sampler, midi: &[TimedMidi<'_>], frames: usize) -> Vec<f32> {
+        let mut left = vec![0.0; frames];
+        let mut right = vec![0.0; frames];
+        sampler.process(midi, &mut left, &mut right);
+        left
+    }
+
+    #[test]
+    fn root_key_plays_the_sample_as_is() {
+        let mut sampler = Sampler::new(SAMPLE_RATE, Arc::new(vec![zone(ramp(4), 0, 127)]));
+        let out = process(&mut sampler, &[midi(1, &[0x90, 60, 127])], 6);
+        assert_eq!(out, vec![0.0, 0.0, 1.0, 2.0, 3.0, 0.0]);
+        assert_eq!(sampler.active_voices(), 0);
+    }
+
+    #[test]
+    fn keys_are_transposed_from_the_root() {
+        let mut sampler = Sampler::new(SAMPLE_RATE, Arc::new(vec![zone(ramp(8), 0, 127)]));
+        let out = process(&mut sampler, &[midi(0, &[0x90, 72, 127])], 4);
+        assert_eq!(out, vec![0.0, 2.0, 4.0, 6.0]);
+    }
+
+    #[test]
+    fn zones_are_selected_by_key_and_velocity() {
+        let mut soft = zone(ramp(100), 0, 63);
+        soft.high_velocity = 63;
+        let mut loud = zone(ramp(100), 0, 63);
+        loud.low_velocity = 64;
+        let high = zone(ramp(100), 64, 127);
+        let mut sampler = Sampler::new(SAMPLE_RATE, Arc::new(vec![soft, loud, high]));
+
+        process(&mut sampler, &[midi(0, &[0x90, 60, 100])], 1);
+        assert_eq!(sampler.active_voices(), 1);
+        assert_eq!(sampler.voices[0].zone, 1);
+        process(&mut sampler, &[midi(0, &[0x90, 70, 10])], 1);
+        assert_eq!(sampler.active_voices(), 2);
+        assert_eq!(sampler.voices[1].zone, 2);
+    }
+
+    #[test]
+    fn loops_play_until_released() {
+        let mut looped = zone(ramp(10), 0, 127);
+        looped.loop_points = Some((4, 8));
+        looped.release = 0.05;
+        let mut sampler = Sampler::new(SAMPLE_RATE, Arc::new(vec![looped]));
+
+        let out = process(&mut sampler, &[midi(0, &[0x90, 60, 127])], 12);
+        assert_eq!(
+            out,
+            vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 4.0, 5.0, 6.0, 7.0]
+        );
+        assert_eq!(sampler.active_voices(), 1);
+
+        let released = process(&mut sampler, &[midi(0, &[0x80, 60, 0])], 4);
+        assert!(released[3] < 7.0 && released[3] > 0.0);
+        process(&mut sampler, &[], 1000);
+        assert_eq!(sampler.active_voices(), 0);
+    }
+
+    #[test]
+    fn sustain_loops_play_to_the_end_after_release() {
+        let mut looped = zone(ramp(10), 0, 127);
+        looped.loop_points = Some((4, 8));
+        looped.loop_sustain = true;
+        looped.release = 1.0;
+        let mut sampler = Sampler::new(SAMPLE_RATE, Arc::new(vec![looped]));
+
+        process(&mut sampler, &[midi(0, &[0x90, 60, 127])], 12);
+        let released = process(&mut sampler, &[midi(0, &[0x80, 60, 0])], 8);
+        assert_eq!(released[0], 4.0);
+        // The voice leaves the loop and fades while it plays frames 8 and 9.
+        assert!(released[4] > 7.0 && released[4] < 8.0);
+        assert!(released[5] > 8.0 && released[5] < 9.0);
+        assert_eq!(released[6], 0.0);
+        assert_eq!(sampler.active_voices(), 0);
+    }
+
+    #[test]
+    fn stolen_voices_fade_out() {
+        let constant = Arc::new(Sample {
+            sample_rate: SAMPLE_RATE,
+            left: vec![1.0; 10000],
+            right: vec![1.0; 10000],
+        });
+        let mut sampler = Sampler::new(SAMPLE_RATE, Arc::new(vec![zone(constant, 0, 127)]));
+        let notes: Vec<_> = (0..MAX_VOICES as u8)
+            .map(|key| midi(0, &[0x90, key, 127]))
+            .collect();
+        process(&mut sampler, &notes, 1);
+        assert_eq!(sampler.active_voices(), MAX_VOICES);
+
+        let out = process(&mut sampler, &[midi(0, &[0x90, 100, 127])], 2);
+        // The stolen voice still plays next to the new voice and fades out.
+        assert_eq!(sampler.active_voices(), MAX_VOICES + 1);
+        assert_eq!(out[0], MAX_VOICES as f32 + 1.0);
+        assert!(out[1] > MAX_VOICES as f32 && out[1] < out[0]);
+        process(&mut sampler, &[], 100);
+        assert_eq!(sampler.active_voices(), MAX_VOICES);
+    }
+}
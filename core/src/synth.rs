@@ -0,0 +1,626 @@
+use crate::plugin::{ParameterInfo, PluginInstance};
+use crate::TimedMidi;
+
+/// The number of notes that can play at the same time.
+pub const MAX_VOICES: usize = 16;
+
+/// Envelopes below this level are considered silent.
+const SILENCE: f32 = 1e-4;
+
+/// The longest attack, decay and release time in seconds.
+const MAX_ENVELOPE_SECONDS: f32 = 10.0;
+
+/// Exponential segments reach about 99% of the way to their target in their set time.
+const TIME_CONSTANTS_PER_SEGMENT: f32 = 4.6;
+
+/// How much the triangle integrator leaks each sample to keep it centered.
+const TRIANGLE_LEAK: f32 = 0.999;
+
+/// The shape of the oscillator.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+}
+
+impl Waveform {
+    fn from_value(value: f32) -> Waveform {
+        match value.round().clamp(0.0, 3.0) as i32 {
+            1 => Waveform::Saw,
+            2 => Waveform::Square,
+            3 => Waveform::Triangle,
+            _ => Waveform::Sine,
+        }
+    }
+}
+
+/// The parameters of the synth, in the order of their indices for `set_parameter`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SynthParameter {
+    /// 0 for sine, 1 for saw, 2 for square and 3 for triangle.
+    Waveform,
+    /// The attack time in seconds.
+    Attack,
+    /// The decay time in seconds.
+    Decay,
+    /// The sustain level from 0 to 1.
+    Sustain,
+    /// The release time in seconds.
+    Release,
+    /// The cutoff frequency of the low pass filter in Hz.
+    Cutoff,
+    /// The resonance of the filter from 0 to 1.
+    Resonance,
+    /// How much the velocity affects the level, from 0 (not at all) to 1.
+    VelocitySensitivity,
+    /// The pitch bend range in semitones.
+    PitchBendRange,
+    /// The output level.
+    Volume,
+}
+
+impl SynthParameter {
+    pub const ALL: [SynthParameter; 10] = [
+        SynthParameter::Waveform,
+        SynthParameter::Attack,
+        SynthParameter::Decay,
+        SynthParameter::Sustain,
+        SynthParameter::Release,
+        SynthParameter::Cutoff,
+        SynthParameter::Resonance,
+        SynthParameter::VelocitySensitivity,
+        SynthParameter::PitchBendRange,
+        SynthParameter::Volume,
+    ];
+
+    pub fn from_index(index: usize) -> Option<SynthParameter> {
+        SynthParameter::ALL.get(index).copied()
+    }
+
+    /// Describe the parameter, with its default from `SynthSettings::default`.
+    pub fn info(self) -> ParameterInfo {
+        let default = SynthSettings::default().get(self);
+        let info =
+            |id, name, min, max| ParameterInfo::new(self as usize, id, name, min, max, default);
+        let seconds = |id, name| info(id, name, 0.0, MAX_ENVELOPE_SECONDS).with_unit("s");
+        match self {
+            SynthParameter::Waveform => info("waveform", "Waveform", 0.0, 3.0).with_enumeration(&[
+                (0.0, "Sine"),
+                (1.0, "Saw"),
+                (2.0, "Square"),
+                (3.0, "Triangle"),
+            ]),
+            SynthParameter::Attack => seconds("attack", "Attack"),
+            SynthParameter::Decay => seconds("decay", "Decay"),
+            SynthParameter::Sustain => info("sustain", "Sustain", 0.0, 1.0),
+            SynthParameter::Release => seconds("release", "Release"),
+            SynthParameter::Cutoff => info("cutoff", "Cutoff", 20.0, 20000.0).with_unit("Hz"),
+            SynthParameter::Resonance => info("resonance", "Resonance", 0.0, 1.0),
+            SynthParameter::VelocitySensitivity => {
+                info("velocity_sensitivity", "Velocity Sensitivity", 0.0, 1.0)
+            }
+            SynthParameter::PitchBendRange => {
+                info("pitch_bend_range", "Pitch Bend Range", 0.0, 48.0).with_unit("semitones")
+            }
+            SynthParameter::Volume => info("volume", "Volume", 0.0, 1.0),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SynthSettings {
+    pub waveform: Waveform,
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    pub cutoff: f32,
+    pub resonance: f32,
+    pub velocity_sensitivity: f32,
+    pub pitch_bend_range: f32,
+    pub volume: f32,
+}
+
+impl Default for SynthSettings {
+    fn default() -> SynthSettings {
+        SynthSettings {
+            waveform: Waveform::Saw,
+            attack: 0.005,
+            decay: 0.2,
+            sustain: 0.7,
+            release: 0.3,
+            cutoff: 8000.0,
+            resonance: 0.2,
+            velocity_sensitivity: 0.8,
+            pitch_bend_range: 2.0,
+            volume: 0.25,
+        }
+    }
+}
+
+impl SynthSettings {
+    fn set(&mut self, parameter: SynthParameter, value: f32) {
+        match parameter {
+            SynthParameter::Waveform => self.waveform = Waveform::from_value(value),
+            SynthParameter::Attack => self.attack = value.clamp(0.0, MAX_ENVELOPE_SECONDS),
+            SynthParameter::Decay => self.decay = value.clamp(0.0, MAX_ENVELOPE_SECONDS),
+            SynthParameter::Sustain => self.sustain = value.clamp(0.0, 1.0),
+            SynthParameter::Release => self.release = value.clamp(0.0, MAX_ENVELOPE_SECONDS),
+            SynthParameter::Cutoff => self.cutoff = value.clamp(20.0, 20000.0),
+            SynthParameter::Resonance => self.resonance = value.clamp(0.0, 1.0),
+            SynthParameter::VelocitySensitivity => {
+                self.velocity_sensitivity = value.clamp(0.0, 1.0)
+            }
+            SynthParameter::PitchBendRange => self.pitch_bend_range = value.clamp(0.0, 48.0),
+            SynthParameter::Volume => self.volume = value.clamp(0.0, 1.0),
+        }
+    }
+
+    fn get(&self, parameter: SynthParameter) -> f32 {
+        match parameter {
+            SynthParameter::Waveform => self.waveform as usize as f32,
+            SynthParameter::Attack => self.attack,
+            SynthParameter::Decay => self.decay,
+            SynthParameter::Sustain => self.sustain,
+            SynthParameter::Release => self.release,
+            SynthParameter::Cutoff => self.cutoff,
+            SynthParameter::Resonance => self.resonance,
+            SynthParameter::VelocitySensitivity => self.velocity_sensitivity,
+            SynthParameter::PitchBendRange => self.pitch_bend_range,
+            SynthParameter::Volume => self.volume,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Release,
+}
+
+/// Per sample rates of the envelope, computed once per block from the settings.
+#[derive(Copy, Clone, Debug)]
+struct EnvelopeRates {
+    attack_step: f32,
+    decay_coefficient: f32,
+    release_coefficient: f32,
+    sustain: f32,
+}
+
+impl EnvelopeRates {
+    fn new(settings: &SynthSettings, sample_rate: f32) -> EnvelopeRates {
+        let coefficient = |seconds: f32| {
+            let samples = seconds * sample_rate;
+            if samples < 1.0 {
+                0.0
+            } else {
+                (-TIME_CONSTANTS_PER_SEGMENT / samples).exp()
+            }
+        };
+        EnvelopeRates {
+            attack_step: 1.0 / (settings.attack * sample_rate).max(1.0),
+            decay_coefficient: coefficient(settings.decay),
+            release_coefficient: coefficient(settings.release),
+            sustain: settings.sustain,
+        }
+    }
+}
+
+/// A low pass state variable filter.
+#[derive(Copy, Clone, Debug, Default)]
+struct FilterState {
+    ic1: f32,
+    ic2: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct FilterCoefficients {
+    a1: f32,
+    a2: f32,
+    a3: f32,
+}
+
+impl FilterCoefficients {
+    fn new(settings: &SynthSettings, sample_rate: f32) -> FilterCoefficients {
+        let cutoff = settings.cutoff.min(0.49 * sample_rate);
+        let g = (std::f32::consts::PI * cutoff / sample_rate).tan();
+        let k = 2.0 - 1.95 * settings.resonance;
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+        FilterCoefficients { a1, a2, a3 }
+    }
+}
+
+impl FilterState {
+    fn process(&mut self, c: &FilterCoefficients, input: f32) -> f32 {
+        let v3 = input - self.ic2;
+        let v1 = c.a1 * self.ic1 + c.a2 * v3;
+        let v2 = self.ic2 + c.a2 * self.ic1 + c.a3 * v3;
+        self.ic1 = 2.0 * v1 - self.ic1;
+        self.ic2 = 2.0 * v2 - self.ic2;
+        v2
+    }
+}
+
+/// Smooths the discontinuity of a waveform that jumps by 2 when `phase` wraps.
+fn poly_blep(phase: f32, phase_step: f32) -> f32 {
+    if phase < phase_step {
+        let t = phase / phase_step;
+        t + t - t * t - 1.0
+    } else if phase > 1.0 - phase_step {
+        let t = (phase - 1.0) / phase_step;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Voice {
+    channel: u8,
+    note: wmidi::Note,
+    // True while the key is held.
+    held: bool,
+    // When the voice was started, used to steal the oldest voice.
+    started: u64,
+    stage: Stage,
+    level: f32,
+    gain: f32,
+    frequency: f32,
+    phase: f32,
+    triangle: f32,
+    filter: FilterState,
+}
+
+impl Default for Voice {
+    fn default() -> Voice {
+        Voice {
+            channel: 0,
+            note: wmidi::Note::C4,
+            held: false,
+            started: 0,
+            stage: Stage::Idle,
+            level: 0.0,
+            gain: 0.0,
+            frequency: 0.0,
+            phase: 0.0,
+            triangle: 0.0,
+            filter: FilterState::default(),
+        }
+    }
+}
+
+impl Voice {
+    fn is_active(&self) -> bool {
+        self.stage != Stage::Idle
+    }
+
+    /// Start playing `note`. A voice that is still sounding keeps its level and phase so that the
+    /// new note starts without a click.
+    fn start(&mut self, channel: u8, note: wmidi::Note, gain: f32, started: u64) {
+        if !self.is_active() {
+            self.phase = 0.0;
+            self.triangle = -1.0;
+            self.level = 0.0;
+            self.filter = FilterState::default();
+        }
+        self.channel = channel;
+        self.note = note;
+        self.held = true;
+        self.started = started;
+        self.stage = Stage::Attack;
+        self.gain = gain;
+        self.frequency = note.to_freq_f32();
+    }
+
+    fn release(&mut self) {
+        self.held = false;
+        if self.is_active() {
+            self.stage = Stage::Release;
+        }
+    }
+
+    fn next_level(&mut self, rates: &EnvelopeRates) -> f32 {
+        match self.stage {
+            Stage::Idle => {}
+            Stage::Attack => {
+                self.level += rates.attack_step;
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level = rates.sustain + (self.level - rates.sustain) * rates.decay_coefficient;
+            }
+            Stage::Release => {
+                self.level *= rates.release_coefficient;
+                if self.level < SILENCE {
+                    self.level = 0.0;
+                    self.stage = Stage::Idle;
+                }
+            }
+        }
+        self.level
+    }
+
+    fn next_sample(&mut self, waveform: Waveform, phase_step: f32) -> f32 {
+        let phase = self.phase;
+        let sample = match waveform {
+            Waveform::Sine => (2.0 * std::f32::consts::PI * phase).sin(),
+            Waveform::Saw => 2.0 * phase - 1.0 - poly_blep(phase, phase_step),
+            Waveform::Square | Waveform::Triangle => {
+                let naive = if phase < 0.5 { 1.0 } else { -1.0 };
+                let square = naive + poly_blep(phase, phase_step)
+                    - poly_blep((phase + 0.5).fract(), phase_step);
+                if waveform == Waveform::Square {
+                    square
+                } else {
+                    self.triangle = 4.0 * phase_step * square + TRIANGLE_LEAK * self.triangle;
+                    self.triangle
+                }
+            }
+        };
+        self.phase = (phase + phase_step).fract();
+        sample
+    }
+}
+
+/// A polyphonic subtractive synthesizer. Each voice is an oscillator followed by a low pass filter
+/// and an ADSR envelope. When all voices are in use, the quietest released voice or else the
+/// oldest voice is stolen.
+#[derive(Clone, Debug)]
+pub struct Synth {
+    sample_rate: f32,
+    settings: SynthSettings,
+    voices: [Voice; MAX_VOICES],
+    // The number of notes started so far.
+    notes_started: u64,
+    // The pitch bend from -1 to 1.
+    pitch_bend: f32,
+}
+
+impl Synth {
+    pub fn new(sample_rate: f32) -> Synth {
+        Synth {
+            sample_rate,
+            settings: SynthSettings::default(),
+            voices: [Voice::default(); MAX_VOICES],
+            notes_started: 0,
+            pitch_bend: 0.0,
+        }
+    }
+
+    pub fn settings(&self) -> &SynthSettings {
+        &self.settings
+    }
+
+    /// The number of voices that are making sound.
+    pub fn active_voices(&self) -> usize {
+        self.voices.iter().filter(|v| v.is_active()).count()
+    }
+
+    fn handle_midi(&mut self, message: &wmidi::MidiMessage<'_>) {
+        match message {
+            wmidi::MidiMessage::NoteOn(channel, note, velocity) if u8::from(*velocity) > 0 => {
+                self.note_on(channel.index(), *note, u8::from(*velocity))
+            }
+            wmidi::MidiMessage::NoteOn(channel, note, _)
+            | wmidi::MidiMessage::NoteOff(channel, note, _) => {
+                let channel = channel.index();
+                for v in self.voices.iter_mut() {
+                    if v.held && v.channel == channel && v.note == *note {
+                        v.release();
+                    }
+                }
+            }
+            wmidi::MidiMessage::PitchBendChange(_, bend) => {
+                self.pitch_bend = (f32::from(u16::from(*bend)) - 8192.0) / 8192.0;
+            }
+            wmidi::MidiMessage::ControlChange(_, wmidi::ControlFunction::ALL_NOTES_OFF, _)
+            | wmidi::MidiMessage::ControlChange(_, wmidi::ControlFunction::ALL_SOUND_OFF, _) => {
+                for v in self.voices.iter_mut() {
+                    v.release();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn note_on(&mut self, channel: u8, note: wmidi::Note, velocity: u8) {
+        let sensitivity = self.settings.velocity_sensitivity;
+        let gain = 1.0 - sensitivity + sensitivity * f32::from(velocity) / 127.0;
+        let index = self.voice_for(channel, note);
+        self.notes_started += 1;
+        self.voices[index].start(channel, note, gain, self.notes_started);
+    }
+
+    /// The voice to play a new note on. A voice already playing the same note is reused, then an
+    /// idle voice, then the quietest released voice and finally the oldest voice.
+    fn voice_for(&self, channel: u8, note: wmidi::Note) -> usize {
+        let position = |f: &dyn Fn(&Voice) -> bool| self.voices.iter().position(f);
+        if let Some(i) = position(&|v| v.is_active() && v.channel == channel && v.note == note) {
+            return i;
+        }
+        if let Some(i) = position(&|v| !v.is_active()) {
+            return i;
+        }
+        let released = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| !v.held)
+            .min_by(|(_, a), (_, b)| a.level.total_cmp(&b.level));
+        if let Some((i, _)) = released {
+            return i;
+        }
+        self.voices
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, v)| v.started)
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    }
+}
+
+impl PluginInstance for Synth {
+    fn process(&mut self, midi: &[TimedMidi<'_>], out_left: &mut [f32], out_right: &mut [f32]) {
+        let rates = EnvelopeRates::new(&self.settings, self.sample_rate);
+        let filter = FilterCoefficients::new(&self.settings, self.sample_rate);
+        let waveform = self.settings.waveform;
+        let volume = self.settings.volume;
+        let nyquist = 0.5 * self.sample_rate;
+        let mut midi_iter = midi.iter().peekable();
+        for (frame, output) in out_left.iter_mut().enumerate() {
+            while let Some(m) = midi_iter.next_if(|m| m.frame <= frame) {
+                self.handle_midi(&m.message);
+            }
+            let bend = 2f32.powf(self.pitch_bend * self.settings.pitch_bend_range / 12.0);
+            let mut sample = 0.0;
+            for v in self.voices.iter_mut().filter(|v| v.is_active()) {
+                let phase_step = (v.frequency * bend).min(nyquist) / self.sample_rate;
+                let oscillator = v.next_sample(waveform, phase_step);
+                let filtered = v.filter.process(&filter, oscillator);
+                sample += filtered * v.next_level(&rates) * v.gain;
+            }
+            *output = sample * volume;
+        }
+        out_right.copy_from_slice(out_left);
+    }
+
+    fn parameters(&self) -> Vec<ParameterInfo> {
+        SynthParameter::ALL.iter().map(|p| p.info()).collect()
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        if let Some(parameter) = SynthParameter::from_index(index) {
+            self.settings.set(parameter, value);
+        }
+    }
+
+    fn get_parameter(&self, index: usize) -> Option<f32> {
+        SynthParameter::from_index(index).map(|p| self.settings.get(p))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    const SAMPLE_RATE: f32 = 44100.0;
+
+    fn midi(frame: usize, bytes: &[u8]) -> TimedMidi<'static> {
+        TimedMidi {
+            frame,
+            message: wmidi::MidiMessage::try_from(bytes)
+                .unwrap()
+                .drop_unowned_sysex()
+                .unwrap(),
+        }
+    }
+
+    fn process(synth: &mut Synth, midi: &[TimedMidi<'_>], frames: usize) -> Vec<f32> {
+        let mut left = vec![0.0; frames];
+        let mut right = vec![0.0; frames];
+        synth.process(midi, &mut left, &mut right);
+        assert_eq!(left, right);
+        left
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |p, s| p.max(s.abs()))
+    }
+
+    #[test]
+    fn chords_play_every_note() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        let chord = [
+            midi(0, &[0x90, 60, 100]),
+            midi(0, &[0x90, 64, 100]),
+            midi(0, &[0x90, 67, 100]),
+        ];
+        process(&mut synth, &chord, 64);
+        assert_eq!(synth.active_voices(), 3);
+
+        process(&mut synth, &[midi(0, &[0x80, 64, 0])], 64);
+        assert_eq!(synth.active_voices(), 3);
+        process(&mut synth, &[], SAMPLE_RATE as usize);
+        assert_eq!(synth.active_voices(), 2);
+    }
+
+    #[test]
+    fn release_fades_out_without_clicks() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        synth.set_parameter(SynthParameter::Waveform as usize, 0.0);
+        process(&mut synth, &[midi(0, &[0x90, 69, 127])], 4410);
+        let released = process(&mut synth, &[midi(0, &[0x80, 69, 0])], 4410);
+        let max_step = released
+            .windows(2)
+            .fold(0.0f32, |m, w| m.max((w[1] - w[0]).abs()));
+        assert!(peak(&released[..100]) > 0.05);
+        assert!(max_step < 0.1, "step of {}", max_step);
+        process(&mut synth, &[], SAMPLE_RATE as usize);
+        assert_eq!(synth.active_voices(), 0);
+    }
+
+    #[test]
+    fn voices_are_stolen_when_all_are_used() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        let notes: Vec<_> = (0..MAX_VOICES as u8 + 4)
+            .map(|i| midi(usize::from(i), &[0x90, 40 + i, 100]))
+            .collect();
+        process(&mut synth, &notes, 256);
+        assert_eq!(synth.active_voices(), MAX_VOICES);
+        let newest = wmidi::Note::try_from(40 + MAX_VOICES as u8 + 3).unwrap();
+        assert!(synth.voices.iter().any(|v| v.note == newest));
+        assert!(synth.voices.iter().all(|v| v.note != wmidi::Note::E2));
+    }
+
+    #[test]
+    fn band_limited_waveforms_stay_in_range() {
+        for waveform in 0..4 {
+            let mut synth = Synth::new(SAMPLE_RATE);
+            synth.set_parameter(SynthParameter::Waveform as usize, waveform as f32);
+            synth.set_parameter(SynthParameter::Cutoff as usize, 20000.0);
+            synth.set_parameter(SynthParameter::Resonance as usize, 0.0);
+            synth.set_parameter(SynthParameter::Volume as usize, 1.0);
+            let out = process(&mut synth, &[midi(0, &[0x90, 100, 127])], 8192);
+            assert!(peak(&out) > 0.3, "waveform {}", waveform);
+            assert!(peak(&out) < 1.5, "waveform {}", waveform);
+        }
+    }
+
+    #[test]
+    fn velocity_and_pitch_bend() {
+        let level = |velocity| {
+            let mut synth = Synth::new(SAMPLE_RATE);
+            synth.set_parameter(SynthParameter::Waveform as usize, 0.0);
+            peak(&process(
+                &mut synth,
+                &[midi(0, &[0x90, 69, velocity])],
+                4410,
+            ))
+        };
+        assert!(level(127) > 2.0 * level(20));
+
+        let mut synth = Synth::new(SAMPLE_RATE);
+        process(&mut synth, &[midi(0, &[0xE0, 0x7F, 0x7F])], 1);
+        synth.note_on(0, wmidi::Note::A4, 100);
+        let phase_step_before = synth.voices[0].phase;
+        process(&mut synth, &[], 1);
+        let bent_step = synth.voices[0].phase - phase_step_before;
+        let expected = 440.0 * 2f32.powf(2.0 / 12.0) / SAMPLE_RATE;
+        assert!((bent_step - expected).abs() < 1e-4, "{}", bent_step);
+    }
+
+    #[test]
+    fn declared_parameters_match_the_settings() {
+        crate::effects::check_parameters(&mut Synth::new(SAMPLE_RATE));
+    }
+}
@@ -0,0 +1,309 @@
+/// A change of tempo and meter that takes effect at the start of a bar.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TempoChange {
+    /// The zero based bar at which the change takes effect.
+    pub bar: u32,
+    pub bpm: f64,
+    pub beats_per_bar: u32,
+}
+
+impl Default for TempoChange {
+    fn default() -> TempoChange {
+        TempoChange {
+            bar: 0,
+            bpm: 120.0,
+            beats_per_bar: 4,
+        }
+    }
+}
+
+/// A musical position. All fields are zero based.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Position {
+    pub bar: u32,
+    pub beat: u32,
+    /// How far into the beat the position is, in the range [0, 1).
+    pub beat_fraction: f64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Segment {
+    change: TempoChange,
+    start_beat: f64,
+    start_frame: f64,
+}
+
+impl Segment {
+    fn frames_per_beat(&self, sample_rate: f64) -> f64 {
+        60.0 * sample_rate / self.change.bpm
+    }
+}
+
+/// Maps between frames, beats and bars.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TempoMap {
+    sample_rate: f64,
+    segments: Vec<Segment>,
+}
+
+impl TempoMap {
+    /// Create a tempo map with a constant tempo and meter.
+    pub fn new(sample_rate: f64, bpm: f64, beats_per_bar: u32) -> TempoMap {
+        TempoMap::with_changes(
+            sample_rate,
+            &[TempoChange {
+                bar: 0,
+                bpm,
+                beats_per_bar,
+            }],
+        )
+    }
+
+    /// Create a tempo map from a list of changes. If no change starts at bar 0, the default tempo
+    /// and meter are used until the first change. Changes with a non positive or infinite tempo or
+    /// no beats per bar are ignored.
+    pub fn with_changes(sample_rate: f64, changes: &[TempoChange]) -> TempoMap {
+        let mut changes: Vec<TempoChange> = changes
+            .iter()
+            .filter(|c| c.bpm.is_finite() && c.bpm > 0.0 && c.beats_per_bar > 0)
+            .copied()
+            .collect();
+        changes.sort_by_key(|c| c.bar);
+        changes.dedup_by_key(|c| c.bar);
+        if changes.first().map(|c| c.bar != 0).unwrap_or(true) {
+            changes.insert(0, TempoChange::default());
+        }
+        let mut segments: Vec<Segment> = Vec::with_capacity(changes.len());
+        for change in changes {
+            let segment = match segments.last() {
+                None => Segment {
+                    change,
+                    start_beat: 0.0,
+                    start_frame: 0.0,
+                },
+                Some(previous) => {
+                    let bars = f64::from(change.bar - previous.change.bar);
+                    let beats = bars * f64::from(previous.change.beats_per_bar);
+                    Segment {
+                        change,
+                        start_beat: previous.start_beat + beats,
+                        start_frame: previous.start_frame
+                            + beats * previous.frames_per_beat(sample_rate),
+                    }
+                }
+            };
+            segments.push(segment);
+        }
+        TempoMap {
+            sample_rate,
+            segments,
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// Replace the tempo map with a constant tempo and meter anchored so that `beat` occurs at
+    /// `frame`. This is used to follow an external tempo source and does not allocate.
+    pub fn follow(&mut self, bpm: f64, beats_per_bar: u32, frame: f64, beat: f64) {
+        if !bpm.is_finite() || bpm <= 0.0 || beats_per_bar == 0 {
+            return;
+        }
+        let change = TempoChange {
+            bar: 0,
+            bpm,
+            beats_per_bar,
+        };
+        let frames_per_beat = 60.0 * self.sample_rate / bpm;
+        self.segments.clear();
+        self.segments.push(Segment {
+            change,
+            start_beat: 0.0,
+            start_frame: frame - beat * frames_per_beat,
+        });
+    }
+
+    /// All the tempo changes, ordered by bar.
+    pub fn changes(&self) -> impl Iterator<Item = &'_ TempoChange> {
+        self.segments.iter().map(|s| &s.change)
+    }
+
+    /// The tempo and meter at the given frame.
+    pub fn tempo_at_frame(&self, frame: f64) -> &TempoChange {
+        &self.segment_at_frame(frame).change
+    }
+
+    /// The number of beats, including fractional beats, that have elapsed by `frame`.
+    pub fn beat_at_frame(&self, frame: f64) -> f64 {
+        let s = self.segment_at_frame(frame);
+        s.start_beat + (frame - s.start_frame) / s.frames_per_beat(self.sample_rate)
+    }
+
+    /// The frame at which `beat` occurs.
+    pub fn frame_at_beat(&self, beat: f64) -> f64 {
+        let s = self.segment_at_beat(beat);
+        s.start_frame + (beat - s.start_beat) * s.frames_per_beat(self.sample_rate)
+    }
+
+    /// The frame at which `bar` starts.
+    pub fn frame_at_bar(&self, bar: u32) -> f64 {
+        let s = self
+            .segments
+            .iter()
+            .rev()
+            .find(|s| s.change.bar <= bar)
+            .unwrap_or(&self.segments[0]);
+        let beats = f64::from(bar - s.change.bar) * f64::from(s.change.beats_per_bar);
+        s.start_frame + beats * s.frames_per_beat(self.sample_rate)
+    }
+
+    /// The musical position of `beat`.
+    pub fn position_at_beat(&self, beat: f64) -> Position {
+        let s = self.segment_at_beat(beat);
+        let beats_per_bar = f64::from(s.change.beats_per_bar);
+        let local_beat = (beat - s.start_beat).max(0.0);
+        let bars = (local_beat / beats_per_bar).floor();
+        let beat_in_bar = local_beat - bars * beats_per_bar;
+        Position {
+            bar: s.change.bar + bars as u32,
+            beat: beat_in_bar.floor() as u32,
+            beat_fraction: beat_in_bar.fract(),
+        }
+    }
+
+    /// The musical position of `frame`.
+    pub fn position_at_frame(&self, frame: f64) -> Position {
+        self.position_at_beat(self.beat_at_frame(frame))
+    }
+
+    /// The number of frames in a beat at the given frame.
+    pub fn frames_per_beat_at_frame(&self, frame: f64) -> f64 {
+        self.segment_at_frame(frame)
+            .frames_per_beat(self.sample_rate)
+    }
+
+    fn segment_at_frame(&self, frame: f64) -> &Segment {
+        self.segments
+            .iter()
+            .rev()
+            .find(|s| s.start_frame <= frame)
+            .unwrap_or(&self.segments[0])
+    }
+
+    fn segment_at_beat(&self, beat: f64) -> &Segment {
+        self.segments
+            .iter()
+            .rev()
+            .find(|s| s.start_beat <= beat)
+            .unwrap_or(&self.segments[0])
+    }
+}
+
+impl Default for TempoMap {
+    fn default() -> TempoMap {
+        TempoMap::with_changes(44100.0, &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_tempo_maps_frames_to_beats() {
+        let m = TempoMap::new(48000.0, 120.0, 4);
+        assert_eq!(m.beat_at_frame(24000.0), 1.0);
+        assert_eq!(m.frame_at_beat(3.5), 84000.0);
+        assert_eq!(
+            m.position_at_frame(24000.0 * 6.5),
+            Position {
+                bar: 1,
+                beat: 2,
+                beat_fraction: 0.5
+            }
+        );
+    }
+
+    #[test]
+    fn tempo_changes_take_effect_at_bar() {
+        let m = TempoMap::with_changes(
+            100.0,
+            &[
+                TempoChange {
+                    bar: 2,
+                    bpm: 30.0,
+                    beats_per_bar: 3,
+                },
+                TempoChange {
+                    bar: 0,
+                    bpm: 60.0,
+                    beats_per_bar: 4,
+                },
+            ],
+        );
+        // Two bars of 4 beats at 100 frames per beat.
+        assert_eq!(m.frame_at_bar(2), 800.0);
+        assert_eq!(m.beat_at_frame(800.0), 8.0);
+        // After the change, beats are 200 frames long.
+        assert_eq!(m.beat_at_frame(1000.0), 9.0);
+        assert_eq!(m.frame_at_bar(3), 1400.0);
+        assert_eq!(
+            m.position_at_frame(1400.0),
+            Position {
+                bar: 3,
+                beat: 0,
+                beat_fraction: 0.0
+            }
+        );
+        assert_eq!(m.tempo_at_frame(799.0).bpm, 60.0);
+        assert_eq!(m.tempo_at_frame(800.0).bpm, 30.0);
+    }
+
+    #[test]
+    fn following_anchors_beat_to_frame() {
+        let mut m = TempoMap::new(100.0, 60.0, 4);
+        m.follow(120.0, 3, 1000.0, 7.0);
+        assert_eq!(m.beat_at_frame(1000.0), 7.0);
+        assert_eq!(m.beat_at_frame(1050.0), 8.0);
+        m.follow(f64::INFINITY, 3, 0.0, 0.0);
+        assert_eq!(m.beat_at_frame(1050.0), 8.0);
+        assert_eq!(
+            m.position_at_frame(1050.0),
+            Position {
+                bar: 2,
+                beat: 2,
+                beat_fraction: 0.0
+            }
+        );
+    }
+
+    #[test]
+    fn missing_initial_tempo_uses_default() {
+        let m = TempoMap::with_changes(
+            44100.0,
+            &[TempoChange {
+                bar: 1,
+                bpm: 90.0,
+                beats_per_bar: 3,
+            }],
+        );
+        let changes: Vec<_> = m.changes().copied().collect();
+        assert_eq!(changes[0], TempoChange::default());
+        assert_eq!(changes[1].bpm, 90.0);
+    }
+
+    #[test]
+    fn infinite_tempo_is_ignored() {
+        let m = TempoMap::with_changes(
+            44100.0,
+            &[TempoChange {
+                bar: 0,
+                bpm: f64::INFINITY,
+                beats_per_bar: 4,
+            }],
+        );
+        let changes: Vec<_> = m.changes().copied().collect();
+        assert_eq!(changes, vec![TempoChange::default()]);
+    }
+}
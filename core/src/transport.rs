@@ -0,0 +1,124 @@
+/// The playback and recording state of the processor.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Transport {
+    playing: bool,
+    record_enabled: bool,
+    frame: u64,
+    // The number of frames left in the count-in.
+    count_in: u64,
+}
+
+impl Transport {
+    pub fn new() -> Transport {
+        Transport::default()
+    }
+
+    /// Returns true if the transport is rolling.
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Returns true if audio is being recorded. Recording only happens while the transport is
+    /// both playing and record enabled and has finished counting in.
+    pub fn is_recording(&self) -> bool {
+        self.playing && self.record_enabled && self.count_in == 0
+    }
+
+    pub fn is_record_enabled(&self) -> bool {
+        self.record_enabled
+    }
+
+    /// Returns true if the transport is counting in. The position of the transport does not
+    /// change while counting in.
+    pub fn is_counting_in(&self) -> bool {
+        self.playing && self.count_in > 0
+    }
+
+    /// The position of the transport in frames.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Start or stop the transport. Stopping the transport cancels any count-in.
+    pub fn set_playing(&mut self, playing: bool) {
+        self.playing = playing;
+        if !playing {
+            self.count_in = 0;
+        }
+    }
+
+    /// Count in for the given number of frames before the transport starts advancing.
+    pub fn start_count_in(&mut self, frames: u64) {
+        self.count_in = frames;
+    }
+
+    pub fn set_record_enabled(&mut self, record_enabled: bool) {
+        self.record_enabled = record_enabled;
+    }
+
+    /// Move the transport to the given frame.
+    pub fn locate(&mut self, frame: u64) {
+        self.frame = frame;
+    }
+
+    /// Advance the transport by the given number of frames if it is playing. If the transport
+    /// is counting in, the count-in is advanced instead.
+    pub fn advance(&mut self, frames: usize) {
+        if !self.playing {
+            return;
+        }
+        let count_in_frames = self.count_in.min(frames as u64);
+        self.count_in -= count_in_frames;
+        self.frame += frames as u64 - count_in_frames;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transport_only_advances_while_playing() {
+        let mut t = Transport::new();
+        t.advance(64);
+        assert_eq!(t.frame(), 0);
+
+        t.set_playing(true);
+        t.advance(64);
+        assert_eq!(t.frame(), 64);
+
+        t.locate(10);
+        t.advance(5);
+        assert_eq!(t.frame(), 15);
+    }
+
+    #[test]
+    fn transport_records_only_while_playing() {
+        let mut t = Transport::new();
+        t.set_record_enabled(true);
+        assert!(!t.is_recording());
+
+        t.set_playing(true);
+        assert!(t.is_recording());
+
+        t.set_record_enabled(false);
+        assert!(!t.is_recording());
+    }
+
+    #[test]
+    fn count_in_delays_recording() {
+        let mut t = Transport::new();
+        t.set_record_enabled(true);
+        t.set_playing(true);
+        t.start_count_in(100);
+        assert!(t.is_counting_in());
+        assert!(!t.is_recording());
+
+        t.advance(64);
+        assert_eq!(t.frame(), 0);
+        t.advance(64);
+        assert_eq!(t.frame(), 28);
+        assert!(!t.is_counting_in());
+        assert!(t.is_recording());
+    }
+}